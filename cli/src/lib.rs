@@ -1,10 +1,12 @@
 use std::io;
+use std::path::Path;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 
 use log::{error, info};
 
-use zecwalletlitelib::lightclient::lightclient_config::LightClientConfig;
+use zecwalletlitelib::grpc_connector::server_list::ServerList;
+use zecwalletlitelib::lightclient::lightclient_config::{LightClientConfig, DEFAULT_SYNC_SAVE_INTERVAL};
 use zecwalletlitelib::primitives::consensus::{MainNetwork, Parameters};
 use zecwalletlitelib::{commands, lightclient::LightClient};
 
@@ -46,8 +48,10 @@ macro_rules! configure_clapapp {
             .arg(Arg::with_name("server")
                 .long("server")
                 .value_name("server")
-                .help("Lightwalletd server to connect to.")
+                .help("Lightwalletd server to connect to. Can be given more than once, in which case the first server is used and the others are failed over to, in order.")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .default_value(lightclient::lightclient_config::DEFAULT_SERVER)
                 .takes_value(true))
             .arg(Arg::with_name("data-dir")
@@ -55,6 +59,34 @@ macro_rules! configure_clapapp {
                 .value_name("data-dir")
                 .help("Absolute path to use as data directory")
                 .takes_value(true))
+            .arg(Arg::with_name("block-cache")
+                .long("block-cache")
+                .value_name("size_mb")
+                .help("Keep downloaded compact blocks in a cache of up to this many MB in the data directory, so rescans don't have to download them again. The tree states are cached too. Rescans still need the server for the chain tip and the wallet's transactions")
+                .takes_value(true))
+            .arg(Arg::with_name("save-interval")
+                .long("save-interval")
+                .value_name("blocks")
+                .help("Sync at most this many blocks between saves of the wallet, so an interrupted sync can resume from the last save. The wallet is saved after every batch of blocks, so this limits the size of the batches. 0 doesn't limit them")
+                .takes_value(true))
+            .arg(Arg::with_name("sync-memory")
+                .long("sync-memory")
+                .value_name("size_mb")
+                .help("Keep the memory used by each batch of blocks that is synced within this many MB. Larger values sync faster, but use more memory")
+                .takes_value(true))
+            .arg(Arg::with_name("cross-check")
+                .long("cross-check")
+                .value_name("servers")
+                .help("Before syncing, check that this many of the --server servers agree on the chain, and refuse to sync if they don't")
+                .takes_value(true))
+            .arg(Arg::with_name("checkpoints")
+                .long("checkpoints")
+                .value_name("file")
+                .help("Load extra checkpoints from this JSON file, besides the built-in ones and the ones in zecwallet-light-checkpoints.json in the data directory")
+                .takes_value(true))
+            .arg(Arg::with_name("trust-checkpoints")
+                .long("trust-checkpoints")
+                .help("Use the loaded checkpoints even if they can't be checked against the server. By default they are only used once the server confirms them"))
             .arg(Arg::with_name("COMMAND")
                 .help("Command to execute. If a command is not specified, zecwallet-cli will start in interactive mode.")
                 .required(false)
@@ -82,16 +114,31 @@ pub fn report_permission_error() {
 }
 
 pub fn startup(
-    server: http::Uri,
+    servers: Vec<http::Uri>,
     seed: Option<String>,
     birthday: u64,
     data_dir: Option<String>,
+    block_cache_size: Option<u64>,
+    sync_save_interval: Option<u64>,
+    sync_memory_budget: Option<u64>,
+    cross_check_servers: usize,
+    checkpoints_file: Option<String>,
+    trust_checkpoints: bool,
     first_sync: bool,
     print_updates: bool,
     ledger: bool,
 ) -> io::Result<(Sender<(String, Vec<String>)>, Receiver<String>)> {
     // Try to get the configuration
-    let (config, latest_block_height) = LightClientConfig::<MainNetwork>::create(server.clone(), data_dir)?;
+    let (mut config, latest_block_height) = LightClientConfig::<MainNetwork>::create(servers, data_dir)?;
+    config.block_cache_size = block_cache_size;
+    if let Some(interval) = sync_save_interval {
+        config.sync_save_interval = interval;
+    }
+    if let Some(budget) = sync_memory_budget {
+        config.sync_memory_budget = budget;
+    }
+    config.cross_check_servers = cross_check_servers;
+    config.load_checkpoints(checkpoints_file.as_ref().map(Path::new), trust_checkpoints)?;
 
     let lightclient = match seed {
         Some(phrase) => Arc::new(LightClient::new_from_phrase(phrase, &config, birthday, false)?),
@@ -116,7 +163,8 @@ pub fn startup(
     info!("Light Client config {:?}", config);
 
     if print_updates {
-        println!("Lightclient connecting to {}", config.server);
+        let servers = config.servers.uris().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        println!("Lightclient connecting to {}", servers.join(", "));
     }
 
     // At startup, run a sync.
@@ -247,12 +295,17 @@ pub fn command_loop<P: Parameters + Send + Sync + 'static>(
 pub fn attempt_recover_seed(_password: Option<String>) {
     // Create a Light Client Config in an attempt to recover the file.
     let _config = LightClientConfig::<MainNetwork> {
-        server: "0.0.0.0:0".parse().unwrap(),
+        servers: ServerList::new(vec!["0.0.0.0:0".parse().unwrap()]),
+        cross_check_servers: 0,
         chain_name: "main".to_string(),
         sapling_activation_height: 0,
         anchor_offset: [0u32; 5],
         monitor_mempool: false,
         data_dir: None,
+        block_cache_size: None,
+        sync_save_interval: DEFAULT_SYNC_SAVE_INTERVAL,
+        sync_memory_budget: 0,
+        checkpoints: vec![],
         params: MainNetwork,
     };
 }
@@ -27,10 +27,65 @@ pub fn main() {
         .or(Some(vec![]))
         .unwrap();
 
-    let maybe_server = matches.value_of("server").map(|s| s.to_string());
+    let maybe_servers = matches
+        .values_of("server")
+        .map(|v| v.map(|s| s.to_string()).collect::<Vec<_>>())
+        .unwrap_or(vec![]);
 
     let maybe_data_dir = matches.value_of("data-dir").map(|s| s.to_string());
 
+    let block_cache_size = match matches.value_of("block-cache").map(|s| s.parse::<u64>()) {
+        Some(Ok(mb)) => Some(mb * 1024 * 1024),
+        Some(Err(e)) => {
+            eprintln!(
+                "Couldn't parse block cache size. This should be a number of MB. Error={}",
+                e
+            );
+            return;
+        }
+        None => None,
+    };
+
+    let sync_save_interval = match matches.value_of("save-interval").map(|s| s.parse::<u64>()) {
+        Some(Ok(blocks)) => Some(blocks),
+        Some(Err(e)) => {
+            eprintln!(
+                "Couldn't parse save interval. This should be a number of blocks. Error={}",
+                e
+            );
+            return;
+        }
+        None => None,
+    };
+
+    let sync_memory_budget = match matches.value_of("sync-memory").map(|s| s.parse::<u64>()) {
+        Some(Ok(mb)) if mb > 0 => Some(mb * 1024 * 1024),
+        Some(Ok(_)) => {
+            eprintln!("The sync memory should be at least 1 MB");
+            return;
+        }
+        Some(Err(e)) => {
+            eprintln!("Couldn't parse sync memory. This should be a number of MB. Error={}", e);
+            return;
+        }
+        None => None,
+    };
+
+    let cross_check_servers = match matches.value_of("cross-check").map(|s| s.parse::<usize>()) {
+        Some(Ok(n)) => n,
+        Some(Err(e)) => {
+            eprintln!(
+                "Couldn't parse cross-check. This should be a number of servers. Error={}",
+                e
+            );
+            return;
+        }
+        None => 0,
+    };
+
+    let checkpoints_file = matches.value_of("checkpoints").map(|s| s.to_string());
+    let trust_checkpoints = matches.is_present("trust-checkpoints");
+
     let seed = matches.value_of("seed").map(|s| s.to_string());
     let ledger = matches.is_present("ledger");
     let maybe_birthday = matches.value_of("birthday");
@@ -50,13 +105,31 @@ pub fn main() {
         }
     };
 
-    let server = LightClientConfig::<MainNetwork>::get_server_or_default(maybe_server);
+    let servers = if maybe_servers.is_empty() {
+        vec![LightClientConfig::<MainNetwork>::get_server_or_default(None)]
+    } else {
+        maybe_servers
+            .into_iter()
+            .map(|s| LightClientConfig::<MainNetwork>::get_server_or_default(Some(s)))
+            .collect::<Vec<_>>()
+    };
+
+    // Test to make sure the servers have all of scheme, host and port
+    for server in servers.iter() {
+        if server.scheme_str().is_none() || server.host().is_none() || server.port().is_none() {
+            eprintln!(
+                "Please provide the --server parameter as [scheme]://[host]:[port].\nYou provided: {}",
+                server
+            );
+            return;
+        }
+    }
 
-    // Test to make sure the server has all of scheme, host and port
-    if server.scheme_str().is_none() || server.host().is_none() || server.port().is_none() {
+    if cross_check_servers > servers.len() {
         eprintln!(
-            "Please provide the --server parameter as [scheme]://[host]:[port].\nYou provided: {}",
-            server
+            "Can't cross-check with {} servers when only {} were provided with --server",
+            cross_check_servers,
+            servers.len()
         );
         return;
     }
@@ -64,10 +137,16 @@ pub fn main() {
     let nosync = matches.is_present("nosync");
 
     let startup_chan = startup(
-        server,
+        servers,
         seed,
         birthday,
         maybe_data_dir,
+        block_cache_size,
+        sync_save_interval,
+        sync_memory_budget,
+        cross_check_servers,
+        checkpoints_file,
+        trust_checkpoints,
         !nosync,
         command.is_none(),
         ledger,
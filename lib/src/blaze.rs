@@ -1,8 +1,12 @@
+pub(super) mod batch_sizer;
+pub(super) mod block_cache;
 pub(super) mod block_witness_data;
+pub(super) mod cross_check;
 pub(super) mod fetch_compact_blocks;
 pub(super) mod fetch_full_tx;
 pub(super) mod fetch_taddr_txns;
 pub(super) mod sync_status;
+pub(super) mod sync_token;
 pub(super) mod syncdata;
 pub(super) mod trial_decryptions;
 pub(super) mod update_notes;
@@ -0,0 +1,119 @@
+use std::cmp;
+
+// Nothing is known about the size of the blocks before the first batch, so it is kept small, assuming heavy blocks
+const INITIAL_BATCH_SIZE: u64 = 1_000;
+const INITIAL_BYTES_PER_BLOCK: u64 = 100 * 1024;
+const MIN_BATCH_SIZE: u64 = 100;
+const MAX_BATCH_SIZE: u64 = 50_000;
+
+// A batch can be at most this many times larger than the one before it, so a sudden jump in the size of the blocks
+// doesn't overshoot the memory budget by much
+const MAX_GROWTH: u64 = 4;
+
+// Rough memory used for every output while a batch is processed, besides the compact block itself: its commitment
+// in the sapling tree, the trial decryption and the nullifier and witness updates
+const OUTPUT_OVERHEAD: u64 = 256;
+
+/// Picks how many blocks to sync in a batch, so that everything kept in memory for the batch fits in the memory
+/// budget. The memory used per block is learned from the bytes and the outputs of the batches that were synced
+/// before, so it follows the size of the blocks on any chain and at any height.
+#[derive(Debug)]
+pub struct BatchSizer {
+    memory_budget: u64,
+
+    // Estimated memory used per block, None until a batch was synced
+    bytes_per_block: Option<u64>,
+    last_batch_size: u64,
+}
+
+impl BatchSizer {
+    pub fn new(memory_budget: u64) -> Self {
+        Self {
+            memory_budget,
+            bytes_per_block: None,
+            last_batch_size: INITIAL_BATCH_SIZE,
+        }
+    }
+
+    /// Record the `bytes` and `outputs` in the `blocks` of a batch that was synced
+    pub fn record_batch(&mut self, blocks: u64, bytes: u64, outputs: u64) {
+        if blocks == 0 {
+            return;
+        }
+
+        let observed = cmp::max(1, (bytes + outputs * OUTPUT_OVERHEAD) / blocks);
+
+        // Heavier blocks are taken into account right away, but lighter blocks only slowly, since spam tends to come
+        // and go
+        self.bytes_per_block = Some(match self.bytes_per_block {
+            Some(estimate) if observed < estimate => (estimate + observed) / 2,
+            _ => observed,
+        });
+        self.last_batch_size = blocks;
+    }
+
+    pub fn next_batch_size(&self) -> u64 {
+        let size = match self.bytes_per_block {
+            Some(b) => cmp::min(self.memory_budget / b, self.last_batch_size * MAX_GROWTH),
+            None => cmp::min(self.memory_budget / INITIAL_BYTES_PER_BLOCK, INITIAL_BATCH_SIZE),
+        };
+
+        cmp::max(MIN_BATCH_SIZE, cmp::min(MAX_BATCH_SIZE, size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BatchSizer, INITIAL_BATCH_SIZE, MAX_BATCH_SIZE, MIN_BATCH_SIZE};
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn follows_block_size() {
+        let mut sizer = BatchSizer::new(100 * MB);
+        assert_eq!(sizer.next_batch_size(), INITIAL_BATCH_SIZE);
+
+        // Small blocks let the batches grow, but only a few times at a time
+        sizer.record_batch(1_000, 1_000 * 200, 0);
+        assert_eq!(sizer.next_batch_size(), 4_000);
+        sizer.record_batch(4_000, 4_000 * 200, 0);
+        assert_eq!(sizer.next_batch_size(), 16_000);
+        sizer.record_batch(16_000, 16_000 * 200, 0);
+        assert_eq!(sizer.next_batch_size(), MAX_BATCH_SIZE);
+
+        // Big blocks shrink the next batch right away, so it fits in the budget
+        sizer.record_batch(MAX_BATCH_SIZE, MAX_BATCH_SIZE * 50 * 1024, MAX_BATCH_SIZE * 100);
+        let size = sizer.next_batch_size();
+        assert!(size * (50 * 1024 + 100 * 256) <= 100 * MB);
+        assert!(size > 1_000);
+
+        // Huge blocks can't make the batches smaller than the minimum
+        sizer.record_batch(size, size * 10 * MB, 0);
+        assert_eq!(sizer.next_batch_size(), MIN_BATCH_SIZE);
+
+        // And once the blocks are small again, the batches grow back slowly
+        sizer.record_batch(MIN_BATCH_SIZE, MIN_BATCH_SIZE * 200, 0);
+        assert_eq!(sizer.next_batch_size(), MIN_BATCH_SIZE);
+    }
+
+    #[test]
+    fn empty_batch() {
+        let mut sizer = BatchSizer::new(100 * MB);
+        sizer.record_batch(0, 0, 0);
+        assert_eq!(sizer.next_batch_size(), INITIAL_BATCH_SIZE);
+
+        // Even empty blocks use some memory
+        sizer.record_batch(1_000, 0, 0);
+        assert_eq!(sizer.next_batch_size(), 4_000);
+    }
+
+    #[test]
+    fn small_budget() {
+        // The first batch already fits in the budget
+        let sizer = BatchSizer::new(10 * MB);
+        assert_eq!(sizer.next_batch_size(), 102);
+
+        let sizer = BatchSizer::new(1);
+        assert_eq!(sizer.next_batch_size(), MIN_BATCH_SIZE);
+    }
+}
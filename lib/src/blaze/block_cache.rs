@@ -0,0 +1,747 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+use prost::Message;
+use zcash_primitives::block::BlockHash;
+
+use crate::{
+    compact_formats::{CompactBlock, TreeState},
+    lightclient::lightclient_config::MAX_REORG,
+};
+
+const DATA_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+const TAIL_FILE: &str = "tail.dat";
+const TREES_FILE: &str = "trees.dat";
+
+// The index starts with a version and the spam filter threshold the blocks were fetched with
+const INDEX_HEADER_SIZE: u64 = 16;
+// Each index entry is the height, the offset of the block in the data file and its length
+const INDEX_ENTRY_SIZE: u64 = 20;
+// The most blocks fetched to link the tail up with the chain tip. If the tip is further up, the tail is dropped
+const MAX_TAIL_GAP: u64 = 1_000;
+
+/// An on-disk cache of the compact blocks and tree states fetched from the server, so rescans and interrupted syncs
+/// don't have to download them again.
+///
+/// The blocks are appended to a data file, and their height, offset and length are appended to an index file. The
+/// block is always written before its index entry, so if the wallet is killed halfway through a write, at most some
+/// unindexed bytes are left at the end of the data file. Blocks are only removed by `clear()`, or by `remove_above()`
+/// after a reorg deeper than MAX_REORG. Once the data file reaches `max_size`, no more blocks are added.
+///
+/// The last MAX_REORG blocks can still be reorged, so they are kept apart in a tail file. Whenever the chain tip moves,
+/// `verify_tail()` follows the prev_hash links from the tip down through the tail, drops the blocks that aren't on the
+/// chain any more, and moves the ones that are now MAX_REORG blocks deep to the data file.
+///
+/// Tree states are kept in a file of their own. A tree state is only used if the cached block above it links to its
+/// block, so a tree state of a reorged block is never used.
+pub struct BlockCache {
+    dir: PathBuf,
+    max_size: u64,
+
+    // Blocks less than MAX_REORG blocks below the tip go in the tail, since they can still be reorged
+    chain_tip: u64,
+
+    // Loaded from the index file when the cache is first used
+    index: Option<HashMap<u64, (u64, u32)>>,
+    spam_filter_threshold: i64,
+    data_size: u64,
+
+    // Kept open while the cache is in use, so reading a run of blocks doesn't open the data file for every block
+    data: Option<File>,
+
+    // The blocks that can still be reorged, and the tree states, loaded together with the index
+    tail: BTreeMap<u64, CompactBlock>,
+    tree_states: HashMap<u64, TreeState>,
+}
+
+impl BlockCache {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            max_size,
+            chain_tip: 0,
+            index: None,
+            spam_filter_threshold: -1,
+            data_size: 0,
+            data: None,
+            tail: BTreeMap::new(),
+            tree_states: HashMap::new(),
+        }
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.dir.join(DATA_FILE)
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX_FILE)
+    }
+
+    fn tail_path(&self) -> PathBuf {
+        self.dir.join(TAIL_FILE)
+    }
+
+    fn trees_path(&self) -> PathBuf {
+        self.dir.join(TREES_FILE)
+    }
+
+    pub fn set_chain_tip(&mut self, chain_tip: u64) {
+        self.chain_tip = chain_tip;
+    }
+
+    /// Load the index from disk, if it isn't loaded yet. Blocks fetched with a different spam filter threshold are
+    /// missing some outputs, so if the threshold changed, the cache is cleared.
+    pub fn open(&mut self, spam_filter_threshold: i64) -> io::Result<()> {
+        if self.index.is_some() && self.spam_filter_threshold == spam_filter_threshold {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        self.data_size = fs::metadata(self.data_path()).map(|m| m.len()).unwrap_or(0);
+
+        let index = match self.read_index()? {
+            Some((threshold, index)) if threshold == spam_filter_threshold => index,
+            Some(_) => {
+                info!("Spam filter threshold changed, clearing the block cache");
+                self.clear()?;
+                self.write_index(spam_filter_threshold, &HashMap::new())?;
+                HashMap::new()
+            }
+            None => {
+                self.write_index(spam_filter_threshold, &HashMap::new())?;
+                HashMap::new()
+            }
+        };
+
+        self.data = Some(
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.data_path())?,
+        );
+        self.tail = read_messages::<CompactBlock>(&self.tail_path())?
+            .into_iter()
+            .map(|cb| (cb.height, cb))
+            .collect();
+        self.tree_states = read_messages::<TreeState>(&self.trees_path())?
+            .into_iter()
+            .map(|ts| (ts.height, ts))
+            .collect();
+
+        info!(
+            "Opened block cache at {:?} with {} blocks",
+            self.dir,
+            index.len() + self.tail.len()
+        );
+        self.index = Some(index);
+        self.spam_filter_threshold = spam_filter_threshold;
+
+        Ok(())
+    }
+
+    fn read_index(&self) -> io::Result<Option<(i64, HashMap<u64, (u64, u32)>)>> {
+        let mut f = match File::open(self.index_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut buf = vec![];
+        f.read_to_end(&mut buf)?;
+        if (buf.len() as u64) < INDEX_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut reader = &buf[..];
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version > Self::serialized_version() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Can't read block cache version {}", version),
+            ));
+        }
+        let threshold = reader.read_i64::<LittleEndian>()?;
+
+        // A partly written entry at the end, or entries of blocks that aren't in the data file, are dropped
+        let num_entries = (buf.len() as u64 - INDEX_HEADER_SIZE) / INDEX_ENTRY_SIZE;
+        let mut index = HashMap::new();
+        let mut dropped = false;
+        for _ in 0..num_entries {
+            let height = reader.read_u64::<LittleEndian>()?;
+            let offset = reader.read_u64::<LittleEndian>()?;
+            let len = reader.read_u32::<LittleEndian>()?;
+
+            if offset + len as u64 <= self.data_size {
+                index.insert(height, (offset, len));
+            } else {
+                dropped = true;
+            }
+        }
+
+        if dropped || INDEX_HEADER_SIZE + num_entries * INDEX_ENTRY_SIZE != buf.len() as u64 {
+            warn!("Block cache index was damaged, keeping {} of its blocks", index.len());
+            self.write_index(threshold, &index)?;
+        }
+
+        Ok(Some((threshold, index)))
+    }
+
+    // Write out a whole new index file
+    fn write_index(&self, spam_filter_threshold: i64, index: &HashMap<u64, (u64, u32)>) -> io::Result<()> {
+        let mut buf = vec![];
+        buf.write_u64::<LittleEndian>(Self::serialized_version())?;
+        buf.write_i64::<LittleEndian>(spam_filter_threshold)?;
+
+        for (height, (offset, len)) in index {
+            buf.write_u64::<LittleEndian>(*height)?;
+            buf.write_u64::<LittleEndian>(*offset)?;
+            buf.write_u32::<LittleEndian>(*len)?;
+        }
+
+        fs::write(self.index_path(), buf)
+    }
+
+    pub fn contains(&self, height: u64) -> bool {
+        self.tail.contains_key(&height) || self.index.as_ref().map(|i| i.contains_key(&height)).unwrap_or(false)
+    }
+
+    pub fn get(&self, height: u64) -> Option<CompactBlock> {
+        if let Some(cb) = self.tail.get(&height) {
+            return Some(cb.clone());
+        }
+
+        let (offset, len) = *self.index.as_ref()?.get(&height)?;
+
+        let read = || -> io::Result<CompactBlock> {
+            let mut f = self
+                .data
+                .as_ref()
+                .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "The data file isn't open"))?;
+            f.seek(SeekFrom::Start(offset))?;
+
+            let mut buf = vec![0u8; len as usize];
+            f.read_exact(&mut buf)?;
+
+            CompactBlock::decode(&buf[..]).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+        };
+
+        match read() {
+            Ok(cb) if cb.height == height => Some(cb),
+            Ok(cb) => {
+                warn!("Block cache has block {} at the index of block {}", cb.height, height);
+                None
+            }
+            Err(e) => {
+                warn!("Couldn't read block {} from the block cache: {}", height, e);
+                None
+            }
+        }
+    }
+
+    /// Add the blocks that aren't cached already. Blocks within MAX_REORG of the tip go in the tail, where they
+    /// replace the blocks of another chain at the same heights.
+    /// Returns the number of blocks added.
+    pub fn insert(&mut self, blocks: &[CompactBlock]) -> io::Result<usize> {
+        if self.index.is_none() {
+            return Ok(0);
+        }
+
+        let mut deep = vec![];
+        let mut tail_added = 0;
+        for block in blocks {
+            if block.height + MAX_REORG as u64 > self.chain_tip {
+                if self.tail.get(&block.height) != Some(block) {
+                    self.tail.insert(block.height, block.clone());
+                    tail_added += 1;
+                }
+            } else if !self.contains(block.height) {
+                deep.push(block);
+            }
+        }
+
+        if tail_added > 0 {
+            self.write_tail()?;
+        }
+
+        Ok(self.append(&deep)? + tail_added)
+    }
+
+    // Append blocks to the data file and the index, until the data file is full
+    fn append(&mut self, blocks: &[&CompactBlock]) -> io::Result<usize> {
+        let (data, index) = match (&self.data, &mut self.index) {
+            (Some(data), Some(index)) => (data, index),
+            _ => return Ok(0),
+        };
+        let mut index_file = OpenOptions::new().append(true).open(self.dir.join(INDEX_FILE))?;
+
+        let mut added = 0;
+        for block in blocks {
+            let mut buf = vec![];
+            block
+                .encode(&mut buf)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+            if self.data_size + buf.len() as u64 > self.max_size {
+                info!(
+                    "Block cache is full at {} bytes, not caching any more blocks",
+                    self.data_size
+                );
+                break;
+            }
+
+            let offset = self.data_size;
+            let mut data = data;
+            data.write_all(&buf)?;
+            data.flush()?;
+            self.data_size += buf.len() as u64;
+
+            let mut entry = vec![];
+            entry.write_u64::<LittleEndian>(block.height)?;
+            entry.write_u64::<LittleEndian>(offset)?;
+            entry.write_u32::<LittleEndian>(buf.len() as u32)?;
+            index_file.write_all(&entry)?;
+
+            index.insert(block.height, (offset, buf.len() as u32));
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    fn write_tail(&self) -> io::Result<()> {
+        write_messages(&self.tail_path(), self.tail.values())
+    }
+
+    /// The blocks from the top of the tail up to the chain tip, as (start, end) from the top down. They have to be
+    /// added before `verify_tail()`, so the tail can be linked up with the tip. `None` if the tail is empty, already
+    /// reaches the tip, or is more than MAX_TAIL_GAP blocks below it.
+    pub fn tail_gap(&self) -> Option<(u64, u64)> {
+        let top = *self.tail.keys().next_back()?;
+        if top >= self.chain_tip || top + MAX_TAIL_GAP < self.chain_tip {
+            return None;
+        }
+
+        Some((self.chain_tip, top + 1))
+    }
+
+    /// Check the tail against the chain tip, whose block hash is `tip_hash`. Going down from the tip, every block has
+    /// to be the one the block above it points to. The blocks from the first one that isn't are dropped, since they
+    /// are from another chain, or can't be checked. The blocks that are now MAX_REORG blocks deep are moved to the
+    /// data file. Returns the number of blocks dropped.
+    pub fn verify_tail(&mut self, tip_hash: &[u8]) -> io::Result<usize> {
+        if self.index.is_none() {
+            return Ok(0);
+        }
+
+        let before = self.tail.len();
+        let mut linked = BTreeMap::new();
+        let mut expected = tip_hash.to_vec();
+        let mut height = self.chain_tip;
+        while let Some(cb) = self.tail.remove(&height) {
+            if cb.hash != expected {
+                break;
+            }
+
+            expected = cb.prev_hash.clone();
+            linked.insert(height, cb);
+            if height == 0 {
+                break;
+            }
+            height -= 1;
+        }
+
+        let dropped = before - linked.len();
+        if dropped > 0 {
+            info!(
+                "Dropped {} blocks that aren't on the chain ending at {} from the block cache",
+                dropped, self.chain_tip
+            );
+        }
+
+        // The tail is now the blocks that can still be reorged, and the ones below them are appended to the data file
+        self.tail = linked.split_off(&(self.chain_tip + 1).saturating_sub(MAX_REORG as u64));
+        let deep = linked
+            .values()
+            .filter(|cb| !self.contains(cb.height))
+            .collect::<Vec<_>>();
+        self.append(&deep)?;
+        self.write_tail()?;
+
+        Ok(dropped)
+    }
+
+    /// The tree state at `height`, if it is cached and the cached block above it points to its block
+    pub fn get_tree_state(&self, height: u64) -> Option<TreeState> {
+        let tree_state = self.tree_states.get(&height)?;
+        let above = self.get(height + 1)?;
+        if above.prev_hash.len() != 32 || BlockHash::from_slice(&above.prev_hash).to_string() != tree_state.hash {
+            return None;
+        }
+
+        Some(tree_state.clone())
+    }
+
+    /// The highest tree state below `height` that can be used, if there is one at most `max_blocks` below it
+    pub fn tree_state_below(&self, height: u64, max_blocks: u64) -> Option<TreeState> {
+        let mut heights = self
+            .tree_states
+            .keys()
+            .filter(|h| **h < height && **h + max_blocks >= height)
+            .copied()
+            .collect::<Vec<_>>();
+        heights.sort_unstable();
+
+        heights.into_iter().rev().find_map(|h| self.get_tree_state(h))
+    }
+
+    /// Add a tree state fetched from the server. It is only used once the block above it is cached
+    pub fn insert_tree_state(&mut self, tree_state: &TreeState) -> io::Result<()> {
+        if self.index.is_none() || self.tree_states.get(&tree_state.height) == Some(tree_state) {
+            return Ok(());
+        }
+
+        let mut f = OpenOptions::new().create(true).append(true).open(self.trees_path())?;
+        f.write_all(&encode_message(tree_state)?)?;
+        self.tree_states.insert(tree_state.height, tree_state.clone());
+
+        Ok(())
+    }
+
+    /// Number of cached blocks and the size of the data file
+    pub fn stats(&self) -> (usize, u64) {
+        (
+            self.index.as_ref().map(|i| i.len()).unwrap_or(0) + self.tail.len(),
+            self.data_size,
+        )
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Drop the blocks and tree states above `height`, after a reorg deeper than MAX_REORG replaced them. The bytes of
+    /// the blocks stay in the data file until the cache is cleared. Returns the number of blocks removed.
+    pub fn remove_above(&mut self, height: u64) -> io::Result<usize> {
+        let mut index = match self.index.take() {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+
+        let before = index.len() + self.tail.len();
+        index.retain(|h, _| *h <= height);
+        self.tail.retain(|h, _| *h <= height);
+        let removed = before - index.len() - self.tail.len();
+
+        let num_trees = self.tree_states.len();
+        self.tree_states.retain(|h, _| *h <= height);
+        let trees_removed = num_trees - self.tree_states.len();
+
+        let res = (|| {
+            if removed > 0 {
+                self.write_index(self.spam_filter_threshold, &index)?;
+                self.write_tail()?;
+            }
+            if trees_removed > 0 {
+                write_messages(&self.trees_path(), self.tree_states.values())?;
+            }
+            Ok::<_, io::Error>(())
+        })();
+        self.index = Some(index);
+
+        res.map(|_| removed)
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        // Close the data file before removing it
+        self.data = None;
+
+        for path in [self.data_path(), self.index_path(), self.tail_path(), self.trees_path()] {
+            match fs::remove_file(path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.index = None;
+        self.data_size = 0;
+        self.tail.clear();
+        self.tree_states.clear();
+
+        Ok(())
+    }
+}
+
+// A message with its length in front, as it is stored in the tail and tree state files
+fn encode_message<M: Message>(message: &M) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.write_u32::<LittleEndian>(message.encoded_len() as u32)?;
+    message
+        .encode(&mut buf)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    Ok(buf)
+}
+
+fn write_messages<'a, M: Message + 'a>(path: &Path, messages: impl Iterator<Item = &'a M>) -> io::Result<()> {
+    let mut buf = vec![];
+    for message in messages {
+        buf.extend(encode_message(message)?);
+    }
+
+    fs::write(path, buf)
+}
+
+// Read all the messages in a file. A partly written message at the end is dropped, and the file is written again
+// without it, so the next message is appended after the last complete one
+fn read_messages<M: Message + Default>(path: &Path) -> io::Result<Vec<M>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut messages = vec![];
+    let mut reader = &buf[..];
+    while reader.len() >= 4 {
+        let len = (&reader[..4]).read_u32::<LittleEndian>()? as usize;
+        match reader.get(4..4 + len).map(|b| M::decode(b)) {
+            Some(Ok(message)) => messages.push(message),
+            _ => break,
+        }
+        reader = &reader[4 + len..];
+    }
+
+    if !reader.is_empty() {
+        warn!("{:?} was damaged, keeping {} of its entries", path, messages.len());
+        write_messages(path, messages.iter())?;
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::OpenOptions, io::Write};
+
+    use tempdir::TempDir;
+
+    use zcash_primitives::block::BlockHash;
+
+    use super::{BlockCache, MAX_TAIL_GAP};
+    use crate::compact_formats::{CompactBlock, TreeState};
+
+    fn block(height: u64) -> CompactBlock {
+        let mut cb = CompactBlock::default();
+        cb.height = height;
+        cb.hash = vec![height as u8; 32];
+        cb.prev_hash = vec![(height - 1) as u8; 32];
+        cb
+    }
+
+    // The block of another chain at the same height
+    fn reorged(height: u64) -> CompactBlock {
+        let mut cb = block(height);
+        cb.hash[0] ^= 0xff;
+        cb
+    }
+
+    #[test]
+    fn insert_and_reopen() {
+        let dir = TempDir::new("blockcache").unwrap();
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.set_chain_tip(250);
+
+        // Nothing is cached until the cache is opened
+        assert_eq!(cache.insert(&[block(10)]).unwrap(), 0);
+
+        cache.open(-1).unwrap();
+        let blocks = (100..=200).rev().map(block).collect::<Vec<_>>();
+
+        // Blocks within MAX_REORG of the tip are cached in the tail
+        assert_eq!(cache.insert(&blocks).unwrap(), 101);
+        assert!(cache.contains(150));
+        assert!(cache.contains(151));
+        assert_eq!(cache.get(120).unwrap(), block(120));
+        assert_eq!(cache.get(190).unwrap(), block(190));
+
+        // Already cached blocks are skipped
+        assert_eq!(cache.insert(&blocks).unwrap(), 0);
+
+        // Everything is still there after reopening
+        let (num, size) = cache.stats();
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.open(-1).unwrap();
+        assert_eq!(cache.stats(), (num, size));
+        assert_eq!(cache.get(100).unwrap(), block(100));
+        assert_eq!(cache.get(200).unwrap(), block(200));
+
+        // A different spam filter threshold clears the cache
+        cache.open(50).unwrap();
+        assert_eq!(cache.stats(), (0, 0));
+        assert!(cache.get(100).is_none());
+    }
+
+    #[test]
+    fn tail_follows_the_tip() {
+        let dir = TempDir::new("blockcache").unwrap();
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.set_chain_tip(200);
+        cache.open(-1).unwrap();
+        cache.insert(&(100..=200).rev().map(block).collect::<Vec<_>>()).unwrap();
+        assert_eq!(cache.verify_tail(&block(200).hash).unwrap(), 0);
+        let size = cache.stats().1;
+
+        // When the tip moves, the blocks up to it are needed to link the tail up with it
+        cache.set_chain_tip(210);
+        assert_eq!(cache.tail_gap(), Some((210, 201)));
+        cache.insert(&(201..=210).rev().map(block).collect::<Vec<_>>()).unwrap();
+        assert_eq!(cache.tail_gap(), None);
+
+        // Blocks that far up aren't fetched just for the tail
+        cache.set_chain_tip(210 + MAX_TAIL_GAP + 1);
+        assert_eq!(cache.tail_gap(), None);
+        cache.set_chain_tip(210);
+
+        // The blocks that are deep enough now are moved to the data file
+        assert_eq!(cache.verify_tail(&block(210).hash).unwrap(), 0);
+        assert_eq!(cache.stats().0, 111);
+        assert!(cache.stats().1 > size);
+        assert_eq!(cache.get(110).unwrap(), block(110));
+
+        // The tree state of a block is used if the block above it points to it
+        let mut tree_state = TreeState::default();
+        tree_state.height = 205;
+        tree_state.hash = BlockHash::from_slice(&block(205).hash).to_string();
+        tree_state.tree = "00".to_string();
+        cache.insert_tree_state(&tree_state).unwrap();
+        assert_eq!(cache.get_tree_state(205).unwrap(), tree_state);
+        assert!(cache.get_tree_state(204).is_none());
+
+        // A reorg of the blocks from 206 up is found by following the links down from the new tip. The blocks below
+        // the reorged ones in the tail can't be linked up with the tip any more either
+        let mut new_chain = (206..=212).rev().map(reorged).collect::<Vec<_>>();
+        for cb in new_chain.iter_mut().filter(|cb| cb.height > 206) {
+            cb.prev_hash = reorged(cb.height - 1).hash;
+        }
+        cache.set_chain_tip(212);
+        assert_eq!(cache.tail_gap(), Some((212, 211)));
+        cache.insert(&new_chain[..2]).unwrap();
+
+        assert_eq!(cache.verify_tail(&reorged(212).hash).unwrap(), 100);
+        assert_eq!(cache.get(212).unwrap(), reorged(212));
+        assert!(!cache.contains(210));
+        assert!(!cache.contains(150));
+        assert!(cache.contains(110));
+        assert!(cache.get_tree_state(205).is_none());
+
+        // The blocks of the new chain can be cached in the tail again, and everything is still there after reopening
+        cache.insert(&new_chain[2..]).unwrap();
+        cache.insert(&(111..=205).rev().map(block).collect::<Vec<_>>()).unwrap();
+        assert_eq!(cache.verify_tail(&reorged(212).hash).unwrap(), 0);
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.open(-1).unwrap();
+        assert_eq!(cache.get(206).unwrap(), reorged(206));
+        assert_eq!(cache.get(205).unwrap(), block(205));
+        assert_eq!(cache.stats().0, 113);
+
+        // A tip that doesn't match the tail drops all of it
+        cache.set_chain_tip(212);
+        assert_eq!(cache.verify_tail(&block(212).hash).unwrap(), 100);
+        assert_eq!(cache.stats().0, 13);
+    }
+
+    #[test]
+    fn size_cap_and_clear() {
+        let dir = TempDir::new("blockcache").unwrap();
+
+        let mut one = vec![];
+        prost::Message::encode(&block(1), &mut one).unwrap();
+
+        // Room for 3 blocks
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), one.len() as u64 * 3);
+        cache.set_chain_tip(1_000);
+        cache.open(-1).unwrap();
+
+        let blocks = (1..=5).map(block).collect::<Vec<_>>();
+        assert_eq!(cache.insert(&blocks).unwrap(), 3);
+        assert!(!cache.contains(4));
+
+        cache.clear().unwrap();
+        assert_eq!(cache.stats(), (0, 0));
+        assert!(!cache.contains(1));
+        assert!(!dir.path().join(super::DATA_FILE).exists());
+
+        cache.open(-1).unwrap();
+        assert_eq!(cache.insert(&blocks).unwrap(), 3);
+    }
+
+    #[test]
+    fn remove_above() {
+        let dir = TempDir::new("blockcache").unwrap();
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.set_chain_tip(1_000);
+        cache.open(-1).unwrap();
+        cache.insert(&(1..=10).map(block).collect::<Vec<_>>()).unwrap();
+
+        assert_eq!(cache.remove_above(6).unwrap(), 4);
+        assert!(cache.contains(6));
+        assert!(!cache.contains(7));
+
+        // The blocks of the new chain can be cached at the same heights
+        let mut reorged = block(8);
+        reorged.hash = vec![0xff; 32];
+        assert_eq!(cache.insert(&[reorged.clone()]).unwrap(), 1);
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.open(-1).unwrap();
+        assert_eq!(cache.stats().0, 7);
+        assert!(cache.get(9).is_none());
+        assert_eq!(cache.get(8).unwrap(), reorged);
+    }
+
+    #[test]
+    fn torn_index_entry() {
+        let dir = TempDir::new("blockcache").unwrap();
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.set_chain_tip(1_000);
+        cache.open(-1).unwrap();
+        cache.insert(&[block(1), block(2)]).unwrap();
+
+        // Simulate a crash while writing an index entry
+        OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(super::INDEX_FILE))
+            .unwrap()
+            .write_all(&[1, 2, 3])
+            .unwrap();
+
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.set_chain_tip(1_000);
+        cache.open(-1).unwrap();
+        assert_eq!(cache.stats().0, 2);
+
+        cache.insert(&[block(3)]).unwrap();
+        let mut cache = BlockCache::new(dir.path().to_path_buf(), 1_000_000);
+        cache.open(-1).unwrap();
+        assert_eq!(cache.get(3).unwrap(), block(3));
+        assert_eq!(cache.get(2).unwrap(), block(2));
+    }
+}
@@ -1,35 +1,53 @@
 use crate::{
     compact_formats::{CompactBlock, CompactTx, TreeState},
-    grpc_connector::GrpcConnector,
+    grpc_connector::server_list::ServerList,
     lightclient::{
         checkpoints::get_all_main_checkpoints,
         lightclient_config::{LightClientConfig, MAX_REORG},
     },
     lightwallet::{
         data::{BlockData, WalletTx, WitnessCache},
+        events::WalletEvent,
         wallet_txns::WalletTxns,
     },
 };
 
 use futures::{stream::FuturesOrdered, StreamExt};
-use http::Uri;
-use std::{sync::Arc, time::Duration};
+use std::{
+    cmp,
+    io::{self, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     sync::{
         mpsc::{self, Sender, UnboundedSender},
-        RwLock,
+        Mutex, RwLock,
     },
     task::{yield_now, JoinHandle},
     time::sleep,
 };
+use zcash_encoding::{Optional, Vector};
 use zcash_primitives::{
     consensus::{self, BlockHeight},
-    merkle_tree::{CommitmentTree, IncrementalWitness},
+    merkle_tree::{CommitmentTree, Hashable, IncrementalWitness},
     sapling::{Node, Nullifier},
     transaction::TxId,
 };
 
-use super::{fixed_size_buffer::FixedSizeBuffer, sync_status::SyncStatus};
+use super::{
+    block_cache::BlockCache,
+    fetch_compact_blocks::get_sapling_tree,
+    fixed_size_buffer::FixedSizeBuffer,
+    sync_status::SyncStatus,
+    sync_token::{SyncToken, SYNC_CANCELLED},
+};
+
+// The server sent blocks that don't form a chain. Unlike a reorg, this can't be fixed by re-fetching blocks
+pub const INCONSISTENT_CHAIN: &str = "Server served an inconsistent chain";
+
+// Every block the wallet keeps for reorgs was reorged, so there is no telling how far back the reorg goes
+pub const DEEP_REORG: &str = "Reorg is deeper than the blocks the wallet keeps";
 
 pub struct BlockAndWitnessData {
     // List of all blocks and their hashes/commitment trees. Stored from smallest block height to tallest block height
@@ -90,6 +108,11 @@ impl BlockAndWitnessData {
         self.existing_blocks.write().await.extend(existing_blocks);
     }
 
+    // Tree states that the downloaded blocks have to match, checked by `verify_sapling_tree` at the end of the sync
+    pub async fn add_verification_trees(&self, trees: Vec<TreeState>) {
+        self.verification_list.write().await.extend(trees);
+    }
+
     // Finish up the sync. This method will delete all the elements in the blocks, and return
     // the top `num` blocks
     pub async fn finish_get_blocks(&self, num: usize) -> Vec<BlockData> {
@@ -242,8 +265,10 @@ impl BlockAndWitnessData {
             .collect::<FuturesOrdered<_>>();
 
         while let Some(r) = handles.next().await {
-            if r.is_err() {
-                return (false, None);
+            match r {
+                Ok(true) => (),
+                // Either a tree didn't match the blocks, or the task verifying it failed
+                _ => return (false, None),
             }
         }
 
@@ -263,7 +288,9 @@ impl BlockAndWitnessData {
         }
 
         // Remove all wallet txns at the height
-        wallet_txns.write().await.remove_txns_at_height(reorg_height);
+        let mut txns = wallet_txns.write().await;
+        txns.remove_txns_at_height(reorg_height);
+        txns.events.emit(WalletEvent::ReorgDetected { height: reorg_height });
     }
 
     /// Start a new sync where we ingest all the blocks
@@ -273,6 +300,7 @@ impl BlockAndWitnessData {
         end_block: u64,
         wallet_txns: Arc<RwLock<WalletTxns>>,
         reorg_tx: UnboundedSender<Option<u64>>,
+        sync_token: Arc<SyncToken>,
     ) -> (JoinHandle<Result<u64, String>>, Sender<CompactBlock>) {
         //info!("Starting node and witness sync");
         let batch_size = self.batch_size;
@@ -295,12 +323,36 @@ impl BlockAndWitnessData {
         let h0: JoinHandle<Result<u64, String>> = tokio::spawn(async move {
             // Temporary holding place for blocks while we process them.
             let mut blks = vec![];
+            let mut blks_outputs = 0;
             let mut earliest_block_height = 0;
 
             // Reorg stuff
             let mut last_block_expecting = end_block;
 
+            // The height and prev_hash of the block received before this one, which is the block right above it
+            let mut above: Option<(u64, Vec<u8>)> = None;
+            let mut inconsistent = None;
+            let mut deep_reorg = None;
+
             while let Some(cb) = rx.recv().await {
+                sync_token.wait_while_paused().await;
+
+                // Every block has to link to the block above it. A reorg only happens at the bottom of the range,
+                // where the blocks are checked against the wallet's blocks below
+                if let Some((above_height, above_prev_hash)) = &above {
+                    if cb.height + 1 != *above_height || &cb.hash != above_prev_hash {
+                        inconsistent = Some(format!(
+                            "{}: block {} doesn't link to block {}",
+                            INCONSISTENT_CHAIN, cb.height, above_height
+                        ));
+
+                        // Keep it along with the others, since the other processors might be waiting on it
+                        blks.push(BlockData::new(cb));
+                        break;
+                    }
+                }
+                above = Some((cb.height, cb.prev_hash.clone()));
+
                 //println!("block_witness recieved {:?}", cb.height);
                 // We'll process batch_size (1_000) blocks at a time.
                 // println!("Recieved block # {}", cb.height);
@@ -308,7 +360,8 @@ impl BlockAndWitnessData {
                     // println!("Batch size hit at height {} with len {}", cb.height, blks.len());
                     if !blks.is_empty() {
                         // Add these blocks to the list
-                        sync_status.write().await.blocks_done += blks.len() as u64;
+                        Self::add_blocks_done(&sync_status, &blks, blks_outputs).await;
+                        blks_outputs = 0;
                         blocks.write().await.append(&mut blks);
                     }
                 }
@@ -326,7 +379,14 @@ impl BlockAndWitnessData {
                             }
                         }
                         None => {
-                            // There is no top wallet block, so we can't really check for reorgs.
+                            // There is no top wallet block, so we can't really check for reorgs. But if there was
+                            // one before this reorg, the reorg went below all of the wallet's blocks
+                            if last_block_expecting != end_block {
+                                deep_reorg = Some(format!(
+                                    "{}: all the blocks down to {} were reorged",
+                                    DEEP_REORG, last_block_expecting
+                                ));
+                            }
                             None
                         }
                     };
@@ -340,6 +400,7 @@ impl BlockAndWitnessData {
                 }
 
                 earliest_block_height = cb.height;
+                blks_outputs += cb.vtx.iter().map(|tx| tx.outputs.len() as u64).sum::<u64>();
                 blks.push(BlockData::new(cb));
             }
 
@@ -350,10 +411,20 @@ impl BlockAndWitnessData {
             // );
             if !blks.is_empty() {
                 // We'll now dispatch these blocks for updating the witness
-                sync_status.write().await.blocks_done += blks.len() as u64;
+                Self::add_blocks_done(&sync_status, &blks, blks_outputs).await;
                 blocks.write().await.append(&mut blks);
             }
 
+            if let Some(e) = inconsistent.or(deep_reorg) {
+                return Err(e);
+            }
+
+            // If the sync was cancelled, the fetcher stopped before sending all the blocks. The blocks that did
+            // arrive are still added above, since the other processors might be waiting on them.
+            if sync_token.is_cancelled() {
+                return Err(SYNC_CANCELLED.to_string());
+            }
+
             Ok(earliest_block_height)
         });
 
@@ -369,6 +440,15 @@ impl BlockAndWitnessData {
         return (h, tx);
     }
 
+    // Count the blocks that are handed to the other processors, along with their size and outputs, which are used to
+    // size the next batch
+    async fn add_blocks_done(sync_status: &RwLock<SyncStatus>, blks: &[BlockData], outputs: u64) {
+        let mut sync_status = sync_status.write().await;
+        sync_status.blocks_done += blks.len() as u64;
+        sync_status.blocks_bytes += blks.iter().map(|b| b.ecb.len() as u64).sum::<u64>();
+        sync_status.blocks_outputs += outputs;
+    }
+
     async fn wait_for_first_block(&self) -> u64 {
         while self.blocks.read().await.is_empty() {
             yield_now().await;
@@ -432,7 +512,8 @@ impl BlockAndWitnessData {
 
     pub async fn get_note_witness(
         &self,
-        uri: Uri,
+        servers: ServerList,
+        block_cache: Option<Arc<Mutex<BlockCache>>>,
         height: BlockHeight,
         tx_num: usize,
         output_num: usize,
@@ -445,7 +526,13 @@ impl BlockAndWitnessData {
             let tree = if prev_height < self.sapling_activation_height {
                 CommitmentTree::empty()
             } else {
-                let tree_state = GrpcConnector::get_sapling_tree(uri, prev_height).await?;
+                let tree_state = get_sapling_tree(
+                    servers,
+                    block_cache.as_deref(),
+                    self.sapling_activation_height,
+                    prev_height,
+                )
+                .await?;
                 let sapling_tree = hex::decode(&tree_state.tree).unwrap();
                 // self.verification_list.write().await.push(tree_state);
                 CommitmentTree::read(&sapling_tree[..]).map_err(|e| format!("{}", e))?
@@ -588,15 +675,107 @@ impl BlockAndWitnessData {
     }
 }
 
+// The left and right leaves and the parents of a commitment tree, which CommitmentTree doesn't expose. They are read
+// from the tree's serialization.
+type TreeParts = (Option<Node>, Option<Node>, Vec<Option<Node>>);
+
+fn read_tree_parts<R: Read>(mut reader: R) -> io::Result<TreeParts> {
+    let left = Optional::read(&mut reader, |r| Node::read(r))?;
+    let right = Optional::read(&mut reader, |r| Node::read(r))?;
+    let parents = Vector::read(&mut reader, |r| Optional::read(r, |r| Node::read(r)))?;
+
+    Ok((left, right, parents))
+}
+
+fn write_tree_parts<W: Write>(mut writer: W, (left, right, parents): &TreeParts) -> io::Result<()> {
+    Optional::write(&mut writer, left.as_ref(), |w, n| n.write(w))?;
+    Optional::write(&mut writer, right.as_ref(), |w, n| n.write(w))?;
+    Vector::write(&mut writer, parents, |w, p| {
+        Optional::write(w, p.as_ref(), |w, n| n.write(w))
+    })
+}
+
+/// Rewind `witness` to when the commitment tree was `tree`, dropping the commitments that were appended to it after
+/// that. Returns None if the note isn't in `tree`, if the witness doesn't go as far as `tree`, or if the rewound witness
+/// doesn't add up to `tree`.
+///
+/// Only the complete subtrees of the witness that are inside `tree` are kept. The partly filled subtree after them is
+/// taken from the bottom of `tree`, so this works even if the witness went on with commitments from another chain.
+pub(crate) fn rewind_witness(
+    witness: &IncrementalWitness<Node>,
+    tree: &CommitmentTree<Node>,
+) -> Option<IncrementalWitness<Node>> {
+    // A witness is serialized as the tree up to the note, the subtrees filled in after it, and the partly filled
+    // subtree after those
+    let mut bytes = vec![];
+    witness.write(&mut bytes).ok()?;
+    let mut reader = &bytes[..];
+    let note_tree = CommitmentTree::<Node>::read(&mut reader).ok()?;
+    let filled = Vector::read(&mut reader, |r| Node::read(r)).ok()?;
+
+    let mut remaining = tree.size().checked_sub(note_tree.size())?;
+
+    // The subtrees after the note are filled in at the depths of the empty slots of the note's tree, from the bottom
+    // up, and then at ever larger depths above it
+    let mut note_tree_bytes = vec![];
+    note_tree.write(&mut note_tree_bytes).ok()?;
+    let (note_left, note_right, note_parents) = read_tree_parts(&note_tree_bytes[..]).ok()?;
+    let mut depths = [note_left.is_none(), note_right.is_none()]
+        .iter()
+        .map(|empty| (*empty, 0))
+        .chain(note_parents.iter().enumerate().map(|(i, p)| (p.is_none(), i + 1)))
+        .filter(|(empty, _)| *empty)
+        .map(|(_, depth)| depth)
+        .chain(note_parents.len() + 1..);
+
+    let mut tree_bytes = vec![];
+    tree.write(&mut tree_bytes).ok()?;
+    let (left, right, parents) = read_tree_parts(&tree_bytes[..]).ok()?;
+
+    let mut kept = vec![];
+    let mut cursor = None;
+    while remaining > 0 {
+        let depth = depths.next()?;
+        if remaining >= 1 << depth {
+            // This subtree is complete in `tree`, so it was complete in the witness too
+            kept.push(filled.get(kept.len()).cloned()?);
+            remaining -= 1 << depth;
+        } else {
+            // The subtree is only partly filled, and it is at the bottom right of `tree`, so its leaves and parents are
+            // the ones of `tree` below its depth
+            let mut parents = parents[..cmp::min(depth - 1, parents.len())].to_vec();
+            while parents.last() == Some(&None) {
+                parents.pop();
+            }
+            cursor = Some((left, right, parents));
+            break;
+        }
+    }
+
+    let mut bytes = vec![];
+    note_tree.write(&mut bytes).ok()?;
+    Vector::write(&mut bytes, &kept, |w, n| n.write(w)).ok()?;
+    Optional::write(&mut bytes, cursor.as_ref(), |w, c| write_tree_parts(w, c)).ok()?;
+    let rewound = IncrementalWitness::<Node>::read(&bytes[..]).ok()?;
+
+    // If the witness went on with another chain before the end of `tree`, its subtrees won't add up to `tree`
+    if rewound.root() != tree.root() {
+        return None;
+    }
+
+    Some(rewound)
+}
+
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
 
     use crate::blaze::sync_status::SyncStatus;
+    use crate::blaze::sync_token::{SyncToken, SYNC_CANCELLED};
     use crate::lightclient::lightclient_config::UnitTestNetwork;
     use crate::lightwallet::wallet_txns::WalletTxns;
     use crate::{
-        blaze::test_utils::{FakeCompactBlock, FakeCompactBlockList},
+        blaze::test_utils::{random_u8_32, FakeCompactBlock, FakeCompactBlockList},
         lightclient::lightclient_config::LightClientConfig,
         lightwallet::data::BlockData,
     };
@@ -606,8 +785,10 @@ mod test {
     use tokio::sync::RwLock;
     use tokio::{sync::mpsc::unbounded_channel, task::JoinHandle};
     use zcash_primitives::block::BlockHash;
+    use zcash_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
+    use zcash_primitives::sapling::Node;
 
-    use super::BlockAndWitnessData;
+    use super::{BlockAndWitnessData, DEEP_REORG, INCONSISTENT_CHAIN};
 
     #[tokio::test]
     async fn setup_finish_simple() {
@@ -670,6 +851,7 @@ mod test {
                 end_block,
                 Arc::new(RwLock::new(WalletTxns::new())),
                 reorg_tx,
+                Arc::new(SyncToken::new()),
             )
             .await;
 
@@ -691,6 +873,101 @@ mod test {
         try_join_all(vec![send_h]).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn cancelled_sync() {
+        let mut config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        config.sapling_activation_height = 1;
+
+        let blocks = FakeCompactBlockList::new(200).into_blockdatas();
+
+        let start_block = blocks.first().unwrap().height;
+        let end_block = blocks.last().unwrap().height;
+
+        let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
+        let mut nw = BlockAndWitnessData::new(&config, sync_status);
+        nw.setup_sync(vec![], None).await;
+
+        let (reorg_tx, _reorg_rx) = unbounded_channel();
+        let sync_token = Arc::new(SyncToken::new());
+
+        let (h, cb_sender) = nw
+            .start(
+                start_block,
+                end_block,
+                Arc::new(RwLock::new(WalletTxns::new())),
+                reorg_tx,
+                sync_token.clone(),
+            )
+            .await;
+
+        // Only the top 80 blocks are sent before the sync is cancelled
+        for block in blocks.iter().take(80) {
+            cb_sender.send(block.cb()).await.unwrap();
+        }
+        sync_token.cancel();
+        drop(cb_sender);
+
+        assert_eq!(h.await.unwrap().unwrap_err(), SYNC_CANCELLED);
+
+        // The blocks that were received are still available to the other processors
+        assert_eq!(nw.blocks.read().await.len(), 80);
+        assert_eq!(nw.blocks.read().await.first().unwrap().height, start_block);
+    }
+
+    #[tokio::test]
+    async fn inconsistent_chain() {
+        let mut config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        config.sapling_activation_height = 1;
+
+        let mut blocks = FakeCompactBlockList::new(200).into_blockdatas();
+
+        // Break the link between blocks 100 and 99 (blocks are in reverse order)
+        {
+            let mut cb = blocks[100].cb();
+            assert_eq!(cb.height, 100);
+
+            let mut hash = [0u8; 32];
+            OsRng.fill_bytes(&mut hash);
+            cb.prev_hash = hash.to_vec();
+            blocks[100] = BlockData::new(cb);
+        }
+
+        let start_block = blocks.first().unwrap().height;
+        let end_block = blocks.last().unwrap().height;
+
+        let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
+        let mut nw = BlockAndWitnessData::new(&config, sync_status);
+        nw.setup_sync(vec![], None).await;
+
+        let (reorg_tx, _reorg_rx) = unbounded_channel();
+
+        let (h, cb_sender) = nw
+            .start(
+                start_block,
+                end_block,
+                Arc::new(RwLock::new(WalletTxns::new())),
+                reorg_tx,
+                Arc::new(SyncToken::new()),
+            )
+            .await;
+
+        // The processor stops listening at the broken link, so the rest of the blocks can't be sent
+        tokio::spawn(async move {
+            for block in blocks {
+                if cb_sender.send(block.cb()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let e = h.await.unwrap().unwrap_err();
+        assert!(e.starts_with(INCONSISTENT_CHAIN));
+        assert!(e.contains("block 99 doesn't link to block 100"));
+
+        // No reorg was needed, and the blocks up to the broken link were kept
+        assert_eq!(nw.blocks.read().await.len(), 102);
+    }
+
     #[tokio::test]
     async fn with_existing_batched() {
         let mut config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
@@ -718,6 +995,7 @@ mod test {
                 end_block,
                 Arc::new(RwLock::new(WalletTxns::new())),
                 reorg_tx,
+                Arc::new(SyncToken::new()),
             )
             .await;
 
@@ -811,6 +1089,7 @@ mod test {
                 end_block,
                 Arc::new(RwLock::new(WalletTxns::new())),
                 reorg_tx,
+                Arc::new(SyncToken::new()),
             )
             .await;
 
@@ -860,4 +1139,138 @@ mod test {
             assert_eq!(finished_blks[i].hash(), finished_blks[i].cb().hash().to_string());
         }
     }
+
+    #[tokio::test]
+    async fn deep_reorg() {
+        let mut config = LightClientConfig::create_unconnected(UnitTestNetwork, None);
+        config.sapling_activation_height = 1;
+
+        let mut blocks = FakeCompactBlockList::new(100).into_blockdatas();
+
+        // The wallet only kept blocks 46-50, and the new chain replaces all of them
+        let mut existing_blocks = blocks.split_off(50);
+        existing_blocks.truncate(5);
+
+        let mut hash = [0u8; 32];
+        OsRng.fill_bytes(&mut hash);
+        {
+            let mut cb = blocks.pop().unwrap().cb();
+            cb.prev_hash = hash.to_vec();
+            blocks.push(BlockData::new(cb));
+        }
+
+        let mut reorged_blocks = vec![];
+        for b in existing_blocks.iter() {
+            let mut cb = b.cb();
+            cb.hash = hash.to_vec();
+            OsRng.fill_bytes(&mut hash);
+            cb.prev_hash = hash.to_vec();
+            reorged_blocks.push(BlockData::new(cb));
+        }
+
+        let start_block = blocks.first().unwrap().height;
+        let end_block = blocks.last().unwrap().height;
+
+        let sync_status = Arc::new(RwLock::new(SyncStatus::default()));
+        let mut nw = BlockAndWitnessData::new(&config, sync_status);
+        nw.setup_sync(existing_blocks, None).await;
+
+        let (reorg_tx, mut reorg_rx) = unbounded_channel();
+
+        let (h, cb_sender) = nw
+            .start(
+                start_block,
+                end_block,
+                Arc::new(RwLock::new(WalletTxns::new())),
+                reorg_tx,
+                Arc::new(SyncToken::new()),
+            )
+            .await;
+
+        let send_h: JoinHandle<Result<(), String>> = tokio::spawn(async move {
+            for block in blocks {
+                cb_sender
+                    .send(block.cb())
+                    .await
+                    .map_err(|e| format!("Couldn't send block: {}", e))?;
+            }
+
+            // Every one of the wallet's blocks is asked for again, and then there's nothing left to compare with
+            let mut reorged_blocks = reorged_blocks.into_iter();
+            while let Some(Some(h)) = reorg_rx.recv().await {
+                let cb = reorged_blocks.next().unwrap().cb();
+                assert_eq!(h, cb.height);
+
+                cb_sender
+                    .send(cb)
+                    .await
+                    .map_err(|e| format!("Couldn't send block: {}", e))?;
+            }
+            assert!(reorged_blocks.next().is_none());
+
+            Ok(())
+        });
+
+        let e = h.await.unwrap().unwrap_err();
+        assert!(e.starts_with(DEEP_REORG));
+        assert!(e.ends_with("down to 46 were reorged"));
+
+        try_join_all(vec![send_h]).await.unwrap();
+    }
+
+    #[test]
+    fn rewind_witness() {
+        let random_node = || Node::new(random_u8_32());
+        let witness_to_string = |w: &IncrementalWitness<Node>| {
+            let mut b = vec![];
+            w.write(&mut b).unwrap();
+            hex::encode(b)
+        };
+
+        for note_pos in [0, 1, 5, 12, 33] {
+            // Witness a note, and keep the tree and the witness after every commitment that follows it
+            let mut tree = CommitmentTree::<Node>::empty();
+            for _ in 0..note_pos {
+                tree.append(random_node()).unwrap();
+            }
+            tree.append(random_node()).unwrap();
+
+            let mut witness = IncrementalWitness::from_tree(&tree);
+            let mut history = vec![(tree.clone(), witness.clone())];
+            for _ in 0..70 {
+                let node = random_node();
+                tree.append(node).unwrap();
+                witness.append(node).unwrap();
+                history.push((tree.clone(), witness.clone()));
+            }
+
+            // Rewinding the latest witness to any earlier tree gives the witness as it was then
+            for (tree, expected) in &history {
+                let rewound = super::rewind_witness(&witness, tree).unwrap();
+                assert_eq!(witness_to_string(&rewound), witness_to_string(expected));
+                assert_eq!(rewound.root(), tree.root());
+            }
+
+            // The witness can't be rewound to before the note
+            let mut before = CommitmentTree::<Node>::empty();
+            for _ in 0..note_pos {
+                before.append(random_node()).unwrap();
+            }
+            assert!(super::rewind_witness(&witness, &before).is_none());
+
+            // A witness that went on with another chain can still be rewound to before the fork, but not to after it
+            let mut forked = history[20].1.clone();
+            for _ in 0..50 {
+                forked.append(random_node()).unwrap();
+            }
+            for (tree, expected) in &history[..=20] {
+                let rewound = super::rewind_witness(&forked, tree).unwrap();
+                assert_eq!(witness_to_string(&rewound), witness_to_string(expected));
+            }
+            assert!(super::rewind_witness(&forked, &history[70].0).is_none());
+
+            // And a witness can't be rewound to a tree it is far behind
+            assert!(super::rewind_witness(&history[10].1, &history[70].0).is_none());
+        }
+    }
 }
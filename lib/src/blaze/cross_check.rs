@@ -0,0 +1,114 @@
+use std::collections::BTreeSet;
+
+use futures::future::join_all;
+use log::{info, warn};
+use rand::Rng;
+use zcash_primitives::{merkle_tree::CommitmentTree, sapling::Node};
+
+use crate::{
+    compact_formats::TreeState,
+    grpc_connector::{server_list::ServerList, GrpcConnector},
+    lightclient::lightclient_config::MAX_REORG,
+};
+
+// How many random heights below the reorg window are compared, besides the tip and the edge of the window
+const SAMPLED_HEIGHTS: usize = 3;
+
+/// Ask several servers about the chain, so a single server can't feed us a fake one. The servers have to agree on the
+/// block hash and the sapling tree at the tip, at the edge of the reorg window, and at a few random heights from
+/// `start_height` up. Disagreeing inside the reorg window is only a warning, since the servers may not have seen the
+/// same reorg yet. Returns the tree states that all the servers agree on, so they can be verified against the blocks
+/// that are downloaded.
+pub async fn cross_check_servers(servers: &[http::Uri], start_height: u64) -> Result<Vec<TreeState>, String> {
+    // Ask every server on its own, without failing over to another one
+    let servers = servers
+        .iter()
+        .map(|s| ServerList::new(vec![s.clone()]))
+        .collect::<Vec<_>>();
+    let names = servers.iter().map(|s| s.current().to_string()).collect::<Vec<_>>();
+
+    let tips = join_all(servers.iter().map(|s| GrpcConnector::get_latest_block(s.clone())))
+        .await
+        .into_iter()
+        .zip(names.iter())
+        .map(|(r, name)| r.map_err(|e| format!("Couldn't cross-check the tip with {}: {}", name, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let min_tip = tips.iter().map(|b| b.height).min().unwrap_or(0);
+    let max_tip = tips.iter().map(|b| b.height).max().unwrap_or(0);
+    if max_tip - min_tip > MAX_REORG as u64 {
+        return Err(format!(
+            "Servers disagree about the chain tip: {}",
+            describe(&names, tips.iter().map(|b| b.height.to_string()))
+        ));
+    }
+
+    // Anything at or below this height can't be reorged anymore, so all servers have to agree on it
+    let window_edge = min_tip.saturating_sub(MAX_REORG as u64);
+
+    let mut heights = BTreeSet::new();
+    heights.insert(min_tip);
+    if window_edge >= start_height {
+        heights.insert(window_edge);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..SAMPLED_HEIGHTS {
+            heights.insert(rng.gen_range(start_height..=window_edge));
+        }
+    }
+
+    let mut agreed = vec![];
+    for height in heights {
+        let trees = join_all(
+            servers
+                .iter()
+                .map(|s| GrpcConnector::get_sapling_tree(s.clone(), height)),
+        )
+        .await
+        .into_iter()
+        .zip(names.iter())
+        .map(|(r, name)| r.map_err(|e| format!("Couldn't cross-check block {} with {}: {}", height, name, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let roots = trees
+            .iter()
+            .map(|t| tree_root(t).map(|root| (t.hash.clone(), root)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if roots.iter().all(|r| *r == roots[0]) {
+            agreed.push(trees[0].clone());
+        } else {
+            let e = format!(
+                "Servers disagree about block {}: {}",
+                height,
+                describe(&names, trees.iter().map(|t| t.hash.clone()))
+            );
+
+            if height <= window_edge {
+                return Err(e);
+            }
+            warn!("{}, which is inside the reorg window", e);
+        }
+    }
+
+    info!("{} servers agree on the chain up to {}", servers.len(), min_tip);
+    Ok(agreed)
+}
+
+fn tree_root(tree_state: &TreeState) -> Result<Node, String> {
+    let bytes = hex::decode(&tree_state.tree)
+        .map_err(|e| format!("Couldn't decode the tree at {}: {}", tree_state.height, e))?;
+    let tree = CommitmentTree::<Node>::read(&bytes[..])
+        .map_err(|e| format!("Couldn't read the tree at {}: {}", tree_state.height, e))?;
+
+    Ok(tree.root())
+}
+
+fn describe(names: &[String], values: impl Iterator<Item = String>) -> String {
+    names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{} has {}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,19 +1,34 @@
 use std::{cmp::max, sync::Arc};
 
 use crate::{
-    compact_formats::CompactBlock, grpc_connector::GrpcConnector, lightclient::lightclient_config::LightClientConfig,
+    compact_formats::{BlockId, CompactBlock, TreeState},
+    grpc_connector::{server_list::ServerList, GrpcConnector},
+    lightclient::lightclient_config::LightClientConfig,
 };
-use log::info;
-use tokio::sync::mpsc::{Sender, UnboundedReceiver};
-use zcash_primitives::consensus;
+use log::{info, warn};
+use tokio::sync::{
+    mpsc::{Sender, UnboundedReceiver},
+    Mutex,
+};
+use zcash_primitives::{block::BlockHash, consensus, merkle_tree::CommitmentTree, sapling::Node};
+
+use super::{block_cache::BlockCache, sync_token::SyncToken};
+
+// A tree state that isn't cached is built from an earlier one and the cached blocks above it, as long as there aren't
+// more than this many blocks to go through
+const MAX_TREE_BLOCKS: u64 = 10_000;
 
 pub struct FetchCompactBlocks<P> {
     config: LightClientConfig<P>,
+    block_cache: Option<Arc<Mutex<BlockCache>>>,
 }
 
 impl<P: consensus::Parameters> FetchCompactBlocks<P> {
-    pub fn new(config: &LightClientConfig<P>) -> Self {
-        Self { config: config.clone() }
+    pub fn new(config: &LightClientConfig<P>, block_cache: Option<Arc<Mutex<BlockCache>>>) -> Self {
+        Self {
+            config: config.clone(),
+            block_cache,
+        }
     }
 
     async fn fetch_blocks_range(
@@ -22,28 +37,148 @@ impl<P: consensus::Parameters> FetchCompactBlocks<P> {
         start_block: u64,
         end_block: u64,
         spam_filter_threshold: i64,
+        sync_token: &SyncToken,
     ) -> Result<(), String> {
-        let grpc_client = Arc::new(GrpcConnector::new(self.config.server.clone()));
+        let grpc_client = Arc::new(GrpcConnector::new(self.config.servers.clone()));
         const STEP: u64 = 1_000;
 
         // We need the `rev()` here because rust ranges can only go up
         for b in (end_block..(start_block + 1)).rev().step_by(STEP as usize) {
+            // Stop fetching if the sync was cancelled, and wait here if it was paused
+            sync_token.checkpoint().await?;
+
             let start = b;
             let end = max((b as i64) - (STEP as i64) + 1, end_block as i64) as u64;
             if start < end {
                 return Err(format!("Wrong block order"));
             }
 
-            info!("Fetching blocks {}-{}", start, end);
+            match &self.block_cache {
+                Some(cache) => {
+                    self.fetch_blocks_range_cached(&grpc_client, cache, receivers, start, end, spam_filter_threshold)
+                        .await?
+                }
+                None => {
+                    info!("Fetching blocks {}-{}", start, end);
 
-            grpc_client
-                .get_block_range(start, end, spam_filter_threshold, receivers)
-                .await?;
+                    grpc_client
+                        .get_block_range(start, end, spam_filter_threshold, receivers)
+                        .await?
+                }
+            }
         }
 
         Ok(())
     }
 
+    // Send the blocks from `start` down to `end`, reading the ones that are in the block cache from disk, and
+    // fetching the rest from the server, adding them to the cache.
+    async fn fetch_blocks_range_cached(
+        &self,
+        grpc_client: &GrpcConnector,
+        cache: &Mutex<BlockCache>,
+        receivers: &[Sender<CompactBlock>; 2],
+        start: u64,
+        end: u64,
+        spam_filter_threshold: i64,
+    ) -> Result<(), String> {
+        let mut height = start;
+        loop {
+            // Find the run of blocks from `height` down that are all cached, or all not cached
+            let (cached, run_end) = {
+                let cache = cache.lock().await;
+                let cached = cache.contains(height);
+
+                let mut run_end = height;
+                while run_end > end && cache.contains(run_end - 1) == cached {
+                    run_end -= 1;
+                }
+                (cached, run_end)
+            };
+
+            let blocks = if cached {
+                let cache = cache.lock().await;
+                (run_end..=height)
+                    .rev()
+                    .map(|h| cache.get(h))
+                    .collect::<Option<Vec<_>>>()
+            } else {
+                None
+            };
+
+            let blocks = match blocks {
+                Some(blocks) => blocks,
+                None => {
+                    if cached {
+                        warn!(
+                            "Couldn't read blocks {}-{} from the block cache, fetching them",
+                            height, run_end
+                        );
+                    }
+
+                    info!("Fetching blocks {}-{}", height, run_end);
+                    let blocks = grpc_client
+                        .download_block_range(height, run_end, spam_filter_threshold)
+                        .await?;
+
+                    if let Err(e) = cache.lock().await.insert(&blocks) {
+                        warn!("Couldn't add blocks to the block cache: {}", e);
+                    }
+
+                    blocks
+                }
+            };
+
+            for block in blocks {
+                receivers[0].send(block.clone()).await.map_err(|e| format!("{}", e))?;
+                receivers[1].send(block).await.map_err(|e| format!("{}", e))?;
+            }
+
+            if run_end == end {
+                return Ok(());
+            }
+            height = run_end - 1;
+        }
+    }
+
+    /// Tell the block cache about the new chain tip, so it can check the blocks that could have been reorged since
+    /// they were cached. The blocks between the cached ones and the tip are fetched for that.
+    pub async fn update_chain_tip(&self, tip: &BlockId, spam_filter_threshold: i64) {
+        let cache = match &self.block_cache {
+            Some(cache) => cache,
+            None => return,
+        };
+
+        let mut cache = cache.lock().await;
+        if let Err(e) = cache.open(spam_filter_threshold) {
+            warn!("Couldn't open the block cache: {}", e);
+            return;
+        }
+        cache.set_chain_tip(tip.height);
+
+        // Without the tip's hash the blocks can't be checked, so there is no use fetching the ones up to the tip, and
+        // `verify_tail()` drops them all
+        let gap = if tip.hash.is_empty() { None } else { cache.tail_gap() };
+        if let Some((start, end)) = gap {
+            info!(
+                "Fetching blocks {}-{} to check the block cache against the chain tip",
+                start, end
+            );
+            let blocks = GrpcConnector::new(self.config.servers.clone())
+                .download_block_range(start, end, spam_filter_threshold)
+                .await
+                .and_then(|blocks| cache.insert(&blocks).map_err(|e| e.to_string()));
+
+            if let Err(e) = blocks {
+                warn!("Couldn't add blocks {}-{} to the block cache: {}", start, end, e);
+            }
+        }
+
+        if let Err(e) = cache.verify_tail(&tip.hash) {
+            warn!("Couldn't check the block cache against the chain tip: {}", e);
+        }
+    }
+
     // Load all the blocks from LightwalletD
     pub async fn start(
         &self,
@@ -52,19 +187,29 @@ impl<P: consensus::Parameters> FetchCompactBlocks<P> {
         end_block: u64,
         spam_filter_threshold: i64,
         mut reorg_rx: UnboundedReceiver<Option<u64>>,
+        sync_token: Arc<SyncToken>,
     ) -> Result<(), String> {
         if start_block < end_block {
             return Err(format!("Expected blocks in reverse order"));
         }
 
+        if let Some(cache) = &self.block_cache {
+            if let Err(e) = cache.lock().await.open(spam_filter_threshold) {
+                warn!(
+                    "Couldn't open the block cache, fetching all blocks from the server: {}",
+                    e
+                );
+            }
+        }
+
         //info!("Starting fetch compact blocks");
-        self.fetch_blocks_range(&receivers, start_block, end_block, spam_filter_threshold)
+        self.fetch_blocks_range(&receivers, start_block, end_block, spam_filter_threshold, &sync_token)
             .await?;
 
         // After fetching all the normal blocks, we actually wait to see if any re-org'd blocks are recieved
         while let Some(Some(reorg_block)) = reorg_rx.recv().await {
             // Fetch the additional block.
-            self.fetch_blocks_range(&receivers, reorg_block, reorg_block, spam_filter_threshold)
+            self.fetch_blocks_range(&receivers, reorg_block, reorg_block, spam_filter_threshold, &sync_token)
                 .await?;
         }
 
@@ -72,3 +217,98 @@ impl<P: consensus::Parameters> FetchCompactBlocks<P> {
         Ok(())
     }
 }
+
+/// Get the tree state at `height` from the block cache, or from the server, adding it to the cache
+pub async fn get_sapling_tree(
+    servers: ServerList,
+    block_cache: Option<&Mutex<BlockCache>>,
+    sapling_activation_height: u64,
+    height: u64,
+) -> Result<TreeState, String> {
+    let cache = match block_cache {
+        Some(cache) => cache,
+        None => return GrpcConnector::get_sapling_tree(servers, height).await,
+    };
+
+    if let Some(tree_state) = cached_sapling_tree(cache, sapling_activation_height, height).await {
+        return Ok(tree_state);
+    }
+
+    let tree_state = GrpcConnector::get_sapling_tree(servers, height).await?;
+    if let Err(e) = cache.lock().await.insert_tree_state(&tree_state) {
+        warn!("Couldn't add the tree state at {} to the block cache: {}", height, e);
+    }
+
+    Ok(tree_state)
+}
+
+/// The tree state at `height` from the block cache. If it isn't cached, it is built up from the closest cached tree
+/// state below it, or the empty tree before sapling activation, and the outputs of the cached blocks in between.
+pub async fn cached_sapling_tree(
+    cache: &Mutex<BlockCache>,
+    sapling_activation_height: u64,
+    height: u64,
+) -> Option<TreeState> {
+    // Only collect the outputs while holding the lock, the tree is built after letting go of it
+    let (start, nodes, top_hash) = {
+        let cache = cache.lock().await;
+        if let Some(tree_state) = cache.get_tree_state(height) {
+            return Some(tree_state);
+        }
+
+        let start = match cache.tree_state_below(height, MAX_TREE_BLOCKS) {
+            Some(tree_state) => Some(tree_state),
+            None if height < sapling_activation_height + MAX_TREE_BLOCKS => None,
+            None => return None,
+        };
+        let start_height = match &start {
+            Some(tree_state) => tree_state.height,
+            None => sapling_activation_height.saturating_sub(1),
+        };
+
+        // The blocks have to follow each other, the first one is already checked against the start tree state
+        let mut nodes = vec![];
+        let mut top_hash = None;
+        for h in (start_height + 1)..=height {
+            let cb = cache.get(h)?;
+            if top_hash.as_ref().map_or(false, |hash| *hash != cb.prev_hash) {
+                return None;
+            }
+
+            for co in cb.vtx.iter().flat_map(|ctx| ctx.outputs.iter()) {
+                if co.cmu.len() != 32 {
+                    return None;
+                }
+                nodes.push(Node::new(co.cmu().ok()?.into()));
+            }
+            top_hash = Some(cb.hash);
+        }
+
+        (start, nodes, top_hash?)
+    };
+
+    let mut tree = match &start {
+        Some(tree_state) => CommitmentTree::<Node>::read(&hex::decode(&tree_state.tree).ok()?[..]).ok()?,
+        None => CommitmentTree::empty(),
+    };
+    for node in nodes {
+        tree.append(node).ok()?;
+    }
+
+    let mut buf = vec![];
+    tree.write(&mut buf).ok()?;
+    if top_hash.len() != 32 {
+        return None;
+    }
+
+    let mut tree_state = TreeState::default();
+    tree_state.height = height;
+    tree_state.hash = BlockHash::from_slice(&top_hash).to_string();
+    tree_state.tree = hex::encode(buf);
+
+    if let Err(e) = cache.lock().await.insert_tree_state(&tree_state) {
+        warn!("Couldn't add the tree state at {} to the block cache: {}", height, e);
+    }
+
+    Some(tree_state)
+}
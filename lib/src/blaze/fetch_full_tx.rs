@@ -33,7 +33,7 @@ use zcash_primitives::{
     transaction::{Transaction, TxId},
 };
 
-use super::syncdata::BlazeSyncData;
+use super::{sync_token::SyncToken, syncdata::BlazeSyncData};
 
 pub struct FetchFullTxns<P> {
     config: LightClientConfig<P>,
@@ -56,6 +56,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
         &self,
         fulltx_fetcher: UnboundedSender<(TxId, oneshot::Sender<Result<Transaction, String>>)>,
         bsync_data: Arc<RwLock<BlazeSyncData>>,
+        sync_token: Arc<SyncToken>,
     ) -> (
         JoinHandle<Result<(), String>>,
         UnboundedSender<(TxId, BlockHeight)>,
@@ -69,6 +70,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
         let end_height = bsync_data.read().await.sync_status.read().await.end_block;
 
         let bsync_data_i = bsync_data.clone();
+        let sync_token_i = sync_token.clone();
 
         let (txid_tx, mut txid_rx) = unbounded_channel::<(TxId, BlockHeight)>();
         let h1: JoinHandle<Result<(), String>> = tokio::spawn(async move {
@@ -76,6 +78,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
             let mut workers = FuturesUnordered::new();
 
             while let Some((txid, height)) = txid_rx.recv().await {
+                sync_token_i.wait_while_paused().await;
+
                 let config = config.clone();
                 let keys = keys.clone();
                 let wallet_txns = wallet_txns.clone();
@@ -126,6 +130,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
             let bsync_data = bsync_data.clone();
 
             while let Some((tx, height)) = tx_rx.recv().await {
+                sync_token.wait_while_paused().await;
+
                 let config = config.clone();
                 let keys = keys.clone();
                 let wallet_txns = wallet_txns.clone();
@@ -312,6 +318,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
 
                     // info!("A sapling note was received into the wallet in {}", tx.txid());
                     if unconfirmed {
+                        let at_change_address = keys.read().await.is_change_address(&to).await;
                         wallet_txns.write().await.add_pending_note(
                             tx.txid(),
                             height,
@@ -319,6 +326,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> FetchFullTxns<P> {
                             note.clone(),
                             to,
                             &ivk,
+                            at_change_address,
                         );
                     }
 
@@ -1,5 +1,5 @@
 use core::fmt;
-use std::cmp;
+use std::{cmp, time::Instant};
 
 #[derive(Clone, Debug, Default)]
 pub struct SyncStatus {
@@ -16,6 +16,14 @@ pub struct SyncStatus {
 
     pub blocks_total: u64,
 
+    // Size of the compact blocks received so far in this batch, and the number of sapling outputs in them
+    pub blocks_bytes: u64,
+    pub blocks_outputs: u64,
+
+    // Sapling outputs that were trial decrypted in this batch, and when the batch started
+    pub trial_dec_outputs: u64,
+    pub batch_started: Option<Instant>,
+
     pub batch_num: usize,
     pub batch_total: usize,
 }
@@ -28,6 +36,9 @@ impl SyncStatus {
         self.blocks_done = 0;
         self.trial_dec_done = 0;
         self.blocks_total = 0;
+        self.blocks_bytes = 0;
+        self.blocks_outputs = 0;
+        self.trial_dec_outputs = 0;
         self.txn_scan_done = 0;
         self.batch_num = 0;
         self.batch_total = batch_total;
@@ -43,10 +54,24 @@ impl SyncStatus {
         self.blocks_done = 0;
         self.trial_dec_done = 0;
         self.blocks_total = 0;
+        self.blocks_bytes = 0;
+        self.blocks_outputs = 0;
+        self.trial_dec_outputs = 0;
+        self.batch_started = Some(Instant::now());
         self.txn_scan_done = 0;
         self.batch_num = batch_num;
     }
 
+    /// Sapling outputs trial decrypted per second in this batch
+    pub fn trial_dec_rate(&self) -> f64 {
+        match self.batch_started {
+            Some(started) if started.elapsed().as_secs_f64() > 0.0 => {
+                self.trial_dec_outputs as f64 / started.elapsed().as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
     /// Finish up a sync
     pub fn finish(&mut self) {
         self.in_progress = false;
@@ -75,13 +100,14 @@ impl fmt::Display for SyncStatus {
         if self.blocks_total > 0 && self.in_progress {
             write!(
                 f,
-                "id: {}, batch: {}/{}, blocks: {}/{}, decryptions: {}, tx_scan: {}",
+                "id: {}, batch: {}/{}, blocks: {}/{}, decryptions: {} ({:.0}/s), tx_scan: {}",
                 self.sync_id,
                 self.batch_num,
                 self.batch_total,
                 self.blocks_done,
                 self.blocks_total,
                 self.trial_dec_done,
+                self.trial_dec_rate(),
                 self.txn_scan_done,
             )
         } else {
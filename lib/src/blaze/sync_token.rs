@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use tokio::sync::Notify;
+
+pub const SYNC_CANCELLED: &str = "Sync was cancelled";
+
+const RUNNING: u8 = 0;
+const PAUSED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Shared between the sync processors, so that a running sync can be paused, resumed or cancelled.
+/// The processors wait while it is paused, and the block fetcher stops fetching once it is cancelled.
+#[derive(Debug, Default)]
+pub struct SyncToken {
+    state: AtomicU8,
+    notify: Notify,
+}
+
+impl SyncToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get ready for a new sync
+    pub fn reset(&self) {
+        self.state.store(RUNNING, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn cancel(&self) {
+        self.state.store(CANCELLED, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Pause the sync. Returns false if it was already paused or cancelled
+    pub fn pause(&self) -> bool {
+        self.state
+            .compare_exchange(RUNNING, PAUSED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Resume a paused sync. Returns false if it wasn't paused
+    pub fn resume(&self) -> bool {
+        let resumed = self
+            .state
+            .compare_exchange(PAUSED, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        self.notify.notify_waiters();
+
+        resumed
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == PAUSED
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == CANCELLED
+    }
+
+    /// Wait for as long as the sync is paused. Returns straight away if it is running or cancelled.
+    pub async fn wait_while_paused(&self) {
+        loop {
+            // Register for the notification before checking, so a resume in between isn't missed
+            let notified = self.notify.notified();
+            if !self.is_paused() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Wait while the sync is paused, and return an error if it has been cancelled
+    pub async fn checkpoint(&self) -> Result<(), String> {
+        self.wait_while_paused().await;
+
+        if self.is_cancelled() {
+            Err(SYNC_CANCELLED.to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::{SyncToken, SYNC_CANCELLED};
+
+    #[tokio::test]
+    async fn pause_resume_cancel() {
+        let token = Arc::new(SyncToken::new());
+        assert!(token.checkpoint().await.is_ok());
+
+        // A paused token blocks the checkpoint until it is resumed
+        assert!(token.pause());
+        assert!(!token.pause());
+
+        let t = token.clone();
+        let h = tokio::spawn(async move { t.checkpoint().await });
+        assert!(timeout(Duration::from_millis(100), token.wait_while_paused())
+            .await
+            .is_err());
+
+        assert!(token.resume());
+        assert!(!token.resume());
+        assert!(h.await.unwrap().is_ok());
+
+        // Cancelling a paused token releases the waiters with an error
+        assert!(token.pause());
+        let t = token.clone();
+        let h = tokio::spawn(async move { t.checkpoint().await });
+        token.cancel();
+        assert_eq!(h.await.unwrap().unwrap_err(), SYNC_CANCELLED);
+        assert!(!token.pause());
+
+        // And a new sync starts again from a clean state
+        token.reset();
+        assert!(token.checkpoint().await.is_ok());
+    }
+}
@@ -1,19 +1,27 @@
 use std::sync::Arc;
 
-use http::Uri;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use zcash_primitives::consensus;
 
-use super::{block_witness_data::BlockAndWitnessData, sync_status::SyncStatus};
+use super::{
+    block_cache::BlockCache, block_witness_data::BlockAndWitnessData, sync_status::SyncStatus, sync_token::SyncToken,
+};
 use crate::compact_formats::TreeState;
+use crate::grpc_connector::server_list::ServerList;
 use crate::lightwallet::WalletOptions;
 use crate::{lightclient::lightclient_config::LightClientConfig, lightwallet::data::BlockData};
 
 pub struct BlazeSyncData {
     pub(crate) sync_status: Arc<RwLock<SyncStatus>>,
     pub(crate) block_data: BlockAndWitnessData,
-    uri: Uri,
+    servers: ServerList,
     pub(crate) wallet_options: WalletOptions,
+
+    // On-disk cache of compact blocks, if enabled in the config
+    pub(crate) block_cache: Option<Arc<Mutex<BlockCache>>>,
+
+    // Lets the running sync be paused or cancelled
+    pub(crate) sync_token: Arc<SyncToken>,
 }
 
 impl BlazeSyncData {
@@ -22,14 +30,18 @@ impl BlazeSyncData {
 
         Self {
             sync_status: sync_status.clone(),
-            uri: config.server.clone(),
+            servers: config.servers.clone(),
             block_data: BlockAndWitnessData::new(config, sync_status),
             wallet_options: WalletOptions::default(),
+            block_cache: config
+                .block_cache_size
+                .map(|size| Arc::new(Mutex::new(BlockCache::new(config.get_block_cache_path().into(), size)))),
+            sync_token: Arc::new(SyncToken::new()),
         }
     }
 
-    pub fn uri(&self) -> &'_ Uri {
-        &self.uri
+    pub fn servers(&self) -> &'_ ServerList {
+        &self.servers
     }
 
     pub async fn setup_for_sync(
@@ -193,9 +193,12 @@ impl FakeTransaction {
     // Returns the nullifier of the new note.
     pub fn add_tx_paying(&mut self, extfvk: &ExtendedFullViewingKey, value: u64) -> Note {
         let to = extfvk.default_address().1;
-        let note = self.add_sapling_output(value, None, &to);
+        self.add_tx_paying_address(&to, value)
+    }
 
-        note
+    // Add a new tx into the block, paying the given address (e.g., a diversified address) the amount.
+    pub fn add_tx_paying_address(&mut self, to: &PaymentAddress, value: u64) -> Note {
+        self.add_sapling_output(value, None, to)
     }
 
     // Add a t output which will be paid to the given PubKey
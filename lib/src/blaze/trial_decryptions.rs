@@ -1,11 +1,13 @@
 use crate::{
-    compact_formats::CompactBlock,
+    compact_formats::{CompactBlock, CompactTx},
     lightwallet::{data::WalletTx, keys::Keystores, wallet_txns::WalletTxns, MemoDownloadOption},
 };
 use futures::{stream::FuturesUnordered, StreamExt};
+use lazy_static::lazy_static;
 use log::info;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use tokio::{
     sync::{
         mpsc::{channel, Sender, UnboundedSender},
@@ -14,13 +16,80 @@ use tokio::{
     task::JoinHandle,
 };
 
+use zcash_note_encryption::batch;
 use zcash_primitives::{
     consensus::{self, BlockHeight},
-    sapling::{note_encryption::try_sapling_compact_note_decryption, Nullifier, SaplingIvk},
+    sapling::{note_encryption::SaplingDomain, Note, Nullifier, PaymentAddress, SaplingIvk},
     transaction::{Transaction, TxId},
 };
 
-use super::syncdata::BlazeSyncData;
+use super::{sync_token::SyncToken, syncdata::BlazeSyncData};
+
+lazy_static! {
+    // Trial decryption is CPU bound, so it runs on its own threads instead of holding up the async runtime
+    static ref TRIAL_DECRYPTION_POOL: ThreadPool = ThreadPoolBuilder::new()
+        .thread_name(|i| format!("trial-decrypt-{}", i))
+        .build()
+        .expect("Couldn't start the trial decryption threads");
+}
+
+// An output in a batch of blocks that one of the wallet's ivks could decrypt
+pub(crate) struct DecryptedOutput {
+    pub block_num: usize,
+    pub tx_num: usize,
+    pub output_num: usize,
+    pub ivk_num: usize,
+    pub note: Note,
+    pub to: PaymentAddress,
+}
+
+// Servers can leave the ciphertexts out of the compact txs, and then there is nothing to trial decrypt
+fn has_ciphertexts(ctx: &CompactTx) -> bool {
+    ctx.outputs.len() > 0 && ctx.outputs[0].epk.len() > 0 && ctx.outputs[0].ciphertext.len() > 0
+}
+
+/// Trial decrypt all the sapling outputs in `cbs` with all the `ivks`. The blocks are decrypted in parallel, and the
+/// outputs of a block are decrypted together, so the key agreement for an output is shared by all the ivks.
+pub(crate) fn trial_decrypt_blocks<P: consensus::Parameters + Send + Sync>(
+    params: &P,
+    cbs: &[CompactBlock],
+    ivks: &[SaplingIvk],
+) -> Vec<DecryptedOutput> {
+    cbs.par_iter()
+        .enumerate()
+        .flat_map_iter(|(block_num, cb)| {
+            let height = BlockHeight::from_u32(cb.height as u32);
+
+            let mut positions = vec![];
+            let mut outputs = vec![];
+            for (tx_num, ctx) in cb.vtx.iter().enumerate() {
+                if !has_ciphertexts(ctx) {
+                    continue;
+                }
+
+                for (output_num, co) in ctx.outputs.iter().enumerate() {
+                    positions.push((tx_num, output_num));
+                    outputs.push((SaplingDomain::for_height(params.clone(), height), co.clone()));
+                }
+            }
+
+            batch::try_compact_note_decryption(ivks, &outputs)
+                .into_iter()
+                .zip(positions)
+                .filter_map(move |(decrypted, (tx_num, output_num))| {
+                    decrypted.map(|((note, to), ivk_num)| DecryptedOutput {
+                        block_num,
+                        tx_num,
+                        output_num,
+                        ivk_num,
+                        note,
+                        to,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
 
 pub struct TrialDecryptions<P> {
     keys: Arc<RwLock<Keystores<P>>>,
@@ -37,6 +106,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
         bsync_data: Arc<RwLock<BlazeSyncData>>,
         detected_txid_sender: Sender<(TxId, Option<Nullifier>, BlockHeight, Option<u32>)>,
         fulltx_fetcher: UnboundedSender<(TxId, oneshot::Sender<Result<Transaction, String>>)>,
+        sync_token: Arc<SyncToken>,
     ) -> (JoinHandle<Result<(), String>>, Sender<CompactBlock>) {
         //info!("Starting trial decrptions processor");
 
@@ -53,6 +123,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
             let ivks = Arc::new(keys.read().await.get_all_ivks().await.collect::<Vec<_>>());
 
             while let Some(cb) = rx.recv().await {
+                sync_token.wait_while_paused().await;
+
                 //println!("trial_witness recieved {:?}", cb.height);
                 cbs.push(cb);
 
@@ -112,79 +184,103 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
         // println!("Starting batch at {}", temp_start);
         let config = keys.read().await.config().clone();
         let blk_count = cbs.len();
+        let output_count = cbs
+            .iter()
+            .flat_map(|cb| cb.vtx.iter())
+            .map(|ctx| ctx.outputs.len() as u64)
+            .sum::<u64>();
         let mut workers = FuturesUnordered::new();
 
         let download_memos = bsync_data.read().await.wallet_options.download_memos;
 
-        for cb in cbs {
+        // Decrypt on the trial decryption threads, and wait for them without blocking the runtime
+        let cbs = Arc::new(cbs);
+        let decrypted = {
+            let (tx, rx) = oneshot::channel();
+            let params = config.get_params();
+            let cbs = cbs.clone();
+            let ivks = ivks.clone();
+
+            TRIAL_DECRYPTION_POOL.spawn(move || {
+                // The receiver only goes away if the sync is dropped
+                let _ = tx.send(trial_decrypt_blocks(&params, &cbs, &ivks));
+            });
+            rx.await.map_err(|e| format!("Trial decryption failed: {}", e))?
+        };
+
+        let mut wallet_txs = HashSet::new();
+        for DecryptedOutput {
+            block_num,
+            tx_num,
+            output_num,
+            ivk_num,
+            note,
+            to,
+        } in decrypted
+        {
+            let cb = &cbs[block_num];
             let height = BlockHeight::from_u32(cb.height as u32);
+            let ivk = SaplingIvk(ivks[ivk_num].0);
+            wallet_txs.insert((block_num, tx_num));
+
+            let keys = keys.clone();
+            let bsync_data = bsync_data.clone();
+            let wallet_txns = wallet_txns.clone();
+            let detected_txid_sender = detected_txid_sender.clone();
+            let timestamp = cb.time as u64;
+            let ctx = cb.vtx[tx_num].clone();
+
+            workers.push(tokio::spawn(async move {
+                let keys = keys.read().await;
+                let have_spending_key = keys.have_spending_key(&ivk).await;
+                let servers = bsync_data.read().await.servers().clone();
+                let block_cache = bsync_data.read().await.block_cache.clone();
+
+                // Get the witness for the note
+                let witness = bsync_data
+                    .read()
+                    .await
+                    .block_data
+                    .get_note_witness(servers, block_cache, height, tx_num, output_num)
+                    .await?;
+
+                let txid = WalletTx::new_txid(&ctx.hash);
+                let nullifier = keys.get_note_nullifier(&ivk, witness.position() as u64, &note).await?;
+                let at_change_address = keys.is_change_address(&to).await;
+
+                wallet_txns.write().await.add_new_sapling_note(
+                    txid.clone(),
+                    height,
+                    false,
+                    timestamp,
+                    note,
+                    to,
+                    &ivk,
+                    nullifier,
+                    have_spending_key,
+                    witness,
+                    at_change_address,
+                );
+
+                info!("Trial decrypt Detected txid {}", &txid);
+
+                detected_txid_sender
+                    .send((txid, Some(nullifier), height, Some(output_num as u32)))
+                    .await
+                    .unwrap();
+
+                Ok::<_, String>(())
+            }));
+        }
 
-            for (tx_num, ctx) in cb.vtx.iter().enumerate() {
-                let mut wallet_tx = false;
-
-                if !(ctx.outputs.len() > 0 && ctx.outputs[0].epk.len() > 0 && ctx.outputs[0].ciphertext.len() > 0) {
-                    continue;
-                }
-
-                for (output_num, co) in ctx.outputs.iter().enumerate() {
-                    for (_, ivk) in ivks.iter().enumerate() {
-                        if let Some((note, to)) =
-                            try_sapling_compact_note_decryption(&config.get_params(), height, &ivk, co)
-                        {
-                            let ivk = SaplingIvk(ivk.0);
-                            wallet_tx = true;
-
-                            let keys = keys.clone();
-                            let bsync_data = bsync_data.clone();
-                            let wallet_txns = wallet_txns.clone();
-                            let detected_txid_sender = detected_txid_sender.clone();
-                            let timestamp = cb.time as u64;
-                            let ctx = ctx.clone();
-
-                            workers.push(tokio::spawn(async move {
-                                let keys = keys.read().await;
-                                let have_spending_key = keys.have_spending_key(&ivk).await;
-                                let uri = bsync_data.read().await.uri().clone();
-
-                                // Get the witness for the note
-                                let witness = bsync_data
-                                    .read()
-                                    .await
-                                    .block_data
-                                    .get_note_witness(uri, height, tx_num, output_num)
-                                    .await?;
-
-                                let txid = WalletTx::new_txid(&ctx.hash);
-                                let nullifier = keys.get_note_nullifier(&ivk, witness.position() as u64, &note).await?;
-
-                                wallet_txns.write().await.add_new_sapling_note(
-                                    txid.clone(),
-                                    height,
-                                    false,
-                                    timestamp,
-                                    note,
-                                    to,
-                                    &ivk,
-                                    nullifier,
-                                    have_spending_key,
-                                    witness,
-                                );
-
-                                info!("Trial decrypt Detected txid {}", &txid);
-
-                                detected_txid_sender
-                                    .send((txid, Some(nullifier), height, Some(output_num as u32)))
-                                    .await
-                                    .unwrap();
-
-                                Ok::<_, String>(())
-                            }));
-                        }
+        // Check option to see if we are fetching all txns.
+        if download_memos == MemoDownloadOption::AllMemos {
+            for (block_num, cb) in cbs.iter().enumerate() {
+                for (tx_num, ctx) in cb.vtx.iter().enumerate() {
+                    if !has_ciphertexts(ctx) || wallet_txs.contains(&(block_num, tx_num)) {
+                        continue;
                     }
-                }
 
-                // Check option to see if we are fetching all txns.
-                if !wallet_tx && download_memos == MemoDownloadOption::AllMemos {
                     let txid = WalletTx::new_txid(&ctx.hash);
                     let (tx, rx) = oneshot::channel();
                     fulltx_fetcher.send((txid, tx)).unwrap();
@@ -202,10 +298,109 @@ impl<P: consensus::Parameters + Send + Sync + 'static> TrialDecryptions<P> {
         }
 
         // Update sync status
-        bsync_data.read().await.sync_status.write().await.trial_dec_done += blk_count as u64;
+        {
+            let bsync_data = bsync_data.read().await;
+            let mut sync_status = bsync_data.sync_status.write().await;
+            sync_status.trial_dec_done += blk_count as u64;
+            sync_status.trial_dec_outputs += output_count;
+        }
 
         // Return a nothing-value
         // println!("Finished batch at {}", temp_start);
         Ok::<(), String>(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use zcash_primitives::{
+        consensus::BlockHeight,
+        sapling::{note_encryption::try_sapling_compact_note_decryption, SaplingIvk},
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use crate::{blaze::test_utils::FakeCompactBlockList, lightclient::lightclient_config::UnitTestNetwork};
+
+    use super::trial_decrypt_blocks;
+
+    fn keys(num: u8) -> (Vec<ExtendedFullViewingKey>, Vec<SaplingIvk>) {
+        let extfvks = (0..num)
+            .map(|i| ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[100 + i; 32])))
+            .collect::<Vec<_>>();
+        let ivks = extfvks.iter().map(|extfvk| extfvk.fvk.vk.ivk()).collect();
+
+        (extfvks, ivks)
+    }
+
+    #[test]
+    fn finds_notes() {
+        let (extfvks, ivks) = keys(3);
+
+        let mut fcbl = FakeCompactBlockList::new(10);
+        let (_, height1, note1) = fcbl.add_tx_paying(&extfvks[2], 100_000);
+        fcbl.add_blocks(10);
+        let (_, height2, note2) = fcbl.add_tx_paying(&extfvks[0], 20_000);
+        fcbl.add_blocks(5);
+        let cbs = fcbl.into_compact_blocks();
+
+        let mut decrypted = trial_decrypt_blocks(&UnitTestNetwork, &cbs, &ivks);
+        decrypted.sort_by_key(|d| cbs[d.block_num].height);
+        assert_eq!(decrypted.len(), 2);
+
+        for (d, (height, note, ivk_num)) in decrypted.iter().zip(vec![(height1, note1, 2), (height2, note2, 0)]) {
+            assert_eq!(cbs[d.block_num].height, height);
+            assert_eq!((d.tx_num, d.output_num), (0, 0));
+            assert_eq!(d.ivk_num, ivk_num);
+            assert_eq!(d.note.value, note.value);
+            assert_eq!(d.note.cmu(), note.cmu());
+            assert_eq!(d.to, extfvks[ivk_num].default_address().1);
+        }
+
+        // Without the keys, nothing is found
+        assert!(trial_decrypt_blocks(&UnitTestNetwork, &cbs, &ivks[1..2]).is_empty());
+    }
+
+    // Run with `cargo test --release trial_decryption_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn trial_decryption_benchmark() {
+        const OUTPUTS: usize = 2_000;
+        let (_, ivks) = keys(5);
+        let (others, _) = keys(10);
+
+        // Outputs that are properly encrypted, but not to any of the wallet's keys
+        let mut fcbl = FakeCompactBlockList::new(0);
+        for i in 0..OUTPUTS {
+            fcbl.add_tx_paying(&others[5 + i % 5], 1);
+        }
+        let cbs = fcbl.into_compact_blocks();
+
+        let start = Instant::now();
+        assert!(trial_decrypt_blocks(&UnitTestNetwork, &cbs, &ivks).is_empty());
+        let batched = start.elapsed();
+
+        // The way it used to be done, one output and one ivk at a time on a single thread
+        let start = Instant::now();
+        for cb in &cbs {
+            let height = BlockHeight::from_u32(cb.height as u32);
+            for co in cb.vtx.iter().flat_map(|ctx| ctx.outputs.iter()) {
+                for ivk in &ivks {
+                    assert!(try_sapling_compact_note_decryption(&UnitTestNetwork, height, ivk, co).is_none());
+                }
+            }
+        }
+        let single = start.elapsed();
+
+        println!(
+            "{} outputs, {} ivks: batched {:?} ({:.0} outputs/s), one at a time {:?} ({:.0} outputs/s)",
+            OUTPUTS,
+            ivks.len(),
+            batched,
+            OUTPUTS as f64 / batched.as_secs_f64(),
+            single,
+            OUTPUTS as f64 / single.as_secs_f64(),
+        );
+    }
+}
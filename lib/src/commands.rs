@@ -1,10 +1,14 @@
 use crate::lightwallet::keys::Keystores;
-use crate::lightwallet::MemoDownloadOption;
-use crate::{lightclient::LightClient, lightwallet::utils};
+use crate::lightwallet::{ChangeAddressOption, FeePolicy, MemoDownloadOption, NoteSelectionOption};
+use crate::{
+    lightclient::LightClient,
+    lightwallet::{batch, utils, zip321},
+};
 use json::object;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::path::PathBuf;
 use tokio::runtime::Runtime;
 use zcash_primitives::consensus::{self};
 
@@ -24,9 +28,13 @@ struct SyncCommand {}
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SyncCommand {
     fn help(&self) -> String {
         let mut h = vec![];
-        h.push("Sync the light client with the server");
+        h.push("Sync the light client with the server, or control a sync that is already running");
         h.push("Usage:");
-        h.push("sync");
+        h.push("sync [cancel | pause | resume]");
+        h.push("");
+        h.push("cancel: Stop the running sync. The wallet is kept as of the last batch of blocks that was synced");
+        h.push("pause: Pause the running sync until it is resumed");
+        h.push("resume: Resume a paused sync");
         h.push("");
 
         h.join("\n")
@@ -36,11 +44,24 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SyncComman
         "Download CompactBlocks and sync to the server".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient<P>) -> String {
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
         RT.block_on(async move {
-            match lightclient.do_sync(true).await {
-                Ok(j) => j.pretty(2),
-                Err(e) => e,
+            let r = match args {
+                [] => {
+                    return match lightclient.do_sync(true).await {
+                        Ok(j) => j.pretty(2),
+                        Err(e) => e,
+                    }
+                }
+                ["cancel"] => lightclient.cancel_sync().await,
+                ["pause"] => lightclient.pause_sync().await,
+                ["resume"] => lightclient.resume_sync().await,
+                _ => return Command::<P>::help(self),
+            };
+
+            match r {
+                Ok(_) => object! { "result" => "success" }.pretty(2),
+                Err(e) => object! { "error" => e }.pretty(2),
             }
         })
     }
@@ -98,6 +119,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SyncStatus
                     "end_block" => status.end_block,
                     "synced_blocks" => status.blocks_done,
                     "trial_decryptions_blocks" => status.trial_dec_done,
+                    "trial_decryptions_per_sec" => status.trial_dec_rate() as u64,
                     "txn_scan_blocks" => status.txn_scan_done,
                     "total_blocks" => status.blocks_total,
                     "batch_num" => status.batch_num,
@@ -150,6 +172,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for RescanComm
         h.push("");
         h.push("This command will download all blocks since the intial block again from the light client server");
         h.push("and attempt to scan each block for transactions belonging to the wallet.");
+        h.push("If the block cache is enabled (--block-cache), cached blocks and tree states are read from disk");
+        h.push("instead. The rescan still needs the light client server for the chain tip, the wallet's transactions");
+        h.push("and its transparent history.");
 
         h.join("\n")
     }
@@ -167,6 +192,89 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for RescanComm
     }
 }
 
+struct CacheCommand {}
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for CacheCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show or clear the on-disk compact block cache");
+        h.push("Usage:");
+        h.push("cache [status | clear]");
+        h.push("");
+        h.push("The cache is enabled by starting with --block-cache <size in MB>. It keeps the compact blocks");
+        h.push("downloaded while syncing, so rescans don't have to download them again from the light client server.");
+        h.push("");
+        h.push("The tree states the wallet needs are cached too. The last 100 blocks can still be reorged, so they");
+        h.push("are checked against the chain tip before every sync, and the ones that aren't on the chain any more");
+        h.push("are dropped.");
+        h.push("");
+        h.push("The cache does not make rescans work offline. The chain tip, the full transactions that belong to");
+        h.push("the wallet, its transparent history and the prices are still fetched from the server.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show or clear the compact block cache".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        RT.block_on(async move {
+            let r = match args {
+                [] | ["status"] => lightclient.do_block_cache_status().await,
+                ["clear"] => lightclient.do_block_cache_clear().await,
+                _ => return Command::<P>::help(self),
+            };
+
+            match r {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct CheckpointCommand {}
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for CheckpointCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Export the sapling tree at a block height as a checkpoint");
+        h.push("Usage:");
+        h.push("checkpoint export <height> [file]");
+        h.push("");
+        h.push("The height has to be one of the last 100 blocks the wallet synced, so the tree can be checked");
+        h.push("against the wallet's chain. The checkpoint is written to the file, or to the data directory if no");
+        h.push("file is given, in the same format as the checkpoint files loaded with --checkpoints.");
+        h.push("Example:");
+        h.push("checkpoint export 1500000");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Export the sapling tree at a block height as a checkpoint".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        let (height, path) = match args {
+            ["export", height] => (height, None),
+            ["export", height, path] => (height, Some(PathBuf::from(path))),
+            _ => return Command::<P>::help(self),
+        };
+
+        let height = match height.parse::<u64>() {
+            Ok(h) => h,
+            Err(e) => return format!("Couldn't parse height {}: {}", height, e),
+        };
+
+        RT.block_on(async move {
+            match lightclient.do_checkpoint_export(height, path).await {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
 struct ClearCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ClearCommand {
@@ -349,6 +457,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ExportComm
         h.push("export [t-address or z-address]");
         h.push("");
         h.push("If no address is passed, private key for all addresses in the wallet are exported.");
+        h.push("z addresses also include their unified full viewing key (ufvk).");
         h.push("");
         h.push("Example:");
         h.push("export ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d");
@@ -555,9 +664,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ShieldComm
         let mut h = vec![];
         h.push("Shield all your transparent funds");
         h.push("Usage:");
-        h.push("shield [optional address]");
+        h.push("shield [optional address] [--fee <fee policy>]");
         h.push("");
         h.push("NOTE: The fee required to send this transaction (currently ZEC 0.0001) is additionally deducted from your balance.");
+        h.push("The fee policy is one of zip317 | fixed:<zats> | multiplier:<n>, and defaults to the fee_policy wallet option.");
         h.push("Example:");
         h.push("shield");
         h.push("");
@@ -569,6 +679,11 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ShieldComm
         "Shield your transparent ZEC into a sapling address".to_string()
     }
     fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        let (args, fee_policy) = match parse_fee_arg(args) {
+            Ok(r) => r,
+            Err(e) => return format!("Error: {}\n{}", e, Command::<P>::help(self)),
+        };
+
         // Parse the address or amount
         let address = if args.len() > 0 {
             Some(args[0].to_string())
@@ -576,7 +691,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ShieldComm
             None
         };
         RT.block_on(async move {
-            match lightclient.do_shield(address).await {
+            match lightclient.do_shield(address, fee_policy).await {
                 Ok(txid) => {
                     object! { "txid" => txid }
                 }
@@ -589,6 +704,62 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ShieldComm
     }
 }
 
+struct ConsolidateCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ConsolidateCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Merge your smallest shielded notes into fewer, larger notes");
+        h.push("Usage:");
+        h.push("consolidate [max_inputs] [optional address]");
+        h.push("");
+        h.push("The notes are merged in batches of at most max_inputs notes (default 20), one transaction per batch.");
+        h.push("The funds are sent to the given address, which has to be one of your own z addresses, or to your first z address.");
+        h.push("NOTE: Each batch pays its own fee. If the smallest notes are worth less than the fee, your largest note pays for them.");
+        h.push("Notes that can't be paid for even then are left out, and reported as unmerged_notes and");
+        h.push("unmerged_value.");
+        h.push("Example:");
+        h.push("consolidate 10");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Merge small shielded notes into larger ones".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() > 2 {
+            return Command::<P>::help(self);
+        }
+
+        let max_inputs = if args.len() > 0 {
+            match args[0].parse::<usize>() {
+                Ok(n) => n,
+                Err(e) => return format!("Couldn't parse max_inputs: {}\n{}", e, Command::<P>::help(self)),
+            }
+        } else {
+            20
+        };
+        let address = if args.len() > 1 {
+            Some(args[1].to_string())
+        } else {
+            None
+        };
+
+        RT.block_on(async move {
+            match lightclient.do_consolidate(max_inputs, address).await {
+                Ok(j) => j,
+                Err(e) => {
+                    object! { "error" => e }
+                }
+            }
+            .pretty(2)
+        })
+    }
+}
+
 struct EncryptMessageCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for EncryptMessageCommand {
@@ -691,6 +862,113 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for DecryptMes
     }
 }
 
+/// Parse the arguments of a send. There are three argument types.
+/// 1 - A set of 2(+1 optional) arguments for a single address send representing address, value, memo?
+/// 2 - A single argument in the form of a JSON string that is "[{address: address, value: value, memo: memo},...]"
+/// 3 - A single argument that is a ZIP-321 payment request URI, "zcash:<address>?amount=<ZEC>&memo=<base64>..."
+async fn parse_send_args<P: consensus::Parameters + Send + Sync + 'static>(
+    args: &[&str],
+    lightclient: &LightClient<P>,
+) -> Result<Vec<(String, u64, Option<String>)>, String> {
+    // Check for a payment request URI
+    if args.len() == 1 && zip321::is_payment_request(args[0]) {
+        let payments = zip321::parse_uri(&lightclient.config.get_params(), args[0])?;
+
+        Ok(payments
+            .iter()
+            .map(|p| (p.address.clone(), p.amount, p.memo_string()))
+            .collect())
+    } else if args.len() == 1 {
+        // Check for a single argument that can be parsed as JSON
+        let arg_list = args[0];
+
+        let json_args = json::parse(&arg_list).map_err(|e| format!("Couldn't understand JSON: {}", e))?;
+
+        if !json_args.is_array() {
+            return Err(format!("Couldn't parse argument as array"));
+        }
+
+        let all_zbalance = lightclient.wallet.verified_zbalance(None).await;
+        let address_book = lightclient.wallet.address_book.read().await.clone();
+
+        json_args
+            .members()
+            .map(|j| {
+                if !j.has_key("address") || !j.has_key("amount") {
+                    Err(format!("Need 'address' and 'amount'\n"))
+                } else {
+                    let amt = match j["amount"].as_str() {
+                        Some("entire-verified-zbalance") => all_zbalance,
+                        _ => j["amount"].as_u64().unwrap(),
+                    };
+
+                    // The address can also be an address book label
+                    let address = j["address"].as_str().unwrap();
+                    let address = address_book.get_address(address).map(|a| a.as_str()).unwrap_or(address);
+
+                    Ok((
+                        address.to_string(),
+                        amt,
+                        j["memo"].as_str().map(|s| s.to_string().clone()),
+                    ))
+                }
+            })
+            .collect::<Result<Vec<(String, u64, Option<String>)>, String>>()
+    } else if args.len() == 2 || args.len() == 3 {
+        // The address can also be an address book label
+        let address = lightclient.wallet.resolve_address(args[0]).await;
+
+        // Make sure we can parse the amount
+        let value = match args[1].parse::<u64>() {
+            Ok(amt) => amt,
+            Err(e) => {
+                if args[1] == "entire-verified-zbalance" {
+                    lightclient.wallet.verified_zbalance(None).await
+                } else {
+                    return Err(format!("Couldn't parse amount: {}", e));
+                }
+            }
+        };
+
+        let memo = if args.len() == 3 {
+            Some(args[2].to_string())
+        } else {
+            None
+        };
+
+        // Memo has to be None if not sending to a shileded address
+        if memo.is_some() && !Keystores::is_shielded_address(&address, &lightclient.config.get_params()) {
+            return Err(format!("Can't send a memo to the non-shielded address {}", address));
+        }
+
+        Ok(vec![(address, value, memo)])
+    } else {
+        Err(format!("Wrong number of arguments"))
+    }
+}
+
+// Pull a "--fee <fee policy>" out of the args
+fn parse_fee_arg<'a>(args: &[&'a str]) -> Result<(Vec<&'a str>, Option<FeePolicy>), String> {
+    let mut rest = vec![];
+    let mut fee_policy = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--fee" {
+            i += 1;
+            match args.get(i) {
+                Some(p) => fee_policy = Some(FeePolicy::parse(p)?),
+                None => return Err("--fee needs a fee policy".to_string()),
+            }
+        } else {
+            rest.push(args[i]);
+        }
+        i += 1;
+    }
+
+    Ok((rest, fee_policy))
+}
+
 struct SendCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SendCommand {
@@ -701,6 +979,13 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SendComman
         h.push("send <address> <amount in zatoshis || \"entire-verified-zbalance\"> \"optional_memo\"");
         h.push("OR");
         h.push("send '[{'address': <address>, 'amount': <amount in zatoshis>, 'memo': <optional memo>}, ...]'");
+        h.push("OR");
+        h.push("send 'zcash:<address>?amount=<amount in ZEC>&memo=<base64url memo>' (a ZIP-321 payment request)");
+        h.push("");
+        h.push("Any <address> can also be the label of an address in the address book (see 'addressbook').");
+        h.push("Pass --dry-run to only show the notes and utxos that would be spent, the change and the fee, without sending.");
+        h.push("Pass --from <address>[,<address>...] to only spend funds received at those addresses. The change is sent back to them.");
+        h.push("Pass --fee <zip317 | fixed:<zats> | multiplier:<n>> to override the fee_policy wallet option for this send.");
         h.push("");
         h.push("NOTE: The fee required to send this transaction (currently ZEC 0.0001) is additionally deducted from your balance.");
         h.push("Example:");
@@ -714,95 +999,320 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SendComman
         "Send ZEC to the given address".to_string()
     }
     fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
-        // Parse the args. There are two argument types.
-        // 1 - A set of 2(+1 optional) arguments for a single address send representing address, value, memo?
-        // 2 - A single argument in the form of a JSON string that is "[{address: address, value: value, memo: memo},...]"
+        let (args, fee_policy) = match parse_fee_arg(args) {
+            Ok(r) => r,
+            Err(e) => return format!("Error: {}\n{}", e, Command::<P>::help(self)),
+        };
+
+        let mut dry_run = false;
+        let mut from = vec![];
+        let mut send_args = vec![];
+
+        let mut i = 0;
+        while i < args.len() {
+            match args[i] {
+                "--dry-run" => dry_run = true,
+                "--from" => {
+                    i += 1;
+                    match args.get(i) {
+                        Some(addrs) => from.extend(addrs.split(',').map(|a| a.trim().to_string())),
+                        None => return Command::<P>::help(self),
+                    }
+                }
+                a => send_args.push(a),
+            }
+            i += 1;
+        }
+        let args = send_args;
+
         if args.len() < 1 || args.len() > 3 {
             return Command::<P>::help(self);
         }
 
         RT.block_on(async move {
-            // Check for a single argument that can be parsed as JSON
-            let send_args = if args.len() == 1 {
-                let arg_list = args[0];
+            let send_args = match parse_send_args(&args, lightclient).await {
+                Ok(a) => a,
+                Err(e) => return format!("Error: {}\n{}", e, Command::<P>::help(self)),
+            };
+
+            // Convert to the right format. String -> &str.
+            let tos = send_args
+                .iter()
+                .map(|(a, v, m)| (a.as_str(), *v, m.clone()))
+                .collect::<Vec<_>>();
 
-                let json_args = match json::parse(&arg_list) {
+            if dry_run {
+                return match lightclient.do_propose_send(tos, &from, fee_policy).await {
                     Ok(j) => j,
                     Err(e) => {
-                        let es = format!("Couldn't understand JSON: {}", e);
-                        return format!("{}\n{}", es, Command::<P>::help(self));
+                        object! { "error" => e }
                     }
-                };
-
-                if !json_args.is_array() {
-                    return format!("Couldn't parse argument as array\n{}", Command::<P>::help(self));
                 }
+                .pretty(2);
+            }
 
-                let all_zbalance = lightclient.wallet.verified_zbalance(None).await;
-
-                let maybe_send_args = json_args
-                    .members()
-                    .map(|j| {
-                        if !j.has_key("address") || !j.has_key("amount") {
-                            Err(format!("Need 'address' and 'amount'\n"))
-                        } else {
-                            let amt = match j["amount"].as_str() {
-                                Some("entire-verified-zbalance") => all_zbalance,
-                                _ => j["amount"].as_u64().unwrap(),
-                            };
-
-                            Ok((
-                                j["address"].as_str().unwrap().to_string().clone(),
-                                amt,
-                                j["memo"].as_str().map(|s| s.to_string().clone()),
-                            ))
-                        }
-                    })
-                    .collect::<Result<Vec<(String, u64, Option<String>)>, String>>();
-
-                match maybe_send_args {
-                    Ok(a) => a.clone(),
-                    Err(s) => {
-                        return format!("Error: {}\n{}", s, Command::<P>::help(self));
-                    }
+            match lightclient.do_send(tos, &from, fee_policy).await {
+                Ok(txid) => {
+                    object! { "txid" => txid }
+                }
+                Err(e) => {
+                    object! { "error" => e }
                 }
-            } else if args.len() == 2 || args.len() == 3 {
-                let address = args[0].to_string();
+            }
+            .pretty(2)
+        })
+    }
+}
 
-                // Make sure we can parse the amount
-                let value = match args[1].parse::<u64>() {
-                    Ok(amt) => amt,
-                    Err(e) => {
-                        if args[1] == "entire-verified-zbalance" {
-                            lightclient.wallet.verified_zbalance(None).await
-                        } else {
-                            return format!("Couldn't parse amount: {}", e);
-                        }
+struct SendBatchCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SendBatchCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Pay every row of a CSV file");
+        h.push("Usage:");
+        h.push("sendbatch <file.csv> [--max-outputs <n>] [--results <results.csv>]");
+        h.push("");
+        h.push(
+            "Each row of the file is address,amount[,memo]. Amounts with a decimal point or a ZEC suffix are in ZEC,",
+        );
+        h.push(
+            "plain integers or amounts with a zats suffix are in zats. Every row is validated before anything is sent.",
+        );
+        h.push(
+            "The rows are sent in transactions of at most max_outputs outputs each (default 50), and the txid or error",
+        );
+        h.push("of every row is written to the results file (default <file>.results.csv).");
+        h.push("Running the same batch again only sends the rows that the results file doesn't show as paid.");
+        h.push("Rows whose error starts with 'Pending broadcast' may or may not have been sent, because the wallet");
+        h.push("stopped while sending them or the broadcast failed. They are not sent again, so check their txid");
+        h.push("before paying them some other way.");
+        h.push("Example:");
+        h.push("sendbatch payouts.csv --max-outputs 20");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Pay a batch of addresses from a CSV file".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        let mut batch_path = None;
+        let mut results_path = None;
+        let mut max_outputs = batch::DEFAULT_BATCH_MAX_OUTPUTS;
+
+        let mut i = 0;
+        while i < args.len() {
+            match args[i] {
+                "--max-outputs" => match args.get(i + 1).map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => max_outputs = n,
+                    _ => {
+                        return format!(
+                            "Error: --max-outputs needs a number more than 0\n{}",
+                            Command::<P>::help(self)
+                        )
                     }
-                };
+                },
+                "--results" => match args.get(i + 1) {
+                    Some(r) => results_path = Some(r.to_string()),
+                    None => return format!("Error: --results needs a file name\n{}", Command::<P>::help(self)),
+                },
+                a if batch_path.is_none() => {
+                    batch_path = Some(a.to_string());
+                    i += 1;
+                    continue;
+                }
+                _ => return Command::<P>::help(self),
+            }
+            i += 2;
+        }
 
-                let memo = if args.len() == 3 {
-                    Some(args[2].to_string())
-                } else {
-                    None
-                };
+        let batch_path = match batch_path {
+            Some(b) => b,
+            None => return Command::<P>::help(self),
+        };
+        let results_path = results_path.unwrap_or(batch::default_results_path(&batch_path));
 
-                // Memo has to be None if not sending to a shileded address
-                if memo.is_some() && !Keystores::is_shielded_address(&address, &lightclient.config.get_params()) {
-                    return format!("Can't send a memo to the non-shielded address {}", address);
+        RT.block_on(async move {
+            match lightclient.do_send_batch(&batch_path, &results_path, max_outputs).await {
+                Ok(j) => j,
+                Err(e) => {
+                    object! { "error" => e }
                 }
+            }
+            .pretty(2)
+        })
+    }
+}
 
-                vec![(args[0].to_string(), value, memo)]
-            } else {
-                return Command::<P>::help(self);
+struct AddressBookCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for AddressBookCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Manage the labels of addresses you send to often");
+        h.push("Usage:");
+        h.push("addressbook add <label> <address>");
+        h.push("addressbook remove <label>");
+        h.push("addressbook [list]");
+        h.push("");
+        h.push("A label can be used in place of its address with 'send', and is shown next to the address in 'list'.");
+        h.push("Labels can't have spaces or commas in them. Adding an existing label changes its address.");
+        h.push("Example:");
+        h.push("addressbook add alice ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d");
+        h.push("send alice 200000 \"Hello Alice\"");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Add, remove and list address book labels".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        RT.block_on(async move {
+            match args {
+                [] | ["list"] => lightclient.do_address_book_list().await,
+                ["add", label, address] => match lightclient.do_address_book_add(label, address).await {
+                    Ok(j) => j,
+                    Err(e) => object! { "error" => e },
+                },
+                ["remove", label] => match lightclient.do_address_book_remove(label).await {
+                    Ok(j) => j,
+                    Err(e) => object! { "error" => e },
+                },
+                _ => return Command::<P>::help(self),
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct ProposeCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for ProposeCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Create an unsigned transaction proposal and save it to a file");
+        h.push("Usage:");
+        h.push("propose <proposal_file> <address> <amount in zatoshis> \"optional_memo\"");
+        h.push("OR");
+        h.push("propose <proposal_file> '[{'address': <address>, 'amount': <amount in zatoshis>, 'memo': <optional memo>}, ...]'");
+        h.push("");
+        h.push(
+            "The proposal contains the selected notes, their witnesses, the outputs and the fee, but no spending keys.",
+        );
+        h.push("It can be created by a watch-only wallet, and then signed with 'signproposal' on the wallet that");
+        h.push("holds the spending keys. The signed transaction is sent with 'broadcast'.");
+        h.push("Example:");
+        h.push("propose /tmp/proposal.dat ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d 200000 \"Hello\"");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Create an unsigned transaction proposal for offline signing".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() < 2 || args.len() > 4 {
+            return Command::<P>::help(self);
+        }
+
+        RT.block_on(async move {
+            let send_args = match parse_send_args(&args[1..], lightclient).await {
+                Ok(a) => a,
+                Err(e) => return format!("Error: {}\n{}", e, Command::<P>::help(self)),
             };
 
-            // Convert to the right format. String -> &str.
             let tos = send_args
                 .iter()
                 .map(|(a, v, m)| (a.as_str(), *v, m.clone()))
                 .collect::<Vec<_>>();
-            match lightclient.do_send(tos).await {
+            match lightclient.do_propose(tos, args[0]).await {
+                Ok(j) => j,
+                Err(e) => {
+                    object! { "error" => e }
+                }
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct SignProposalCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SignProposalCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Sign a transaction proposal created by 'propose'");
+        h.push("Usage:");
+        h.push("signproposal <proposal_file> <signed_tx_file>");
+        h.push("");
+        h.push("The transaction is built and signed with this wallet's spending keys, and written as hex to");
+        h.push("<signed_tx_file>. It is not broadcast, so this can be run on an offline wallet.");
+        h.push("Example:");
+        h.push("signproposal /tmp/proposal.dat /tmp/signed.hex");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Sign a transaction proposal without broadcasting it".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 2 {
+            return Command::<P>::help(self);
+        }
+
+        RT.block_on(async move {
+            match lightclient.do_sign_proposal(args[0], args[1]).await {
+                Ok(txid) => {
+                    object! { "txid" => txid, "signed_file" => args[1] }
+                }
+                Err(e) => {
+                    object! { "error" => e }
+                }
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct BroadcastCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for BroadcastCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Broadcast a signed transaction created by 'signproposal'");
+        h.push("Usage:");
+        h.push("broadcast <signed_tx_file>");
+        h.push("");
+        h.push("The spent notes and utxos are marked as spent in this wallet, just like a regular 'send'");
+        h.push("Example:");
+        h.push("broadcast /tmp/signed.hex");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Broadcast a signed transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 1 {
+            return Command::<P>::help(self);
+        }
+
+        RT.block_on(async move {
+            match lightclient.do_broadcast(args[0]).await {
                 Ok(txid) => {
                     object! { "txid" => txid }
                 }
@@ -815,6 +1325,97 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SendComman
     }
 }
 
+struct RebroadcastCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for RebroadcastCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Broadcast a transaction sent from this wallet again, if it hasn't been mined yet");
+        h.push("Usage:");
+        h.push("rebroadcast <txid>");
+        h.push("");
+        h.push(
+            "Unmined transactions are also rebroadcast automatically while syncing, until they are mined or expire.",
+        );
+        h.push("Example:");
+        h.push("rebroadcast 3c8d3f5d2a7b...");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Broadcast an unmined transaction again".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 1 {
+            return Command::<P>::help(self);
+        }
+
+        RT.block_on(async move {
+            match lightclient.do_rebroadcast(args[0]).await {
+                Ok(txid) => {
+                    object! { "txid" => txid }
+                }
+                Err(e) => {
+                    object! { "error" => e }
+                }
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct PaymentRequestCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for PaymentRequestCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Create a ZIP-321 payment request URI for one of your addresses");
+        h.push("Usage:");
+        h.push("paymentrequest <your address> [amount in zatoshis] [\"label\"] [\"memo\"]");
+        h.push("");
+        h.push("The URI can be paid by any wallet that supports ZIP-321, including with 'send <uri>'.");
+        h.push("A request without an amount leaves the amount to the payer, so it can't be paid with 'send <uri>'.");
+        h.push("Example:");
+        h.push("paymentrequest ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d 200000 \"Invoice 42\" \"Thanks!\"");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Create a ZIP-321 payment request URI".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() < 1 || args.len() > 4 {
+            return Command::<P>::help(self);
+        }
+
+        let amount = match args.get(1).map(|a| a.parse::<u64>()) {
+            None => 0,
+            Some(Ok(amt)) => amt,
+            Some(Err(e)) => return format!("Couldn't parse amount: {}", e),
+        };
+        let label = args.get(2).map(|s| s.to_string());
+        let memo = args.get(3).map(|s| s.to_string());
+
+        RT.block_on(async move {
+            match lightclient.do_payment_request(args[0], amount, label, memo).await {
+                Ok(uri) => {
+                    object! { "uri" => uri }
+                }
+                Err(e) => {
+                    object! { "error" => e }
+                }
+            }
+            .pretty(2)
+        })
+    }
+}
+
 struct SaveCommand {}
 
 impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SaveCommand {
@@ -930,6 +1531,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SetOptionC
         h.push("setoption <optionname>=<optionvalue>");
         h.push("List of available options:");
         h.push("download_memos : none | wallet | all");
+        h.push("spam_filter_threshold : <number of outputs>, or -1 to disable");
+        h.push("note_selection : largest | smallest | oldest | random");
+        h.push("fee_policy : zip317 | fixed:<zats> | multiplier:<n>");
+        h.push("change_address : spent_note | diversified");
 
         h.join("\n")
     }
@@ -969,6 +1574,47 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for SetOptionC
                     let threshold = option_value.parse::<i64>().unwrap();
                     lightclient.wallet.set_spam_filter_threshold(threshold).await
                 }
+                "note_selection" => match option_value {
+                    "largest" => {
+                        lightclient
+                            .wallet
+                            .set_note_selection(NoteSelectionOption::LargestFirst)
+                            .await
+                    }
+                    "smallest" => {
+                        lightclient
+                            .wallet
+                            .set_note_selection(NoteSelectionOption::SmallestFirst)
+                            .await
+                    }
+                    "oldest" => {
+                        lightclient
+                            .wallet
+                            .set_note_selection(NoteSelectionOption::OldestFirst)
+                            .await
+                    }
+                    "random" => lightclient.wallet.set_note_selection(NoteSelectionOption::Random).await,
+                    _ => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
+                "fee_policy" => match FeePolicy::parse(option_value) {
+                    Ok(p) => lightclient.wallet.set_fee_policy(p).await,
+                    Err(e) => return format!("Error: {}", e),
+                },
+                "change_address" => match option_value {
+                    "spent_note" => {
+                        lightclient
+                            .wallet
+                            .set_change_address(ChangeAddressOption::SpentNoteAddress)
+                            .await
+                    }
+                    "diversified" => {
+                        lightclient
+                            .wallet
+                            .set_change_address(ChangeAddressOption::Diversified)
+                            .await
+                    }
+                    _ => return format!("Error: Couldn't understand {} value {}", option_name, option_value),
+                },
                 _ => return format!("Error: Couldn't understand {}", option_name),
             }
 
@@ -1018,6 +1664,19 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for GetOptionC
                     .await
                     .spam_threshold
                     .to_string(),
+                "note_selection" => match lightclient.wallet.wallet_options.read().await.note_selection {
+                    NoteSelectionOption::LargestFirst => "largest",
+                    NoteSelectionOption::SmallestFirst => "smallest",
+                    NoteSelectionOption::OldestFirst => "oldest",
+                    NoteSelectionOption::Random => "random",
+                }
+                .to_string(),
+                "fee_policy" => lightclient.wallet.wallet_options.read().await.fee_policy.to_string(),
+                "change_address" => match lightclient.wallet.wallet_options.read().await.change_address {
+                    ChangeAddressOption::SpentNoteAddress => "spent_note",
+                    ChangeAddressOption::Diversified => "diversified",
+                }
+                .to_string(),
                 _ => return format!("Error: Couldn't understand {}", option_name),
             };
 
@@ -1195,8 +1854,11 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for NewAddress
         let mut h = vec![];
         h.push("Create a new address in this wallet");
         h.push("Usage:");
-        h.push("new [z | t] [path]");
+        h.push("new [z | t | u] [path]");
         h.push("");
+        h.push("A u address is a unified address, with a new z address and a new t address as its receivers.");
+        h.push("It has no Orchard receiver, because this wallet doesn't support the Orchard pool.");
+        h.push("It is listed by 'addresses' along with its receivers.");
         h.push("Example:");
         h.push("To create a new z address:");
         h.push("new z");
@@ -1212,7 +1874,41 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for NewAddress
         }
 
         RT.block_on(async move {
-            match lightclient.do_new_address(args[0], args[1]).await {
+            match lightclient.do_new_address(args[0], args.get(1).unwrap_or(&"")).await {
+                Ok(j) => j,
+                Err(e) => object! { "error" => e },
+            }
+            .pretty(2)
+        })
+    }
+}
+
+struct NewDiversifiedCommand {}
+
+impl<P: consensus::Parameters + Send + Sync + 'static> Command<P> for NewDiversifiedCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Create a new diversified address of an existing z address's key");
+        h.push("Usage:");
+        h.push("newdiversified <zaddr>");
+        h.push("");
+        h.push("Diversified addresses can't be linked to each other, but belong to the same key, so unlike 'new z'");
+        h.push("they don't slow down syncing. 'list' shows which diversified address received each note.");
+        h.push("Example:");
+        h.push("newdiversified zs1fxgluwznkzm52ux7jkf4st5znwzqay8zyz4cydnyegt2rh9uhr9458z0nk62fdsssx0cqhy6lyv");
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Create a new diversified address of an existing z address".to_string()
+    }
+    fn exec(&self, args: &[&str], lightclient: &LightClient<P>) -> String {
+        if args.len() != 1 {
+            return format!("Error: Need exactly 1 argument\n\n{}", Command::<P>::help(self));
+        }
+
+        RT.block_on(async move {
+            match lightclient.do_new_diversified_address(args[0]).await {
                 Ok(j) => j,
                 Err(e) => object! { "error" => e },
             }
@@ -1296,6 +1992,8 @@ pub fn get_commands<P: consensus::Parameters + Send + Sync + 'static>() -> Box<H
     map.insert("decryptmessage".to_string(), Box::new(DecryptMessageCommand {}));
     map.insert("rescan".to_string(), Box::new(RescanCommand {}));
     map.insert("clear".to_string(), Box::new(ClearCommand {}));
+    map.insert("cache".to_string(), Box::new(CacheCommand {}));
+    map.insert("checkpoint".to_string(), Box::new(CheckpointCommand {}));
     map.insert("help".to_string(), Box::new(HelpCommand {}));
     map.insert("lasttxid".to_string(), Box::new(LastTxIdCommand {}));
     map.insert("balance".to_string(), Box::new(BalanceCommand {}));
@@ -1309,12 +2007,21 @@ pub fn get_commands<P: consensus::Parameters + Send + Sync + 'static>() -> Box<H
     map.insert("info".to_string(), Box::new(InfoCommand {}));
     map.insert("zecprice".to_string(), Box::new(ZecPriceCommand {}));
     map.insert("send".to_string(), Box::new(SendCommand {}));
+    map.insert("sendbatch".to_string(), Box::new(SendBatchCommand {}));
+    map.insert("addressbook".to_string(), Box::new(AddressBookCommand {}));
     map.insert("shield".to_string(), Box::new(ShieldCommand {}));
+    map.insert("consolidate".to_string(), Box::new(ConsolidateCommand {}));
+    map.insert("paymentrequest".to_string(), Box::new(PaymentRequestCommand {}));
+    map.insert("propose".to_string(), Box::new(ProposeCommand {}));
+    map.insert("signproposal".to_string(), Box::new(SignProposalCommand {}));
+    map.insert("broadcast".to_string(), Box::new(BroadcastCommand {}));
+    map.insert("rebroadcast".to_string(), Box::new(RebroadcastCommand {}));
     map.insert("save".to_string(), Box::new(SaveCommand {}));
     map.insert("quit".to_string(), Box::new(QuitCommand {}));
     map.insert("list".to_string(), Box::new(TransactionsCommand {}));
     map.insert("notes".to_string(), Box::new(NotesCommand {}));
     map.insert("new".to_string(), Box::new(NewAddressCommand {}));
+    map.insert("newdiversified".to_string(), Box::new(NewDiversifiedCommand {}));
     map.insert("defaultfee".to_string(), Box::new(DefaultFeeCommand {}));
     map.insert("seed".to_string(), Box::new(SeedCommand {}));
     map.insert("encrypt".to_string(), Box::new(EncryptCommand {}));
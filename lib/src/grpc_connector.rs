@@ -1,6 +1,5 @@
 use std::cmp;
 use std::collections::HashMap;
-use std::sync::Arc;
 
 use crate::ServerCert;
 use crate::compact_formats::compact_tx_streamer_client::CompactTxStreamerClient;
@@ -9,7 +8,7 @@ use crate::compact_formats::{
     TransparentAddressBlockFilter, TreeState, TxFilter,
 };
 use futures::stream::FuturesUnordered;
-use futures::StreamExt;
+use futures::{Future, StreamExt};
 use log::warn;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::mpsc::{Sender, UnboundedReceiver};
@@ -19,29 +18,32 @@ use tokio::task::JoinHandle;
 use tonic::transport::{ClientTlsConfig, Certificate};
 use tonic::{
     transport::{Channel, Error},
-    Request,
+    Code, Request, Status,
 };
 use zcash_primitives::consensus::{self, BlockHeight, BranchId};
 use zcash_primitives::transaction::{Transaction, TxId};
 
+use self::server_list::ServerList;
+
+pub mod server_list;
 
 #[derive(Clone)]
 pub struct GrpcConnector {
-    uri: http::Uri,
+    servers: ServerList,
 }
 
 impl GrpcConnector {
-    pub fn new(uri: http::Uri) -> Self {
-        Self { uri }
+    pub fn new(servers: ServerList) -> Self {
+        Self { servers }
     }
 
-    async fn get_client(&self) -> Result<CompactTxStreamerClient<Channel>, Error> {
-        let channel = if self.uri.scheme_str() == Some("http") {
+    async fn get_client(uri: &http::Uri) -> Result<CompactTxStreamerClient<Channel>, Error> {
+        let channel = if uri.scheme_str() == Some("http") {
             //println!("http");
-            Channel::builder(self.uri.clone()).connect().await?
+            Channel::builder(uri.clone()).connect().await?
         } else {
             //println!("https");
-            let mut tls = ClientTlsConfig::new().domain_name(self.uri.host().unwrap());
+            let mut tls = ClientTlsConfig::new().domain_name(uri.host().unwrap());
 
             let server_cert = ServerCert::get("fullchain.pem").unwrap().data;
             if server_cert.len() > 0 {
@@ -49,7 +51,7 @@ impl GrpcConnector {
                 tls = tls.ca_certificate(server_root_ca_cert);
             }
 
-            Channel::builder(self.uri.clone())
+            Channel::builder(uri.clone())
                 .tls_config(tls)?
                 // .timeout(Duration::from_secs(10))
                 // .connect_timeout(Duration::from_secs(10))
@@ -60,6 +62,60 @@ impl GrpcConnector {
         Ok(CompactTxStreamerClient::new(channel))
     }
 
+    /// Make a request to the first server that can answer it. If a server can't be connected to or is unavailable,
+    /// it is backed off and the request is made again to the next one. Servers that haven't been used yet are first
+    /// checked to be on the same chain as the others.
+    async fn with_failover<T, F, Fut>(servers: &ServerList, mut request: F) -> Result<T, Status>
+    where
+        F: FnMut(CompactTxStreamerClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut last_error = Status::unavailable("No lightwalletd servers are configured");
+
+        for (idx, uri, chain_checked) in servers.candidates() {
+            let mut client = match Self::get_client(&uri).await {
+                Ok(client) => client,
+                Err(e) => {
+                    let e = format!("Error getting client: {:?}", e);
+                    servers.mark_failure(idx, e.clone());
+                    last_error = Status::unavailable(e);
+                    continue;
+                }
+            };
+
+            if !chain_checked {
+                match client.get_lightd_info(Request::new(Empty {})).await {
+                    Ok(info) => {
+                        let chain_name = info.into_inner().chain_name;
+                        if !servers.check_chain(idx, &chain_name) {
+                            last_error = Status::failed_precondition(format!("{} is on chain {}", uri, chain_name));
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        servers.mark_failure(idx, format!("{}", e));
+                        last_error = e;
+                        continue;
+                    }
+                }
+            }
+
+            match request(client).await {
+                Err(e) if e.code() == Code::Unavailable => {
+                    servers.mark_failure(idx, format!("{}", e));
+                    last_error = e;
+                }
+                r => {
+                    // Any other error is an answer from the server, so it is still working
+                    servers.mark_success(idx);
+                    return r;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
     pub async fn start_saplingtree_fetcher(
         &self,
     ) -> (
@@ -67,13 +123,12 @@ impl GrpcConnector {
         UnboundedSender<(u64, oneshot::Sender<Result<TreeState, String>>)>,
     ) {
         let (tx, mut rx) = unbounded_channel::<(u64, oneshot::Sender<Result<TreeState, String>>)>();
-        let uri = self.uri.clone();
+        let servers = self.servers.clone();
 
         let h = tokio::spawn(async move {
-            let uri = uri.clone();
             while let Some((height, result_tx)) = rx.recv().await {
                 result_tx
-                    .send(Self::get_sapling_tree(uri.clone(), height).await)
+                    .send(Self::get_sapling_tree(servers.clone(), height).await)
                     .unwrap()
             }
         });
@@ -94,10 +149,9 @@ impl GrpcConnector {
             (Vec<String>, u64, u64),
             oneshot::Sender<Vec<UnboundedReceiver<Result<RawTransaction, String>>>>,
         )>();
-        let uri = self.uri.clone();
+        let servers = self.servers.clone();
 
         let h = tokio::spawn(async move {
-            let uri = uri.clone();
             if let Ok(((taddrs, start_height, end_height), result_tx)) = rx.await {
                 let mut tx_rs = vec![];
                 let mut tx_rs_workers = FuturesUnordered::new();
@@ -107,7 +161,7 @@ impl GrpcConnector {
                     let (tx_s, tx_r) = unbounded_channel();
                     tx_rs.push(tx_r);
                     tx_rs_workers.push(tokio::spawn(Self::get_taddr_txns(
-                        uri.clone(),
+                        servers.clone(),
                         taddr,
                         start_height,
                         end_height,
@@ -141,16 +195,16 @@ impl GrpcConnector {
         UnboundedSender<(TxId, oneshot::Sender<Result<Transaction, String>>)>,
     ) {
         let (tx, mut rx) = unbounded_channel::<(TxId, oneshot::Sender<Result<Transaction, String>>)>();
-        let uri = self.uri.clone();
+        let servers = self.servers.clone();
 
         let h = tokio::spawn(async move {
             let mut workers = FuturesUnordered::new();
             while let Some((txid, result_tx)) = rx.recv().await {
-                let uri = uri.clone();
+                let servers = servers.clone();
                 let parameters = parameters.clone();
                 workers.push(tokio::spawn(async move {
                     result_tx
-                        .send(Self::get_full_tx(uri.clone(), &txid, parameters).await)
+                        .send(Self::get_full_tx(servers, &txid, parameters).await)
                         .unwrap()
                 }));
 
@@ -176,8 +230,27 @@ impl GrpcConnector {
         spam_filter_threshold: i64,
         receivers: &[Sender<CompactBlock>; 2],
     ) -> Result<(), String> {
-        let mut client = self.get_client().await.map_err(|e| format!("{}", e))?;
+        let block_cache = self
+            .download_block_range(start_height, end_height, spam_filter_threshold)
+            .await?;
 
+        // Send all the blocks to the recievers
+        for block in block_cache {
+            //println!("grpc connector Sent {}", block.height);
+            receivers[0].send(block.clone()).await.map_err(|e| format!("{}", e))?;
+            receivers[1].send(block).await.map_err(|e| format!("{}", e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Download all the blocks from `start_height` to `end_height`, in the order the server sends them
+    pub async fn download_block_range(
+        &self,
+        start_height: u64,
+        end_height: u64,
+        spam_filter_threshold: i64,
+    ) -> Result<Vec<CompactBlock>, String> {
         let bs = BlockId {
             height: start_height,
             hash: vec![],
@@ -187,58 +260,51 @@ impl GrpcConnector {
             hash: vec![],
         };
 
-        let request = Request::new(BlockRange {
+        let range = BlockRange {
             start: Some(bs),
             end: Some(be),
             spam_filter_threshold: cmp::max(0, spam_filter_threshold) as u64,
-        });
-
-        let mut response = client
-            .get_block_range(request)
-            .await
-            .map_err(|e| format!("{}", e))?
-            .into_inner();
+        };
 
-        // First download all blocks and save them locally, so we don't timeout
-        let mut block_cache = Vec::new();
+        // The whole range is downloaded again from the next server if one fails halfway through
+        Self::with_failover(&self.servers, |mut client| {
+            let request = Request::new(range.clone());
+            async move {
+                let mut response = client.get_block_range(request).await?.into_inner();
 
-        while let Some(block) = response.message().await.map_err(|e| {
-            // println!("first error");
-            format!("{}", e)
-        })? {
-            block_cache.push(block);
-        }
+                // First download all blocks and save them locally, so we don't timeout
+                let mut block_cache = Vec::new();
 
-        // Send all the blocks to the recievers
-        for block in block_cache {
-            //println!("grpc connector Sent {}", block.height);
-            receivers[0].send(block.clone()).await.map_err(|e| format!("{}", e))?;
-            receivers[1].send(block).await.map_err(|e| format!("{}", e))?;
-        }
+                while let Some(block) = response.message().await? {
+                    block_cache.push(block);
+                }
 
-        Ok(())
+                Ok::<_, Status>(block_cache)
+            }
+        })
+        .await
+        .map_err(|e| format!("{}", e))
     }
 
     async fn get_full_tx<P: consensus::Parameters + Send + Sync + 'static>(
-        uri: http::Uri,
+        servers: ServerList,
         txid: &TxId,
         parameters: P,
     ) -> Result<Transaction, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let request = Request::new(TxFilter {
+        let filter = TxFilter {
             block: None,
             index: 0,
             hash: txid.as_ref().to_vec(),
-        });
+        };
 
         log::info!("Full fetching {}", txid);
 
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let response = client.get_transaction(request).await.map_err(|e| format!("{}", e))?;
+        let response = Self::with_failover(&servers, |mut client| {
+            let request = Request::new(filter.clone());
+            async move { client.get_transaction(request).await }
+        })
+        .await
+        .map_err(|e| format!("{}", e))?;
 
         let height = response.get_ref().height as u32;
         Transaction::read(
@@ -249,14 +315,12 @@ impl GrpcConnector {
     }
 
     async fn get_taddr_txns(
-        uri: http::Uri,
+        servers: ServerList,
         taddr: String,
         start_height: u64,
         end_height: u64,
         txns_sender: UnboundedSender<Result<RawTransaction, String>>,
     ) -> Result<(), String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-
         // Make sure start_height is smaller than end_height, because the API expects it like that
         let (start_height, end_height) = if start_height < end_height {
             (start_height, end_height)
@@ -281,25 +345,22 @@ impl GrpcConnector {
                 spam_filter_threshold: 0,
             }),
         };
-        let request = Request::new(args.clone());
-
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let maybe_response = match client.get_taddress_txids(request).await {
-            Ok(r) => r,
-            Err(e) => {
-                if e.code() == tonic::Code::Unimplemented {
-                    // Try the old, legacy API
-                    let request = Request::new(args);
-                    client.get_address_txids(request).await.map_err(|e| format!("{}", e))?
-                } else {
-                    return Err(format!("{}", e));
+
+        // Only the request fails over. Once the txns are being streamed, they can't be asked for again
+        let maybe_response = Self::with_failover(&servers, |mut client| {
+            let args = args.clone();
+            async move {
+                match client.get_taddress_txids(Request::new(args.clone())).await {
+                    Err(e) if e.code() == tonic::Code::Unimplemented => {
+                        // Try the old, legacy API
+                        client.get_address_txids(Request::new(args)).await
+                    }
+                    r => r,
                 }
             }
-        };
+        })
+        .await
+        .map_err(|e| format!("{}", e))?;
 
         let mut response = maybe_response.into_inner();
 
@@ -310,38 +371,26 @@ impl GrpcConnector {
         Ok(())
     }
 
-    pub async fn get_info(uri: http::Uri) -> Result<LightdInfo, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let request = Request::new(Empty {});
+    pub async fn get_info(servers: ServerList) -> Result<LightdInfo, String> {
+        let response = Self::with_failover(&servers, |mut client| async move {
+            client.get_lightd_info(Request::new(Empty {})).await
+        })
+        .await
+        .map_err(|e| format!("Error with response: {:?}", e))?;
 
-        let response = client
-            .get_lightd_info(request)
-            .await
-            .map_err(|e| format!("Error with response: {:?}", e))?;
         Ok(response.into_inner())
     }
 
-    pub async fn monitor_mempool(uri: http::Uri, mempool_tx: UnboundedSender<RawTransaction>) -> Result<(), String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let request = Request::new(Empty {});
-
-        let mut response = client
-            .get_mempool_stream(request)
-            .await
-            .map_err(|e| format!("{}", e))?
-            .into_inner();
+    pub async fn monitor_mempool(
+        servers: ServerList,
+        mempool_tx: UnboundedSender<RawTransaction>,
+    ) -> Result<(), String> {
+        let mut response = Self::with_failover(&servers, |mut client| async move {
+            client.get_mempool_stream(Request::new(Empty {})).await
+        })
+        .await
+        .map_err(|e| format!("{}", e))?
+        .into_inner();
         while let Some(rtx) = response.message().await.map_err(|e| format!("{}", e))? {
             mempool_tx.send(rtx).map_err(|e| format!("{}", e))?;
         }
@@ -349,122 +398,110 @@ impl GrpcConnector {
         Ok(())
     }
 
-    pub async fn get_sapling_tree(uri: http::Uri, height: u64) -> Result<TreeState, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
+    pub async fn get_sapling_tree(servers: ServerList, height: u64) -> Result<TreeState, String> {
         let b = BlockId {
             height: height as u64,
             hash: vec![],
         };
-        let response = client
-            .get_tree_state(Request::new(b))
-            .await
-            .map_err(|e| format!("Error with response: {:?}", e))?;
+        let response = Self::with_failover(&servers, |mut client| {
+            let b = b.clone();
+            async move { client.get_tree_state(Request::new(b)).await }
+        })
+        .await
+        .map_err(|e| format!("Error with response: {:?}", e))?;
 
         Ok(response.into_inner())
     }
 
-    pub async fn get_current_zec_price(uri: http::Uri) -> Result<PriceResponse, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-        let request = Request::new(Empty {});
-
-        let response = client
-            .get_current_zec_price(request)
-            .await
-            .map_err(|e| format!("Error with response: {:?}", e))?;
+    pub async fn get_current_zec_price(servers: ServerList) -> Result<PriceResponse, String> {
+        let response = Self::with_failover(&servers, |mut client| async move {
+            client.get_current_zec_price(Request::new(Empty {})).await
+        })
+        .await
+        .map_err(|e| format!("Error with response: {:?}", e))?;
 
         Ok(response.into_inner())
     }
 
     pub async fn get_historical_zec_prices(
-        uri: http::Uri,
+        servers: ServerList,
         txids: Vec<(TxId, u64)>,
         currency: String,
     ) -> Result<HashMap<TxId, Option<f64>>, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let mut prices = HashMap::new();
-        let mut error_count: u32 = 0;
-
-        for (txid, ts) in txids {
-            if error_count < 10 {
-                let r = Request::new(PriceRequest {
-                    timestamp: ts,
-                    currency: currency.clone(),
-                });
-                match client.get_zec_price(r).await {
-                    Ok(response) => {
-                        let price_response = response.into_inner();
-                        prices.insert(txid, Some(price_response.price));
-                    }
-                    Err(e) => {
-                        // If the server doesn't support this, bail
-                        if e.code() == tonic::Code::Unimplemented {
-                            return Err(format!("Unsupported by server"));
+        // The prices are fetched one by one, and the ones that fail are retried later. If the server becomes
+        // unavailable, all of them are fetched again from the next one
+        let result = Self::with_failover(&servers, |mut client| {
+            let txids = txids.clone();
+            let currency = currency.clone();
+
+            async move {
+                let mut prices = HashMap::new();
+                let mut error_count: u32 = 0;
+
+                for (txid, ts) in txids {
+                    if error_count < 10 {
+                        let r = Request::new(PriceRequest {
+                            timestamp: ts,
+                            currency: currency.clone(),
+                        });
+                        match client.get_zec_price(r).await {
+                            Ok(response) => {
+                                let price_response = response.into_inner();
+                                prices.insert(txid, Some(price_response.price));
+                            }
+                            // If the server doesn't support this or went away, bail
+                            Err(e) if e.code() == Code::Unimplemented || e.code() == Code::Unavailable => {
+                                return Err(e);
+                            }
+                            Err(e) => {
+                                // Ignore other errors, these are probably just for the particular date/time
+                                // and will be retried anyway
+                                warn!("Ignoring grpc error: {}", e);
+                                error_count += 1;
+                                prices.insert(txid, None);
+                            }
                         }
-
-                        // Ignore other errors, these are probably just for the particular date/time
-                        // and will be retried anyway
-                        warn!("Ignoring grpc error: {}", e);
-                        error_count += 1;
+                    } else {
+                        // If there are too many errors, don't bother querying the server, just return none
                         prices.insert(txid, None);
                     }
                 }
-            } else {
-                // If there are too many errors, don't bother querying the server, just return none
-                prices.insert(txid, None);
+
+                Ok(prices)
             }
-        }
+        })
+        .await;
 
-        Ok(prices)
+        match result {
+            Ok(prices) => Ok(prices),
+            Err(e) if e.code() == Code::Unimplemented => Err(format!("Unsupported by server")),
+            Err(e) => Err(format!("Error getting prices: {}", e)),
+        }
     }
 
     // get_latest_block GRPC call
-    pub async fn get_latest_block(uri: http::Uri) -> Result<BlockId, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let request = Request::new(ChainSpec {});
-
-        let response = client
-            .get_latest_block(request)
-            .await
-            .map_err(|e| format!("Error with response: {:?}", e))?;
+    pub async fn get_latest_block(servers: ServerList) -> Result<BlockId, String> {
+        let response = Self::with_failover(&servers, |mut client| async move {
+            client.get_latest_block(Request::new(ChainSpec {})).await
+        })
+        .await
+        .map_err(|e| format!("Error with response: {:?}", e))?;
 
         Ok(response.into_inner())
     }
 
-    pub async fn send_transaction(uri: http::Uri, tx_bytes: Box<[u8]>) -> Result<String, String> {
-        let client = Arc::new(GrpcConnector::new(uri));
-        let mut client = client
-            .get_client()
-            .await
-            .map_err(|e| format!("Error getting client: {:?}", e))?;
-
-        let request = Request::new(RawTransaction {
+    pub async fn send_transaction(servers: ServerList, tx_bytes: Box<[u8]>) -> Result<String, String> {
+        let raw_tx = RawTransaction {
             data: tx_bytes.to_vec(),
             height: 0,
-        });
+        };
 
-        let response = client
-            .send_transaction(request)
-            .await
-            .map_err(|e| format!("Send Error: {}", e))?;
+        let response = Self::with_failover(&servers, |mut client| {
+            let request = Request::new(raw_tx.clone());
+            async move { client.send_transaction(request).await }
+        })
+        .await
+        .map_err(|e| format!("Send Error: {}", e))?;
 
         let sendresponse = response.into_inner();
         if sendresponse.error_code == 0 {
@@ -0,0 +1,256 @@
+use std::{
+    cmp,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use json::{object, JsonValue};
+use log::{error, info, warn};
+
+// How long to stay away from a server after it fails. This doubles with every failure in a row, up to the max
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct ServerHealth {
+    uri: http::Uri,
+
+    // Failures in a row, and when the server can be tried again after the last one
+    failures: u32,
+    retry_at: Option<Instant>,
+    last_error: Option<String>,
+
+    // None until the server's chain has been compared with the chain the wallet is on
+    same_chain: Option<bool>,
+}
+
+#[derive(Debug)]
+struct Servers {
+    servers: Vec<ServerHealth>,
+
+    // The chain all the servers have to be on. If it isn't known up front, the first server checked decides it
+    chain_name: Option<String>,
+}
+
+// lightwalletd reports the chain as "main", "test" or "regtest", while a config that wasn't created from a server
+// uses the HRP of the network's Sapling addresses
+fn network_name(chain_name: &str) -> &str {
+    match chain_name {
+        "zs" | "main" => "main",
+        "ztestsapling" | "test" => "test",
+        "zregtestsapling" | "regtest" => "regtest",
+        c => c,
+    }
+}
+
+/// A prioritized list of lightwalletd servers, shared by all the copies of the config. Requests go to the first
+/// server that isn't backing off. A server that fails is backed off for a while, so the ones after it are tried
+/// first until it is due to be retried.
+#[derive(Clone, Debug)]
+pub struct ServerList {
+    inner: Arc<Mutex<Servers>>,
+}
+
+impl ServerList {
+    pub fn new(uris: Vec<http::Uri>) -> Self {
+        let servers = uris
+            .into_iter()
+            .map(|uri| ServerHealth {
+                uri,
+                failures: 0,
+                retry_at: None,
+                last_error: None,
+                same_chain: None,
+            })
+            .collect();
+
+        Self {
+            inner: Arc::new(Mutex::new(Servers {
+                servers,
+                chain_name: None,
+            })),
+        }
+    }
+
+    /// A list of servers that all have to be on `chain_name`. Servers that report another chain are never used
+    pub fn for_chain(uris: Vec<http::Uri>, chain_name: &str) -> Self {
+        let list = Self::new(uris);
+        list.inner.lock().unwrap().chain_name = Some(network_name(chain_name).to_string());
+
+        list
+    }
+
+    pub fn uris(&self) -> Vec<http::Uri> {
+        let inner = self.inner.lock().unwrap();
+        inner.servers.iter().map(|s| s.uri.clone()).collect()
+    }
+
+    /// The server that the next request will go to first
+    pub fn current(&self) -> http::Uri {
+        self.candidates()
+            .into_iter()
+            .next()
+            .map(|(_, uri, _)| uri)
+            .unwrap_or_default()
+    }
+
+    /// The servers in the order they should be tried in, with their index and whether their chain was already
+    /// checked. The ones that aren't backing off come first, in priority order, followed by the ones that are,
+    /// soonest to be retried first, so a request is still attempted when every server is failing. Servers on
+    /// another chain are left out.
+    pub(crate) fn candidates(&self) -> Vec<(usize, http::Uri, bool)> {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let mut candidates = inner
+            .servers
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.same_chain != Some(false))
+            .map(|(i, s)| (s.retry_at.filter(|t| *t > now), i))
+            .collect::<Vec<_>>();
+        // None (not backing off) sorts before Some
+        candidates.sort();
+
+        candidates
+            .into_iter()
+            .map(|(_, i)| (i, inner.servers[i].uri.clone(), inner.servers[i].same_chain.is_some()))
+            .collect()
+    }
+
+    pub(crate) fn mark_success(&self, idx: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let server = &mut inner.servers[idx];
+
+        if server.failures > 0 {
+            info!("Server {} is available again", server.uri);
+        }
+        server.failures = 0;
+        server.retry_at = None;
+    }
+
+    pub(crate) fn mark_failure(&self, idx: usize, e: String) {
+        let mut inner = self.inner.lock().unwrap();
+        let server = &mut inner.servers[idx];
+
+        let backoff = cmp::min(INITIAL_BACKOFF * 2u32.saturating_pow(server.failures), MAX_BACKOFF);
+        warn!("Server {} failed, not using it for {:?}: {}", server.uri, backoff, e);
+
+        server.failures += 1;
+        server.retry_at = Some(Instant::now() + backoff);
+        server.last_error = Some(e);
+    }
+
+    /// Record the chain that a server says it is on. Unless the list was created for a chain, the first server
+    /// checked decides the chain, so the wallet never fails over to a server on another chain. Returns false if the
+    /// server is on a different chain.
+    pub(crate) fn check_chain(&self, idx: usize, chain_name: &str) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        let same_chain = match &inner.chain_name {
+            Some(c) => c == network_name(chain_name),
+            None => {
+                inner.chain_name = Some(network_name(chain_name).to_string());
+                true
+            }
+        };
+
+        let server = &mut inner.servers[idx];
+        if !same_chain {
+            error!("Server {} is on chain {}, not using it", server.uri, chain_name);
+            server.last_error = Some(format!("Server is on a different chain: {}", chain_name));
+        }
+        server.same_chain = Some(same_chain);
+
+        same_chain
+    }
+
+    pub fn status(&self) -> JsonValue {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        JsonValue::Array(
+            inner
+                .servers
+                .iter()
+                .map(|s| {
+                    object! {
+                        "uri" => s.uri.to_string(),
+                        "same_chain" => s.same_chain,
+                        "failures" => s.failures,
+                        "retry_in_secs" => s.retry_at.filter(|t| *t > now).map(|t| (t - now).as_secs()),
+                        "last_error" => s.last_error.clone(),
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ServerList;
+
+    fn uris() -> Vec<http::Uri> {
+        vec![
+            "http://127.0.0.1:9001".parse().unwrap(),
+            "http://127.0.0.1:9002".parse().unwrap(),
+            "http://127.0.0.1:9003".parse().unwrap(),
+        ]
+    }
+
+    fn order(list: &ServerList) -> Vec<usize> {
+        list.candidates().into_iter().map(|(i, _, _)| i).collect()
+    }
+
+    #[test]
+    fn failover_order() {
+        let list = ServerList::new(uris());
+        assert_eq!(order(&list), vec![0, 1, 2]);
+        assert_eq!(list.current(), uris()[0]);
+
+        // A failed server goes to the back, but is still tried as a last resort
+        list.mark_failure(0, "down".to_string());
+        assert_eq!(order(&list), vec![1, 2, 0]);
+        assert_eq!(list.current(), uris()[1]);
+
+        // The one that failed first is retried first
+        list.mark_failure(2, "down".to_string());
+        assert_eq!(order(&list), vec![1, 0, 2]);
+
+        // And once a server works again, it is back in its place
+        list.mark_success(0);
+        assert_eq!(order(&list), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn same_chain() {
+        let list = ServerList::new(uris());
+        assert!(list.candidates().iter().all(|(_, _, checked)| !checked));
+
+        assert!(list.check_chain(1, "main"));
+        assert!(!list.check_chain(0, "test"));
+        assert!(list.check_chain(2, "main"));
+
+        assert_eq!(
+            list.candidates(),
+            vec![(1, uris()[1].clone(), true), (2, uris()[2].clone(), true)]
+        );
+    }
+
+    #[test]
+    fn expected_chain() {
+        // A list for a chain rejects other chains, even from the first server checked
+        let list = ServerList::for_chain(uris(), "zs");
+        assert!(!list.check_chain(0, "test"));
+        assert!(list.check_chain(1, "main"));
+        assert!(list.check_chain(2, "zs"));
+        assert_eq!(order(&list), vec![1, 2]);
+
+        let list = ServerList::for_chain(uris(), "ztestsapling");
+        assert!(!list.check_chain(0, "main"));
+        assert!(!list.check_chain(1, "regtest"));
+        assert!(list.check_chain(2, "test"));
+        assert_eq!(order(&list), vec![2]);
+    }
+}
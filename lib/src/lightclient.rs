@@ -1,31 +1,41 @@
 use self::lightclient_config::LightClientConfig;
 use crate::{
     blaze::{
-        block_witness_data::BlockAndWitnessData, fetch_compact_blocks::FetchCompactBlocks,
-        fetch_full_tx::FetchFullTxns, fetch_taddr_txns::FetchTaddrTxns, sync_status::SyncStatus,
-        syncdata::BlazeSyncData, trial_decryptions::TrialDecryptions, update_notes::UpdateNotes,
+        batch_sizer::BatchSizer,
+        block_witness_data::{BlockAndWitnessData, DEEP_REORG, INCONSISTENT_CHAIN},
+        cross_check::cross_check_servers,
+        fetch_compact_blocks::{cached_sapling_tree, FetchCompactBlocks},
+        fetch_full_tx::FetchFullTxns,
+        fetch_taddr_txns::FetchTaddrTxns,
+        sync_status::SyncStatus,
+        sync_token::SYNC_CANCELLED,
+        syncdata::BlazeSyncData,
+        trial_decryptions::TrialDecryptions,
+        update_notes::UpdateNotes,
+    },
+    compact_formats::{RawTransaction, TreeState},
+    grpc_connector::{server_list::ServerList, GrpcConnector},
+    lightclient::lightclient_config::{MAX_REORG, REBROADCAST_INTERVAL},
+    lightwallet::{
+        self, data::WalletTx, events::WalletEvent, keys::KeystoresKind, message::Message, now, FeePolicy, LightWallet,
     },
-    compact_formats::RawTransaction,
-    grpc_connector::GrpcConnector,
-    lightclient::lightclient_config::MAX_REORG,
-    lightwallet::{self, data::WalletTx, keys::KeystoresKind, message::Message, now, LightWallet},
 };
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{future::join_all, stream::FuturesUnordered, StreamExt};
 use json::{array, object, JsonValue};
 use log::{error, info, warn};
 use std::{
     cmp,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{self, BufReader, Error, ErrorKind, Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use tokio::{
     join,
     runtime::Runtime,
-    sync::{mpsc::unbounded_channel, oneshot, Mutex, RwLock},
+    sync::{broadcast, mpsc::unbounded_channel, oneshot, Mutex, RwLock},
     task::yield_now,
     time::sleep,
 };
@@ -34,6 +44,8 @@ use zcash_primitives::{
     block::BlockHash,
     consensus::{self, BlockHeight, BranchId},
     memo::{Memo, MemoBytes},
+    merkle_tree::CommitmentTree,
+    sapling::Node,
     transaction::{Transaction, TxId},
 };
 
@@ -98,7 +110,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         l.set_wallet_initial_state(height).await;
 
         info!("Created new wallet!");
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers.current());
         Ok(l)
     }
 
@@ -202,8 +214,42 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         Ok(())
     }
 
-    pub async fn set_wallet_initial_state(&self, height: u64) {
+    // Get the tree state at `height` from the block cache, or else from the server or the closest checkpoint, adding
+    // it to the block cache so a rescan from the same height doesn't have to fetch it again
+    async fn get_initial_state(&self, height: u64) -> Option<(u64, String, String)> {
+        let block_cache = self.bsync_data.read().await.block_cache.clone();
+        let cache = match block_cache {
+            Some(cache) if height > self.config.sapling_activation_height => cache,
+            _ => return self.config.get_initial_state(height).await,
+        };
+
+        let spam_filter_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+        if let Err(e) = cache.lock().await.open(spam_filter_threshold) {
+            warn!("Couldn't open the block cache: {}", e);
+        }
+
+        if let Some(tree_state) = cached_sapling_tree(&cache, self.config.sapling_activation_height, height).await {
+            info!("Using the sapling tree at height {} from the block cache", height);
+            return Some((tree_state.height, tree_state.hash, tree_state.tree));
+        }
+
         let state = self.config.get_initial_state(height).await;
+        if let Some((height, hash, tree)) = &state {
+            let mut tree_state = TreeState::default();
+            tree_state.height = *height;
+            tree_state.hash = hash.clone();
+            tree_state.tree = tree.clone();
+
+            if let Err(e) = cache.lock().await.insert_tree_state(&tree_state) {
+                warn!("Couldn't add the tree state at {} to the block cache: {}", height, e);
+            }
+        }
+
+        state
+    }
+
+    pub async fn set_wallet_initial_state(&self, height: u64) {
+        let state = self.get_initial_state(height).await;
 
         match state {
             Some((height, hash, tree)) => {
@@ -217,7 +263,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub async fn clear_and_set_wallet_initial_state(&self, height: u64) {
-        let state = self.config.get_initial_state(height).await;
+        let state = self.get_initial_state(height).await;
 
         match state {
             Some((height, hash, tree)) => {
@@ -243,7 +289,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             l.set_wallet_initial_state(latest_block).await;
 
             info!("Created new wallet with a new seed!");
-            info!("Created LightClient to {}", &config.server);
+            info!("Created LightClient to {}", &config.servers.current());
 
             // Save
             l.do_save(true)
@@ -285,7 +331,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             l.set_wallet_initial_state(latest_block).await;
 
             info!("Created new wallet with a ledger!");
-            info!("Created LightClient to {}", &config.server);
+            info!("Created LightClient to {}", &config.servers.current());
 
             // Save
             l.do_save(true)
@@ -362,7 +408,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             })
         };
 
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers.current());
 
         lr
     }
@@ -380,7 +426,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             };
 
             info!("Read wallet with birthday {}", lc.wallet.get_birthday().await);
-            info!("Created LightClient to {}", &config.server);
+            info!("Created LightClient to {}", &config.servers.current());
 
             Ok(lc)
         });
@@ -412,7 +458,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             };
 
             info!("Read wallet with birthday {}", lc.wallet.get_birthday().await);
-            info!("Created LightClient to {}", &config.server);
+            info!("Created LightClient to {}", &config.servers.current());
 
             Ok(lc)
         });
@@ -435,6 +481,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             return Err("Wallet is locked");
         }
 
+        let ufvks = self
+            .wallet
+            .in_memory_keys()
+            .await
+            .expect("in memory keystore")
+            .get_unified_full_viewing_keys()
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+
         // Clone address so it can be moved into the closure
         let address = addr.clone();
         // Go over all z addresses
@@ -451,6 +506,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                     "address"     => addr.clone(),
                     "private_key" => pk.clone(),
                     "viewing_key" => vk.clone(),
+                    "ufvk"        => ufvks.get(addr).cloned(),
                 }
             })
             .collect::<Vec<JsonValue>>();
@@ -505,10 +561,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         let t_addresses= t_addresses_res.collect::<Vec<_>>();
         let t_paths= t_paths_res.collect::<Vec<_>>();
 
+        // Collect unified addresses
+        let u_addresses = self.wallet.keys().read().await.get_all_uaddresses().await;
+
         object! {
             "z_addresses" => z_addresses,
             "t_addresses" => t_addresses,
             "t_paths" => t_paths,
+            "u_addresses" => u_addresses,
         }
     }
 
@@ -587,8 +647,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 let mut wallet_bytes = vec![];
                 match self.wallet.write(&mut wallet_bytes).await {
                     Ok(_) => {
-                        let mut file = File::create(self.config.get_wallet_path()).unwrap();
+                        // Write to a temp file first and then move it over the wallet, so that getting killed
+                        // in the middle of a save doesn't leave a half-written wallet behind
+                        let wallet_path = self.config.get_wallet_path();
+                        let tmp_path = wallet_path.with_extension("dat.tmp");
+
+                        let mut file = File::create(&tmp_path).map_err(|e| format!("{}", e))?;
                         file.write_all(&wallet_bytes).map_err(|e| format!("{}", e))?;
+                        file.sync_all().map_err(|e| format!("{}", e))?;
+                        std::fs::rename(&tmp_path, &wallet_path).map_err(|e| format!("{}", e))?;
                         Ok(())
                     }
                     Err(e) => {
@@ -636,8 +703,13 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         }
     }
 
+    /// The server that requests currently go to
     pub fn get_server_uri(&self) -> http::Uri {
-        self.config.server.clone()
+        self.config.servers.current()
+    }
+
+    pub fn get_servers(&self) -> ServerList {
+        self.config.servers.clone()
     }
 
     pub async fn do_zec_price(&self) -> String {
@@ -664,13 +736,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub async fn do_info(&self) -> String {
-        match GrpcConnector::get_info(self.get_server_uri()).await {
+        match GrpcConnector::get_info(self.get_servers()).await {
             Ok(i) => {
                 let o = object! {
                     "version" => i.version,
                     "zcashd_version" => format!("{}/{}", i.zcashd_build, i.zcashd_subversion),
                     "git_commit" => i.git_commit,
                     "server_uri" => self.get_server_uri().to_string(),
+                    "servers" => self.config.servers.status(),
                     "vendor" => i.vendor,
                     "taddr_support" => i.taddr_support,
                     "chain_name" => i.chain_name,
@@ -684,6 +757,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         }
     }
 
+    /// Subscribe to the wallet's events: sync progress, incoming notes, reorgs, send progress and price updates.
+    /// A subscriber that falls too far behind misses the oldest events, and gets a `Lagged` error for them.
+    pub fn subscribe(&self) -> broadcast::Receiver<WalletEvent> {
+        self.wallet.events().subscribe()
+    }
+
     pub async fn do_send_progress(&self) -> Result<JsonValue, String> {
         let progress = self.wallet.get_send_progress().await;
 
@@ -725,26 +804,28 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         {
             // Collect Sapling notes
-            // First, collect all extfvk's that are spendable (i.e., we have the private key)
-            let spendable_zaddress: HashSet<String> = self
+            // First, collect all ivk's that are spendable (i.e., we have the private key). Notes are matched by
+            // ivk rather than by address, so notes received at diversified addresses are included too
+            let spendable_ivks: HashSet<[u8; 32]> = self
                 .wallet
                 .keys()
                 .read()
                 .await
-                .get_all_spendable_zaddresses()
+                .get_all_spendable_ivks()
                 .await
+                .map(|ivk| ivk.to_repr())
                 .collect();
 
             self.wallet.txns.read().await.current.iter()
                 .flat_map( |(txid, wtx)| {
-                    let spendable_address = spendable_zaddress.clone();
+                    let spendable_ivks = spendable_ivks.clone();
                     wtx.notes.iter().filter_map(move |nd|
                         if !all_notes && nd.spent.is_some() {
                             None
                         } else {
                             let address = LightWallet::<P>::note_address(self.config.hrp_sapling_address(), nd);
                             let spendable = address.is_some() &&
-                                                    spendable_address.contains(&address.clone().unwrap()) &&
+                                                    spendable_ivks.contains(&nd.ivk.to_repr()) &&
                                                     wtx.block <= anchor_height && nd.spent.is_none() && nd.unconfirmed_spent.is_none();
 
                             let created_block:u32 = wtx.block.into();
@@ -874,6 +955,20 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
     }
 
     pub async fn do_list_transactions(&self, include_memo_hex: bool) -> JsonValue {
+        // The diversified addresses we created, so we can tell which key and diversifier index received each note
+        let diversified: HashMap<String, (String, u64)> = self
+            .wallet
+            .keys()
+            .read()
+            .await
+            .get_all_diversified_zaddresses()
+            .await
+            .into_iter()
+            .map(|(addr, zaddr, index)| (addr, (zaddr, index)))
+            .collect();
+
+        let address_book = self.wallet.address_book.read().await.clone();
+
         // Create a list of TransactionItems from wallet txns
         let mut tx_list = self
             .wallet
@@ -907,6 +1002,10 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                                 "memo"    => LightWallet::<P>::memo_str(Some(om.memo.clone()))
                             };
 
+                            if let Some(label) = address_book.get_label(&om.address) {
+                                o.insert("label", label.clone()).unwrap();
+                            }
+
                             if include_memo_hex {
                                 let memo_bytes: MemoBytes = om.memo.clone().into();
                                 o.insert("memohex", hex::encode(memo_bytes.as_slice())).unwrap();
@@ -924,6 +1023,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                         "txid"         => format!("{}", v.txid),
                         "zec_price"    => v.zec_price.map(|p| (p * 100.0).round() / 100.0),
                         "amount"       => total_change as i64 - v.total_funds_spent() as i64,
+                        "fee"          => v.fee,
                         "outgoing_metadata" => outgoing_json,
                     });
                 }
@@ -943,6 +1043,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                         "memo"         => LightWallet::<P>::memo_str(nd.memo.clone())
                     };
 
+                    // If the note was received at a diversified address, also say which address it was diversified from
+                    if let Some((zaddr, index)) = o["address"].as_str().and_then(|a| diversified.get(a)) {
+                        o.insert("diversified_from", zaddr.clone()).unwrap();
+                        o.insert("diversifier_index", *index).unwrap();
+                    }
+
                     if include_memo_hex {
                         o.insert(
                             "memohex",
@@ -1003,6 +1109,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             let (addr, path) = match addr_type {
                 "z" => self.wallet.keys().write().await.add_zaddr(path).await,
                 "t" => self.wallet.keys().write().await.add_taddr(path).await,
+                "u" => self.wallet.keys().write().await.add_uaddr().await,
                 _ => {
                     let e = format!("Unrecognized address type: {}", addr_type);
                     error!("{}", e);
@@ -1025,6 +1132,64 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         Ok(array![new_address, path])
     }
 
+    /// Create a new diversified address of the key that `zaddr` belongs to
+    pub async fn do_new_diversified_address(&self, zaddr: &str) -> Result<JsonValue, String> {
+        let (new_address, key_address, index) = self
+            .wallet
+            .keys()
+            .write()
+            .await
+            .add_diversified_zaddr(zaddr)
+            .await
+            .map_err(|e| {
+                let e = format!("Error creating new diversified address: {}", e);
+                error!("{}", e);
+                e
+            })?;
+
+        Ok(object! {
+            "address" => new_address,
+            "diversified_from" => key_address,
+            "diversifier_index" => index,
+        })
+    }
+
+    /// Add a label for `address` to the address book. If the label exists, it now points to `address`
+    pub async fn do_address_book_add(&self, label: &str, address: &str) -> Result<JsonValue, String> {
+        self.wallet.add_address_book_entry(label, address).await?;
+
+        Ok(object! {
+            "label" => label,
+            "address" => address,
+        })
+    }
+
+    pub async fn do_address_book_remove(&self, label: &str) -> Result<JsonValue, String> {
+        let address = self.wallet.remove_address_book_entry(label).await?;
+
+        Ok(object! {
+            "label" => label,
+            "address" => address,
+        })
+    }
+
+    pub async fn do_address_book_list(&self) -> JsonValue {
+        JsonValue::Array(
+            self.wallet
+                .address_book
+                .read()
+                .await
+                .entries()
+                .map(|(label, address)| {
+                    object! {
+                        "label" => label.clone(),
+                        "address" => address.clone(),
+                    }
+                })
+                .collect(),
+        )
+    }
+
     /// Convinence function to determine what type of key this is and import it
     pub async fn do_import_key(&self, key: String, birthday: u64) -> Result<JsonValue, String> {
         if key.starts_with(self.config.hrp_sapling_private_key()) {
@@ -1138,7 +1303,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
     async fn update_current_price(&self) {
         // Get the zec price from the server
-        match GrpcConnector::get_current_zec_price(self.get_server_uri()).await {
+        match GrpcConnector::get_current_zec_price(self.get_servers()).await {
             Ok(p) => {
                 self.wallet.set_latest_zec_price(p.price).await;
             }
@@ -1171,8 +1336,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         info!("Fetching historical prices for {} txids", txids_to_fetch.len());
 
         let retry_count_increase =
-            match GrpcConnector::get_historical_zec_prices(self.get_server_uri(), txids_to_fetch, price.currency).await
-            {
+            match GrpcConnector::get_historical_zec_prices(self.get_servers(), txids_to_fetch, price.currency).await {
                 Ok(prices) => {
                     let mut any_failed = false;
 
@@ -1208,6 +1372,41 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         self.bsync_data.read().await.sync_status.read().await.clone()
     }
 
+    /// Stop the running sync. The batches that were already synced are kept, and whatever was found in the
+    /// unfinished batch is removed, so the wallet is saved as of the last finished batch.
+    pub async fn cancel_sync(&self) -> Result<(), String> {
+        let bsync_data = self.bsync_data.read().await;
+        if !bsync_data.sync_status.read().await.in_progress {
+            return Err("No sync is in progress".to_string());
+        }
+
+        bsync_data.sync_token.cancel();
+        Ok(())
+    }
+
+    /// Pause the running sync until `resume_sync` is called. A paused sync still holds the sync lock,
+    /// so anything else that needs it keeps waiting.
+    pub async fn pause_sync(&self) -> Result<(), String> {
+        let bsync_data = self.bsync_data.read().await;
+        if !bsync_data.sync_status.read().await.in_progress {
+            return Err("No sync is in progress".to_string());
+        }
+
+        if bsync_data.sync_token.pause() {
+            Ok(())
+        } else {
+            Err("The sync is already paused or cancelled".to_string())
+        }
+    }
+
+    pub async fn resume_sync(&self) -> Result<(), String> {
+        if self.bsync_data.read().await.sync_token.resume() {
+            Ok(())
+        } else {
+            Err("The sync is not paused".to_string())
+        }
+    }
+
     pub fn start_mempool_monitor(lc: Arc<LightClient<P>>) {
         if !lc.config.monitor_mempool {
             return;
@@ -1219,7 +1418,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         let config = lc.config.clone();
         let parameters = config.get_params();
-        let uri = config.server.clone();
+        let servers = config.servers.clone();
         let lci = lc.clone();
 
         info!("Mempool monitoring starting");
@@ -1262,7 +1461,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 let h2 = tokio::spawn(async move {
                     loop {
                         //info!("Monitoring mempool");
-                        let r = GrpcConnector::monitor_mempool(uri.clone(), mempool_tx.clone()).await;
+                        let r = GrpcConnector::monitor_mempool(servers.clone(), mempool_tx.clone()).await;
 
                         if r.is_err() {
                             warn!("Mempool monitor returned {:?}, will restart listening", r);
@@ -1287,38 +1486,34 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         // Start the sync
         let r_fut = self.start_sync();
 
-        // If printing updates, start a new task to print updates every 2 seconds.
-        let sync_result = if print_updates {
-            let sync_status = self.bsync_data.read().await.sync_status.clone();
-            let (tx, mut rx) = oneshot::channel::<i32>();
+        // Start a new task to send the progress to the subscribers every 3 seconds, and print it if asked to.
+        let sync_status = self.bsync_data.read().await.sync_status.clone();
+        let events = self.wallet.events().clone();
+        let (tx, mut rx) = oneshot::channel::<i32>();
 
-            tokio::spawn(async move {
-                while sync_status.read().await.sync_id == prev_sync_id {
-                    yield_now().await;
-                    sleep(Duration::from_secs(3)).await;
+        tokio::spawn(async move {
+            loop {
+                if let Ok(_t) = rx.try_recv() {
+                    break;
                 }
 
-                loop {
-                    if let Ok(_t) = rx.try_recv() {
-                        break;
-                    }
-
-                    let progress = format!("{}", sync_status.read().await);
+                // Nothing to report until the new sync has started. It may also fail before it does, which is
+                // why this checks for the stop signal first.
+                let status = sync_status.read().await.clone();
+                if status.sync_id != prev_sync_id {
                     if print_updates {
-                        println!("{}", progress);
+                        println!("{}", status);
                     }
-
-                    yield_now().await;
-                    sleep(Duration::from_secs(3)).await;
+                    events.emit(WalletEvent::SyncProgress(status));
                 }
-            });
 
-            let r = r_fut.await;
-            tx.send(1).unwrap();
-            r
-        } else {
-            r_fut.await
-        };
+                yield_now().await;
+                sleep(Duration::from_secs(3)).await;
+            }
+        });
+
+        let sync_result = r_fut.await;
+        tx.send(1).unwrap();
 
         // Mark the sync data as finished, which should clear everything
         self.bsync_data.read().await.finish().await;
@@ -1333,11 +1528,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         // If we allow multiple syncs, they'll all get jumbled up.
         let _lock = self.sync_lock.lock().await;
 
+        // Clear any pause or cancel left over from a previous sync
+        let sync_token = self.bsync_data.read().await.sync_token.clone();
+        sync_token.reset();
+
         // The top of the wallet
         let last_scanned_height = self.wallet.last_scanned_height().await;
 
-        let uri = self.config.server.clone();
-        let latest_blockid = GrpcConnector::get_latest_block(uri.clone()).await?;
+        let latest_blockid = GrpcConnector::get_latest_block(self.get_servers()).await?;
         if latest_blockid.height < last_scanned_height {
             let w = format!(
                 "Server's latest block({}) is behind ours({})",
@@ -1363,56 +1561,424 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             }
         }
 
+        // Blocks close to the tip can still be reorged, so the block cache needs to know where the tip is, and
+        // checks the blocks it has near the tip against it
+        let block_cache = self.bsync_data.read().await.block_cache.clone();
+        if block_cache.is_some() {
+            let spam_filter_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+            FetchCompactBlocks::new(&self.config, block_cache)
+                .update_chain_tip(&latest_blockid, spam_filter_threshold)
+                .await;
+        }
+
         // Re-read the last scanned height
         let last_scanned_height = self.wallet.last_scanned_height().await;
 
-        let mut latest_block_batches = vec![];
-        let mut prev = last_scanned_height;
-        while latest_block_batches.is_empty() || prev != latest_blockid.height {
-            let mut batch_size = 50_000;
-            if prev + batch_size > 1_700_000 {
-                batch_size = 1_000;
+        // Make sure enough servers agree on the chain before trusting any of them with the sync
+        let verify_trees = if self.config.cross_check_servers > 1 {
+            let uris = self
+                .config
+                .servers
+                .candidates()
+                .into_iter()
+                .map(|(_, uri, _)| uri)
+                .take(self.config.cross_check_servers)
+                .collect::<Vec<_>>();
+            if uris.len() < self.config.cross_check_servers {
+                return Err(format!(
+                    "Need {} servers to cross-check the chain, but only {} are available",
+                    self.config.cross_check_servers,
+                    uris.len()
+                ));
             }
 
-            let batch = cmp::min(latest_blockid.height, prev + batch_size);
-            prev = batch;
-            latest_block_batches.push(batch);
-        }
+            // The tip may have moved on since we asked for it, and blocks above it won't be downloaded in this sync
+            let mut trees = cross_check_servers(&uris, last_scanned_height + 1).await?;
+            trees.retain(|t| t.height <= latest_blockid.height);
+            trees
+        } else {
+            vec![]
+        };
 
-        // println!("Batches are {:?}", latest_block_batches);
+        // The wallet is saved after every batch, so keep the batches within the save interval. Blocks in a batch are
+        // scanned from the top down, so there is no consistent wallet state to save until the whole batch is done.
+        let save_interval = self.config.sync_save_interval;
 
-        // Increment the sync ID so the caller can determine when it is over
-        {
-            let l1 = self.bsync_data.write().await;
-            // println!("l1");
+        // The batches are sized to keep the memory used by a batch within the budget, going by the size of the blocks
+        // in the batches before
+        let mut batch_sizer = BatchSizer::new(self.config.sync_memory_budget);
 
-            let mut l2 = l1.sync_status.write().await;
-            // println!("l2");
+        let sync_status = self.bsync_data.read().await.sync_status.clone();
+        let events = self.wallet.events();
 
-            l2.start_new(latest_block_batches.len());
-        }
-        // println!("Started new sync");
+        // A chain that still doesn't link up with the wallet after recovering from a deep reorg won't after another
+        // recovery either
+        let mut recovered_from_deep_reorg = false;
+
+        let mut batch_num = 0;
+        let mut batch_start_block = last_scanned_height + 1;
+        let res = loop {
+            let mut batch_size = batch_sizer.next_batch_size();
+            if save_interval > 0 {
+                batch_size = cmp::min(batch_size, save_interval);
+            }
+            let batch_latest_block = cmp::min(latest_blockid.height, batch_start_block - 1 + batch_size);
+
+            // The number of batches is only an estimate until the last one, since the batches after this one might
+            // be sized differently
+            let batches_left = (latest_blockid.height - batch_latest_block + batch_size - 1) / batch_size;
+            let batch_total = batch_num + 1 + batches_left as usize;
+            if batch_num == 0 {
+                // Increment the sync ID so the caller can determine when it is over
+                sync_status.write().await.start_new(batch_total);
+            } else {
+                sync_status.write().await.batch_total = batch_total;
+            }
 
-        let mut res = Err("No batches were run!".to_string());
-        for (batch_num, batch_latest_block) in latest_block_batches.into_iter().enumerate() {
             // println!("Starting batch {}", batch_num);
-            res = self.start_sync_batch(batch_latest_block, batch_num).await;
+            events.emit(WalletEvent::SyncBatchStarted {
+                batch_num,
+                batch_total,
+                start_block: batch_start_block,
+                end_block: batch_latest_block,
+            });
+
+            let res = self
+                .start_sync_batch(batch_latest_block, batch_num, verify_trees.clone())
+                .await;
             if res.is_err() {
+                if sync_token.is_cancelled() {
+                    // Remove whatever the abandoned batch found, so the wallet is back to where the last batch ended
+                    let last_scanned_height = self.wallet.last_scanned_height().await;
+                    self.wallet
+                        .txns()
+                        .write()
+                        .await
+                        .remove_txns_above_height(last_scanned_height);
+
+                    info!("Sync cancelled, wallet is at height {}", last_scanned_height);
+                    self.do_save(false).await?;
+                    return Err(SYNC_CANCELLED.to_string());
+                }
+
+                // Don't keep anything that was found in a chain that doesn't hold together
+                if res.as_ref().err().unwrap().starts_with(INCONSISTENT_CHAIN) {
+                    let last_scanned_height = self.wallet.last_scanned_height().await;
+                    self.wallet
+                        .txns()
+                        .write()
+                        .await
+                        .remove_txns_above_height(last_scanned_height);
+                }
+
+                // The wallet's blocks don't go back far enough to undo the reorg, so roll the wallet back further and
+                // sync the new chain from there
+                if res.as_ref().err().unwrap().starts_with(DEEP_REORG) && !recovered_from_deep_reorg {
+                    warn!("{}", res.as_ref().err().unwrap());
+                    recovered_from_deep_reorg = true;
+                    let height = self.recover_from_deep_reorg().await?;
+                    self.do_save(false).await?;
+
+                    batch_num += 1;
+                    batch_start_block = height + 1;
+                    continue;
+                }
+
                 info!("Sync failed, not saving: {:?}", res.as_ref().err());
                 return res;
             } else {
                 self.do_save(false).await?;
+                info!("Saved the wallet at height {}", batch_latest_block);
+
+                // Size the next batch by the blocks in this one
+                {
+                    let status = sync_status.read().await;
+                    batch_sizer.record_batch(status.blocks_done, status.blocks_bytes, status.blocks_outputs);
+                }
+
+                events.emit(WalletEvent::SyncBatchFinished {
+                    batch_num,
+                    batch_total,
+                    height: batch_latest_block,
+                });
             }
-        }
+
+            if sync_token.is_cancelled() {
+                info!("Sync cancelled, wallet is at height {}", batch_latest_block);
+                return Err(SYNC_CANCELLED.to_string());
+            }
+
+            if batch_latest_block == latest_blockid.height {
+                break res;
+            }
+            batch_num += 1;
+            batch_start_block = batch_latest_block + 1;
+        };
+
+        // Now that we're at the tip, resend any of our txns that should have been mined by now
+        self.rebroadcast_pending_sends(latest_blockid.height).await;
 
         res
     }
 
+    /// Roll the wallet back below a reorg that went deeper than the blocks it keeps, so the new chain can be synced
+    /// from there instead of rescanning from the birthday. Returns the height the wallet was rolled back to.
+    async fn recover_from_deep_reorg(&self) -> Result<u64, String> {
+        let oldest_block = match self.wallet.get_blocks().await.last() {
+            Some(b) => b.height,
+            None => return Err("Wallet has no blocks to roll back".to_string()),
+        };
+
+        // There's no telling how far below the wallet's blocks the reorg goes, so go down by another MAX_REORG blocks,
+        // but not below the birthday
+        let birthday = self.wallet.get_birthday().await;
+        let height = cmp::max(
+            oldest_block.saturating_sub(MAX_REORG as u64 + 1),
+            birthday.saturating_sub(1),
+        );
+
+        // Start over from the server's tree state at that height, or the closest checkpoint below it. Before sapling
+        // activation the tree is empty.
+        let (height, state) = if height <= self.config.sapling_activation_height {
+            (self.config.sapling_activation_height - 1, None)
+        } else {
+            match self.config.get_initial_state(height).await {
+                Some((height, hash, tree)) => (height, Some((hash, tree))),
+                None => {
+                    return Err(format!(
+                        "Couldn't get a tree state or checkpoint at height {} to recover from the reorg",
+                        height
+                    ))
+                }
+            }
+        };
+
+        let tree = match &state {
+            Some((_, tree)) => hex::decode(tree)
+                .map_err(|e| e.to_string())
+                .and_then(|b| CommitmentTree::<Node>::read(&b[..]).map_err(|e| e.to_string()))
+                .map_err(|e| format!("Couldn't read the tree at {}: {}", height, e))?,
+            None => CommitmentTree::empty(),
+        };
+
+        // The txns below that height stay. The witnesses of their notes only go back MAX_REORG blocks, so rewind them to
+        // the tree at that height. The sync brings them up to date again along the new chain.
+        {
+            let mut txns = self.wallet.txns().write().await;
+            txns.rewind_witnesses(height, &tree)?;
+            txns.remove_txns_at_height(height + 1);
+        }
+
+        // The blocks and the verified tree are from the old chain
+        self.wallet.set_blocks(vec![]).await;
+        *self.wallet.verified_tree.write().await = None;
+        if let Some((hash, tree)) = &state {
+            self.wallet.set_initial_block(height, hash, tree).await;
+        }
+
+        // Cached blocks above it may be from the old chain
+        if let Some(cache) = &self.bsync_data.read().await.block_cache {
+            let spam_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+            let mut cache = cache.lock().await;
+            if let Err(e) = cache.open(spam_threshold).and_then(|_| cache.remove_above(height)) {
+                warn!("Couldn't remove the reorged blocks from the block cache: {}", e);
+            }
+        }
+
+        info!("Rolled back to height {} to recover from a deep reorg", height);
+        Ok(height)
+    }
+
+    /// The number of blocks in the on-disk block cache, and its size
+    pub async fn do_block_cache_status(&self) -> Result<JsonValue, String> {
+        let cache = match &self.bsync_data.read().await.block_cache {
+            Some(cache) => cache.clone(),
+            None => return Err("The block cache is not enabled".to_string()),
+        };
+
+        let mut cache = cache.lock().await;
+        let spam_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+        cache
+            .open(spam_threshold)
+            .map_err(|e| format!("Couldn't open the block cache: {}", e))?;
+
+        let (blocks, size) = cache.stats();
+        Ok(object! {
+            "path" => self.config.get_block_cache_path().to_string_lossy().to_string(),
+            "blocks" => blocks,
+            "size" => size,
+            "max_size" => cache.max_size(),
+        })
+    }
+
+    /// Delete all the blocks in the on-disk block cache
+    pub async fn do_block_cache_clear(&self) -> Result<JsonValue, String> {
+        // Don't clear the cache in the middle of a sync
+        let _lock = self.sync_lock.lock().await;
+
+        let cache = match &self.bsync_data.read().await.block_cache {
+            Some(cache) => cache.clone(),
+            None => return Err("The block cache is not enabled".to_string()),
+        };
+
+        let mut cache = cache.lock().await;
+
+        // Open it first, just to count what is being removed. A damaged cache is removed all the same
+        let spam_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+        let (blocks, size) = cache.open(spam_threshold).map(|_| cache.stats()).unwrap_or((0, 0));
+        cache
+            .clear()
+            .map_err(|e| format!("Couldn't clear the block cache: {}", e))?;
+
+        info!("Cleared {} blocks from the block cache", blocks);
+        Ok(object! {
+            "result" => "success",
+            "blocks_removed" => blocks,
+            "bytes_removed" => size,
+        })
+    }
+
+    /// Write the sapling tree at `height` to a file, in the format that checkpoint files are loaded in, so it can be
+    /// shared. The height has to be one of the blocks the wallet keeps, so the server's tree can be checked against
+    /// the chain the wallet synced. The file goes in the data dir, unless a `path` is given.
+    pub async fn do_checkpoint_export(&self, height: u64, path: Option<PathBuf>) -> Result<JsonValue, String> {
+        // From the top down
+        let blocks = self.wallet.get_blocks().await;
+        let pos = match blocks.iter().position(|b| b.height == height) {
+            Some(pos) => pos,
+            None => {
+                return Err(match (blocks.last(), blocks.first()) {
+                    (Some(bottom), Some(top)) => format!(
+                        "Can only export a checkpoint at one of the wallet's last blocks, from {} to {}",
+                        bottom.height, top.height
+                    ),
+                    _ => "The wallet has no blocks yet, sync it first".to_string(),
+                });
+            }
+        };
+
+        let tree_state = GrpcConnector::get_sapling_tree(self.get_servers(), height).await?;
+        if tree_state.hash != blocks[pos].hash() {
+            return Err(format!("Server's block {} is not the one the wallet synced", height));
+        }
+
+        // The outputs of the wallet's blocks above it have to add up to the tree at the top, so the server can't pass
+        // off a tree that doesn't belong to the chain. The wallet's verified tree is used for the top if it is there.
+        let top = &blocks[0];
+        let top_tree = match self.wallet.verified_tree.read().await.clone() {
+            Some(t) if t.height == top.height => t,
+            _ => GrpcConnector::get_sapling_tree(self.get_servers(), top.height).await?,
+        };
+        if top_tree.hash != top.hash() {
+            return Err(format!(
+                "Server's block {} is not the one the wallet synced",
+                top.height
+            ));
+        }
+
+        let read_tree = |t: &TreeState| {
+            hex::decode(&t.tree)
+                .map_err(|e| e.to_string())
+                .and_then(|b| CommitmentTree::<Node>::read(&b[..]).map_err(|e| e.to_string()))
+                .map_err(|e| format!("Couldn't read the tree at {}: {}", t.height, e))
+        };
+
+        let mut tree = read_tree(&tree_state)?;
+        for block in blocks[..pos].iter().rev() {
+            for ctx in block.cb().vtx {
+                for co in ctx.outputs {
+                    let cmu = co.cmu().map_err(|_| format!("Bad output in block {}", block.height))?;
+                    tree.append(Node::new(cmu.into()))
+                        .map_err(|_| "Sapling tree is full".to_string())?;
+                }
+            }
+        }
+        if tree.root() != read_tree(&top_tree)?.root() {
+            return Err(format!(
+                "Server's tree at {} doesn't add up to its tree at {}",
+                height, top.height
+            ));
+        }
+
+        let path = path.unwrap_or_else(|| {
+            self.config
+                .get_zcash_data_path()
+                .join(format!("zecwallet-light-checkpoint-{}.json", height))
+        });
+        let checkpoint = (tree_state.height, tree_state.hash.clone(), tree_state.tree);
+        std::fs::write(&path, checkpoints::checkpoints_to_json(&[checkpoint]).pretty(2))
+            .map_err(|e| format!("Couldn't write {:?}: {}", path, e))?;
+
+        info!("Exported the checkpoint at {} to {:?}", height, path);
+        Ok(object! {
+            "height" => height,
+            "hash" => tree_state.hash,
+            "path" => path.to_string_lossy().to_string(),
+        })
+    }
+
+    /// Broadcast our pending sends again if they haven't been mined in the last `REBROADCAST_INTERVAL` blocks,
+    /// in case the server dropped them from its mempool.
+    async fn rebroadcast_pending_sends(&self, latest_height: u64) {
+        let to_rebroadcast = self
+            .wallet
+            .txns()
+            .read()
+            .await
+            .pending_sends_to_rebroadcast(latest_height, REBROADCAST_INTERVAL);
+
+        for (txid, raw_tx) in to_rebroadcast {
+            info!("Rebroadcasting unmined tx {}", txid);
+            if let Err(e) = GrpcConnector::send_transaction(self.get_servers(), raw_tx.into_boxed_slice()).await {
+                warn!("Rebroadcasting {} failed: {}", txid, e);
+            }
+
+            self.wallet
+                .txns()
+                .write()
+                .await
+                .set_pending_send_broadcast(&txid, latest_height as u32);
+        }
+    }
+
+    /// Broadcast one of our pending sends again, identified by its `txid`
+    pub async fn do_rebroadcast(&self, txid: &str) -> Result<String, String> {
+        let pending = self
+            .wallet
+            .txns()
+            .read()
+            .await
+            .pending_sends
+            .iter()
+            .find(|(t, _)| format!("{}", t) == txid)
+            .map(|(t, ps)| (t.clone(), ps.raw_tx.clone()));
+
+        let (txid, raw_tx) = match pending {
+            Some(p) => p,
+            None => return Err(format!("{} is not a pending transaction sent by this wallet", txid)),
+        };
+
+        let result = GrpcConnector::send_transaction(self.get_servers(), raw_tx.into_boxed_slice()).await?;
+
+        let latest_height = self.wallet.last_scanned_height().await;
+        self.wallet
+            .txns()
+            .write()
+            .await
+            .set_pending_send_broadcast(&txid, latest_height as u32);
+
+        Ok(result)
+    }
+
     /// start_sync will start synchronizing the blockchain from the wallet's last height. This function will return immediately after starting the sync
     /// Use the `sync_status` command to get the status of the sync
-    async fn start_sync_batch(&self, latest_block: u64, batch_num: usize) -> Result<JsonValue, String> {
-        let uri = self.config.server.clone();
-
+    /// `verify_trees` are tree states the downloaded blocks have to match, e.g. the ones the servers agreed on
+    async fn start_sync_batch(
+        &self,
+        latest_block: u64,
+        batch_num: usize,
+        verify_trees: Vec<TreeState>,
+    ) -> Result<JsonValue, String> {
         // The top of the wallet
         // println!("Trying to get last scanned height");
         let last_scanned_height = self.wallet.last_scanned_height().await;
@@ -1430,6 +1996,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
 
         let bsync_data = self.bsync_data.clone();
         let spam_filter_threshold = self.wallet.wallet_options.read().await.spam_threshold;
+        let sync_token = bsync_data.read().await.sync_token.clone();
 
         let start_block = latest_block;
         let end_block = last_scanned_height + 1;
@@ -1448,12 +2015,18 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                 *self.wallet.wallet_options.read().await,
             )
             .await;
+        bsync_data
+            .read()
+            .await
+            .block_data
+            .add_verification_trees(verify_trees)
+            .await;
 
         // 2. Update the current price
         self.update_current_price().await;
 
         // Sapling Tree GRPC Fetcher
-        let grpc_connector = GrpcConnector::new(uri.clone());
+        let grpc_connector = GrpcConnector::new(self.get_servers());
 
         // A signal to detect reorgs, and if so, ask the block_fetcher to fetch new blocks.
         let (reorg_tx, reorg_rx) = unbounded_channel();
@@ -1463,20 +2036,20 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             .read()
             .await
             .block_data
-            .start(start_block, end_block, self.wallet.txns(), reorg_tx)
+            .start(start_block, end_block, self.wallet.txns(), reorg_tx, sync_token.clone())
             .await;
 
         // Full Tx GRPC fetcher
         let params = self.config.get_params();
-        let (_fulltx_fetcher_handle, fulltx_fetcher_tx) = grpc_connector.start_fulltx_fetcher(params).await;
+        let (fulltx_fetcher_handle, fulltx_fetcher_tx) = grpc_connector.start_fulltx_fetcher(params).await;
 
         // Transparent Transactions Fetcher
         let (taddr_fetcher_handle, taddr_fetcher_tx) = grpc_connector.start_taddr_txn_fetcher().await;
 
         // The processor to fetch the full transactions, and decode the memos and the outgoing metadata
         let fetch_full_tx_processor = FetchFullTxns::new(&self.config, self.wallet.keys_clone(), self.wallet.txns());
-        let (_fetch_full_txns_handle, scan_full_txn_tx, fetch_taddr_txns_tx) = fetch_full_tx_processor
-            .start(fulltx_fetcher_tx.clone(), bsync_data.clone())
+        let (fetch_full_txns_handle, scan_full_txn_tx, fetch_taddr_txns_tx) = fetch_full_tx_processor
+            .start(fulltx_fetcher_tx.clone(), bsync_data.clone(), sync_token.clone())
             .await;
 
         // The processor to process Transactions detected by the trial decryptions processor
@@ -1488,11 +2061,17 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         // Do Trial decryptions of all the sapling outputs, and pass on the successful ones to the update_notes processor
         let trial_decryptions_processor = TrialDecryptions::new(self.wallet.keys_clone(), self.wallet.txns());
         let (trial_decrypts_handle, trial_decrypts_tx) = trial_decryptions_processor
-            .start(bsync_data.clone(), detected_txns_tx, fulltx_fetcher_tx)
+            .start(
+                bsync_data.clone(),
+                detected_txns_tx,
+                fulltx_fetcher_tx,
+                sync_token.clone(),
+            )
             .await;
 
         // Fetch Compact blocks and send them to nullifier cache, node-and-witness cache and the trial-decryption processor
-        let fetch_compact_blocks = Arc::new(FetchCompactBlocks::new(&self.config));
+        let block_cache = bsync_data.read().await.block_cache.clone();
+        let fetch_compact_blocks = Arc::new(FetchCompactBlocks::new(&self.config, block_cache));
         let fetch_compact_blocks_handle = tokio::spawn(async move {
             fetch_compact_blocks
                 .start(
@@ -1501,13 +2080,41 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                     end_block,
                     spam_filter_threshold,
                     reorg_rx,
+                    sync_token,
                 )
                 .await
         });
 
         // We wait first for the node's to be updated. This is where reorgs will be handled, so all the steps done after this phase will
         // assume that the reorgs are done.
-        let earliest_block = block_and_witness_handle.await.unwrap().unwrap();
+        let earliest_block = match block_and_witness_handle.await.map_err(|e| e.to_string())? {
+            Ok(b) => b,
+            Err(e) => {
+                if self.bsync_data.read().await.sync_token.is_cancelled()
+                    || e.starts_with(INCONSISTENT_CHAIN)
+                    || e.starts_with(DEEP_REORG)
+                {
+                    // Let the processors finish with the blocks that were already fetched, so that nothing touches the
+                    // wallet after the batch is rolled back. Dropping the senders lets them run to the end.
+                    drop(blocks_done_tx);
+                    drop(taddr_fetcher_tx);
+                    drop(fetch_taddr_txns_tx);
+                    drop(scan_full_txn_tx);
+
+                    join_all(vec![
+                        fetch_compact_blocks_handle,
+                        trial_decrypts_handle,
+                        update_notes_handle,
+                        taddr_fetcher_handle,
+                        fetch_full_txns_handle,
+                        fulltx_fetcher_handle,
+                    ])
+                    .await;
+                }
+
+                return Err(e);
+            }
+        };
         let params = self.config.get_params();
 
         // 1. Fetch the transparent txns only after reorgs are done.
@@ -1562,12 +2169,13 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         // 2. If sync was successfull, also try to get historical prices
         self.update_historical_prices().await;
 
-        // 3. Remove the witnesses for spent notes more than 100 blocks old, since now there
+        // 3. Remove the witnesses for spent notes more than 200 blocks old, since now there
         // is no risk of reorg
         self.wallet.txns().write().await.clear_old_witnesses(latest_block);
 
-        // 4. Remove expired mempool transactions, if any
+        // 4. Remove expired mempool transactions, if any, and stop rebroadcasting sends that were mined or expired
         self.wallet.txns().write().await.clear_expired_mempool(latest_block);
+        self.wallet.txns().write().await.clear_pending_sends(latest_block);
 
         // 5. Set the heighest verified tree
         if heighest_tree.is_some() {
@@ -1590,8 +2198,20 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         branch_id
     }
 
-    pub async fn do_shield(&self, address: Option<String>) -> Result<String, String> {
-        let fee = self.wallet.fee(0, 0, 0, 0, 0);
+    /// Shield all the transparent funds. The fee is worked out with `fee_policy`, or the wallet's default fee policy.
+    pub async fn do_shield(&self, address: Option<String>, fee_policy: Option<FeePolicy>) -> Result<String, String> {
+        let fee_policy = match fee_policy {
+            Some(p) => p,
+            None => self.wallet.wallet_options.read().await.fee_policy,
+        };
+        let utxos_n = self
+            .wallet
+            .get_utxos()
+            .await
+            .iter()
+            .filter(|utxo| utxo.unconfirmed_spent.is_none() && utxo.spent.is_none())
+            .count();
+        let fee = fee_policy.fee(self.wallet.fee(utxos_n, 0, 0, 1, 0))?;
         let tbal = self.wallet.tbalance(None).await;
 
         // Make sure there is a balance, and it is greated than the amount
@@ -1623,7 +2243,9 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
                     prover,
                     true,
                     vec![(&addr.as_str(), tbal - fee, None)],
-                    |txbytes| GrpcConnector::send_transaction(self.get_server_uri(), txbytes),
+                    &[],
+                    Some(fee_policy),
+                    |txbytes| GrpcConnector::send_transaction(self.get_servers(), txbytes),
                 )
                 .await
         };
@@ -1631,7 +2253,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         result.map(|(txid, _, _)| txid)
     }
 
-    pub async fn do_send(&self, addrs: Vec<(&str, u64, Option<String>)>) -> Result<String, String> {
+    /// Send to `addrs`. If `from` isn't empty, only funds received at those addresses are spent, and the change
+    /// is sent back to them. The fee is worked out with `fee_policy`, or the wallet's default fee policy.
+    pub async fn do_send(
+        &self,
+        addrs: Vec<(&str, u64, Option<String>)>,
+        from: &[String],
+        fee_policy: Option<FeePolicy>,
+    ) -> Result<String, String> {
         let branch_id = self.consensus_branch_id().await;
         info!("Creating transaction");
 
@@ -1644,8 +2273,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             let prover = LocalTxProver::from_bytes(&sapling_spend, &sapling_output);
 
             self.wallet
-                .send_to_address(branch_id, prover, false, addrs, |txbytes| {
-                    GrpcConnector::send_transaction(self.get_server_uri(), txbytes)
+                .send_to_address(branch_id, prover, false, addrs, from, fee_policy, |txbytes| {
+                    GrpcConnector::send_transaction(self.get_servers(), txbytes)
                 })
                 .await
         };
@@ -1653,6 +2282,419 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
         result.map(|(txid, _, _)| txid)
     }
 
+    /// Work out what sending to `addrs` would do, without building the transaction or touching the wallet's txns.
+    /// Returns the selected notes and utxos, the change, the number of ZIP-317 logical actions and the fee.
+    pub async fn do_propose_send(
+        &self,
+        addrs: Vec<(&str, u64, Option<String>)>,
+        from: &[String],
+        fee_policy: Option<FeePolicy>,
+    ) -> Result<JsonValue, String> {
+        use zcash_client_backend::address::RecipientAddress;
+
+        let branch_id = self.consensus_branch_id().await;
+        let proposal = self
+            .wallet
+            .create_proposal(branch_id, false, true, addrs, from, fee_policy, false)
+            .await?;
+
+        let (touts_n, sapling_outputs_n) = proposal.outputs.iter().fold((0, 0), |(tout, sout), o| {
+            match lightwallet::unified::decode_recipient(&self.config.get_params(), &o.address) {
+                Ok(RecipientAddress::Transparent(_)) => (tout + 1, sout),
+                _ => (tout, sout + 1),
+            }
+        });
+
+        let notes = proposal
+            .notes
+            .iter()
+            .map(|sn| {
+                object! {
+                    "created_in_txid" => format!("{}", sn.txid),
+                    "value"           => sn.note.value,
+                    "address"         => sn.ivk.to_payment_address(sn.diversifier)
+                                            .map(|a| encode_payment_address(self.config.hrp_sapling_address(), &a)),
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+
+        let utxos = proposal
+            .utxos
+            .iter()
+            .map(|utxo| {
+                object! {
+                    "created_in_txid" => format!("{}", utxo.txid),
+                    "output_index"    => utxo.output_index,
+                    "value"           => utxo.value,
+                    "address"         => utxo.address.clone(),
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+
+        let outputs = proposal
+            .outputs
+            .iter()
+            .map(|o| {
+                object! {
+                    "address" => o.address.clone(),
+                    "value"   => o.value,
+                }
+            })
+            .collect::<Vec<JsonValue>>();
+
+        Ok(object! {
+            "notes"           => notes,
+            "utxos"           => utxos,
+            "outputs"         => outputs,
+            "total_input"     => proposal.total_input(),
+            "total_output"    => proposal.total_output(),
+            "change"          => proposal.change(),
+            "change_address"  => encode_payment_address(self.config.hrp_sapling_address(), &proposal.change_address),
+            "logical_actions" => LightWallet::<P>::logical_actions(
+                                    proposal.utxos.len(),
+                                    touts_n,
+                                    proposal.notes.len(),
+                                    sapling_outputs_n,
+                                    0,
+                                ),
+            "fee"             => proposal.fee,
+        })
+    }
+
+    /// Merge the wallet's smallest spendable notes into `target_address` (or the first z address), which has to be
+    /// one of the wallet's own addresses, spending at most `max_inputs` notes per transaction. Returns the txid, number
+    /// of notes and fee of each batch, and the notes that are worth too little to pay for merging them.
+    pub async fn do_consolidate(&self, max_inputs: usize, target_address: Option<String>) -> Result<JsonValue, String> {
+        let target_address = match target_address {
+            Some(a) => a,
+            None => {
+                let guard = self.wallet.keys().read().await;
+                guard
+                    .first_zkey()
+                    .await
+                    .map(|(_, addr)| guard.encode_zaddr(addr))
+                    .ok_or("No shielded address in wallet".to_string())?
+            }
+        };
+        let branch_id = self.consensus_branch_id().await;
+
+        let _lock = self.sync_lock.lock().await;
+        let (proposals, unmerged) = self
+            .wallet
+            .create_consolidation_proposals(branch_id, max_inputs, &target_address)
+            .await?;
+
+        let (sapling_output, sapling_spend) = self.read_sapling_params()?;
+        let prover = LocalTxProver::from_bytes(&sapling_spend, &sapling_output);
+
+        let mut batches = vec![];
+        for proposal in proposals {
+            let mut batch = object! {
+                "notes" => proposal.notes.len(),
+                "value" => proposal.total_input(),
+                "fee"   => proposal.fee,
+            };
+
+            let result = match self.wallet.sign_proposal(&proposal, &prover).await {
+                Ok(tx) => {
+                    self.wallet
+                        .broadcast_tx(tx, |txbytes| {
+                            GrpcConnector::send_transaction(self.get_servers(), txbytes)
+                        })
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            // Stop at the first failure, the remaining notes can be merged by running this again
+            match result {
+                Ok(txid) => {
+                    batch["txid"] = txid.into();
+                    batches.push(batch);
+                }
+                Err(e) => {
+                    batch["error"] = e.into();
+                    batches.push(batch);
+                    break;
+                }
+            }
+        }
+
+        let total_fee = batches
+            .iter()
+            .filter(|b| b.has_key("txid"))
+            .map(|b| b["fee"].as_u64().unwrap())
+            .sum::<u64>();
+
+        Ok(object! {
+            "target_address" => target_address,
+            "batches"        => batches,
+            "total_fee"      => total_fee,
+            "unmerged_notes" => unmerged.len(),
+            "unmerged_value" => unmerged.iter().map(|sn| sn.note.value).sum::<u64>(),
+        })
+    }
+
+    /// Create an unsigned proposal for sending to `addrs` and write it to `path`. The wallet can be watch-only,
+    /// the proposal is meant to be signed with `do_sign_proposal` by a wallet that holds the spending keys.
+    pub async fn do_propose(&self, addrs: Vec<(&str, u64, Option<String>)>, path: &str) -> Result<JsonValue, String> {
+        let branch_id = self.consensus_branch_id().await;
+
+        let proposal = {
+            let _lock = self.sync_lock.lock().await;
+            self.wallet
+                .create_proposal(branch_id, false, false, addrs, &[], None, true)
+                .await?
+        };
+
+        let mut file = File::create(path).map_err(|e| format!("Couldn't create {}: {}", path, e))?;
+        proposal
+            .write(&mut file)
+            .map_err(|e| format!("Couldn't write proposal: {}", e))?;
+
+        Ok(object! {
+            "proposal_file" => path,
+            "target_height" => proposal.target_height,
+            "notes"         => proposal.notes.len(),
+            "utxos"         => proposal.utxos.len(),
+            "outputs"       => proposal.outputs.len(),
+            "total_input"   => proposal.total_input(),
+            "total_output"  => proposal.total_output(),
+            "fee"           => proposal.fee,
+        })
+    }
+
+    /// Sign the proposal at `proposal_path` and write the signed raw transaction as hex to `signed_path`.
+    /// The transaction is not broadcast, so this can run on an offline wallet.
+    pub async fn do_sign_proposal(&self, proposal_path: &str, signed_path: &str) -> Result<String, String> {
+        let proposal = {
+            let file = File::open(proposal_path).map_err(|e| format!("Couldn't open {}: {}", proposal_path, e))?;
+            lightwallet::proposal::TxProposal::read(BufReader::new(file))
+                .map_err(|e| format!("Couldn't read proposal: {}", e))?
+        };
+
+        let tx = {
+            let _lock = self.sync_lock.lock().await;
+            let (sapling_output, sapling_spend) = self.read_sapling_params()?;
+
+            let prover = LocalTxProver::from_bytes(&sapling_spend, &sapling_output);
+
+            self.wallet.sign_proposal(&proposal, &prover).await?
+        };
+
+        let mut raw_tx = vec![];
+        tx.write(&mut raw_tx).map_err(|e| e.to_string())?;
+
+        let mut file = File::create(signed_path).map_err(|e| format!("Couldn't create {}: {}", signed_path, e))?;
+        file.write_all(hex::encode(raw_tx).as_bytes())
+            .map_err(|e| format!("Couldn't write signed transaction: {}", e))?;
+
+        Ok(tx.txid().to_string())
+    }
+
+    /// Broadcast a signed transaction (hex encoded) from `signed_path`, created by `do_sign_proposal`
+    pub async fn do_broadcast(&self, signed_path: &str) -> Result<String, String> {
+        let mut hex_tx = String::new();
+        File::open(signed_path)
+            .and_then(|mut f| f.read_to_string(&mut hex_tx))
+            .map_err(|e| format!("Couldn't read {}: {}", signed_path, e))?;
+
+        let raw_tx = hex::decode(hex_tx.trim()).map_err(|e| format!("Couldn't decode signed transaction: {}", e))?;
+        let branch_id = self.consensus_branch_id().await;
+
+        let _lock = self.sync_lock.lock().await;
+        self.wallet
+            .broadcast_raw_tx(branch_id, &raw_tx, |txbytes| {
+                GrpcConnector::send_transaction(self.get_servers(), txbytes)
+            })
+            .await
+    }
+
+    /// Pay every row of a batch CSV file (see `lightwallet::batch`), in as many transactions as needed to keep each
+    /// one under `max_outputs` outputs. The outcome of every row is written to `results_path` after each transaction,
+    /// and rows that the results file says were already paid are skipped, so a partially failed batch can be run again.
+    /// Rows are marked as pending in the results file before their transaction is broadcast, so they are skipped too
+    /// if the wallet stops before the outcome is written, or if the broadcast fails after the transaction was built.
+    pub async fn do_send_batch(
+        &self,
+        batch_path: &str,
+        results_path: &str,
+        max_outputs: usize,
+    ) -> Result<JsonValue, String> {
+        self.send_batch(batch_path, results_path, max_outputs, || {
+            let (sapling_output, sapling_spend) = self.read_sapling_params()?;
+            Ok(LocalTxProver::from_bytes(&sapling_spend, &sapling_output))
+        })
+        .await
+    }
+
+    async fn send_batch<Pr: lightwallet::keys::TxProver + Send + Sync>(
+        &self,
+        batch_path: &str,
+        results_path: &str,
+        max_outputs: usize,
+        prover_fn: impl Fn() -> Result<Pr, String>,
+    ) -> Result<JsonValue, String> {
+        use lightwallet::batch;
+        use std::convert::TryFrom;
+
+        if max_outputs == 0 {
+            return Err("max_outputs must be at least 1".to_string());
+        }
+
+        let contents =
+            std::fs::read_to_string(batch_path).map_err(|e| format!("Couldn't read {}: {}", batch_path, e))?;
+        let rows = batch::parse_batch(&self.config.get_params(), &contents)?;
+
+        let previous = match std::fs::read_to_string(results_path) {
+            Ok(r) => batch::read_results(&r)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(format!("Couldn't read {}: {}", results_path, e)),
+        };
+        let mut results = batch::resume_batch(rows, previous)?;
+
+        let write_results = |results: &[batch::BatchResult]| {
+            std::fs::write(results_path, batch::write_results(results))
+                .map_err(|e| format!("Couldn't write {}: {}", results_path, e))
+        };
+        write_results(&results)?;
+
+        let to_send = results
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| !r.is_paid())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let skipped = results.len() - to_send.len();
+
+        let branch_id = self.consensus_branch_id().await;
+        let branch = BranchId::try_from(branch_id).map_err(|e| e.to_string())?;
+        let mut txids = vec![];
+        for chunk in to_send.chunks(max_outputs) {
+            let tos = chunk
+                .iter()
+                .map(|i| {
+                    let row = &results[*i].row;
+                    (row.address.as_str(), row.amount, row.memo.clone())
+                })
+                .collect::<Vec<_>>();
+
+            // The txid of the transaction, once it is built and its rows are marked as pending
+            let built = std::sync::Mutex::new(None);
+            let result = {
+                let _lock = self.sync_lock.lock().await;
+
+                match prover_fn() {
+                    Ok(prover) => self
+                        .wallet
+                        .send_to_address(branch_id, prover, false, tos, &[], None, |txbytes| {
+                            // Mark the rows as pending before the transaction can reach the network, so that running
+                            // the batch again doesn't pay them twice if the outcome can't be written
+                            let marked = Transaction::read(&txbytes[..], branch)
+                                .map_err(|e| format!("Couldn't parse transaction: {}", e))
+                                .and_then(|tx| {
+                                    let mut marked = results.clone();
+                                    for i in chunk {
+                                        marked[*i].txid = Some(tx.txid().to_string());
+                                        marked[*i].error = Some(batch::PENDING_BROADCAST.to_string());
+                                    }
+                                    write_results(&marked)?;
+                                    *built.lock().unwrap() = Some(tx.txid().to_string());
+                                    Ok(())
+                                });
+
+                            let servers = self.get_servers();
+                            async move {
+                                marked?;
+                                GrpcConnector::send_transaction(servers, txbytes).await
+                            }
+                        })
+                        .await
+                        .map(|(txid, _, _)| txid),
+                    Err(e) => Err(e),
+                }
+            };
+
+            match &result {
+                Ok(txid) => {
+                    info!("Sent batch of {} payments in {}", chunk.len(), txid);
+                    txids.push(txid.clone());
+                }
+                Err(e) => warn!("Couldn't send batch of {} payments: {}", chunk.len(), e),
+            }
+
+            let built = built.into_inner().unwrap();
+            for i in chunk {
+                let (txid, error) = match (&result, &built) {
+                    (Ok(txid), _) => (Some(txid.clone()), None),
+                    // The transaction may have reached the network even though the broadcast failed, so the rows
+                    // stay pending instead of being paid again on the next run
+                    (Err(e), Some(txid)) => (Some(txid.clone()), Some(format!("{}: {}", batch::PENDING_BROADCAST, e))),
+                    (Err(e), None) => (None, Some(e.clone())),
+                };
+                results[*i].txid = txid;
+                results[*i].error = error;
+            }
+
+            // Record the outcome before sending anything else, so an interrupted batch is never paid twice
+            write_results(&results)?;
+        }
+
+        let paid = to_send
+            .iter()
+            .filter(|i| results[**i].is_paid() && results[**i].error.is_none())
+            .count();
+        let failed = to_send.iter().filter(|i| !results[**i].is_paid()).count();
+        let pending = results.iter().filter(|r| r.is_pending()).count();
+        Ok(object! {
+            "results_file" => results_path,
+            "paid"         => paid,
+            "failed"       => failed,
+            "skipped"      => skipped,
+            "pending"      => pending,
+            "txids"        => txids,
+        })
+    }
+
+    /// Create a ZIP-321 payment request URI for one of this wallet's addresses
+    pub async fn do_payment_request(
+        &self,
+        address: &str,
+        amount: u64,
+        label: Option<String>,
+        memo: Option<String>,
+    ) -> Result<String, String> {
+        let is_ours = {
+            let keys = self.wallet.keys().read().await;
+            let mut zaddrs = keys.get_all_zaddresses().await;
+            let (mut taddrs, _) = keys.get_all_taddrs().await;
+            let uaddrs = keys.get_all_uaddresses().await;
+
+            zaddrs.any(|a| a == address) || taddrs.any(|a| a == address) || uaddrs.iter().any(|a| a == address)
+        };
+        if !is_ours {
+            return Err(format!("{} is not an address in this wallet", address));
+        }
+
+        let memo = match memo {
+            None => None,
+            Some(m) => {
+                if !lightwallet::keys::Keystores::is_shielded_address(&address.to_string(), &self.config.get_params()) {
+                    return Err(format!("Can't request a memo for the non-shielded address {}", address));
+                }
+                Some(lightwallet::utils::interpret_memo_string(m)?)
+            }
+        };
+
+        Ok(lightwallet::zip321::to_uri(&[lightwallet::zip321::Payment {
+            address: address.to_string(),
+            amount,
+            memo,
+            label,
+            message: None,
+        }]))
+    }
+
     pub fn do_wallet_kind_sync(&self) -> String {
         Runtime::new()
             .unwrap()
@@ -1680,14 +2722,27 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightClient<P> {
             let prover = crate::blaze::test_utils::FakeTxProver {};
 
             self.wallet
-                .send_to_address(branch_id, prover, false, addrs, |txbytes| {
-                    GrpcConnector::send_transaction(self.get_server_uri(), txbytes)
+                .send_to_address(branch_id, prover, false, addrs, &[], None, |txbytes| {
+                    GrpcConnector::send_transaction(self.get_servers(), txbytes)
                 })
                 .await
         };
 
         result.map(|(txid, _, fees)| (txid, fees))
     }
+
+    #[cfg(test)]
+    pub async fn test_do_send_batch(
+        &self,
+        batch_path: &str,
+        results_path: &str,
+        max_outputs: usize,
+    ) -> Result<JsonValue, String> {
+        self.send_batch(batch_path, results_path, max_outputs, || {
+            Ok(crate::blaze::test_utils::FakeTxProver {})
+        })
+        .await
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,129 @@
-pub fn get_closest_checkpoint(chain_name: &str, height: u64) -> Option<(u64, &'static str, &'static str)> {
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use json::{object, JsonValue};
+use log::warn;
+use zcash_primitives::{merkle_tree::CommitmentTree, sapling::Node};
+
+use crate::grpc_connector::{server_list::ServerList, GrpcConnector};
+
+/// The closest checkpoint at or below `height`, out of the built-in ones and the `user_checkpoints` that were loaded
+/// from checkpoint files
+pub fn get_closest_checkpoint(
+    chain_name: &str,
+    height: u64,
+    user_checkpoints: &[(u64, String, String)],
+) -> Option<(u64, String, String)> {
     log::info!("Trying to get checkpoint closest to block {}", height);
-    match chain_name {
+    let builtin = match chain_name {
         "ztestsapling" => get_test_checkpoint(height),
         "zs" | "main" => get_main_checkpoint(height),
         _ => None,
+    };
+
+    let user = user_checkpoints
+        .iter()
+        .filter(|(h, _, _)| *h <= height)
+        .max_by_key(|(h, _, _)| *h)
+        .cloned();
+
+    builtin
+        .map(|(h, hash, tree)| (h, hash.to_string(), tree.to_string()))
+        .into_iter()
+        .chain(user)
+        .max_by_key(|(h, _, _)| *h)
+}
+
+/// Read a checkpoints file, which is a JSON list of `{"height", "hash", "tree"}` objects, as written by
+/// `checkpoints_to_json`
+pub fn read_checkpoints(path: &Path) -> io::Result<Vec<(u64, String, String)>> {
+    let bad = |e: String| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Bad checkpoints file {:?}: {}", path, e),
+        )
+    };
+
+    let j = json::parse(&fs::read_to_string(path)?).map_err(|e| bad(e.to_string()))?;
+    if !j.is_array() {
+        return Err(bad("Expected a list of checkpoints".to_string()));
+    }
+
+    j.members()
+        .map(|c| {
+            let height = c["height"]
+                .as_u64()
+                .ok_or_else(|| bad("Checkpoint without a height".to_string()))?;
+            let hash = c["hash"]
+                .as_str()
+                .ok_or_else(|| bad(format!("Checkpoint at {} without a hash", height)))?;
+            let tree = c["tree"]
+                .as_str()
+                .ok_or_else(|| bad(format!("Checkpoint at {} without a tree", height)))?;
+
+            match hex::decode(hash) {
+                Ok(h) if h.len() == 32 => {}
+                _ => return Err(bad(format!("Checkpoint at {} has a bad hash", height))),
+            }
+            hex::decode(tree)
+                .map_err(|e| e.to_string())
+                .and_then(|t| CommitmentTree::<Node>::read(&t[..]).map_err(|e| e.to_string()))
+                .map_err(|e| bad(format!("Checkpoint at {} has a bad tree: {}", height, e)))?;
+
+            Ok((height, hash.to_string(), tree.to_string()))
+        })
+        .collect()
+}
+
+pub fn checkpoints_to_json(checkpoints: &[(u64, String, String)]) -> JsonValue {
+    JsonValue::Array(
+        checkpoints
+            .iter()
+            .map(|(height, hash, tree)| {
+                object! {
+                    "height" => *height,
+                    "hash" => hash.clone(),
+                    "tree" => tree.clone(),
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Check the checkpoints against the tree states the server has at their heights, and keep the ones that match. Fails
+/// if the server can't be reached at all, so the caller can decide what to do with checkpoints that can't be checked.
+pub async fn validate_checkpoints(
+    servers: ServerList,
+    checkpoints: Vec<(u64, String, String)>,
+) -> Result<Vec<(u64, String, String)>, String> {
+    let tip = GrpcConnector::get_latest_block(servers.clone()).await?.height;
+
+    let mut valid = vec![];
+    for (height, hash, tree) in checkpoints {
+        if height > tip {
+            warn!(
+                "Checkpoint at {} is above the server's tip {}, skipping it",
+                height, tip
+            );
+            continue;
+        }
+
+        match GrpcConnector::get_sapling_tree(servers.clone(), height).await {
+            Ok(ts) if ts.hash.eq_ignore_ascii_case(&hash) && ts.tree.eq_ignore_ascii_case(&tree) => {
+                valid.push((height, hash, tree))
+            }
+            Ok(_) => warn!(
+                "Checkpoint at {} doesn't match the server's tree state, skipping it",
+                height
+            ),
+            Err(e) => warn!("Couldn't check the checkpoint at {}, skipping it: {}", height, e),
+        }
     }
+
+    Ok(valid)
 }
 
 fn get_test_checkpoint(height: u64) -> Option<(u64, &'static str, &'static str)> {
@@ -149,4 +268,47 @@ pub mod tests {
         assert_eq!(get_main_checkpoint(610000).unwrap().0, 610000);
         assert_eq!(get_main_checkpoint(625000).unwrap().0, 610000);
     }
+
+    #[test]
+    fn user_checkpoints() {
+        let (height, hash, tree) = get_test_checkpoint(600000).unwrap();
+        let user = vec![(620000, hash.to_string(), tree.to_string())];
+
+        // A user checkpoint is only used when it is closer than the built-in ones
+        assert_eq!(get_closest_checkpoint("ztestsapling", 610000, &user).unwrap().0, height);
+        assert_eq!(get_closest_checkpoint("ztestsapling", 625000, &user).unwrap().0, 620000);
+        assert_eq!(get_closest_checkpoint("ztestsapling", 655000, &user).unwrap().0, 650000);
+        assert_eq!(get_closest_checkpoint("ztestsapling", 500000, &user), None);
+
+        // And on chains without built-in checkpoints
+        assert_eq!(
+            get_closest_checkpoint("zregtestsapling", 625000, &user).unwrap().0,
+            620000
+        );
+        assert_eq!(get_closest_checkpoint("zregtestsapling", 625000, &[]), None);
+    }
+
+    #[test]
+    fn checkpoints_file() {
+        let dir = tempdir::TempDir::new("checkpoints").unwrap();
+        let path = dir.path().join("checkpoints.json");
+
+        let checkpoints = get_all_main_checkpoints()
+            .into_iter()
+            .take(3)
+            .map(|(h, hash, tree)| (h, hash.to_string(), tree.to_string()))
+            .collect::<Vec<_>>();
+        fs::write(&path, checkpoints_to_json(&checkpoints).pretty(2)).unwrap();
+        assert_eq!(read_checkpoints(&path).unwrap(), checkpoints);
+
+        // Anything that isn't a list of proper checkpoints is refused
+        for bad in [
+            "{}",
+            "[{\"height\": 10, \"hash\": \"00\", \"tree\": \"00\"}]",
+            "[{\"height\": 10, \"hash\": \"00000000015493abba3e3bb384562f09141548f60581e06d4056993388d2ea2f\"}]",
+        ] {
+            fs::write(&path, bad).unwrap();
+            assert_eq!(read_checkpoints(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
 }
@@ -3,7 +3,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use log4rs::{
     append::rolling_file::{
         policy::compound::{roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy},
@@ -20,13 +20,24 @@ use zcash_primitives::{
     constants,
 };
 
-use crate::{grpc_connector::GrpcConnector, lightclient::checkpoints};
+use crate::{
+    grpc_connector::{server_list::ServerList, GrpcConnector},
+    lightclient::checkpoints,
+};
 
 pub const DEFAULT_SERVER: &str = "https://lwdv3.zecwallet.co";
 pub const WALLET_NAME: &str = "zecwallet-light-wallet.dat";
 pub const LOGFILE_NAME: &str = "zecwallet-light-wallet.debug.log";
+pub const BLOCK_CACHE_DIR: &str = "blockcache";
+pub const CHECKPOINTS_NAME: &str = "zecwallet-light-checkpoints.json";
 pub const ANCHOR_OFFSET: [u32; 5] = [4, 0, 0, 0, 0];
 pub const MAX_REORG: usize = 100;
+// Most blocks in a sync batch. The wallet is saved after every batch, so an interrupted sync loses at most this many
+pub const DEFAULT_SYNC_SAVE_INTERVAL: u64 = 10_000;
+// Memory in bytes that a sync batch should stay within, used to pick the size of the batches
+pub const DEFAULT_SYNC_MEMORY_BUDGET: u64 = 256 * 1024 * 1024;
+// Number of blocks to wait before broadcasting an unmined tx of ours again
+pub const REBROADCAST_INTERVAL: u32 = 3;
 pub const GAP_RULE_UNUSED_ADDRESSES: usize = if cfg!(any(target_os = "ios", target_os = "android")) {
     0
 } else {
@@ -138,46 +149,71 @@ pub const UNITTEST_NETWORK: UnitTestNetwork = UnitTestNetwork;
 
 #[derive(Clone, Debug)]
 pub struct LightClientConfig<P> {
-    pub server: http::Uri,
+    // The lightwalletd servers to use, in order of preference
+    pub servers: ServerList,
+    // Number of servers that have to agree on the chain before syncing. 0 or 1 doesn't cross-check
+    pub cross_check_servers: usize,
     pub chain_name: String,
     pub sapling_activation_height: u64,
     pub anchor_offset: [u32; 5],
     pub monitor_mempool: bool,
     pub data_dir: Option<String>,
+    // Max size in bytes of the on-disk compact block cache. None disables the cache
+    pub block_cache_size: Option<u64>,
+    // Most blocks in a sync batch. The wallet can only be saved between batches, so this is how far apart the saves
+    // during a sync are. 0 doesn't limit the batches, which are then sized by `sync_memory_budget` alone
+    pub sync_save_interval: u64,
+    // Memory in bytes that the blocks of a sync batch and their processing should fit in
+    pub sync_memory_budget: u64,
+    // Checkpoints loaded from checkpoint files, used along with the built-in ones
+    pub checkpoints: Vec<(u64, String, String)>,
     pub params: P,
 }
 
 impl<P: consensus::Parameters> LightClientConfig<P> {
     // Create an unconnected (to any server) config to test for local wallet etc...
     pub fn create_unconnected(params: P, dir: Option<String>) -> LightClientConfig<P> {
+        let chain_name = params.hrp_sapling_payment_address().to_string();
+
         LightClientConfig {
-            server: http::Uri::default(),
-            chain_name: params.hrp_sapling_payment_address().to_string(),
+            servers: ServerList::for_chain(vec![http::Uri::default()], &chain_name),
+            cross_check_servers: 0,
+            chain_name,
             sapling_activation_height: 1,
             monitor_mempool: false,
             anchor_offset: [4; 5],
             data_dir: dir,
+            block_cache_size: None,
+            sync_save_interval: DEFAULT_SYNC_SAVE_INTERVAL,
+            sync_memory_budget: DEFAULT_SYNC_MEMORY_BUDGET,
+            checkpoints: vec![],
             params: params.clone(),
         }
     }
 
-    pub fn create(server: http::Uri, data_dir: Option<String>) -> io::Result<(LightClientConfig<Network>, u64)> {
+    pub fn create(servers: Vec<http::Uri>, data_dir: Option<String>) -> io::Result<(LightClientConfig<Network>, u64)> {
         use std::net::ToSocketAddrs;
 
-        let s = server.clone();
+        let s = ServerList::new(servers.clone());
+        let server_list = s.clone();
         if let Ok((chain_name, sapling_activation_height, block_height)) =
             Runtime::new().unwrap().block_on(async move {
                 // Test for a connection first
-                format!("{}:{}", server.host().unwrap(), server.port().unwrap())
-                    .to_socket_addrs()?
-                    .next()
+                servers
+                    .iter()
+                    .find_map(|server| {
+                        format!("{}:{}", server.host().unwrap(), server.port().unwrap())
+                            .to_socket_addrs()
+                            .ok()
+                            .and_then(|mut addrs| addrs.next())
+                    })
                     .ok_or(std::io::Error::new(
                         ErrorKind::ConnectionRefused,
                         "Couldn't resolve server!",
                     ))?;
 
-                // Do a getinfo first, before opening the wallet
-                let info = GrpcConnector::get_info(server.clone())
+                // Do a getinfo first, before opening the wallet. This also decides the chain all the servers must be on
+                let info = GrpcConnector::get_info(server_list)
                     .await
                     .map_err(|e| std::io::Error::new(ErrorKind::ConnectionRefused, e))?;
 
@@ -192,12 +228,17 @@ impl<P: consensus::Parameters> LightClientConfig<P> {
 
             // Create a Light Client Config
             let config = LightClientConfig {
-                server: s,
+                servers: s,
+                cross_check_servers: 0,
                 chain_name,
                 monitor_mempool: false,
                 sapling_activation_height,
                 anchor_offset: ANCHOR_OFFSET,
                 data_dir,
+                block_cache_size: None,
+                sync_save_interval: DEFAULT_SYNC_SAVE_INTERVAL,
+                sync_memory_budget: DEFAULT_SYNC_MEMORY_BUDGET,
+                checkpoints: vec![],
                 params,
             };
 
@@ -347,6 +388,60 @@ impl<P: consensus::Parameters> LightClientConfig<P> {
         Ok(backup_file_str)
     }
 
+    pub fn get_block_cache_path(&self) -> Box<Path> {
+        let mut cache_path = self.get_zcash_data_path().into_path_buf();
+        cache_path.push(BLOCK_CACHE_DIR);
+
+        cache_path.into_boxed_path()
+    }
+
+    pub fn get_checkpoints_path(&self) -> Box<Path> {
+        let mut checkpoints_path = self.get_zcash_data_path().into_path_buf();
+        checkpoints_path.push(CHECKPOINTS_NAME);
+
+        checkpoints_path.into_boxed_path()
+    }
+
+    /// Load the checkpoints from the checkpoints file in the data dir, if there is one, and from `file`. Each one is
+    /// checked against the server's tree state at its height, and the ones that don't match are dropped. If the
+    /// server can't be reached, none of them are used, unless `trust_unchecked` is set. Returns the number of
+    /// checkpoints loaded.
+    pub fn load_checkpoints(&mut self, file: Option<&Path>, trust_unchecked: bool) -> io::Result<usize> {
+        let mut loaded = vec![];
+        if self.get_checkpoints_path().exists() {
+            loaded.extend(checkpoints::read_checkpoints(&self.get_checkpoints_path())?);
+        }
+        if let Some(file) = file {
+            loaded.extend(checkpoints::read_checkpoints(file)?);
+        }
+
+        if loaded.is_empty() {
+            return Ok(0);
+        }
+
+        let servers = self.servers.clone();
+        let checked = Runtime::new()
+            .unwrap()
+            .block_on(checkpoints::validate_checkpoints(servers, loaded.clone()));
+        self.checkpoints = match checked {
+            Ok(valid) => valid,
+            Err(e) if trust_unchecked => {
+                warn!(
+                    "Couldn't check the checkpoints with the server, using them unchecked: {}",
+                    e
+                );
+                loaded
+            }
+            Err(e) => {
+                warn!("Couldn't check the checkpoints with the server, not using them: {}", e);
+                vec![]
+            }
+        };
+
+        info!("Loaded {} checkpoints", self.checkpoints.len());
+        Ok(self.checkpoints.len())
+    }
+
     pub fn get_log_path(&self) -> Box<Path> {
         let mut log_path = self.get_zcash_data_path().into_path_buf();
         log_path.push(LOGFILE_NAME);
@@ -361,7 +456,7 @@ impl<P: consensus::Parameters> LightClientConfig<P> {
         }
 
         info!("Getting sapling tree from LightwalletD at height {}", height);
-        match GrpcConnector::get_sapling_tree(self.server.clone(), height).await {
+        match GrpcConnector::get_sapling_tree(self.servers.clone(), height).await {
             Ok(tree_state) => {
                 let hash = tree_state.hash.clone();
                 let tree = tree_state.tree.clone();
@@ -369,10 +464,7 @@ impl<P: consensus::Parameters> LightClientConfig<P> {
             }
             Err(e) => {
                 error!("Error getting sapling tree:{}\nWill return checkpoint instead.", e);
-                match checkpoints::get_closest_checkpoint(&self.chain_name, height) {
-                    Some((height, hash, tree)) => Some((height, hash.to_string(), tree.to_string())),
-                    None => None,
-                }
+                checkpoints::get_closest_checkpoint(&self.chain_name, height, &self.checkpoints)
             }
         }
     }
@@ -1,4 +1,4 @@
-use crate::blaze::test_utils::{tree_to_string, FakeCompactBlockList};
+use crate::blaze::test_utils::{random_u8_32, tree_to_string, FakeCompactBlockList};
 use crate::compact_formats::compact_tx_streamer_server::CompactTxStreamer;
 use crate::compact_formats::compact_tx_streamer_server::CompactTxStreamerServer;
 use crate::compact_formats::{
@@ -6,6 +6,7 @@ use crate::compact_formats::{
     GetAddressUtxosArg, GetAddressUtxosReply, GetAddressUtxosReplyList, LightdInfo, PingResponse, PriceRequest,
     PriceResponse, RawTransaction, SendResponse, TransparentAddressBlockFilter, TreeState, TxFilter,
 };
+use crate::grpc_connector::server_list::ServerList;
 use crate::lightwallet::data::WalletTx;
 use crate::lightwallet::now;
 use futures::{FutureExt, Stream};
@@ -50,7 +51,7 @@ pub async fn create_test_server<P: consensus::Parameters + Send + Sync + 'static
     let addr = server_port.parse().unwrap();
 
     let mut config = LightClientConfig::create_unconnected(params, None);
-    config.server = uri.parse().unwrap();
+    config.servers = ServerList::for_chain(vec![uri.parse().unwrap()], &config.chain_name);
 
     let (service, data) = TestGRPCService::new(config.clone());
 
@@ -145,6 +146,16 @@ pub struct TestServerData<P> {
     pub config: LightClientConfig<P>,
     pub zec_price: f64,
     pub tree_states: Vec<(u64, String, String)>,
+    // Number of compact blocks sent by get_block_range
+    pub blocks_served: u64,
+    // Number of tree states sent by get_tree_state
+    pub tree_states_served: u64,
+    // Fail any get_block_range request for blocks above this height
+    pub unavailable_above: Option<u64>,
+    // Serve the blocks at these heights with a prev_hash that doesn't match the block below them
+    pub broken_links: Vec<u64>,
+    // Keep the txns sent to send_transaction, but answer with an error, as if the response was lost
+    pub drop_send_responses: bool,
 }
 
 impl<P: consensus::Parameters> TestServerData<P> {
@@ -156,6 +167,11 @@ impl<P: consensus::Parameters> TestServerData<P> {
             config,
             zec_price: 140.5,
             tree_states: vec![],
+            blocks_served: 0,
+            tree_states_served: 0,
+            unavailable_above: None,
+            broken_links: vec![],
+            drop_send_responses: false,
         };
 
         data
@@ -258,17 +274,33 @@ impl<P: consensus::Parameters + Send + Sync + 'static> CompactTxStreamer for Tes
 
         let rev = start < end;
 
+        if let Some(h) = self.data.read().await.unavailable_above {
+            if cmp::max(start, end) > h {
+                return Err(Status::unavailable(format!("Blocks above {} are not available", h)));
+            }
+        }
+
         let (tx, rx) = mpsc::channel(self.data.read().await.blocks.len());
 
         let blocks = self.data.read().await.blocks.clone();
+        let broken_links = self.data.read().await.broken_links.clone();
+        self.data.write().await.blocks_served += blocks
+            .iter()
+            .filter(|b| b.height >= cmp::min(start, end) && b.height <= cmp::max(start, end))
+            .count() as u64;
+
         tokio::spawn(async move {
             let (iter, min, max) = if rev {
                 (blocks.iter().rev().map(|b| b.clone()).collect(), start, end)
             } else {
                 (blocks, end, start)
             };
-            for b in iter {
+            for mut b in iter {
                 if b.height >= min && b.height <= max {
+                    if broken_links.contains(&b.height) {
+                        b.prev_hash = random_u8_32().to_vec();
+                    }
+
                     Self::wait_random().await;
                     tx.send(Ok(b)).await.unwrap();
                 }
@@ -312,7 +344,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> CompactTxStreamer for Tes
             .unwrap()
             .txid();
 
-        self.data.write().await.sent_txns.push(rtx);
+        let mut data = self.data.write().await;
+        data.sent_txns.push(rtx);
+        if data.drop_send_responses {
+            return Err(Status::deadline_exceeded("Send response was lost"));
+        }
+
         Ok(Response::new(SendResponse {
             error_message: txid.to_string(),
             error_code: 0,
@@ -389,6 +426,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> CompactTxStreamer for Tes
 
         let block = request.into_inner();
         println!("Getting tree state at {}", block.height);
+        self.data.write().await.tree_states_served += 1;
 
         // See if it is manually set.
         if let Some((height, hash, tree)) = self
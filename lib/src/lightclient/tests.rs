@@ -1,5 +1,8 @@
-use std::fs;
+use std::collections::HashSet;
+use std::fs::{self, File};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use ff::{Field, PrimeField};
 use group::GroupEncoding;
@@ -7,16 +10,20 @@ use json::JsonValue;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use tempdir::TempDir;
+use tokio::join;
 use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
 use tonic::transport::Channel;
 use tonic::Request;
 
 use zcash_client_backend::address::RecipientAddress;
 use zcash_client_backend::encoding::{
-    encode_extended_full_viewing_key, encode_extended_spending_key, encode_payment_address,
+    decode_payment_address, encode_extended_full_viewing_key, encode_extended_spending_key, encode_payment_address,
 };
 use zcash_note_encryption::{EphemeralKeyBytes, NoteEncryption};
 use zcash_primitives::consensus::{BlockHeight, BranchId, TEST_NETWORK};
+use zcash_primitives::legacy::TransparentAddress;
 use zcash_primitives::memo::Memo;
 use zcash_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
 use zcash_primitives::sapling::note_encryption::SaplingDomain;
@@ -27,19 +34,28 @@ use zcash_primitives::transaction::components::{sapling, Amount, OutputDescripti
 use zcash_primitives::transaction::{Transaction, TransactionData};
 use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
 
+use crate::blaze::block_witness_data::{BlockAndWitnessData, INCONSISTENT_CHAIN};
 use crate::blaze::fetch_full_tx::FetchFullTxns;
+use crate::blaze::sync_token::SYNC_CANCELLED;
+use crate::blaze::syncdata::BlazeSyncData;
 use crate::blaze::test_utils::{FakeCompactBlockList, FakeTransaction};
 use crate::compact_formats::compact_tx_streamer_client::CompactTxStreamerClient;
 
 use super::lightclient_config::UnitTestNetwork;
 use crate::compact_formats::{CompactSaplingOutput, CompactTx, Empty};
+use crate::grpc_connector::server_list::ServerList;
+use crate::grpc_connector::GrpcConnector;
 use crate::lightclient::faketx::new_transactiondata;
 use crate::lightclient::test_server::{create_test_server, mine_pending_blocks, mine_random_blocks};
 use crate::lightclient::LightClient;
+use crate::lightwallet::batch;
 use crate::lightwallet::data::WalletTx;
+use crate::lightwallet::events::{SendStep, WalletEvent};
+use crate::lightwallet::unified;
+use crate::lightwallet::{ChangeAddressOption, FeePolicy, LightWallet};
 
 use super::checkpoints;
-use super::lightclient_config::{LightClientConfig, UnitTestNetwork};
+use super::lightclient_config::{LightClientConfig, UnitTestNetwork, REBROADCAST_INTERVAL};
 
 #[test]
 fn new_wallet_from_phrase() {
@@ -148,7 +164,7 @@ async fn basic_no_wallet_txns() {
 
     ready_rx.await.unwrap();
 
-    let uri = config.server.clone();
+    let uri = config.servers.current();
     let mut client = CompactTxStreamerClient::new(Channel::builder(uri).connect().await.unwrap());
 
     let r = client
@@ -505,6 +521,7 @@ async fn multiple_incoming_same_tx() {
         txns[4]["amount"].as_i64().unwrap(),
         -(sent_value as i64) - i64::from(fees)
     );
+    assert_eq!(txns[4]["fee"].as_u64().unwrap(), u64::from(fees));
     assert_eq!(txns[4]["outgoing_metadata"][0]["address"], EXT_ZADDR.to_string());
     assert_eq!(txns[4]["outgoing_metadata"][0]["value"].as_u64().unwrap(), sent_value);
     assert_eq!(txns[4]["outgoing_metadata"][0]["memo"].is_null(), true);
@@ -1306,7 +1323,25 @@ async fn witness_clearing() {
         .clone();
     assert_eq!(witnesses.len(), 6);
 
-    // 5. Mine 100 blocks, witness should now disappear
+    // 6. Mine 100 blocks, witness should still be there, since a reorg deeper than the blocks the wallet keeps could
+    // still unspend the note
+    mine_random_blocks(&mut fcbl, &data, &lc, 100).await;
+    let witnesses = lc
+        .wallet
+        .txns()
+        .read()
+        .await
+        .current
+        .get(&tx.txid())
+        .unwrap()
+        .notes
+        .get(0)
+        .unwrap()
+        .witnesses
+        .clone();
+    assert_eq!(witnesses.len(), 6);
+
+    // 7. Mine another 100 blocks, witness should now disappear
     mine_random_blocks(&mut fcbl, &data, &lc, 100).await;
     let witnesses = lc
         .wallet
@@ -1508,6 +1543,2034 @@ async fn mempool_and_balance() {
     h1.await.unwrap();
 }
 
+#[tokio::test]
+async fn propose_sign_broadcast() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+
+    // 2. Send an incoming tx to fill the wallet
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    let (_ztx, _height, _) = fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 3. Create a proposal, and roundtrip it through its serialized form
+    let sent_value = 20_000;
+    let branch_id = lc.consensus_branch_id().await;
+    let proposal = lc
+        .wallet
+        .create_proposal(
+            branch_id,
+            false,
+            false,
+            vec![(EXT_ZADDR, sent_value, Some("Offline".to_string()))],
+            &[],
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+    assert_eq!(proposal.notes.len(), 1);
+    assert_eq!(proposal.total_input(), zvalue);
+    assert_eq!(proposal.total_output(), sent_value);
+
+    let mut buf = vec![];
+    proposal.write(&mut buf).unwrap();
+    let proposal = crate::lightwallet::proposal::TxProposal::read(&buf[..]).unwrap();
+
+    // Creating the proposal doesn't touch the wallet
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["unspent_notes"].len(), 1);
+    assert_eq!(notes["pending_notes"].len(), 0);
+
+    // A proposal that sends the change or encrypts the outputs for someone else, or has values out of range, isn't
+    // signed
+    let mut tampered = proposal.clone();
+    tampered.change_address = match RecipientAddress::decode(&config.get_params(), EXT_ZADDR) {
+        Some(RecipientAddress::Shielded(pa)) => pa,
+        _ => panic!("Expected a z address"),
+    };
+    let e = lc
+        .wallet
+        .sign_proposal(&tampered, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .unwrap_err();
+    assert!(e.contains("change"));
+
+    let mut tampered = proposal.clone();
+    tampered.ovk = zcash_primitives::keys::OutgoingViewingKey([7u8; 32]);
+    let e = lc
+        .wallet
+        .sign_proposal(&tampered, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .unwrap_err();
+    assert!(e.contains("ovk"));
+
+    let mut tampered = proposal.clone();
+    tampered.outputs[0].value = u64::MAX;
+    assert!(lc
+        .wallet
+        .sign_proposal(&tampered, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .is_err());
+
+    // 4. Sign and broadcast it
+    let tx = lc
+        .wallet
+        .sign_proposal(&proposal, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .unwrap();
+    let expected_txid = tx.txid().to_string();
+
+    let sent_txid = lc
+        .wallet
+        .broadcast_tx(tx, |txbytes| {
+            crate::grpc_connector::GrpcConnector::send_transaction(lc.get_servers(), txbytes)
+        })
+        .await
+        .unwrap();
+    assert_eq!(sent_txid, expected_txid);
+
+    // The spent note is now pending
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["pending_notes"].len(), 1);
+    assert_eq!(notes["pending_notes"][0]["unconfirmed_spent"], sent_txid);
+
+    // 5. Mine it, and the note should be spent
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["spent_notes"].len(), 1);
+    assert_eq!(notes["spent_notes"][0]["spent"], sent_txid);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn watch_only_proposal() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    // The signing wallet holds the spending key, the watch-only one only has its viewing key
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let watch = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and import the signing wallet's viewing key into the watch-only wallet
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    watch.do_sync(true).await.unwrap();
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    watch
+        .do_import_vk(
+            encode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), &extfvk1),
+            1,
+        )
+        .await
+        .unwrap();
+
+    // 2. Receive a note, which the watch-only wallet sees but can't spend
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    watch.do_sync(true).await.unwrap();
+
+    assert_eq!(watch.do_balance().await["zbalance"].as_u64().unwrap(), zvalue);
+    assert_eq!(watch.do_balance().await["spendable_zbalance"].as_u64().unwrap(), 0);
+
+    let sent_value = 20_000;
+    assert!(watch.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.is_err());
+
+    // 3. The watch-only wallet can still propose spending the note
+    let dir = TempDir::new("proposal").unwrap();
+    let proposal_path = dir.path().join("proposal.bin").to_str().unwrap().to_string();
+    let j = watch
+        .do_propose(vec![(EXT_ZADDR, sent_value, None)], &proposal_path)
+        .await
+        .unwrap();
+    assert_eq!(j["notes"].as_usize().unwrap(), 1);
+    assert_eq!(j["total_input"].as_u64().unwrap(), zvalue);
+    assert_eq!(j["total_output"].as_u64().unwrap(), sent_value);
+
+    let proposal = crate::lightwallet::proposal::TxProposal::read(File::open(&proposal_path).unwrap()).unwrap();
+
+    // 4. It can't sign the proposal, but the wallet with the spending key can
+    assert!(watch
+        .wallet
+        .sign_proposal(&proposal, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .is_err());
+    let tx = lc
+        .wallet
+        .sign_proposal(&proposal, &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .unwrap();
+
+    // 5. The signed tx is broadcast from the watch-only wallet, which then sees its note as pending
+    let mut raw_tx = vec![];
+    tx.write(&mut raw_tx).unwrap();
+    let signed_path = dir.path().join("signed.hex");
+    fs::write(&signed_path, hex::encode(raw_tx)).unwrap();
+
+    let sent_txid = watch.do_broadcast(signed_path.to_str().unwrap()).await.unwrap();
+    assert_eq!(sent_txid, tx.txid().to_string());
+
+    let notes = watch.do_list_notes(true).await;
+    assert_eq!(notes["pending_notes"].len(), 1);
+    assert_eq!(notes["pending_notes"][0]["unconfirmed_spent"], sent_txid);
+
+    // 6. Once it is mined, both wallets see the note as spent
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    watch.do_sync(true).await.unwrap();
+
+    for wallet in [&lc, &watch] {
+        let notes = wallet.do_list_notes(true).await;
+        assert_eq!(notes["spent_notes"].len(), 1);
+        assert_eq!(notes["spent_notes"][0]["spent"], sent_txid);
+    }
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn propose_send_dry_run() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks and receive a note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    let (_ztx, _height, _) = fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let notes_before = lc.do_list_notes(true).await;
+
+    // 2. A dry run shows the breakdown
+    let sent_value = 20_000;
+    let j = lc
+        .do_propose_send(
+            vec![(EXT_ZADDR, sent_value, None), (EXT_TADDR, sent_value, None)],
+            &[],
+            None,
+        )
+        .await
+        .unwrap();
+
+    let fee = lc.wallet.fee(0, 1, 1, 1, 0);
+    assert_eq!(j["notes"].len(), 1);
+    assert_eq!(j["notes"][0]["value"].as_u64().unwrap(), zvalue);
+    assert_eq!(j["utxos"].len(), 0);
+    assert_eq!(j["logical_actions"].as_u64().unwrap(), 2);
+    assert_eq!(j["fee"].as_u64().unwrap(), fee);
+    assert_eq!(j["change"].as_u64().unwrap(), zvalue - 2 * sent_value - fee);
+    assert_eq!(j["change_address"], lc.do_address().await["z_addresses"][0]);
+
+    // 3. Nothing in the wallet changed, and nothing was sent
+    assert_eq!(lc.do_list_notes(true).await, notes_before);
+    assert_eq!(data.read().await.sent_txns.len(), 0);
+
+    // Not even with a diversified change address, which is shown but not recorded with the key
+    let diversified_before = lc.wallet.keys().read().await.get_all_diversified_zaddresses().await;
+    lc.wallet.set_change_address(ChangeAddressOption::Diversified).await;
+    let j = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    assert_ne!(j["change_address"], lc.do_address().await["z_addresses"][0]);
+    let again = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    assert_eq!(again["change_address"], j["change_address"]);
+    assert_eq!(
+        lc.wallet.keys().read().await.get_all_diversified_zaddresses().await,
+        diversified_before
+    );
+    lc.wallet
+        .set_change_address(ChangeAddressOption::SpentNoteAddress)
+        .await;
+
+    // 4. Asking for too much fails
+    assert!(lc
+        .do_propose_send(vec![(EXT_ZADDR, zvalue, None)], &[], None)
+        .await
+        .is_err());
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn consolidate_notes() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    // 2. Receive a few notes of different values
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    for value in [20_000, 1_000, 50_000, 3_000, 2_000] {
+        fcbl.add_tx_paying(&extfvk1, value);
+        mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    }
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let zaddr = lc.do_address().await["z_addresses"][0].as_str().unwrap().to_string();
+    let branch_id = lc.consensus_branch_id().await;
+
+    // 3. Need at least 2 notes per batch
+    assert!(lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 1, &zaddr)
+        .await
+        .is_err());
+
+    // 4. Only into the wallet's own addresses
+    let e = lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 2, EXT_ZADDR)
+        .await
+        .unwrap_err();
+    assert!(e.contains("spendable address of this wallet"));
+
+    // 5. In pairs, smallest first. [1k, 2k] and [2k, 3k] are worth less than the fee, so the largest note left pays for
+    // them, and [3k] has nothing to merge
+    let (proposals, unmerged) = lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 2, &zaddr)
+        .await
+        .unwrap();
+    assert_eq!(proposals.len(), 2);
+    assert_eq!(
+        proposals[0].notes.iter().map(|n| n.note.value).collect::<Vec<_>>(),
+        vec![1_000, 50_000]
+    );
+    assert_eq!(
+        proposals[1].notes.iter().map(|n| n.note.value).collect::<Vec<_>>(),
+        vec![2_000, 20_000]
+    );
+    assert_eq!(proposals[0].fee, 10_000);
+    assert_eq!(proposals[0].total_output(), 41_000);
+    assert_eq!(proposals[0].change(), 0);
+    assert!(unmerged.is_empty());
+
+    // 6. All the notes fit in a single batch
+    let (proposals, unmerged) = lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 5, &zaddr)
+        .await
+        .unwrap();
+    assert_eq!(proposals.len(), 1);
+    assert!(unmerged.is_empty());
+    assert_eq!(proposals[0].notes.len(), 5);
+    assert_eq!(proposals[0].fee, 25_000);
+    assert_eq!(proposals[0].outputs[0].address, zaddr);
+    assert_eq!(proposals[0].total_output(), 76_000 - 25_000);
+
+    // 7. Sign, broadcast and mine it
+    let tx = lc
+        .wallet
+        .sign_proposal(&proposals[0], &crate::blaze::test_utils::FakeTxProver {})
+        .await
+        .unwrap();
+    let sent_txid = lc
+        .wallet
+        .broadcast_tx(tx, |txbytes| {
+            crate::grpc_connector::GrpcConnector::send_transaction(lc.get_servers(), txbytes)
+        })
+        .await
+        .unwrap();
+
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    // All the notes were merged into one
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["spent_notes"].len(), 5);
+    assert_eq!(notes["unspent_notes"].len(), 1);
+    assert_eq!(notes["unspent_notes"][0]["created_in_txid"], sent_txid);
+    assert_eq!(notes["unspent_notes"][0]["value"].as_u64().unwrap(), 51_000);
+
+    // Nothing left to merge
+    let (proposals, unmerged) = lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 5, &zaddr)
+        .await
+        .unwrap();
+    assert!(proposals.is_empty());
+    assert!(unmerged.is_empty());
+
+    // 8. Receive 11 dust notes. Merging all 12 notes costs more than they are worth, so the smallest dust notes are
+    // left out, and reported, until the rest pay for themselves
+    for _ in 0..11 {
+        fcbl.add_tx_paying(&extfvk1, 10);
+    }
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let (proposals, unmerged) = lc
+        .wallet
+        .create_consolidation_proposals(branch_id, 20, &zaddr)
+        .await
+        .unwrap();
+    assert_eq!(proposals.len(), 1);
+    assert_eq!(proposals[0].notes.len(), 10);
+    assert_eq!(proposals[0].fee, 50_000);
+    assert_eq!(proposals[0].total_output(), 51_090 - 50_000);
+    assert_eq!(unmerged.iter().map(|n| n.note.value).collect::<Vec<_>>(), vec![10, 10]);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_from_address() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    // 2. Create a second z address, and receive a note at each address
+    let zaddr1 = lc.do_address().await["z_addresses"][0].as_str().unwrap().to_string();
+    let zaddr2 = lc.do_new_address("z", "").await.unwrap()[0]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let extfvks = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks();
+    fcbl.add_tx_paying(&extfvks[0], 100_000);
+    fcbl.add_tx_paying(&extfvks[1], 50_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 3. Spending from the second address only picks its note, and sends the change back to it
+    let sent_value = 20_000;
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[zaddr2.clone()], None)
+        .await
+        .unwrap();
+    assert_eq!(proposal["notes"].len(), 1);
+    assert_eq!(proposal["notes"][0]["value"].as_u64().unwrap(), 50_000);
+    assert_eq!(proposal["change_address"], zaddr2);
+
+    // Same for the first address
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[zaddr1.clone()], None)
+        .await
+        .unwrap();
+    assert_eq!(proposal["notes"].len(), 1);
+    assert_eq!(proposal["notes"][0]["value"].as_u64().unwrap(), 100_000);
+    assert_eq!(proposal["change_address"], zaddr1);
+
+    // 4. The second address doesn't have enough to cover this, even though the wallet does
+    assert!(lc
+        .do_propose_send(vec![(EXT_ZADDR, 60_000, None)], &[zaddr2.clone()], None)
+        .await
+        .is_err());
+    assert!(lc
+        .do_propose_send(vec![(EXT_ZADDR, 60_000, None)], &[], None)
+        .await
+        .is_ok());
+
+    // 5. Invalid from addresses are rejected
+    assert!(lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &["invalid".to_string()], None)
+        .await
+        .is_err());
+
+    // 6. Send it, and the change should come back to the second address
+    let (sent_txid, _) = {
+        let branch_id = lc.consensus_branch_id().await;
+        lc.wallet
+            .send_to_address(
+                branch_id,
+                crate::blaze::test_utils::FakeTxProver {},
+                false,
+                vec![(EXT_ZADDR, sent_value, None)],
+                &[zaddr2.clone()],
+                None,
+                |txbytes| crate::grpc_connector::GrpcConnector::send_transaction(lc.get_servers(), txbytes),
+            )
+            .await
+            .map(|(txid, _, fees)| (txid, fees))
+            .unwrap()
+    };
+
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["spent_notes"].len(), 1);
+    assert_eq!(notes["spent_notes"][0]["address"], zaddr2);
+    assert_eq!(notes["spent_notes"][0]["spent"], sent_txid);
+
+    let change = notes["unspent_notes"]
+        .members()
+        .find(|n| n["created_in_txid"] == sent_txid)
+        .unwrap();
+    assert_eq!(change["address"], zaddr2);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_with_fee_policy() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive a note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 2. The conventional fee for 1 spend and 2 outputs (including change) is 2 actions
+    let sent_value = 20_000;
+    let conventional_fee = lc.wallet.fee(0, 0, 1, 2, 0);
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    assert_eq!(proposal["fee"].as_u64().unwrap(), conventional_fee);
+
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], Some(FeePolicy::Multiplier(3)))
+        .await
+        .unwrap();
+    assert_eq!(proposal["fee"].as_u64().unwrap(), conventional_fee * 3);
+
+    // 3. The wallet's default policy is used when none is passed
+    lc.wallet.set_fee_policy(FeePolicy::Fixed(12_345)).await;
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    assert_eq!(proposal["fee"].as_u64().unwrap(), 12_345);
+    assert_eq!(proposal["change"].as_u64().unwrap(), zvalue - sent_value - 12_345);
+
+    // 4. Send it, and the fee should be recorded on the tx
+    let (sent_txid, fees) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+    assert_eq!(u64::from(fees), 12_345);
+
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let txns = lc.do_list_transactions(false).await;
+    let sent = txns.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert_eq!(sent["fee"].as_u64().unwrap(), 12_345);
+    assert_eq!(sent["amount"].as_i64().unwrap(), -(sent_value as i64) - 12_345);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue - sent_value - 12_345);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn rebroadcast_pending_sends() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive 2 notes
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 16);
+
+    // 2. Send a tx. Its raw bytes are kept until it is mined
+    let sent_value = 20_000;
+    let (sent_txid, _) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+
+    let sent_txns = data.write().await.sent_txns.drain(..).collect::<Vec<_>>();
+    assert_eq!(sent_txns.len(), 1);
+    {
+        let txns = lc.wallet.txns.read().await;
+        assert_eq!(txns.pending_sends.len(), 1);
+
+        let (txid, ps) = txns.pending_sends.iter().next().unwrap();
+        assert_eq!(format!("{}", txid), sent_txid);
+        assert_eq!(ps.raw_tx, sent_txns[0].data);
+        assert_eq!(ps.expiry_height, 17 + 40);
+    }
+
+    // 3. The server dropped it, but it isn't rebroadcast until REBROADCAST_INTERVAL blocks have passed
+    mine_random_blocks(&mut fcbl, &data, &lc, 1).await;
+    assert_eq!(data.read().await.sent_txns.len(), 0);
+
+    // 4. It can be rebroadcast manually
+    assert!(lc.do_rebroadcast("not a txid").await.is_err());
+    assert_eq!(lc.do_rebroadcast(&sent_txid).await.unwrap(), sent_txid);
+    let sent_txns = data.write().await.sent_txns.drain(..).collect::<Vec<_>>();
+    assert_eq!(sent_txns.len(), 1);
+    assert_eq!(
+        sent_txns[0].data,
+        lc.wallet
+            .txns
+            .read()
+            .await
+            .pending_sends
+            .values()
+            .next()
+            .unwrap()
+            .raw_tx
+    );
+
+    // 5. And automatically, once it has been unmined for long enough
+    mine_random_blocks(&mut fcbl, &data, &lc, REBROADCAST_INTERVAL as u64).await;
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    // 6. Once it is mined, it is no longer pending
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    assert!(lc.wallet.txns.read().await.pending_sends.is_empty());
+    assert!(lc.do_rebroadcast(&sent_txid).await.is_err());
+
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["spent_notes"][0]["spent"], sent_txid);
+
+    // 7. A tx that is never mined is forgotten once it expires
+    let (sent_txid, _) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+    data.write().await.sent_txns.clear();
+    assert_eq!(lc.wallet.txns.read().await.pending_sends.len(), 1);
+
+    mine_random_blocks(&mut fcbl, &data, &lc, 50).await;
+    assert!(lc.wallet.txns.read().await.pending_sends.is_empty());
+    assert_eq!(data.read().await.sent_txns.len(), 0);
+
+    // It is still in the wallet as an unconfirmed tx until the mempool expires
+    let txns = lc.do_list_transactions(false).await;
+    let sent = txns.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert_eq!(sent["unconfirmed"].as_bool().unwrap(), true);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_batch_resumes() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive a single note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let dir = TempDir::new("batch").unwrap();
+    let batch_path = dir.path().join("payouts.csv").to_str().unwrap().to_string();
+    let results_path = dir.path().join("payouts.results.csv").to_str().unwrap().to_string();
+
+    // 2. An invalid row means nothing is sent
+    fs::write(
+        &batch_path,
+        format!("address,amount,memo\n{},1000\nnotanaddress,1000\n", EXT_ZADDR),
+    )
+    .unwrap();
+    let err = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap_err();
+    assert!(err.contains("Line 3"));
+    assert!(!Path::new(&results_path).exists());
+    assert_eq!(data.read().await.sent_txns.len(), 0);
+
+    // 3. Send 3 rows, 2 per tx. The first tx spends the only note, so the second one fails
+    fs::write(
+        &batch_path,
+        format!(
+            "address,amount,memo\n{},10000,first\n{},0.0001,\n{},10000 zats\n",
+            EXT_ZADDR, EXT_TADDR, EXT_ZADDR2
+        ),
+    )
+    .unwrap();
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 2);
+    assert_eq!(r["failed"].as_usize().unwrap(), 1);
+    assert_eq!(r["txids"].len(), 1);
+    let first_txid = r["txids"][0].as_str().unwrap().to_string();
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    let results = batch::read_results(&fs::read_to_string(&results_path).unwrap()).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].txid, Some(first_txid.clone()));
+    assert_eq!(results[1].txid, Some(first_txid.clone()));
+    assert_eq!(results[1].row.amount, 10_000);
+    assert!(results[2]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("Insufficient verified funds"));
+
+    // 4. Once the change is spendable, running the batch again only pays the failed row
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 1);
+    assert_eq!(r["failed"].as_usize().unwrap(), 0);
+    assert_eq!(r["skipped"].as_usize().unwrap(), 2);
+    let second_txid = r["txids"][0].as_str().unwrap().to_string();
+    assert_ne!(first_txid, second_txid);
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    let results = batch::read_results(&fs::read_to_string(&results_path).unwrap()).unwrap();
+    assert_eq!(results[0].txid, Some(first_txid));
+    assert_eq!(results[2].txid, Some(second_txid));
+    assert!(results.iter().all(|r| r.error.is_none()));
+
+    // 5. A completed batch doesn't send anything
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 0);
+    assert_eq!(r["skipped"].as_usize().unwrap(), 3);
+    assert_eq!(r["pending"].as_usize().unwrap(), 0);
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    // 6. Neither does one that stopped after marking a row as pending, since it may already have been sent
+    let mut stopped = results.clone();
+    stopped[2].error = Some(batch::PENDING_BROADCAST.to_string());
+    fs::write(&results_path, batch::write_results(&stopped)).unwrap();
+
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 0);
+    assert_eq!(r["skipped"].as_usize().unwrap(), 3);
+    assert_eq!(r["pending"].as_usize().unwrap(), 1);
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+    assert_eq!(
+        batch::read_results(&fs::read_to_string(&results_path).unwrap()).unwrap(),
+        stopped
+    );
+
+    // 7. Nor one whose paid rows were changed
+    fs::write(
+        &batch_path,
+        format!("{},20000\n{},10000\n{},10000\n", EXT_ZADDR, EXT_TADDR, EXT_ZADDR2),
+    )
+    .unwrap();
+    assert!(lc.test_do_send_batch(&batch_path, &results_path, 2).await.is_err());
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_batch_broadcast_error() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive a single note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let dir = TempDir::new("batch").unwrap();
+    let batch_path = dir.path().join("payouts.csv").to_str().unwrap().to_string();
+    let results_path = dir.path().join("payouts.results.csv").to_str().unwrap().to_string();
+    fs::write(&batch_path, format!("{},10000\n{},10000\n", EXT_ZADDR, EXT_TADDR)).unwrap();
+
+    // 2. The server gets the tx, but its response is lost, so the rows stay pending with the error
+    data.write().await.drop_send_responses = true;
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 0);
+    assert_eq!(r["failed"].as_usize().unwrap(), 0);
+    assert_eq!(r["pending"].as_usize().unwrap(), 2);
+    assert_eq!(r["txids"].len(), 0);
+
+    let sent = data.read().await.sent_txns.clone();
+    assert_eq!(sent.len(), 1);
+    let branch = BranchId::for_height(&UnitTestNetwork, BlockHeight::from_u32(0));
+    let sent_txid = Transaction::read(&sent[0].data[..], branch).unwrap().txid().to_string();
+
+    let results = batch::read_results(&fs::read_to_string(&results_path).unwrap()).unwrap();
+    for r in &results {
+        assert!(r.is_pending());
+        assert_eq!(r.txid, Some(sent_txid.clone()));
+        assert!(r.error.as_ref().unwrap().contains("Send response was lost"));
+    }
+
+    // 3. Running the batch again doesn't pay the rows twice
+    data.write().await.drop_send_responses = false;
+    let r = lc.test_do_send_batch(&batch_path, &results_path, 2).await.unwrap();
+    assert_eq!(r["paid"].as_usize().unwrap(), 0);
+    assert_eq!(r["skipped"].as_usize().unwrap(), 2);
+    assert_eq!(r["pending"].as_usize().unwrap(), 2);
+    assert_eq!(data.read().await.sent_txns.len(), 1);
+    assert_eq!(
+        batch::read_results(&fs::read_to_string(&results_path).unwrap()).unwrap(),
+        results
+    );
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn unified_addresses() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. A new unified address bundles a new z address and a new t address
+    let ua = lc.do_new_address("u", "").await.unwrap()[0]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(ua.starts_with("u1"));
+
+    let addresses = lc.do_address().await;
+    let zaddr = addresses["z_addresses"][1].as_str().unwrap().to_string();
+    let taddr = addresses["t_addresses"][1].as_str().unwrap().to_string();
+    match unified::decode_recipient(&config.get_params(), &ua) {
+        Ok(RecipientAddress::Shielded(pa)) => {
+            assert_eq!(encode_payment_address(config.hrp_sapling_address(), &pa), zaddr)
+        }
+        _ => panic!("Expected a sapling receiver"),
+    }
+    // It has no Orchard receiver, since Orchard notes sent to it would never be seen by the wallet
+    let items = unified::decode("u", &ua).unwrap();
+    assert_eq!(items.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0, 2]);
+    let t_receiver = items[0].1.clone();
+    match RecipientAddress::decode(&config.get_params(), &taddr) {
+        Some(RecipientAddress::Transparent(TransparentAddress::PublicKey(hash))) => assert_eq!(t_receiver, hash),
+        _ => panic!("Expected a t address"),
+    }
+
+    // The unified address is kept with the keys, so it's listed and can be used in payment requests
+    assert_eq!(addresses["u_addresses"].len(), 1);
+    assert_eq!(addresses["u_addresses"][0], ua);
+    let uri = lc.do_payment_request(&ua, 1_000, None, None).await.unwrap();
+    assert!(uri.contains(&ua));
+
+    // 2. Every z address has a UFVK, and only the first one has the transparent account key
+    let keys = lc.do_export(None).await.unwrap();
+    let ufvk0 = unified::decode("uview", keys[0]["ufvk"].as_str().unwrap()).unwrap();
+    let ufvk1 = unified::decode("uview", keys[1]["ufvk"].as_str().unwrap()).unwrap();
+    assert_eq!(ufvk0.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![0, 2]);
+    assert_eq!(ufvk0[0].1.len(), 65);
+    assert_eq!(ufvk1.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![2]);
+
+    // 3. Receive a note, and send to a unified address. The sapling receiver is used
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    let ext_zaddr = match RecipientAddress::decode(&config.get_params(), EXT_ZADDR) {
+        Some(RecipientAddress::Shielded(pa)) => pa,
+        _ => panic!("Expected a z address"),
+    };
+    let ext_taddr = match RecipientAddress::decode(&config.get_params(), EXT_TADDR) {
+        Some(RecipientAddress::Transparent(ta)) => ta,
+        _ => panic!("Expected a t address"),
+    };
+    let ext_ua = unified::encode_address(&config.get_params(), Some(&ext_zaddr), Some(&ext_taddr)).unwrap();
+
+    let sent_value = 20_000;
+    let (sent_txid, _) = lc
+        .test_do_send(vec![(ext_ua.as_str(), sent_value, Some("Hello UA".to_string()))])
+        .await
+        .unwrap();
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let list = lc.do_list_transactions(false).await;
+    let sent = list.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert_eq!(sent["outgoing_metadata"][0]["address"], EXT_ZADDR.to_string());
+    assert_eq!(sent["outgoing_metadata"][0]["memo"], "Hello UA");
+
+    // 4. Without a Sapling receiver, the transparent receiver is paid
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    let ext_t_receiver = match ext_taddr {
+        TransparentAddress::PublicKey(hash) => hash.to_vec(),
+        TransparentAddress::Script(hash) => hash.to_vec(),
+    };
+    let orchard_ua = unified::encode("u", &[(0, ext_t_receiver), (3, vec![1u8; 43])]);
+    let (sent_txid, _) = lc
+        .test_do_send(vec![(orchard_ua.as_str(), sent_value, None)])
+        .await
+        .unwrap();
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let list = lc.do_list_transactions(false).await;
+    let sent = list.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert_eq!(sent["outgoing_metadata"][0]["address"], EXT_TADDR.to_string());
+
+    // 5. A corrupted unified address is rejected
+    let mut bad_ua = ext_ua.clone();
+    bad_ua.pop();
+    assert!(lc
+        .test_do_send(vec![(bad_ua.as_str(), sent_value, None)])
+        .await
+        .is_err());
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn diversified_change_address() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive a note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zaddr1 = encode_payment_address(config.hrp_sapling_address(), &extfvk1.default_address().1);
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 2. By default, the change goes back to the spent note's address
+    let sent_value = 20_000;
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    assert_eq!(proposal["change_address"], zaddr1);
+
+    // 3. With the diversified option, every send gets a new change address of the same key
+    lc.wallet.set_change_address(ChangeAddressOption::Diversified).await;
+    let proposal = lc
+        .do_propose_send(vec![(EXT_ZADDR, sent_value, None)], &[], None)
+        .await
+        .unwrap();
+    let change_address = proposal["change_address"].as_str().unwrap().to_string();
+    assert_ne!(change_address, zaddr1);
+
+    let (_, fees) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    // 4. The change note is detected at a diversified address, marked as change, and is spendable
+    let change_value = zvalue - sent_value - u64::from(fees);
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["unspent_notes"].len(), 1);
+    let change_note = &notes["unspent_notes"][0];
+    assert_eq!(change_note["value"].as_u64().unwrap(), change_value);
+    assert_eq!(change_note["is_change"].as_bool().unwrap(), true);
+    assert_ne!(change_note["address"], zaddr1);
+    assert_eq!(lc.wallet.zbalance(None).await, change_value);
+
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    let notes = lc.do_list_notes(true).await;
+    assert_eq!(notes["unspent_notes"][0]["spendable"].as_bool().unwrap(), true);
+    assert_eq!(lc.wallet.spendable_zbalance(None).await, change_value);
+
+    // 5. The change addresses are recorded with the key, so they are listed with its diversified addresses
+    let change_note_address = notes["unspent_notes"][0]["address"].as_str().unwrap().to_string();
+    let diversified = lc.wallet.keys().read().await.get_all_diversified_zaddresses().await;
+    for address in [&change_address, &change_note_address] {
+        let (_, key_address, _) = diversified.iter().find(|(a, _, _)| a == address).unwrap();
+        assert_eq!(key_address, &zaddr1);
+    }
+
+    // 6. With --from, the change also goes to a new diversified address of the from address's key
+    let branch_id = lc.consensus_branch_id().await;
+    let proposal = lc
+        .wallet
+        .create_proposal(
+            branch_id,
+            false,
+            true,
+            vec![(EXT_ZADDR, 1_000, None)],
+            &[change_note_address.clone()],
+            None,
+            true,
+        )
+        .await
+        .unwrap();
+    let change_pa = proposal.change_address;
+    let from_change_address = encode_payment_address(config.hrp_sapling_address(), &change_pa);
+    assert_ne!(from_change_address, change_note_address);
+    assert_ne!(from_change_address, zaddr1);
+    assert_eq!(
+        extfvk1.fvk.vk.ivk().to_payment_address(*change_pa.diversifier()),
+        Some(change_pa.clone())
+    );
+    assert!(lc.wallet.keys().read().await.is_change_address(&change_pa).await);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn diversified_addresses() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zaddr1 = encode_payment_address(config.hrp_sapling_address(), &extfvk1.default_address().1);
+
+    // 2. Create 2 diversified addresses. The second one is created from the first one, but both belong to the same key
+    let d1 = lc.do_new_diversified_address(&zaddr1).await.unwrap();
+    let d2 = lc
+        .do_new_diversified_address(d1["address"].as_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(d1["diversified_from"], zaddr1);
+    assert_eq!(d2["diversified_from"], zaddr1);
+    assert_ne!(d1["address"], zaddr1);
+    assert_ne!(d1["address"], d2["address"]);
+    assert!(d2["diversifier_index"].as_u64().unwrap() > d1["diversifier_index"].as_u64().unwrap());
+
+    // No new keys were created, and addresses of other wallets are rejected
+    assert_eq!(lc.wallet.in_memory_keys().await.unwrap().get_all_extfvks().len(), 1);
+    assert!(lc.do_new_diversified_address(EXT_ZADDR).await.is_err());
+    assert!(lc.do_new_diversified_address(EXT_TADDR).await.is_err());
+
+    // 3. Pay both addresses
+    let mut ftx = FakeTransaction::new();
+    for (d, value) in [(&d1, 10_000), (&d2, 20_000)] {
+        let to = decode_payment_address(config.hrp_sapling_address(), d["address"].as_str().unwrap())
+            .unwrap()
+            .unwrap();
+        ftx.add_tx_paying_address(&to, value);
+    }
+    fcbl.add_ftx(ftx);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    // 4. Both notes are detected, and the list says which diversified address received each of them
+    assert_eq!(lc.wallet.zbalance(None).await, 30_000);
+
+    let list = lc.do_list_transactions(false).await;
+    assert_eq!(list.len(), 2);
+    for d in [&d1, &d2] {
+        let tx = list.members().find(|t| t["address"] == d["address"]).unwrap();
+        assert_eq!(tx["diversified_from"], zaddr1);
+        assert_eq!(tx["diversifier_index"], d["diversifier_index"]);
+    }
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn address_book() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, and receive a note
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    fcbl.add_tx_paying(&extfvk1, 100_000);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+    // 2. Add some labels. Bad labels and addresses are rejected
+    lc.do_address_book_add("shop", EXT_ZADDR).await.unwrap();
+    lc.do_address_book_add("exchange", EXT_TADDR).await.unwrap();
+    assert!(lc.do_address_book_add("bad label", EXT_ZADDR).await.is_err());
+    assert!(lc.do_address_book_add("", EXT_ZADDR).await.is_err());
+    assert!(lc.do_address_book_add(EXT_TADDR, EXT_ZADDR).await.is_err());
+    assert!(lc.do_address_book_add("nowhere", "zs1notanaddress").await.is_err());
+
+    let list = lc.do_address_book_list().await;
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0]["label"], "exchange");
+    assert_eq!(list[1]["label"], "shop");
+    assert_eq!(list[1]["address"], EXT_ZADDR);
+
+    // 3. Labels resolve to their address, and anything else is passed through
+    assert_eq!(lc.wallet.resolve_address("shop").await, EXT_ZADDR);
+    assert_eq!(lc.wallet.resolve_address(EXT_TADDR).await, EXT_TADDR);
+    assert_eq!(lc.wallet.resolve_address("unknown").await, "unknown");
+
+    // 4. Pay the label, and the list shows it next to the address
+    let address = lc.wallet.resolve_address("shop").await;
+    let (sent_txid, _) = lc.test_do_send(vec![(address.as_str(), 20_000, None)]).await.unwrap();
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let list = lc.do_list_transactions(false).await;
+    let sent = list.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert_eq!(sent["outgoing_metadata"][0]["address"], EXT_ZADDR);
+    assert_eq!(sent["outgoing_metadata"][0]["label"], "shop");
+
+    // 5. The address book is saved with the wallet
+    let mut buf = vec![];
+    lc.wallet.write(&mut buf).await.unwrap();
+    let wallet = LightWallet::read(&buf[..], &config).await.unwrap();
+    assert_eq!(*wallet.address_book.read().await, *lc.wallet.address_book.read().await);
+
+    // 6. Remove a label
+    assert_eq!(lc.do_address_book_remove("shop").await.unwrap()["address"], EXT_ZADDR);
+    assert!(lc.do_address_book_remove("shop").await.is_err());
+    assert_eq!(lc.do_address_book_list().await.len(), 1);
+
+    let list = lc.do_list_transactions(false).await;
+    let sent = list.members().find(|t| t["txid"] == sent_txid).unwrap();
+    assert!(sent["outgoing_metadata"][0]["label"].is_null());
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn block_cache_rescan() {
+    let (data, mut config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    config.block_cache_size = Some(10 * 1024 * 1024);
+
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks, receive a note, and mine 150 more blocks. The blocks are cached as they are synced, and each
+    // of them is only downloaded once, even though the cache checks the blocks near the tip when the tip moves
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 150).await;
+
+    assert_eq!(data.read().await.blocks_served, 161);
+    assert_eq!(
+        lc.do_block_cache_status().await.unwrap()["blocks"].as_u64().unwrap(),
+        161
+    );
+
+    // 2. A rescan reads all the blocks, including the ones that could still be reorged, and the tree states from the
+    // cache
+    let served = data.read().await.blocks_served;
+    let trees_served = data.read().await.tree_states_served;
+    lc.do_rescan().await.unwrap();
+    assert_eq!(data.read().await.blocks_served - served, 0);
+    assert_eq!(data.read().await.tree_states_served - trees_served, 0);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue);
+
+    // 3. Another key gets a note, and is then imported. The rescan for it doesn't download anything either, the tree
+    // state for its note is built from the cached blocks
+    let iextfvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[7u8; 32]));
+    let ivalue = 200_000;
+    fcbl.add_tx_paying(&iextfvk, ivalue);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+    assert_eq!(data.read().await.blocks_served, 167);
+
+    lc.do_import_vk(
+        encode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), &iextfvk),
+        1,
+    )
+    .await
+    .unwrap();
+
+    let served = data.read().await.blocks_served;
+    let trees_served = data.read().await.tree_states_served;
+    lc.do_rescan().await.unwrap();
+    assert_eq!(data.read().await.blocks_served - served, 0);
+    assert_eq!(data.read().await.tree_states_served - trees_served, 0);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue + ivalue);
+
+    // 4. Clear the cache, and everything is downloaded again
+    let cleared = lc.do_block_cache_clear().await.unwrap();
+    assert_eq!(cleared["blocks_removed"].as_u64().unwrap(), 167);
+    assert_eq!(lc.do_block_cache_status().await.unwrap()["blocks"].as_u64().unwrap(), 0);
+
+    let served = data.read().await.blocks_served;
+    lc.do_rescan().await.unwrap();
+    assert_eq!(data.read().await.blocks_served - served, 167);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue + ivalue);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn resume_interrupted_sync() {
+    let (data, mut config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    // Save the wallet every 10 blocks
+    config.sync_save_interval = 10;
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+
+    // 2. Put an incoming tx and 34 more blocks on the server, without syncing them
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    let (_ztx, height, _) = fcbl.add_tx_paying(&extfvk1, zvalue);
+    assert_eq!(height, 11);
+    fcbl.add_blocks(34);
+
+    data.write().await.add_blocks(fcbl.into_compact_blocks());
+    data.write().await.add_txns(fcbl.into_txns());
+
+    // 3. The server stops serving blocks above 30, so the sync fails in the middle
+    data.write().await.unavailable_above = Some(30);
+    assert!(lc.do_sync(true).await.is_err());
+
+    // 4. The wallet on disk has the progress up to the last save
+    let wallet = LightWallet::read(File::open(config.get_wallet_path()).unwrap(), &config)
+        .await
+        .unwrap();
+    assert_eq!(wallet.last_scanned_height().await, 30);
+    assert_eq!(wallet.zbalance(None).await, zvalue);
+
+    // 5. Restart from the saved wallet, and the sync only fetches the remaining blocks
+    data.write().await.unavailable_above = None;
+    let lc = LightClient {
+        wallet,
+        config: config.clone(),
+        mempool_monitor: std::sync::RwLock::new(None),
+        sync_lock: tokio::sync::Mutex::new(()),
+        bsync_data: Arc::new(tokio::sync::RwLock::new(BlazeSyncData::new(&config))),
+    };
+
+    let served = data.read().await.blocks_served;
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(data.read().await.blocks_served - served, 15);
+    assert_eq!(lc.wallet.last_scanned_height().await, 45);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn cancel_sync() {
+    let (data, mut config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    config.sync_save_interval = 10;
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    assert!(lc.cancel_sync().await.is_err());
+    assert!(lc.pause_sync().await.is_err());
+
+    // 2. Put an incoming tx and 39 more blocks on the server, without syncing them
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    fcbl.add_tx_paying(&extfvk1, zvalue);
+    fcbl.add_blocks(39);
+
+    data.write().await.add_blocks(fcbl.into_compact_blocks());
+    data.write().await.add_txns(fcbl.into_txns());
+
+    // 3. Pause the sync as soon as it starts, make sure it doesn't make progress, and then cancel it
+    let prev_sync_id = lc.do_sync_status().await.sync_id;
+    let (sync_result, _) = join!(lc.do_sync(true), async {
+        while !(lc.do_sync_status().await.in_progress && lc.do_sync_status().await.sync_id > prev_sync_id) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        lc.pause_sync().await.unwrap();
+        assert!(lc.pause_sync().await.is_err());
+
+        sleep(Duration::from_millis(500)).await;
+        let paused_height = lc.wallet.last_scanned_height().await;
+        sleep(Duration::from_millis(1000)).await;
+        assert_eq!(lc.wallet.last_scanned_height().await, paused_height);
+
+        lc.cancel_sync().await.unwrap();
+        assert!(lc.resume_sync().await.is_err());
+    });
+    assert_eq!(sync_result.unwrap_err(), SYNC_CANCELLED);
+
+    // 4. The wallet stopped at the end of a batch, without anything from the cancelled batch
+    let height = lc.wallet.last_scanned_height().await;
+    assert!(height < 50);
+    assert_eq!(height % 10, 0);
+    assert!(lc
+        .wallet
+        .txns
+        .read()
+        .await
+        .current
+        .values()
+        .all(|wtx| u64::from(wtx.block) <= height));
+    assert_eq!(lc.wallet.zbalance(None).await, if height > 10 { zvalue } else { 0 });
+
+    // ...and that's what was saved
+    let wallet = LightWallet::read(File::open(config.get_wallet_path()).unwrap(), &config)
+        .await
+        .unwrap();
+    assert_eq!(wallet.last_scanned_height().await, height);
+    assert_eq!(wallet.zbalance(None).await, lc.wallet.zbalance(None).await);
+
+    // 5. The next sync picks up from there
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 50);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn wallet_events() {
+    let (data, mut config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    config.sync_save_interval = 5;
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut events = lc.subscribe();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    fn drain(events: &mut broadcast::Receiver<WalletEvent>) -> Vec<WalletEvent> {
+        let mut v = vec![];
+        while let Ok(e) = events.try_recv() {
+            v.push(e);
+        }
+        v
+    }
+
+    // 1. Mine 10 blocks, which are synced in 2 batches
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    let batches = drain(&mut events)
+        .into_iter()
+        .filter_map(|e| match e {
+            WalletEvent::SyncBatchStarted {
+                batch_num,
+                batch_total,
+                start_block,
+                end_block,
+            } => Some((true, batch_num, batch_total, start_block, end_block)),
+            WalletEvent::SyncBatchFinished {
+                batch_num,
+                batch_total,
+                height,
+            } => Some((false, batch_num, batch_total, 0, height)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        batches,
+        vec![
+            (true, 0, 2, 1, 5),
+            (false, 0, 2, 0, 5),
+            (true, 1, 2, 6, 10),
+            (false, 1, 2, 0, 10)
+        ]
+    );
+
+    // 2. A mined incoming note
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let value = 100_000;
+    let (tx, _height, _) = fcbl.add_tx_paying(&extfvk1, value);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let notes = drain(&mut events)
+        .into_iter()
+        .filter_map(|e| match e {
+            WalletEvent::NewIncomingNote {
+                txid,
+                height,
+                value,
+                is_change,
+                mempool,
+            } => Some((txid, height, value, is_change, mempool)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(notes, vec![(tx.txid(), 11, value, false, false)]);
+
+    // 3. Sending goes through the steps, and the change shows up in the mempool
+    let sent_value = 20_000;
+    let (sent_txid, fees) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+    let change = value - sent_value - u64::from(fees);
+
+    let sent = drain(&mut events);
+    let steps = sent
+        .iter()
+        .filter_map(|e| match e {
+            WalletEvent::SendProgress { step, .. } => match step {
+                SendStep::BuildingTransaction { .. } => None,
+                _ => Some(step.clone()),
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        steps,
+        vec![
+            SendStep::SelectingNotes,
+            SendStep::Broadcasting,
+            SendStep::Finished {
+                txid: sent_txid.clone()
+            }
+        ]
+    );
+    assert!(sent.iter().any(|e| match e {
+        WalletEvent::NewIncomingNote {
+            txid,
+            value,
+            is_change,
+            mempool,
+            ..
+        } => txid.to_string() == sent_txid && *value == change && *is_change && *mempool,
+        _ => false,
+    }));
+
+    // 4. Once it is mined, the change is confirmed
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+    assert!(drain(&mut events).iter().any(|e| match e {
+        WalletEvent::NoteConfirmed { txid, height, value } =>
+            txid.to_string() == sent_txid && *height == 12 && *value == change,
+        _ => false,
+    }));
+
+    // 5. A reorg of the top block
+    BlockAndWitnessData::invalidate_block(12, lc.wallet.blocks.clone(), lc.wallet.txns()).await;
+    assert!(drain(&mut events)
+        .iter()
+        .any(|e| matches!(e, WalletEvent::ReorgDetected { height: 12 })));
+
+    // 6. A new price
+    lc.wallet.set_latest_zec_price(140.5).await;
+    assert!(drain(&mut events).iter().any(|e| match e {
+        WalletEvent::PriceUpdated { currency, price } => currency == "USD" && *price == 140.5,
+        _ => false,
+    }));
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn server_failover() {
+    let (data1, mut config, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+    let (data3, config3, ready_rx3, stop_tx3, h3) = create_test_server(UnitTestNetwork).await;
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+    ready_rx3.await.unwrap();
+
+    // The third server is on another chain
+    data3.write().await.config.chain_name = "other".to_string();
+
+    // 1. The first server can't be connected to, and the second one can't serve blocks
+    let dead: http::Uri = format!("http://127.0.0.1:{}", portpicker::pick_unused_port().unwrap())
+        .parse()
+        .unwrap();
+    let uri1 = config.servers.current();
+    let uri2 = config2.servers.current();
+    let uri3 = config3.servers.current();
+    config.servers = ServerList::for_chain(vec![dead, uri1.clone(), uri2], &config.chain_name);
+
+    let mut fcbl = FakeCompactBlockList::new(0);
+    let cbs = fcbl.add_blocks(10).into_compact_blocks();
+    data1.write().await.add_blocks(cbs.clone());
+    data2.write().await.add_blocks(cbs);
+    data1.write().await.unavailable_above = Some(0);
+
+    // 2. The sync fails over to the last server for the blocks
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+    assert_eq!(data1.read().await.blocks_served, 0);
+    assert_eq!(data2.read().await.blocks_served, 10);
+
+    let status = config.servers.status();
+    assert!(status[0]["failures"].as_u32().unwrap() > 0);
+    assert!(!status[1]["last_error"].is_null());
+    assert_eq!(status[2]["failures"].as_u32().unwrap(), 0);
+
+    // 3. A server on another chain is never failed over to
+    config.servers = ServerList::for_chain(vec![uri1.clone(), uri3.clone()], &config.chain_name);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    assert!(lc.do_sync(true).await.is_err());
+    assert_eq!(lc.wallet.last_scanned_height().await, 0);
+
+    let status = config.servers.status();
+    assert_eq!(status[0]["same_chain"].as_bool(), Some(true));
+    assert_eq!(status[1]["same_chain"].as_bool(), Some(false));
+    assert_eq!(data3.read().await.blocks_served, 0);
+
+    // 4. Even when it is the first server checked
+    data1.write().await.unavailable_above = None;
+    config.servers = ServerList::for_chain(vec![uri3, uri1], &config.chain_name);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+    assert_eq!(data3.read().await.blocks_served, 0);
+
+    let status = config.servers.status();
+    assert_eq!(status[0]["same_chain"].as_bool(), Some(false));
+    assert_eq!(status[1]["same_chain"].as_bool(), Some(true));
+
+    // Shutdown everything cleanly
+    stop_tx1.send(true).unwrap();
+    stop_tx2.send(true).unwrap();
+    stop_tx3.send(true).unwrap();
+    h1.await.unwrap();
+    h2.await.unwrap();
+    h3.await.unwrap();
+}
+
+#[tokio::test]
+async fn cross_check_servers() {
+    let (data1, mut config, ready_rx1, stop_tx1, h1) = create_test_server(UnitTestNetwork).await;
+    let (data2, config2, ready_rx2, stop_tx2, h2) = create_test_server(UnitTestNetwork).await;
+    let (data3, config3, ready_rx3, stop_tx3, h3) = create_test_server(UnitTestNetwork).await;
+    ready_rx1.await.unwrap();
+    ready_rx2.await.unwrap();
+    ready_rx3.await.unwrap();
+
+    // 1. The first two servers are on the same chain, the third one has different blocks
+    let mut fcbl = FakeCompactBlockList::new(0);
+    let cbs = fcbl.add_blocks(150).into_compact_blocks();
+    data1.write().await.add_blocks(cbs.clone());
+    data2.write().await.add_blocks(cbs);
+
+    let mut other = FakeCompactBlockList::new(0);
+    data3
+        .write()
+        .await
+        .add_blocks(other.add_blocks(150).into_compact_blocks());
+
+    let uri1 = config.servers.current();
+    let uri2 = config2.servers.current();
+    let uri3 = config3.servers.current();
+
+    // 2. Servers that agree are synced from
+    config.cross_check_servers = 2;
+    config.servers = ServerList::for_chain(vec![uri1.clone(), uri2], &config.chain_name);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 150);
+
+    // 3. Servers that disagree below the reorg window are not
+    config.servers = ServerList::for_chain(vec![uri1.clone(), uri3], &config.chain_name);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let e = lc.do_sync(true).await.unwrap_err();
+    assert!(e.contains("disagree"));
+    assert_eq!(lc.wallet.last_scanned_height().await, 0);
+
+    // 4. And there have to be enough servers to cross-check with
+    config.servers = ServerList::for_chain(vec![uri1], &config.chain_name);
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    assert!(lc.do_sync(true).await.is_err());
+
+    // Shutdown everything cleanly
+    stop_tx1.send(true).unwrap();
+    stop_tx2.send(true).unwrap();
+    stop_tx3.send(true).unwrap();
+    h1.await.unwrap();
+    h2.await.unwrap();
+    h3.await.unwrap();
+}
+
+#[tokio::test]
+async fn inconsistent_chain() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 10 blocks
+    mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+
+    // 2. Put an incoming tx at 15 on the server, between 10 more blocks, without syncing them
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+    let zvalue = 100_000;
+    fcbl.add_blocks(4);
+    let (_ztx, height, _) = fcbl.add_tx_paying(&extfvk1, zvalue);
+    assert_eq!(height, 15);
+    fcbl.add_blocks(6);
+
+    data.write().await.add_blocks(fcbl.into_compact_blocks());
+    data.write().await.add_txns(fcbl.into_txns());
+
+    // 3. Block 12 doesn't point to block 11, which is not a reorg, so the sync fails
+    data.write().await.broken_links = vec![12];
+    let e = lc.do_sync(true).await.unwrap_err();
+    assert!(e.starts_with(INCONSISTENT_CHAIN));
+    assert!(e.contains("block 11 doesn't link to block 12"));
+
+    // 4. And nothing from the blocks that were scanned is kept
+    assert_eq!(lc.wallet.last_scanned_height().await, 10);
+    assert_eq!(lc.wallet.zbalance(None).await, 0);
+    assert_eq!(lc.do_list_transactions(false).await.len(), 0);
+
+    // 5. Once the server serves a proper chain, the sync picks up the tx
+    data.write().await.broken_links = vec![];
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 21);
+    assert_eq!(lc.wallet.zbalance(None).await, zvalue);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn sync_memory_budget() {
+    let (data, mut config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    // Saving doesn't limit the batches, only the memory budget does, which is too small for any batch
+    config.sync_save_interval = 0;
+    config.sync_memory_budget = 1;
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut events = lc.subscribe();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. The batches are kept to the smallest size
+    mine_random_blocks(&mut fcbl, &data, &lc, 210).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 210);
+
+    let mut batches = vec![];
+    while let Ok(e) = events.try_recv() {
+        if let WalletEvent::SyncBatchStarted {
+            batch_num,
+            batch_total,
+            start_block,
+            end_block,
+        } = e
+        {
+            batches.push((batch_num, batch_total, start_block, end_block));
+        }
+    }
+    assert_eq!(batches, vec![(0, 3, 1, 100), (1, 3, 101, 200), (2, 3, 201, 210)]);
+
+    // 2. The size of the blocks was measured along the way
+    let status = lc.do_sync_status().await;
+    assert_eq!(status.blocks_done, 10);
+    assert!(status.blocks_bytes > 0);
+    assert_eq!(status.blocks_outputs, 10 * 2 * 2);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn deep_reorg() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    let extfvk1 = lc
+        .wallet
+        .in_memory_keys()
+        .await
+        .expect("in memory keystore")
+        .get_all_extfvks()[0]
+        .clone();
+
+    // 1. Receive a note at 51, which is far below the reorg, and one at 151, which is reorged out later
+    mine_random_blocks(&mut fcbl, &data, &lc, 50).await;
+    let (_tx, height, _) = fcbl.add_tx_paying(&extfvk1, 100_000);
+    assert_eq!(height, 51);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    mine_random_blocks(&mut fcbl, &data, &lc, 99).await;
+    let (_tx, height, _) = fcbl.add_tx_paying(&extfvk1, 20_000);
+    assert_eq!(height, 151);
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    mine_random_blocks(&mut fcbl, &data, &lc, 149).await;
+    assert_eq!(lc.wallet.last_scanned_height().await, 300);
+    assert_eq!(lc.wallet.zbalance(None).await, 120_000);
+
+    // 2. The server switches to a chain that forks at 150, which is below all the blocks the wallet keeps, and has
+    // another note at 160
+    data.write().await.blocks.retain(|b| b.height <= 150);
+    fcbl.next_height = 151;
+    fcbl.prev_hash = data.read().await.blocks.first().unwrap().hash();
+
+    fcbl.add_blocks(9);
+    let (_tx, height, _) = fcbl.add_tx_paying(&extfvk1, 3_000);
+    assert_eq!(height, 160);
+    fcbl.add_blocks(150);
+    data.write().await.add_blocks(fcbl.into_compact_blocks());
+    data.write().await.add_txns(fcbl.into_txns());
+
+    // 3. The sync rolls back MAX_REORG blocks below the blocks the wallet keeps, and syncs from there
+    let mut events = lc.subscribe();
+    lc.do_sync(true).await.unwrap();
+    assert_eq!(lc.wallet.last_scanned_height().await, 310);
+
+    let mut batches = vec![];
+    let mut reorged = vec![];
+    while let Ok(e) = events.try_recv() {
+        match e {
+            WalletEvent::SyncBatchStarted {
+                batch_num,
+                batch_total,
+                start_block,
+                end_block,
+            } => batches.push((batch_num, batch_total, start_block, end_block)),
+            WalletEvent::ReorgDetected { height } => reorged.push(height),
+            _ => {}
+        }
+    }
+    assert_eq!(batches, vec![(0, 1, 301, 310), (1, 2, 101, 310)]);
+    assert_eq!(reorged, (201..=300).rev().collect::<Vec<_>>());
+
+    // 4. The note from the old chain is gone, the tx at 51 is still there, and the notes on both sides of the rollback
+    // have witnesses to spend them with
+    let b = lc.do_balance().await;
+    assert_eq!(b["zbalance"].as_u64().unwrap(), 103_000);
+    assert_eq!(b["spendable_zbalance"].as_u64().unwrap(), 103_000);
+    let heights = lc
+        .do_list_transactions(false)
+        .await
+        .members()
+        .map(|t| t["block_height"].as_u64().unwrap())
+        .collect::<HashSet<_>>();
+    assert_eq!(heights, HashSet::from([51, 160]));
+
+    // The witness of the note at 51 was rewound and brought up to date along the new chain
+    let tree_state = GrpcConnector::get_sapling_tree(config.servers.clone(), 310)
+        .await
+        .unwrap();
+    let tree = CommitmentTree::<Node>::read(&hex::decode(tree_state.tree).unwrap()[..]).unwrap();
+    let root = lc
+        .wallet
+        .txns()
+        .read()
+        .await
+        .current
+        .values()
+        .find(|wtx| u64::from(wtx.block) == 51)
+        .unwrap()
+        .notes[0]
+        .witnesses
+        .last()
+        .unwrap()
+        .root();
+    assert_eq!(root, tree.root());
+
+    // 5. The note at 51 can be spent
+    let sent_value = 50_000;
+    let (_sent_txid, fees) = lc.test_do_send(vec![(EXT_ZADDR, sent_value, None)]).await.unwrap();
+    fcbl.add_pending_sends(&data).await;
+    mine_pending_blocks(&mut fcbl, &data, &lc).await;
+
+    let b = lc.do_balance().await;
+    assert_eq!(b["zbalance"].as_u64().unwrap(), 103_000 - sent_value - u64::from(fees));
+    let note_51_spent = lc
+        .wallet
+        .txns()
+        .read()
+        .await
+        .current
+        .values()
+        .find(|wtx| u64::from(wtx.block) == 51)
+        .unwrap()
+        .notes[0]
+        .spent
+        .is_some();
+    assert!(note_51_spent);
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[tokio::test]
+async fn checkpoint_export() {
+    let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+    ready_rx.await.unwrap();
+
+    let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+    let mut fcbl = FakeCompactBlockList::new(0);
+
+    // 1. Mine 150 blocks, so the wallet keeps blocks 51-150
+    mine_random_blocks(&mut fcbl, &data, &lc, 150).await;
+
+    // 2. Export the tree at 120, which is the server's tree there
+    let dir = TempDir::new("checkpoints").unwrap();
+    let path = dir.path().join("checkpoint.json");
+    let j = lc.do_checkpoint_export(120, Some(path.clone())).await.unwrap();
+    assert_eq!(j["height"].as_u64().unwrap(), 120);
+
+    let exported = checkpoints::read_checkpoints(&path).unwrap();
+    let tree_state = GrpcConnector::get_sapling_tree(config.servers.clone(), 120)
+        .await
+        .unwrap();
+    assert_eq!(exported, vec![(120, tree_state.hash.clone(), tree_state.tree.clone())]);
+
+    // 3. Only the blocks the wallet keeps can be exported
+    let e = lc.do_checkpoint_export(20, Some(path.clone())).await.unwrap_err();
+    assert!(e.contains("from 51 to 150"));
+
+    // 4. A tree that doesn't add up to the tree at the top of the chain is refused
+    let prev_tree = GrpcConnector::get_sapling_tree(config.servers.clone(), 119)
+        .await
+        .unwrap();
+    data.write()
+        .await
+        .tree_states
+        .push((120, tree_state.hash.clone(), prev_tree.tree.clone()));
+    let e = lc.do_checkpoint_export(120, None).await.unwrap_err();
+    assert!(e.contains("doesn't add up"));
+    data.write().await.tree_states.clear();
+
+    // 5. Checkpoints that are loaded are checked against the server, so only the exported one is kept
+    let wrong_height = (121, tree_state.hash.clone(), tree_state.tree.clone());
+    let above_tip = (200, tree_state.hash.clone(), tree_state.tree.clone());
+    let valid = checkpoints::validate_checkpoints(
+        config.servers.clone(),
+        vec![exported[0].clone(), wrong_height, above_tip],
+    )
+    .await
+    .unwrap();
+    assert_eq!(valid, exported);
+
+    // 6. And it is the closest checkpoint to the blocks above it
+    assert_eq!(
+        checkpoints::get_closest_checkpoint(&config.chain_name, 130, &valid).unwrap(),
+        exported[0]
+    );
+
+    // Shutdown everything cleanly
+    stop_tx.send(true).unwrap();
+    h1.await.unwrap();
+}
+
+#[test]
+fn unchecked_checkpoints() {
+    let dir = TempDir::new("checkpoints").unwrap();
+    let mut config =
+        LightClientConfig::create_unconnected(UnitTestNetwork, Some(dir.path().to_str().unwrap().to_string()));
+
+    // 1. The only server can't be connected to
+    let dead: http::Uri = format!("http://127.0.0.1:{}", portpicker::pick_unused_port().unwrap())
+        .parse()
+        .unwrap();
+    config.servers = ServerList::for_chain(vec![dead], &config.chain_name);
+
+    let checkpoint = (130, hex::encode([0u8; 32]), "000000".to_string());
+    let path = dir.path().join("checkpoint.json");
+    fs::write(&path, checkpoints::checkpoints_to_json(&[checkpoint.clone()]).pretty(2)).unwrap();
+
+    // 2. So the checkpoints can't be checked, and aren't used
+    assert_eq!(config.load_checkpoints(Some(&path), false).unwrap(), 0);
+    assert!(checkpoints::get_closest_checkpoint(&config.chain_name, 140, &config.checkpoints).is_none());
+
+    // 3. Unless they are trusted explicitly
+    assert_eq!(config.load_checkpoints(Some(&path), true).unwrap(), 1);
+    assert_eq!(
+        checkpoints::get_closest_checkpoint(&config.chain_name, 140, &config.checkpoints).unwrap(),
+        checkpoint
+    );
+}
+
 pub const EXT_TADDR: &str = "t1NoS6ZgaUTpmjkge2cVpXGcySasdYDrXqh";
 pub const EXT_ZADDR: &str = "zs1va5902apnzlhdu0pw9r9q7ca8s4vnsrp2alr6xndt69jnepn2v2qrj9vg3wfcnjyks5pg65g9dc";
 pub const EXT_ZADDR2: &str = "zs1fxgluwznkzm52ux7jkf4st5znwzqay8zyz4cydnyegt2rh9uhr9458z0nk62fdsssx0cqhy6lyv";
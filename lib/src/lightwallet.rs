@@ -32,25 +32,40 @@ use zcash_primitives::{
     consensus::{self, BlockHeight, BranchId},
     legacy::Script,
     memo::{Memo, MemoBytes},
-    transaction::components::{Amount, OutPoint, TxOut},
+    sapling::{PaymentAddress, SaplingIvk},
+    transaction::{
+        components::{Amount, OutPoint, TxOut},
+        Transaction, TxId,
+    },
     zip32::ExtendedFullViewingKey,
 };
 
 use self::{
-    data::{BlockData, SaplingNoteData, Utxo, WalletZecPriceInfo},
+    data::{AddressBook, BlockData, SaplingNoteData, Utxo, WalletZecPriceInfo},
+    events::{SendStep, WalletEvent, WalletEvents},
     keys::{InMemoryKeys, Keystores, TxProver},
     message::Message,
+    note_selection::{CandidateNote, NoteSelectionStrategy},
+    proposal::{ProposalOutput, TxProposal},
+    unified,
     wallet_txns::WalletTxns,
+    zip321::MAX_MONEY,
 };
 
+pub(crate) mod batch;
 pub(crate) mod data;
+pub mod events;
 mod extended_key;
 pub(crate) mod keys;
 pub(crate) mod message;
+pub(crate) mod note_selection;
+pub(crate) mod proposal;
+pub(crate) mod unified;
 pub(crate) mod utils;
 pub(crate) mod wallet_txns;
 pub(crate) mod wallettkey;
 mod walletzkey;
+pub(crate) mod zip321;
 
 pub fn now() -> u64 {
     SystemTime::now()
@@ -104,10 +119,124 @@ pub enum MemoDownloadOption {
     AllMemos,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteSelectionOption {
+    LargestFirst = 0,
+    SmallestFirst,
+    OldestFirst,
+    Random,
+}
+
+impl NoteSelectionOption {
+    pub fn strategy(&self) -> Box<dyn NoteSelectionStrategy> {
+        match self {
+            NoteSelectionOption::LargestFirst => Box::new(note_selection::LargestFirst),
+            NoteSelectionOption::SmallestFirst => Box::new(note_selection::SmallestFirst),
+            NoteSelectionOption::OldestFirst => Box::new(note_selection::OldestFirst),
+            NoteSelectionOption::Random => Box::new(note_selection::RandomOrder),
+        }
+    }
+}
+
+/// Where the change of a transaction goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeAddressOption {
+    // Back to the address of the first spent note, or to the first z address if no notes are spent
+    SpentNoteAddress = 0,
+    // To a new diversified address of the spending key, so change can't be linked to the wallet's other addresses
+    Diversified,
+}
+
+// ZIP-317 fee parameters
+const MARGINAL_FEE: u64 = 5000;
+const GRACE_ACTIONS: usize = 2;
+
+/// The smallest ZIP-317 conventional fee, paid by any transaction with up to GRACE_ACTIONS logical actions
+const MIN_FEE: u64 = MARGINAL_FEE * GRACE_ACTIONS as u64;
+
+/// How the fee of a transaction is worked out from its ZIP-317 conventional fee
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePolicy {
+    // Pay the ZIP-317 conventional fee
+    Zip317,
+    // Always pay this many zats, regardless of the size of the transaction
+    Fixed(u64),
+    // Pay a multiple of the ZIP-317 conventional fee, to get mined sooner
+    Multiplier(u64),
+}
+
+impl FeePolicy {
+    /// The fee to pay for a transaction whose conventional fee is `conventional_fee`. Fails if the fee is
+    /// below the conventional fee, since such a transaction wouldn't be relayed, or above MAX_MONEY
+    pub fn fee(&self, conventional_fee: u64) -> Result<u64, String> {
+        match self {
+            FeePolicy::Zip317 => Ok(conventional_fee),
+            FeePolicy::Fixed(fee) if *fee < conventional_fee => Err(format!(
+                "Fixed fee of {} zats is below the ZIP-317 fee of {} zats for this transaction",
+                fee, conventional_fee
+            )),
+            FeePolicy::Fixed(fee) => Ok(*fee),
+            FeePolicy::Multiplier(m) => conventional_fee
+                .checked_mul(*m)
+                .filter(|fee| *fee <= MAX_MONEY)
+                .ok_or(format!("A fee of {} times {} zats is too large", m, conventional_fee)),
+        }
+    }
+
+    /// Parse "zip317", "fixed:<zats>" or "multiplier:<n>"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (kind, value) = match s.split_once(':') {
+            Some((kind, value)) => (kind, Some(value)),
+            None => (s, None),
+        };
+
+        let value = value.map(|v| {
+            v.parse::<u64>()
+                .map_err(|e| format!("Couldn't parse fee policy value '{}': {}", v, e))
+        });
+
+        match (kind, value) {
+            ("zip317", None) => Ok(FeePolicy::Zip317),
+            ("fixed", Some(fee)) => match fee? {
+                fee if fee < MIN_FEE => Err(format!(
+                    "Fixed fee needs to be at least the ZIP-317 minimum of {} zats",
+                    MIN_FEE
+                )),
+                fee if fee > MAX_MONEY => Err(format!("Fixed fee can't be more than {} zats", MAX_MONEY)),
+                fee => Ok(FeePolicy::Fixed(fee)),
+            },
+            ("multiplier", Some(m)) => match m? {
+                0 => Err("Fee multiplier needs to be at least 1".to_string()),
+                m if m > MAX_MONEY / MIN_FEE => {
+                    Err(format!("Fee multiplier can't be more than {}", MAX_MONEY / MIN_FEE))
+                }
+                m => Ok(FeePolicy::Multiplier(m)),
+            },
+            _ => Err(format!(
+                "Unknown fee policy '{}'. Expected zip317, fixed:<zats> or multiplier:<n>",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for FeePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeePolicy::Zip317 => write!(f, "zip317"),
+            FeePolicy::Fixed(fee) => write!(f, "fixed:{}", fee),
+            FeePolicy::Multiplier(m) => write!(f, "multiplier:{}", m),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct WalletOptions {
     pub(crate) download_memos: MemoDownloadOption,
     pub(crate) spam_threshold: i64,
+    pub(crate) note_selection: NoteSelectionOption,
+    pub(crate) fee_policy: FeePolicy,
+    pub(crate) change_address: ChangeAddressOption,
 }
 
 impl Default for WalletOptions {
@@ -115,13 +244,16 @@ impl Default for WalletOptions {
         WalletOptions {
             download_memos: MemoDownloadOption::WalletMemos,
             spam_threshold: -1,
+            note_selection: NoteSelectionOption::LargestFirst,
+            fee_policy: FeePolicy::Zip317,
+            change_address: ChangeAddressOption::SpentNoteAddress,
         }
     }
 }
 
 impl WalletOptions {
     pub fn serialized_version() -> u64 {
-        return 2;
+        return 5;
     }
 
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
@@ -145,9 +277,62 @@ impl WalletOptions {
             reader.read_i64::<LittleEndian>()?
         };
 
+        let note_selection = if version <= 2 {
+            NoteSelectionOption::LargestFirst
+        } else {
+            match reader.read_u8()? {
+                0 => NoteSelectionOption::LargestFirst,
+                1 => NoteSelectionOption::SmallestFirst,
+                2 => NoteSelectionOption::OldestFirst,
+                3 => NoteSelectionOption::Random,
+                v => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Bad note selection option {}", v),
+                    ));
+                }
+            }
+        };
+
+        let fee_policy = if version <= 3 {
+            FeePolicy::Zip317
+        } else {
+            let kind = reader.read_u8()?;
+            let value = reader.read_u64::<LittleEndian>()?;
+            match kind {
+                0 => FeePolicy::Zip317,
+                1 => FeePolicy::Fixed(value),
+                2 => FeePolicy::Multiplier(value),
+                v => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Bad fee policy {}", v),
+                    ));
+                }
+            }
+        };
+
+        let change_address = if version <= 4 {
+            ChangeAddressOption::SpentNoteAddress
+        } else {
+            match reader.read_u8()? {
+                0 => ChangeAddressOption::SpentNoteAddress,
+                1 => ChangeAddressOption::Diversified,
+                v => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Bad change address option {}", v),
+                    ));
+                }
+            }
+        };
+
         Ok(Self {
             download_memos,
             spam_threshold,
+            note_selection,
+            fee_policy,
+            change_address,
         })
     }
 
@@ -157,7 +342,19 @@ impl WalletOptions {
 
         writer.write_u8(self.download_memos as u8)?;
 
-        writer.write_i64::<LittleEndian>(self.spam_threshold)
+        writer.write_i64::<LittleEndian>(self.spam_threshold)?;
+
+        writer.write_u8(self.note_selection as u8)?;
+
+        let (kind, value) = match self.fee_policy {
+            FeePolicy::Zip317 => (0, 0),
+            FeePolicy::Fixed(fee) => (1, fee),
+            FeePolicy::Multiplier(m) => (2, m),
+        };
+        writer.write_u8(kind)?;
+        writer.write_u64::<LittleEndian>(value)?;
+
+        writer.write_u8(self.change_address as u8)
     }
 }
 
@@ -178,6 +375,9 @@ pub struct LightWallet<P> {
     // Wallet options
     pub(crate) wallet_options: Arc<RwLock<WalletOptions>>,
 
+    // Labels for addresses, which can be used in place of the address when sending
+    pub(crate) address_book: Arc<RwLock<AddressBook>>,
+
     // Non-serialized fields
     config: LightClientConfig<P>,
 
@@ -189,20 +389,28 @@ pub struct LightWallet<P> {
 
     // The current price of ZEC. (time_fetched, price in USD)
     pub price: Arc<RwLock<WalletZecPriceInfo>>,
+
+    // Events for anyone that subscribed to changes in the wallet
+    events: WalletEvents,
 }
 
 impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     pub fn with_keystore(config: LightClientConfig<P>, height: u64, keystore: impl Into<Keystores<P>>) -> Self {
+        let txns = WalletTxns::new();
+        let events = txns.events.clone();
+
         Self {
             keys: Arc::new(RwLock::new(keystore.into())),
-            txns: Default::default(),
+            txns: Arc::new(RwLock::new(txns)),
             blocks: Default::default(),
             wallet_options: Default::default(),
+            address_book: Default::default(),
             config,
             birthday: AtomicU64::new(height),
             verified_tree: Default::default(),
             send_progress: Arc::new(RwLock::new(SendProgress::new(0))),
             price: Default::default(),
+            events,
         }
     }
 
@@ -254,6 +462,51 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         self.wallet_options.write().await.spam_threshold = value;
     }
 
+    pub async fn set_note_selection(&self, value: NoteSelectionOption) {
+        self.wallet_options.write().await.note_selection = value;
+    }
+
+    pub async fn set_fee_policy(&self, value: FeePolicy) {
+        self.wallet_options.write().await.fee_policy = value;
+    }
+
+    pub async fn set_change_address(&self, value: ChangeAddressOption) {
+        self.wallet_options.write().await.change_address = value;
+    }
+
+    /// Add a label for `address` to the address book, or point an existing label to a new address
+    pub async fn add_address_book_entry(&self, label: &str, address: &str) -> Result<(), String> {
+        if unified::decode_recipient(&self.config.get_params(), address).is_err() {
+            return Err(format!("{} is not a valid address", address));
+        }
+
+        // Labels are used in place of addresses, so a label can't be an address itself
+        if unified::decode_recipient(&self.config.get_params(), label).is_ok() {
+            return Err(format!("Label {} can't be an address", label));
+        }
+
+        self.address_book.write().await.add(label, address)
+    }
+
+    /// Remove a label from the address book, returning the address it was for
+    pub async fn remove_address_book_entry(&self, label: &str) -> Result<String, String> {
+        self.address_book
+            .write()
+            .await
+            .remove(label)
+            .ok_or(format!("There's no address book entry with the label {}", label))
+    }
+
+    /// If `address` is an address book label, return the address for it. Otherwise, return `address` as is
+    pub async fn resolve_address(&self, address: &str) -> String {
+        self.address_book
+            .read()
+            .await
+            .get_address(address)
+            .cloned()
+            .unwrap_or(address.to_string())
+    }
+
     pub async fn get_birthday(&self) -> u64 {
         let birthday = self.birthday.load(std::sync::atomic::Ordering::SeqCst);
         if birthday == 0 {
@@ -269,8 +522,18 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             return;
         }
 
-        self.price.write().await.zec_price = Some((now(), price));
+        let currency = {
+            let mut p = self.price.write().await;
+            p.zec_price = Some((now(), price));
+            p.currency.clone()
+        };
         info!("Set current ZEC Price to USD {}", price);
+
+        self.events.emit(WalletEvent::PriceUpdated { currency, price });
+    }
+
+    pub fn events(&self) -> &WalletEvents {
+        &self.events
     }
 
     // Get the current sending status.
@@ -278,20 +541,34 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         self.send_progress.read().await.clone()
     }
 
+    // Tell the subscribers how far along the current send is
+    async fn emit_send_step(&self, step: SendStep) {
+        let id = self.send_progress.read().await.id;
+        self.events.emit(WalletEvent::SendProgress { id, step });
+    }
+
     // Set the previous send's status as an error
     async fn set_send_error(&self, e: String) {
-        let mut p = self.send_progress.write().await;
+        {
+            let mut p = self.send_progress.write().await;
 
-        p.is_send_in_progress = false;
-        p.last_error = Some(e);
+            p.is_send_in_progress = false;
+            p.last_error = Some(e.clone());
+        }
+
+        self.emit_send_step(SendStep::Failed { error: e }).await;
     }
 
     // Set the previous send's status as success
     async fn set_send_success(&self, txid: String) {
-        let mut p = self.send_progress.write().await;
+        {
+            let mut p = self.send_progress.write().await;
 
-        p.is_send_in_progress = false;
-        p.last_txid = Some(txid);
+            p.is_send_in_progress = false;
+            p.last_txid = Some(txid.clone());
+        }
+
+        self.emit_send_step(SendStep::Finished { txid }).await;
     }
 
     // Reset the send progress status to blank
@@ -572,7 +849,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     }
 
     pub fn serialized_version() -> u64 {
-        return 25;
+        return 26;
     }
 
     pub fn new(
@@ -584,16 +861,21 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         let keys = InMemoryKeys::<P>::new(&config, seed_phrase, num_zaddrs)
             .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
 
+        let txns = WalletTxns::new();
+        let events = txns.events.clone();
+
         Ok(Self {
             keys: Arc::new(RwLock::new(keys.into())),
-            txns: Arc::new(RwLock::new(WalletTxns::new())),
+            txns: Arc::new(RwLock::new(txns)),
             blocks: Arc::new(RwLock::new(vec![])),
             wallet_options: Arc::new(RwLock::new(WalletOptions::default())),
+            address_book: Arc::new(RwLock::new(AddressBook::new())),
             config,
             birthday: AtomicU64::new(height),
             verified_tree: Arc::new(RwLock::new(None)),
             send_progress: Arc::new(RwLock::new(SendProgress::new(0))),
             price: Arc::new(RwLock::new(WalletZecPriceInfo::new())),
+            events,
         })
     }
 
@@ -648,6 +930,12 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             WalletOptions::read(&mut reader)?
         };
 
+        let address_book = if version <= 25 {
+            AddressBook::new()
+        } else {
+            AddressBook::read(&mut reader)?
+        };
+
         let birthday = reader.read_u64::<LittleEndian>()?;
 
         if version <= 22 {
@@ -680,16 +968,19 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             WalletZecPriceInfo::read(&mut reader)?
         };
 
+        let events = txns.events.clone();
         let mut lw = Self {
             keys: Arc::new(RwLock::new(keys)),
             txns: Arc::new(RwLock::new(txns)),
             blocks: Arc::new(RwLock::new(blocks)),
             config: config.clone(),
             wallet_options: Arc::new(RwLock::new(wallet_options)),
+            address_book: Arc::new(RwLock::new(address_book)),
             birthday: AtomicU64::new(birthday),
             verified_tree: Arc::new(RwLock::new(verified_tree)),
             send_progress: Arc::new(RwLock::new(SendProgress::new(0))),
             price: Arc::new(RwLock::new(price)),
+            events,
         };
 
         // For old wallets, remove unused addresses
@@ -732,6 +1023,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         self.wallet_options.read().await.write(&mut writer)?;
 
+        self.address_book.read().await.write(&mut writer)?;
+
         // While writing the birthday, get it from the fn so we recalculate it properly
         // in case of rescans etc...
         writer.write_u64::<LittleEndian>(self.get_birthday().await)?;
@@ -757,22 +1050,31 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         shield_transparenent: bool,
         tranparent_outputs_n: usize,
         sapling_outputs_n: usize,
-    ) -> (Vec<SpendableNote>, Vec<Utxo>, Amount, Amount) {
+        spendable_only: bool,
+        from: &[String],
+        fee_policy: FeePolicy,
+    ) -> Result<(Vec<SpendableNote>, Vec<Utxo>, Amount, Amount), String> {
         // First, if we are allowed to pick transparent value, pick them all
         let utxos = if transparent_only || shield_transparenent {
             self.get_utxos()
                 .await
                 .iter()
                 .filter(|utxo| utxo.unconfirmed_spent.is_none() && utxo.spent.is_none())
+                .filter(|utxo| from.is_empty() || from.contains(&utxo.address))
                 .map(|utxo| utxo.clone())
                 .collect::<Vec<_>>()
         } else {
             vec![]
         };
 
-        let current_fee = self
-            .fee(utxos.len(), tranparent_outputs_n, 0, sapling_outputs_n, 0)
-            .pipe(|amt| Amount::from_u64(amt).unwrap());
+        // The fee for spending `n_notes` sapling notes along with the selected utxos
+        let fee_for = |n_notes| {
+            self.fee(utxos.len(), tranparent_outputs_n, n_notes, sapling_outputs_n, 0)
+                .pipe(|fee| fee_policy.fee(fee))
+                .map(|amt| Amount::from_u64(amt).unwrap())
+        };
+
+        let current_fee = fee_for(0)?;
 
         // Check how much we've selected
         let transparent_value_selected = utxos.iter().fold(Amount::zero(), |prev, utxo| {
@@ -781,43 +1083,59 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         // If we are allowed only transparent funds or we've selected enough then return
         if transparent_only || (transparent_value_selected - current_fee).unwrap() >= total_value {
-            return (vec![], utxos, transparent_value_selected, current_fee);
+            return Ok((vec![], utxos, transparent_value_selected, current_fee));
         }
 
+        let strategy = self.wallet_options.read().await.note_selection.strategy();
+
         // Start collecting sapling funds at every allowed offset
         for anchor_offset in &self.config.anchor_offset {
             let keys = self.keys().read().await;
 
             let mut candidate_notes = Vec::new();
-            for (txid, note) in self
+            for (txid, height, note) in self
                 .txns
                 .read()
                 .await
                 .current
                 .iter()
-                .flat_map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
-                .filter(|(_, note)| note.note.value > 0)
+                .flat_map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, tx.block, note)))
+                .filter(|(_, _, note)| note.note.value > 0)
                 // Filter out notes that are already spent
-                .filter(|(_, note)| note.spent.is_none() && note.unconfirmed_spent.is_none())
+                .filter(|(_, _, note)| note.spent.is_none() && note.unconfirmed_spent.is_none())
+                // Only spend from the requested addresses
+                .filter(|(_, _, note)| {
+                    from.is_empty()
+                        || Self::note_address(self.config.hrp_sapling_address(), note)
+                            .map(|addr| from.contains(&addr))
+                            .unwrap_or(false)
+                })
             {
-                // select the note if we have the spending key for it
-                if keys.have_spending_key(&note.ivk).await {
+                // select the note if we have the spending key for it, unless we're only proposing
+                // a transaction that will be signed elsewhere
+                if !spendable_only || keys.have_spending_key(&note.ivk).await {
                     //None will return if it's actually not spendable
                     if let Some(spendable) = SpendableNote::from(txid, note, *anchor_offset as usize, &note.ivk) {
-                        candidate_notes.push(spendable);
+                        candidate_notes.push(CandidateNote { spendable, height });
                     }
                 }
             }
 
-            candidate_notes.sort_by(|a, b| b.note.value.cmp(&a.note.value));
+            strategy.order(&mut candidate_notes);
 
-            // Select the minimum number of notes required to satisfy the target value
+            // Select the notes, in the strategy's order, required to satisfy the target value
+            let mut fee_err = None;
             let notes = candidate_notes
                 .into_iter()
+                .map(|c| c.spendable)
                 .scan((0, Amount::zero()), |(n_notes, running_total), spendable| {
-                    let current_fee = self
-                        .fee(utxos.len(), tranparent_outputs_n, *n_notes, sapling_outputs_n, 0)
-                        .pipe(|amt| Amount::from_u64(amt).unwrap());
+                    let current_fee = match fee_for(*n_notes) {
+                        Ok(fee) => fee,
+                        Err(e) => {
+                            fee_err = Some(e);
+                            return None;
+                        }
+                    };
 
                     if *running_total >= (total_value - transparent_value_selected + current_fee).unwrap() {
                         None
@@ -828,23 +1146,25 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
                     }
                 })
                 .collect::<Vec<_>>();
+            if let Some(e) = fee_err {
+                return Err(e);
+            }
+
             let sapling_value_selected = notes.iter().fold(Amount::zero(), |prev, sn| {
                 (prev + Amount::from_u64(sn.note.value).unwrap()).unwrap()
             });
 
-            let fees = self
-                .fee(utxos.len(), tranparent_outputs_n, notes.len(), sapling_outputs_n, 0)
-                .pipe(|amt| Amount::from_u64(amt).unwrap());
+            let fees = fee_for(notes.len())?;
 
             let amount = sapling_value_selected + transparent_value_selected;
             let amount = amount.unwrap();
             if amount >= (total_value + fees).unwrap() {
-                return (notes, utxos, amount, fees);
+                return Ok((notes, utxos, amount, fees));
             }
         }
 
         // If we can't select enough, then we need to return empty handed
-        (vec![], vec![], Amount::zero(), Amount::zero())
+        Ok((vec![], vec![], Amount::zero(), Amount::zero()))
     }
 
     pub async fn is_unlocked_for_spending(&self) -> bool {
@@ -1149,6 +1469,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         prover: Pr,
         transparent_only: bool,
         tos: Vec<(&str, u64, Option<String>)>,
+        from: &[String],
+        fee_policy: Option<FeePolicy>,
         broadcast_fn: F,
     ) -> Result<(String, Vec<u8>, Amount), String>
     where
@@ -1160,7 +1482,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         // Call the internal function
         match self
-            .send_to_address_internal(consensus_branch_id, prover, transparent_only, tos, broadcast_fn)
+            .send_to_address_internal(
+                consensus_branch_id,
+                prover,
+                transparent_only,
+                tos,
+                from,
+                fee_policy,
+                broadcast_fn,
+            )
             .await
         {
             Ok((txid, rawtx, fees)) => {
@@ -1180,6 +1510,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         prover: Pr,
         transparent_only: bool,
         tos: Vec<(&str, u64, Option<String>)>,
+        from: &[String],
+        fee_policy: Option<FeePolicy>,
         broadcast_fn: F,
     ) -> Result<(String, Vec<u8>, Amount), String>
     where
@@ -1191,25 +1523,94 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         }
 
         let start_time = now();
-        if tos.len() == 0 {
-            return Err("Need at least one destination address".to_string());
-        }
-
         let total_value = tos.iter().map(|to| to.1).sum::<u64>();
-        println!(
+        info!(
             "0: Creating transaction sending {} ztoshis to {} addresses",
             total_value,
             tos.len()
         );
 
+        // Select notes to cover the target value
+        info!("{}: Selecting notes", now() - start_time);
+        self.emit_send_step(SendStep::SelectingNotes).await;
+        let proposal = self
+            .create_proposal(consensus_branch_id, transparent_only, true, tos, from, fee_policy, true)
+            .await?;
+
+        info!(
+            "{}: Adding {} notes and {} utxos",
+            now() - start_time,
+            proposal.notes.len(),
+            proposal.utxos.len()
+        );
+
+        info!("{}: Building transaction", now() - start_time);
+        let tx = self.sign_proposal(&proposal, &prover).await?;
+
+        info!("{}: Transaction created", now() - start_time);
+        info!("Transaction ID: {}", tx.txid());
+
+        // Create the TX bytes
+        let mut raw_tx = vec![];
+        tx.write(&mut raw_tx).unwrap();
+
+        self.emit_send_step(SendStep::Broadcasting).await;
+        let txid = self.broadcast_tx(tx, broadcast_fn).await?;
+
+        Ok((txid, raw_tx, Amount::from_u64(proposal.fee).unwrap()))
+    }
+
+    /// Select the notes and utxos needed to pay `tos`, and return them as an unsigned transaction proposal.
+    ///
+    /// If `spendable_only` is false, notes we only have the viewing key for are also selected, so that
+    /// a watch-only wallet can create a proposal to be signed by the wallet holding the spending keys.
+    ///
+    /// If `from` isn't empty, only notes and utxos received at those addresses are spent, and the change
+    /// goes back to the key of the first shielded address in `from`, as set by the change address option.
+    ///
+    /// The fee is worked out with `fee_policy`, or with the wallet's default fee policy if it's `None`.
+    ///
+    /// If `persist` is false, as for a dry run, the keystore isn't changed: a new diversified change address, or the
+    /// z address created when the wallet has none, is worked out but not recorded.
+    pub async fn create_proposal(
+        &self,
+        consensus_branch_id: u32,
+        transparent_only: bool,
+        spendable_only: bool,
+        tos: Vec<(&str, u64, Option<String>)>,
+        from: &[String],
+        fee_policy: Option<FeePolicy>,
+        persist: bool,
+    ) -> Result<TxProposal, String> {
+        if tos.len() == 0 {
+            return Err("Need at least one destination address".to_string());
+        }
+
+        let fee_policy = match fee_policy {
+            Some(p) => p,
+            None => self.wallet_options.read().await.fee_policy,
+        };
+
+        let mut from_zaddr = None;
+        for addr in from {
+            match address::RecipientAddress::decode(&self.config.get_params(), addr) {
+                Some(address::RecipientAddress::Shielded(pa)) => {
+                    if from_zaddr.is_none() {
+                        from_zaddr = Some(pa);
+                    }
+                }
+                Some(address::RecipientAddress::Transparent(_)) => {}
+                None => return Err(format!("Invalid from address: {}", addr)),
+            }
+        }
+
         // Convert address (str) to RecepientAddress and value to Amount
         let recepients = tos
             .iter()
             .map(|to| {
-                let ra = match address::RecipientAddress::decode(&self.config.get_params(), to.0) {
-                    Some(to) => to,
-                    None => {
-                        let e = format!("Invalid recipient address: '{}'", to.0);
+                let ra = match unified::decode_recipient(&self.config.get_params(), to.0) {
+                    Ok(to) => to,
+                    Err(e) => {
                         error!("{}", e);
                         return Err(e);
                     }
@@ -1226,39 +1627,43 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             address::RecipientAddress::Transparent(_) => (tout + 1, sout),
         });
 
-        // Select notes to cover the target value
-        println!("{}: Selecting notes", now() - start_time);
-
-        let total_value = Amount::from_u64(total_value).unwrap();
+        let total_value = Amount::from_u64(tos.iter().map(|to| to.1).sum::<u64>()).unwrap();
         let target_height = match self.get_target_height().await {
-            Some(h) => BlockHeight::from_u32(h),
+            Some(h) => h,
             None => return Err("No blocks in wallet to target, please sync first".to_string()),
         };
 
-        // Create a map from address -> sk for all taddrs, so we can spend from the
-        // right address
-        let (address_to_key, (first_zkey_ovk, first_zkey_addr)) = {
-            let (map, first) = {
-                let guard = self.keys.read().await;
-                tokio::join!(guard.get_taddr_to_key_map(), guard.first_zkey())
-            };
+        // The first z address is used for change and to encrypt outgoing outputs, unless notes of another key are spent
+        let (first_zkey_ovk, first_zkey_addr) = {
+            let first = self.keys.read().await.first_zkey().await;
 
             //create one if it doesn't exist already
-            let first = match first {
+            match first {
                 Some(first) => first,
-                None => {
+                None if persist => {
                     let mut guard = self.keys.write().await;
                     guard.add_zaddr("").await;
                     guard.first_zkey().await.unwrap()
                 }
-            };
-
-            (map, first)
+                None => match self.keys.read().await.next_zkey().await {
+                    Some(next) => next,
+                    None => return Err("No shielded address in wallet to send the change to".to_string()),
+                },
+            }
         };
 
         let (notes, utxos, selected_value, fees) = self
-            .select_notes_and_utxos(total_value, transparent_only, true, touts_n, sapling_outputs_n)
-            .await;
+            .select_notes_and_utxos(
+                total_value,
+                transparent_only,
+                true,
+                touts_n,
+                sapling_outputs_n,
+                spendable_only,
+                from,
+                fee_policy,
+            )
+            .await?;
         if selected_value < (total_value + fees).unwrap() {
             let e = format!(
                 "Insufficient verified funds. Have {} zats, need {} zats + {} zats in fees. NOTE: funds need at least {} confirmations before they can be spent.",
@@ -1271,13 +1676,341 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             return Err(e);
         }
 
-        // Create the transaction
-        println!(
-            "{}: Adding {} notes and {} utxos",
-            now() - start_time,
-            notes.len(),
-            utxos.len()
-        );
+        // Change from utxos alone is sent to `change_address`, which can only be a shielded address
+        let change = ((selected_value - total_value).unwrap() - fees).unwrap();
+        if !from.is_empty() && from_zaddr.is_none() && change.is_positive() {
+            return Err(format!(
+                "Can't send {} zats of change back to a transparent from address",
+                u64::from(change)
+            ));
+        }
+
+        // With `from`, the change goes back to the keys of the from addresses, so it can be spent from them again
+        let change_address = self
+            .change_address_for(&notes, from_zaddr.unwrap_or(first_zkey_addr), persist)
+            .await;
+
+        // The outputs are encrypted with the ovk of the key the change goes to, which is the signer's key when a
+        // watch-only wallet proposes spending notes of an imported viewing key
+        let ovk = self
+            .keys
+            .read()
+            .await
+            .get_ovk_of_address(&change_address)
+            .await
+            .unwrap_or(first_zkey_ovk);
+
+        let mut outputs = vec![];
+        for (to, value, memo) in tos
+            .iter()
+            .zip(recepients.iter())
+            .map(|(to, (_, value, memo))| (to.0, value, memo))
+        {
+            // Compute memo if it exists
+            let encoded_memo = match memo {
+                None => MemoBytes::empty(),
+                Some(s) => {
+                    // If the string starts with an "0x", and contains only hex chars ([a-f0-9]+) then
+                    // interpret it as a hex
+                    match utils::interpret_memo_string(s.clone()) {
+                        Ok(m) => m,
+                        Err(e) => {
+                            error!("{}", e);
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+
+            outputs.push(ProposalOutput {
+                address: to.to_string(),
+                value: u64::from(*value),
+                memo: encoded_memo,
+            });
+        }
+
+        Ok(TxProposal {
+            chain_name: self.config.chain_name.clone(),
+            consensus_branch_id,
+            target_height,
+            fee: u64::from(fees),
+            notes,
+            utxos,
+            outputs,
+            ovk,
+            change_address,
+        })
+    }
+
+    /// The address the change of a tx spending `notes` goes to, according to the wallet's change address option.
+    /// `default` is used if no notes are spent. A new diversified change address is only recorded if `persist` is set.
+    async fn change_address_for(
+        &self,
+        notes: &[SpendableNote],
+        default: PaymentAddress,
+        persist: bool,
+    ) -> PaymentAddress {
+        let spent_note_address = notes
+            .first()
+            .and_then(|sn| sn.ivk.to_payment_address(sn.diversifier))
+            .unwrap_or(default);
+
+        let option = self.wallet_options.read().await.change_address;
+        match option {
+            ChangeAddressOption::SpentNoteAddress => spent_note_address,
+            ChangeAddressOption::Diversified => {
+                // Derive it from the key of the spent notes, so the change is spendable by the same key. The new
+                // address is recorded with the key, so notes received at it are marked as change
+                let ivk = match notes.first() {
+                    Some(sn) => SaplingIvk(sn.ivk.0),
+                    None => {
+                        let keys = self.keys.read().await;
+                        match keys
+                            .get_all_ivks()
+                            .await
+                            .find(|ivk| ivk.to_payment_address(*default.diversifier()).as_ref() == Some(&default))
+                        {
+                            Some(ivk) => ivk,
+                            None => return spent_note_address,
+                        }
+                    }
+                };
+
+                let address = if persist {
+                    self.keys.write().await.add_change_zaddr(&ivk).await
+                } else {
+                    self.keys.read().await.next_change_zaddr(&ivk).await
+                };
+
+                match address {
+                    Some(addr) => addr,
+                    None => {
+                        warn!("Couldn't derive a diversified change address, using the spent note's address");
+                        spent_note_address
+                    }
+                }
+            }
+        }
+    }
+
+    /// Create proposals that merge the wallet's smallest spendable notes into `target_address`, which has to be one of
+    /// the wallet's own shielded addresses, with at most `max_inputs` notes per transaction. The notes in all the
+    /// proposals are disjoint, so they can be signed and broadcast one after the other.
+    ///
+    /// Also returns the notes that were left out because they, even together with the largest note, are worth less than
+    /// the fee for merging them.
+    pub async fn create_consolidation_proposals(
+        &self,
+        consensus_branch_id: u32,
+        max_inputs: usize,
+        target_address: &str,
+    ) -> Result<(Vec<TxProposal>, Vec<SpendableNote>), String> {
+        if max_inputs < 2 {
+            return Err("Need to merge at least 2 notes per transaction".to_string());
+        }
+
+        let target = match address::RecipientAddress::decode(&self.config.get_params(), target_address) {
+            Some(address::RecipientAddress::Shielded(pa)) => pa,
+            _ => {
+                return Err(format!(
+                    "Can only consolidate into a shielded address, not {}",
+                    target_address
+                ))
+            }
+        };
+
+        // The notes are merged into the wallet itself, so a mistyped address can't send the funds away
+        let owned = self
+            .keys
+            .read()
+            .await
+            .get_all_spendable_ivks()
+            .await
+            .any(|ivk| ivk.to_payment_address(*target.diversifier()) == Some(target.clone()));
+        if !owned {
+            return Err(format!(
+                "Can only consolidate into a spendable address of this wallet, not {}",
+                target_address
+            ));
+        }
+
+        let target_height = match self.get_target_height().await {
+            Some(h) => h,
+            None => return Err("No blocks in wallet to target, please sync first".to_string()),
+        };
+
+        let (ovk, change_address) = match self.keys.read().await.first_zkey().await {
+            Some(first) => first,
+            None => return Err("No shielded address in wallet".to_string()),
+        };
+
+        // All the notes spent in a transaction need to use the same anchor, so pick the first
+        // anchor offset that has enough notes to merge
+        let mut candidate_notes = vec![];
+        for anchor_offset in &self.config.anchor_offset {
+            let keys = self.keys().read().await;
+
+            candidate_notes.clear();
+            for (txid, note) in self
+                .txns
+                .read()
+                .await
+                .current
+                .iter()
+                .flat_map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
+                .filter(|(_, note)| note.note.value > 0)
+                .filter(|(_, note)| note.spent.is_none() && note.unconfirmed_spent.is_none())
+            {
+                if keys.have_spending_key(&note.ivk).await {
+                    if let Some(spendable) = SpendableNote::from(txid, note, *anchor_offset as usize, &note.ivk) {
+                        candidate_notes.push(spendable);
+                    }
+                }
+            }
+
+            if candidate_notes.len() >= 2 {
+                break;
+            }
+        }
+
+        // Smallest notes first
+        candidate_notes.sort_by(|a, b| a.note.value.cmp(&b.note.value));
+
+        let fee_policy = self.wallet_options.read().await.fee_policy;
+        let value = |notes: &[SpendableNote]| notes.iter().map(|sn| sn.note.value).sum::<u64>();
+
+        let mut proposals = vec![];
+        let mut unmerged = vec![];
+
+        // The notes from `lo` to `hi` are left to merge
+        let (mut lo, mut hi) = (0, candidate_notes.len());
+        while hi - lo >= 2 {
+            let n = cmp::min(max_inputs, hi - lo);
+            let fee = fee_policy.fee(self.fee(0, 0, n, 1, 0))?;
+
+            // Merge the smallest notes. If they are dust that is worth less than the fee for merging them, have the
+            // largest note that is left pay for them, in place of the largest of them.
+            let smallest = candidate_notes[lo..lo + n].to_vec();
+            let mut with_largest = candidate_notes[lo..lo + n - 1].to_vec();
+            with_largest.push(candidate_notes[hi - 1].clone());
+
+            let batch = if value(&smallest) > fee {
+                lo += n;
+                smallest
+            } else if value(&with_largest) > fee {
+                lo += n - 1;
+                hi -= 1;
+                with_largest
+            } else {
+                // Not even that pays for merging them, so leave out the smallest note and try again
+                unmerged.push(candidate_notes[lo].clone());
+                lo += 1;
+                continue;
+            };
+
+            let batch_value = value(&batch);
+            proposals.push(TxProposal {
+                chain_name: self.config.chain_name.clone(),
+                consensus_branch_id,
+                target_height,
+                fee,
+                notes: batch,
+                utxos: vec![],
+                outputs: vec![ProposalOutput {
+                    address: target_address.to_string(),
+                    value: batch_value - fee,
+                    memo: MemoBytes::empty(),
+                }],
+                ovk,
+                change_address: change_address.clone(),
+            });
+        }
+
+        Ok((proposals, unmerged))
+    }
+
+    /// Build, prove and sign the transaction described by `proposal` using the keys in this wallet.
+    /// The transaction is returned without being broadcast or recorded in the wallet.
+    ///
+    /// The proposal comes from another, possibly compromised, wallet, so its values have to be in range, and its
+    /// change address and ovk have to be this wallet's own.
+    pub async fn sign_proposal<Pr: TxProver + Send + Sync>(
+        &self,
+        proposal: &TxProposal,
+        prover: &Pr,
+    ) -> Result<Transaction, String> {
+        if !self.is_unlocked_for_spending().await {
+            return Err("Cannot spend while wallet is locked".to_string());
+        }
+
+        if proposal.chain_name != self.config.chain_name {
+            return Err(format!(
+                "Proposal is for chain '{}', but this wallet is on '{}'",
+                proposal.chain_name, self.config.chain_name
+            ));
+        }
+
+        let values = proposal.notes.iter().map(|n| n.note.value);
+        let values = values.chain(proposal.utxos.iter().map(|u| u.value));
+        let values = values.chain(proposal.outputs.iter().map(|o| o.value));
+        if values.chain(Some(proposal.fee)).any(|v| v > MAX_MONEY) {
+            return Err("Proposal has a value above the maximum amount of ZEC".to_string());
+        }
+
+        let change = &proposal.change_address;
+        let owned_change = self
+            .keys
+            .read()
+            .await
+            .get_all_ivks()
+            .await
+            .any(|ivk| ivk.to_payment_address(*change.diversifier()).as_ref() == Some(change));
+        if !owned_change {
+            return Err("Proposal sends the change to an address that isn't in this wallet".to_string());
+        }
+
+        let owned_ovk = self
+            .keys
+            .read()
+            .await
+            .get_all_ovks()
+            .await
+            .any(|ovk| ovk.0 == proposal.ovk.0);
+        if !owned_ovk {
+            return Err("Proposal encrypts its outputs with an ovk that isn't in this wallet".to_string());
+        }
+
+        if proposal.total_input() < proposal.total_output() + proposal.fee {
+            return Err(format!(
+                "Proposal spends {} zats, but needs {} zats + {} zats in fees",
+                proposal.total_input(),
+                proposal.total_output(),
+                proposal.fee
+            ));
+        }
+
+        let recepients = proposal
+            .outputs
+            .iter()
+            .map(
+                |o| match unified::decode_recipient(&self.config.get_params(), &o.address) {
+                    Ok(to) => Amount::from_u64(o.value)
+                        .map(|value| (to, value, o.memo.clone()))
+                        .map_err(|_| format!("Invalid value {} for {}", o.value, o.address)),
+                    Err(e) => {
+                        error!("{}", e);
+                        Err(e)
+                    }
+                },
+            )
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let target_height = BlockHeight::from_u32(proposal.target_height);
+
+        // Create a map from address -> sk for all taddrs, so we can spend from the
+        // right address
+        let address_to_key = self.keys.read().await.get_taddr_to_key_map().await;
+
         let (progress_notifier, progress_notifier_rx) = mpsc::channel();
 
         let mut keys = self.keys.write().await;
@@ -1285,13 +2018,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         builder.with_progress_notifier(Some(progress_notifier));
 
         // Add all tinputs
-        utxos
+        proposal
+            .utxos
             .iter()
             .map(|utxo| {
                 let outpoint: OutPoint = utxo.to_outpoint();
 
                 let coin = TxOut {
-                    value: Amount::from_u64(utxo.value).unwrap(),
+                    value: Amount::from_u64(utxo.value)
+                        .map_err(|_| zcash_primitives::transaction::builder::Error::InvalidAmount)?,
                     script_pubkey: Script { 0: utxo.script.clone() },
                 };
 
@@ -1312,48 +2047,29 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             .collect::<Result<Vec<_>, _>>()
             .map_err(|e| format!("{:?}", e))?;
 
-        for selected in notes.iter() {
-            if let Err(e) = builder.add_sapling_spend(
-                &selected.ivk,
-                selected.diversifier,
-                selected.note.clone(),
-                selected.witness.path().unwrap(),
-            ) {
+        for selected in proposal.notes.iter() {
+            let path = selected
+                .witness
+                .path()
+                .ok_or_else(|| format!("Note {} has an invalid witness", selected.txid))?;
+            let spend = builder.add_sapling_spend(&selected.ivk, selected.diversifier, selected.note.clone(), path);
+            if let Err(e) = spend {
                 let e = format!("Error adding note: {:?}", e);
                 error!("{}", e);
                 return Err(e);
             }
         }
 
-        // If no Sapling notes were added, add the change address manually. That is,
-        // send the change to our sapling address manually. Note that if a sapling note was spent,
-        // the builder will automatically send change to that address
-        if notes.len() == 0 {
-            builder.send_change_to(first_zkey_ovk, first_zkey_addr);
+        // If a sapling note was spent, the builder will automatically send change to that note's address.
+        // Otherwise, or if the change goes somewhere else (e.g., a diversified address), set it manually
+        if proposal.spent_note_address() != Some(proposal.change_address.clone()) {
+            builder.send_change_to(proposal.ovk, proposal.change_address.clone());
         }
 
-        // We'll use the first ovk to encrypt outgoing Txns
-        let ovk = first_zkey_ovk;
+        // We'll use the proposal's ovk to encrypt outgoing Txns
+        let ovk = proposal.ovk;
         let mut total_z_recepients = 0u32;
-        for (to, value, memo) in recepients {
-            // Compute memo if it exists
-            let encoded_memo = match memo {
-                None => MemoBytes::empty(),
-                Some(s) => {
-                    // If the string starts with an "0x", and contains only hex chars ([a-f0-9]+) then
-                    // interpret it as a hex
-                    match utils::interpret_memo_string(s) {
-                        Ok(m) => m,
-                        Err(e) => {
-                            error!("{}", e);
-                            return Err(e);
-                        }
-                    }
-                }
-            };
-
-            println!("{}: Adding output", now() - start_time);
-
+        for (to, value, encoded_memo) in recepients {
             if let Err(e) = match to {
                 address::RecipientAddress::Shielded(to) => {
                     total_z_recepients += 1;
@@ -1369,6 +2085,7 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
 
         // Set up a channel to receieve updates on the progress of building the transaction
         let progress = self.send_progress.clone();
+        let events = self.events.clone();
 
         // Use a separate thread to handle sending from std::mpsc to tokio::sync::mpsc
         let (tx2, mut rx2) = tokio::sync::mpsc::unbounded_channel();
@@ -1381,7 +2098,14 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         let progress_handle = tokio::spawn(async move {
             while let Some(r) = rx2.recv().await {
                 println!("Progress: {}", r);
-                progress.write().await.progress = r;
+                let (id, total) = {
+                    let mut p = progress.write().await;
+                    p.progress = r;
+                    (p.id, p.total)
+                };
+
+                let step = SendStep::BuildingTransaction { progress: r, total };
+                events.emit(WalletEvent::SendProgress { id, step });
             }
 
             progress.write().await.is_send_in_progress = false;
@@ -1391,12 +2115,15 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             let mut p = self.send_progress.write().await;
             p.is_send_in_progress = true;
             p.progress = 0;
-            p.total = notes.len() as u32 + total_z_recepients;
+            p.total = proposal.notes.len() as u32 + total_z_recepients;
         }
 
-        println!("{}: Building transaction", now() - start_time);
         let (tx, _) = match builder
-            .build(BranchId::try_from(consensus_branch_id).unwrap(), &prover, fees.into())
+            .build(
+                BranchId::try_from(proposal.consensus_branch_id).map_err(|e| e.to_string())?,
+                prover,
+                proposal.fee,
+            )
             .await
         {
             Ok(res) => {
@@ -1415,46 +2142,92 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         // Wait for all the progress to be updated
         progress_handle.await.unwrap();
 
-        println!("{}: Transaction created", now() - start_time);
-        println!("Transaction ID: {}", tx.txid());
-
         {
             self.send_progress.write().await.is_send_in_progress = false;
         }
 
+        Ok(tx)
+    }
+
+    /// Parse a signed raw transaction and broadcast it. See [`LightWallet::broadcast_tx`]
+    pub async fn broadcast_raw_tx<F, Fut>(
+        &self,
+        consensus_branch_id: u32,
+        raw_tx: &[u8],
+        broadcast_fn: F,
+    ) -> Result<String, String>
+    where
+        F: Fn(Box<[u8]>) -> Fut,
+        Fut: Future<Output = Result<String, String>>,
+    {
+        let branch_id = BranchId::try_from(consensus_branch_id).map_err(|e| e.to_string())?;
+        let tx = Transaction::read(raw_tx, branch_id).map_err(|e| format!("Couldn't parse transaction: {}", e))?;
+
+        self.broadcast_tx(tx, broadcast_fn).await
+    }
+
+    /// Broadcast a signed transaction, mark the notes and utxos it spends as unconfirmed spent
+    /// and add it to the wallet's mempool txns.
+    pub async fn broadcast_tx<F, Fut>(&self, tx: Transaction, broadcast_fn: F) -> Result<String, String>
+    where
+        F: Fn(Box<[u8]>) -> Fut,
+        Fut: Future<Output = Result<String, String>>,
+    {
+        let target_height = match self.get_target_height().await {
+            Some(h) => BlockHeight::from_u32(h),
+            None => return Err("No blocks in wallet to target, please sync first".to_string()),
+        };
+
         // Create the TX bytes
         let mut raw_tx = vec![];
-        tx.write(&mut raw_tx).unwrap();
+        tx.write(&mut raw_tx).map_err(|e| e.to_string())?;
 
         let txid = broadcast_fn(raw_tx.clone().into_boxed_slice()).await?;
 
+        // The fee is the sapling value balance plus the transparent value balance. We only know the
+        // value of the transparent inputs if they're all ours
+        let sent_txid = tx.txid();
+        let expiry_height = u32::from(tx.expiry_height());
+        let mut fee = tx
+            .sapling_bundle()
+            .map(|s_bundle| i64::from(s_bundle.value_balance))
+            .or(Some(0));
+
         // Mark notes as spent.
         {
-            // Mark sapling notes as unconfirmed spent
             let mut txs = self.txns.write().await;
-            for selected in notes {
-                let spent_note: &mut _ = txs
-                    .current
-                    .get_mut(&selected.txid)
-                    .unwrap()
-                    .notes
-                    .iter_mut()
-                    .find(|nd| nd.nullifier == selected.nullifier)
-                    .unwrap();
-                spent_note.unconfirmed_spent = Some((tx.txid(), u32::from(target_height)));
+
+            // Mark sapling notes as unconfirmed spent
+            if let Some(s_bundle) = tx.sapling_bundle() {
+                for spend in s_bundle.shielded_spends.iter() {
+                    if let Some(spent_note) = txs
+                        .current
+                        .values_mut()
+                        .flat_map(|wtx| wtx.notes.iter_mut())
+                        .find(|nd| nd.nullifier == spend.nullifier)
+                    {
+                        spent_note.unconfirmed_spent = Some((tx.txid(), u32::from(target_height)));
+                    }
+                }
             }
 
             // Mark this utxo as unconfirmed spent
-            for utxo in utxos {
-                let spent_utxo: &mut _ = txs
-                    .current
-                    .get_mut(&utxo.txid)
-                    .unwrap()
-                    .utxos
-                    .iter_mut()
-                    .find(|u| utxo.txid == u.txid && utxo.output_index == u.output_index)
-                    .unwrap();
-                spent_utxo.unconfirmed_spent = Some((tx.txid(), u32::from(target_height)));
+            if let Some(t_bundle) = tx.transparent_bundle() {
+                for vin in t_bundle.vin.iter() {
+                    let prev_txid = TxId::from_bytes(*vin.prevout.hash());
+                    if let Some(spent_utxo) = txs
+                        .current
+                        .get_mut(&prev_txid)
+                        .and_then(|wtx| wtx.utxos.iter_mut().find(|u| u.output_index == vin.prevout.n() as u64))
+                    {
+                        spent_utxo.unconfirmed_spent = Some((tx.txid(), u32::from(target_height)));
+                        fee = fee.map(|f| f + spent_utxo.value as i64);
+                    } else {
+                        fee = None;
+                    }
+                }
+
+                fee = fee.map(|f| f - t_bundle.vout.iter().map(|o| i64::from(o.value)).sum::<i64>());
             }
         }
 
@@ -1475,7 +2248,19 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
             .await;
         }
 
-        Ok((txid, raw_tx, fees))
+        // Record the fee we paid, and keep the raw tx around so it can be rebroadcast until it is mined
+        {
+            let mut txs = self.txns.write().await;
+            if let Some(fee) = fee.filter(|f| *f >= 0) {
+                if let Some(wtx) = txs.current.get_mut(&sent_txid) {
+                    wtx.fee = Some(fee as u64);
+                }
+            }
+
+            txs.add_pending_send(sent_txid, raw_tx, expiry_height, u32::from(target_height) - 1);
+        }
+
+        Ok(txid)
     }
 
     pub async fn encrypt(&self, passwd: String) -> io::Result<()> {
@@ -1514,6 +2299,8 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
         }
     }
 
+    /// The ZIP-317 conventional fee for a transaction with these inputs and outputs. Use a `FeePolicy`
+    /// to work out the fee that's actually paid.
     pub fn fee(
         &self,
         tins_n: usize,
@@ -1524,21 +2311,33 @@ impl<P: consensus::Parameters + Send + Sync + 'static> LightWallet<P> {
     ) -> u64 {
         use std::cmp::max;
 
-        let logical_actions = max(tins_n, touts_n) + max(sapling_spends_n, sapling_outputs_n) + orchard_n;
-
-        const MARGINAL_FEE: u64 = 5000;
-        const GRACE_ACTIONS: usize = 2;
+        let logical_actions = Self::logical_actions(tins_n, touts_n, sapling_spends_n, sapling_outputs_n, orchard_n);
 
         let actions = max(GRACE_ACTIONS, logical_actions);
 
         MARGINAL_FEE * (actions as u64)
     }
+
+    /// The number of logical actions in a transaction, as defined by ZIP-317
+    pub fn logical_actions(
+        tins_n: usize,
+        touts_n: usize,
+        sapling_spends_n: usize,
+        sapling_outputs_n: usize,
+        orchard_n: usize,
+    ) -> usize {
+        use std::cmp::max;
+
+        max(tins_n, touts_n) + max(sapling_spends_n, sapling_outputs_n) + orchard_n
+    }
 }
 
 #[cfg(test)]
 mod test {
     use zcash_primitives::transaction::components::Amount;
 
+    use super::FeePolicy;
+
     use crate::lightclient::lightclient_config::UnitTestNetwork;
     use crate::{
         blaze::test_utils::{incw_to_string, FakeCompactBlockList, FakeTransaction},
@@ -1580,7 +2379,11 @@ mod test {
         let amt = Amount::from_u64(10_000).unwrap();
         // Reset the anchor offsets
         lc.wallet.config.anchor_offset = [9, 4, 2, 1, 0];
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected >= amt);
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].note.value, value);
@@ -1597,14 +2400,22 @@ mod test {
 
         // With min anchor_offset at 1, we can't select any notes
         lc.wallet.config.anchor_offset = [9, 4, 2, 1, 1];
-        let (notes, utxos, _selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, _selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert_eq!(notes.len(), 0);
         assert_eq!(utxos.len(), 0);
 
         // Mine 1 block, then it should be selectable
         mine_random_blocks(&mut fcbl, &data, &lc, 1).await;
 
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected >= amt);
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].note.value, value);
@@ -1622,7 +2433,11 @@ mod test {
         // Mine 15 blocks, then selecting the note should result in witness only 10 blocks deep
         mine_random_blocks(&mut fcbl, &data, &lc, 15).await;
         lc.wallet.config.anchor_offset = [9, 4, 2, 1, 1];
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, true, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, true, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected >= amt);
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].note.value, value);
@@ -1639,7 +2454,11 @@ mod test {
 
         // Trying to select a large amount will fail
         let amt = Amount::from_u64(1_000_000).unwrap();
-        let (notes, utxos, _selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, _selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert_eq!(notes.len(), 0);
         assert_eq!(utxos.len(), 0);
 
@@ -1656,14 +2475,22 @@ mod test {
 
         // Trying to select a large amount will now succeed
         let amt = Amount::from_u64(value + tvalue - lc.wallet.fee(1, 1, 0, 0, 0)).unwrap();
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, true, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, true, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert_eq!(selected, Amount::from_u64(value + tvalue).unwrap());
         assert_eq!(notes.len(), 1);
         assert_eq!(utxos.len(), 1);
 
         // If we set transparent-only = true, only the utxo should be selected
         let amt = Amount::from_u64(tvalue - lc.wallet.fee(1, 0, 0, 0, 0)).unwrap();
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, true, true, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, true, true, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert_eq!(selected, Amount::from_u64(tvalue).unwrap());
         assert_eq!(notes.len(), 0);
         assert_eq!(utxos.len(), 1);
@@ -1671,7 +2498,11 @@ mod test {
         // Set min confs to 5, so the sapling note will not be selected
         lc.wallet.config.anchor_offset = [9, 4, 4, 4, 4];
         let amt = Amount::from_u64(tvalue - lc.wallet.fee(1, 0, 0, 0, 0)).unwrap();
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, true, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, true, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert_eq!(selected, Amount::from_u64(tvalue).unwrap());
         assert_eq!(notes.len(), 0);
         assert_eq!(utxos.len(), 1);
@@ -1712,7 +2543,11 @@ mod test {
         let amt = Amount::from_u64(10_000).unwrap();
         // Reset the anchor offsets
         lc.wallet.config.anchor_offset = [9, 4, 2, 1, 0];
-        let (notes, utxos, selected, fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, selected, fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected >= amt);
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].note.value, value1);
@@ -1737,7 +2572,11 @@ mod test {
 
         // Now, try to select a small amount, it should prefer the older note
         let amt = Amount::from_u64(10_000).unwrap();
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected >= amt);
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].note.value, value1);
@@ -1745,7 +2584,11 @@ mod test {
 
         // Selecting a bigger amount should select both notes
         let amt = Amount::from_u64(value1 + value2).unwrap();
-        let (notes, utxos, selected, _fees) = lc.wallet.select_notes_and_utxos(amt, false, false, 0, 0).await;
+        let (notes, utxos, selected, _fees) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
         assert!(selected == amt);
         assert_eq!(notes.len(), 2);
         assert_eq!(utxos.len(), 0);
@@ -1754,4 +2597,65 @@ mod test {
         stop_tx.send(true).unwrap();
         h1.await.unwrap();
     }
+
+    #[test]
+    fn fee_policy() {
+        use super::{WalletOptions, MAX_MONEY};
+
+        assert_eq!(FeePolicy::parse("zip317").unwrap(), FeePolicy::Zip317);
+        assert_eq!(FeePolicy::parse("fixed:20000").unwrap(), FeePolicy::Fixed(20_000));
+        assert_eq!(FeePolicy::parse("multiplier:2").unwrap(), FeePolicy::Multiplier(2));
+        assert!(FeePolicy::parse("multiplier:0").is_err());
+        assert!(FeePolicy::parse("fixed").is_err());
+        assert!(FeePolicy::parse("zip317:1").is_err());
+        assert!(FeePolicy::parse("cheap").is_err());
+
+        // Fees that wouldn't be relayed, or that are more than all the money there is
+        assert!(FeePolicy::parse("fixed:0").is_err());
+        assert!(FeePolicy::parse("fixed:9999").is_err());
+        assert_eq!(FeePolicy::parse("fixed:10000").unwrap(), FeePolicy::Fixed(10_000));
+        assert!(FeePolicy::parse(&format!("fixed:{}", MAX_MONEY + 1)).is_err());
+        assert!(FeePolicy::parse(&format!("multiplier:{}", u64::MAX)).is_err());
+
+        assert_eq!(FeePolicy::Zip317.fee(15_000), Ok(15_000));
+        assert_eq!(FeePolicy::Fixed(20_000).fee(15_000), Ok(20_000));
+        assert!(FeePolicy::Fixed(10_000).fee(15_000).is_err());
+        assert_eq!(FeePolicy::Multiplier(3).fee(15_000), Ok(45_000));
+        assert!(FeePolicy::Multiplier(u64::MAX).fee(15_000).is_err());
+        assert!(FeePolicy::Multiplier(MAX_MONEY / 10_000).fee(15_000).is_err());
+
+        for p in [FeePolicy::Zip317, FeePolicy::Fixed(20_000), FeePolicy::Multiplier(2)] {
+            assert_eq!(FeePolicy::parse(&p.to_string()).unwrap(), p);
+
+            let mut options = WalletOptions::default();
+            options.fee_policy = p;
+
+            let mut buf = vec![];
+            options.write(&mut buf).unwrap();
+            assert_eq!(WalletOptions::read(&buf[..]).unwrap().fee_policy, p);
+        }
+    }
+
+    #[test]
+    fn change_address_option() {
+        use super::{ChangeAddressOption, WalletOptions};
+
+        assert_eq!(
+            WalletOptions::default().change_address,
+            ChangeAddressOption::SpentNoteAddress
+        );
+
+        for c in [ChangeAddressOption::SpentNoteAddress, ChangeAddressOption::Diversified] {
+            let mut options = WalletOptions::default();
+            options.change_address = c;
+            options.fee_policy = FeePolicy::Fixed(20_000);
+
+            let mut buf = vec![];
+            options.write(&mut buf).unwrap();
+
+            let read = WalletOptions::read(&buf[..]).unwrap();
+            assert_eq!(read.change_address, c);
+            assert_eq!(read.fee_policy, FeePolicy::Fixed(20_000));
+        }
+    }
 }
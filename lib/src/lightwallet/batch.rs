@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use zcash_client_backend::address::RecipientAddress;
+use zcash_primitives::consensus;
+
+use super::{unified, utils, zip321};
+
+// Batch payouts from a CSV file with one payment per row:
+//   address,amount,memo
+//   zs1...,1.5,Thanks!
+//   t1...,150000000 zats,
+//
+// Amounts with a decimal point or a "ZEC" suffix are in ZEC, plain integers or amounts with a "zats" suffix are in
+// zats. The memo column is optional, and fields can be quoted if they contain commas or quotes.
+
+/// The default maximum number of outputs in each transaction of a batch
+pub const DEFAULT_BATCH_MAX_OUTPUTS: usize = 50;
+
+const RESULTS_HEADER: &str = "line,address,amount,memo,txid,error";
+
+/// Written as the error of the rows in a transaction just before it is broadcast, and removed once the server accepts
+/// it. If it is still there, the wallet stopped in between or the broadcast failed, so the transaction may or may not
+/// have been sent. A broadcast error is appended to it.
+pub const PENDING_BROADCAST: &str = "Pending broadcast. Check that the txid was not sent before paying this row again";
+
+/// A single payment from a batch file. `line` is the 1-based line number in the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchRow {
+    pub line: usize,
+    pub address: String,
+    pub amount: u64,
+    pub memo: Option<String>,
+}
+
+/// The outcome of a batch row, as written to the results file. Rows that haven't been sent yet have neither, and
+/// rows that may have been sent have both a txid and an error starting with `PENDING_BROADCAST`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchResult {
+    pub row: BatchRow,
+    pub txid: Option<String>,
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    pub fn is_paid(&self) -> bool {
+        self.txid.is_some()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.txid.is_some() && self.error.as_ref().map_or(false, |e| e.starts_with(PENDING_BROADCAST))
+    }
+}
+
+/// The results file that goes with a batch file, i.e., "payouts.csv" -> "payouts.results.csv"
+pub fn default_results_path(batch_path: &str) -> String {
+    match batch_path.strip_suffix(".csv") {
+        Some(stem) => format!("{}.results.csv", stem),
+        None => format!("{}.results.csv", batch_path),
+    }
+}
+
+/// Parse an amount in ZEC ("1.5", "1.5 ZEC") or zats ("150000000", "150000000 zats")
+pub fn parse_batch_amount(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let lower = s.to_lowercase();
+
+    if let Some(zec) = lower.strip_suffix("zec") {
+        zip321::parse_amount(zec.trim())
+    } else if let Some(zats) = lower.strip_suffix("zats").or(lower.strip_suffix("zat")) {
+        parse_zats(zats.trim(), s)
+    } else if s.contains('.') {
+        zip321::parse_amount(s)
+    } else {
+        parse_zats(s, s)
+    }
+}
+
+fn parse_zats(zats: &str, original: &str) -> Result<u64, String> {
+    if zats.is_empty() || !zats.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid amount '{}'", original));
+    }
+
+    zats.parse::<u64>()
+        .ok()
+        .filter(|a| *a <= zip321::MAX_MONEY)
+        .ok_or(format!("Amount '{}' is too large", original))
+}
+
+/// Split a CSV line into its fields. Fields can be quoted, with quotes inside them doubled
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("Unterminated quoted field".to_string());
+    }
+    fields.push(field);
+
+    Ok(fields)
+}
+
+fn escape_csv_field(s: &str) -> String {
+    if s.contains(',') || s.contains('"') || s.contains('\n') || s.contains('\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+// The non-empty lines of a file, along with their 1-based line numbers
+fn csv_lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim_end_matches('\r')))
+        .filter(|(_, l)| !l.trim().is_empty())
+}
+
+/// Parse a batch file, validating every row up front. If any row is invalid, all the errors are returned together
+/// so that nothing in the batch is sent.
+pub fn parse_batch<P: consensus::Parameters>(params: &P, contents: &str) -> Result<Vec<BatchRow>, String> {
+    let mut rows = vec![];
+    let mut errors = vec![];
+
+    for (i, (line, text)) in csv_lines(contents).enumerate() {
+        let fields = match split_csv_line(text) {
+            Ok(f) => f,
+            Err(e) => {
+                errors.push(format!("Line {}: {}", line, e));
+                continue;
+            }
+        };
+
+        // An optional header
+        if i == 0 && fields[0].trim().eq_ignore_ascii_case("address") {
+            continue;
+        }
+
+        match parse_row(params, line, &fields) {
+            Ok(row) => rows.push(row),
+            Err(e) => errors.push(format!("Line {}: {}", line, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    if rows.is_empty() {
+        return Err("The batch doesn't have any payments".to_string());
+    }
+
+    Ok(rows)
+}
+
+fn parse_row<P: consensus::Parameters>(params: &P, line: usize, fields: &[String]) -> Result<BatchRow, String> {
+    if fields.len() < 2 || fields.len() > 3 {
+        return Err(format!("Expected address,amount[,memo], found {} fields", fields.len()));
+    }
+
+    let address = fields[0].trim().to_string();
+    let recipient = unified::decode_recipient(params, &address)?;
+
+    let amount = parse_batch_amount(&fields[1])?;
+    if amount == 0 {
+        return Err("Amount must be more than 0".to_string());
+    }
+
+    let memo = fields.get(2).map(|m| m.to_string()).filter(|m| !m.is_empty());
+    if let Some(m) = &memo {
+        if let RecipientAddress::Transparent(_) = recipient {
+            return Err(format!("Can't send a memo to the transparent address {}", address));
+        }
+        utils::interpret_memo_string(m.clone())?;
+    }
+
+    Ok(BatchRow {
+        line,
+        address,
+        amount,
+        memo,
+    })
+}
+
+/// Parse a results file written by `write_results`
+pub fn read_results(contents: &str) -> Result<Vec<BatchResult>, String> {
+    let mut results = vec![];
+
+    for (i, (line, text)) in csv_lines(contents).enumerate() {
+        if i == 0 && text == RESULTS_HEADER {
+            continue;
+        }
+
+        let fields = split_csv_line(text).map_err(|e| format!("Results line {}: {}", line, e))?;
+        if fields.len() != 6 {
+            return Err(format!(
+                "Results line {}: Expected 6 fields, found {}",
+                line,
+                fields.len()
+            ));
+        }
+
+        let non_empty = |s: &String| if s.is_empty() { None } else { Some(s.clone()) };
+        results.push(BatchResult {
+            row: BatchRow {
+                line: fields[0]
+                    .parse::<usize>()
+                    .map_err(|_| format!("Results line {}: Invalid line number", line))?,
+                address: fields[1].clone(),
+                amount: fields[2]
+                    .parse::<u64>()
+                    .map_err(|_| format!("Results line {}: Invalid amount", line))?,
+                memo: non_empty(&fields[3]),
+            },
+            txid: non_empty(&fields[4]),
+            error: non_empty(&fields[5]),
+        });
+    }
+
+    Ok(results)
+}
+
+/// Write the results of a batch as CSV, one line per row. Amounts are always in zats
+pub fn write_results(results: &[BatchResult]) -> String {
+    let mut out = format!("{}\n", RESULTS_HEADER);
+
+    for r in results {
+        out.push_str(
+            &[
+                format!("{}", r.row.line),
+                escape_csv_field(&r.row.address),
+                format!("{}", r.row.amount),
+                escape_csv_field(r.row.memo.as_deref().unwrap_or("")),
+                r.txid.clone().unwrap_or_default(),
+                escape_csv_field(&r.error.as_deref().unwrap_or("").replace('\n', " ")),
+            ]
+            .join(","),
+        );
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Merge the rows of a batch with the results of a previous run, so a partially failed batch can be resumed.
+/// Rows that were already paid, or may have been, keep their txid, and everything else is reset so it gets sent
+/// again. Fails if
+/// a paid row doesn't match the batch any more, since resuming would then pay the wrong recipients.
+pub fn resume_batch(rows: Vec<BatchRow>, previous: Vec<BatchResult>) -> Result<Vec<BatchResult>, String> {
+    let mut paid = previous
+        .into_iter()
+        .filter(|r| r.is_paid())
+        .map(|r| (r.row.line, r))
+        .collect::<HashMap<_, _>>();
+
+    let results = rows
+        .into_iter()
+        .map(|row| match paid.remove(&row.line) {
+            Some(p) if p.row.address != row.address || p.row.amount != row.amount => Err(format!(
+                "Line {} was already paid to {} for {} zats, but is now {} for {} zats",
+                row.line, p.row.address, p.row.amount, row.address, row.amount
+            )),
+            Some(p) => Ok(BatchResult { row, ..p }),
+            None => Ok(BatchResult {
+                row,
+                txid: None,
+                error: None,
+            }),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(line) = paid.keys().min() {
+        return Err(format!("Line {} was already paid, but is no longer in the batch", line));
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use zcash_primitives::consensus::MainNetwork;
+
+    use super::{
+        default_results_path, parse_batch, parse_batch_amount, read_results, resume_batch, write_results,
+        PENDING_BROADCAST,
+    };
+
+    const ZADDR: &str = "zs1va5902apnzlhdu0pw9r9q7ca8s4vnsrp2alr6xndt69jnepn2v2qrj9vg3wfcnjyks5pg65g9dc";
+    const TADDR: &str = "t1NoS6ZgaUTpmjkge2cVpXGcySasdYDrXqh";
+
+    #[test]
+    fn amounts() {
+        assert_eq!(parse_batch_amount("1.5").unwrap(), 150_000_000);
+        assert_eq!(parse_batch_amount("2 ZEC").unwrap(), 200_000_000);
+        assert_eq!(parse_batch_amount("0.0001zec").unwrap(), 10_000);
+        assert_eq!(parse_batch_amount("10000").unwrap(), 10_000);
+        assert_eq!(parse_batch_amount(" 10000 zats ").unwrap(), 10_000);
+        assert_eq!(parse_batch_amount("1 zat").unwrap(), 1);
+        assert!(parse_batch_amount("1.5 zats").is_err());
+        assert!(parse_batch_amount("-1").is_err());
+        assert!(parse_batch_amount("").is_err());
+        assert!(parse_batch_amount("2100000000000001").is_err());
+    }
+
+    #[test]
+    fn parse_rows() {
+        let csv = format!(
+            "address,amount,memo\n{},1.5,\"Thanks, see you\"\n\n{},10000\r\n{},0.1,\"Say \"\"hi\"\"\"\n",
+            ZADDR, TADDR, ZADDR
+        );
+        let rows = parse_batch(&MainNetwork, &csv).unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[0].amount, 150_000_000);
+        assert_eq!(rows[0].memo, Some("Thanks, see you".to_string()));
+        assert_eq!(rows[1].line, 4);
+        assert_eq!(rows[1].address, TADDR);
+        assert_eq!(rows[1].memo, None);
+        assert_eq!(rows[2].memo, Some("Say \"hi\"".to_string()));
+    }
+
+    #[test]
+    fn invalid_rows() {
+        // Every invalid row is reported
+        let csv = format!(
+            "{},1\nnotanaddress,1\n{},1,memo\n{},0\n{}\n",
+            ZADDR, TADDR, ZADDR, ZADDR
+        );
+        let err = parse_batch(&MainNetwork, &csv).unwrap_err();
+        assert!(err.contains("Line 2: Invalid recipient address"));
+        assert!(err.contains("Line 3: Can't send a memo"));
+        assert!(err.contains("Line 4: Amount must be more than 0"));
+        assert!(err.contains("Line 5: Expected address,amount"));
+        assert!(!err.contains("Line 1"));
+
+        assert!(parse_batch(&MainNetwork, "address,amount\n").is_err());
+        assert!(parse_batch(&MainNetwork, &format!("{},\"1", ZADDR)).is_err());
+    }
+
+    #[test]
+    fn results_roundtrip() {
+        let csv = format!("{},1,\"a, memo\"\n{},2\n", ZADDR, TADDR);
+        let mut results = resume_batch(parse_batch(&MainNetwork, &csv).unwrap(), vec![]).unwrap();
+        results[0].txid = Some("abcd".to_string());
+        results[1].error = Some("Insufficient verified funds, \"oops\"".to_string());
+
+        let written = write_results(&results);
+        assert_eq!(read_results(&written).unwrap(), results);
+
+        assert_eq!(default_results_path("payouts.csv"), "payouts.results.csv");
+        assert_eq!(default_results_path("payouts"), "payouts.results.csv");
+    }
+
+    #[test]
+    fn resume() {
+        let csv = format!("{},1\n{},2\n{},3\n", ZADDR, TADDR, ZADDR);
+        let rows = parse_batch(&MainNetwork, &csv).unwrap();
+
+        let mut previous = resume_batch(rows.clone(), vec![]).unwrap();
+        previous[0].txid = Some("abcd".to_string());
+        previous[1].error = Some("failed".to_string());
+        previous[2].txid = Some("ef01".to_string());
+        previous[2].error = Some(PENDING_BROADCAST.to_string());
+
+        // The paid row keeps its txid, the failed one is sent again
+        let results = resume_batch(rows.clone(), previous.clone()).unwrap();
+        assert_eq!(results[0].txid, Some("abcd".to_string()));
+        assert!(!results[0].is_pending());
+        assert!(!results[1].is_paid());
+        assert_eq!(results[1].error, None);
+
+        // The one that may have been sent isn't sent again
+        assert!(results[2].is_paid());
+        assert!(results[2].is_pending());
+
+        // Neither is one whose broadcast failed
+        let mut failed = previous.clone();
+        failed[2].error = Some(format!("{}: Send Error: timed out", PENDING_BROADCAST));
+        let results = resume_batch(rows.clone(), failed).unwrap();
+        assert!(results[2].is_pending());
+        assert!(results[2].error.as_ref().unwrap().ends_with("timed out"));
+
+        // A paid row that changed can't be resumed
+        let changed = format!("{},1.5\n{},2\n", ZADDR, TADDR);
+        assert!(resume_batch(parse_batch(&MainNetwork, &changed).unwrap(), previous.clone()).is_err());
+
+        // Neither can one that was removed
+        let removed = format!("\n{},2\n{},3\n", TADDR, ZADDR);
+        assert!(resume_batch(parse_batch(&MainNetwork, &removed).unwrap(), previous).is_err());
+    }
+}
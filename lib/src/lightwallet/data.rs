@@ -2,12 +2,14 @@ use crate::compact_formats::CompactBlock;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use ff::PrimeField;
 use prost::Message;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::io::{self, Read, Write};
 use std::usize;
 use zcash_encoding::{Optional, Vector};
 use zcash_primitives::zip32::ExtendedFullViewingKey;
 
+use super::utils;
 use crate::blaze::fixed_size_buffer::FixedSizeBuffer;
 use zcash_primitives::{
     consensus::BlockHeight,
@@ -197,7 +199,7 @@ pub struct SaplingNoteData {
 }
 
 // Reading a note also needs the corresponding address to read from.
-fn read_rseed<R: Read>(mut reader: R) -> io::Result<Rseed> {
+pub(crate) fn read_rseed<R: Read>(mut reader: R) -> io::Result<Rseed> {
     let note_type = reader.read_u8()?;
 
     let mut r_bytes: [u8; 32] = [0; 32];
@@ -212,7 +214,7 @@ fn read_rseed<R: Read>(mut reader: R) -> io::Result<Rseed> {
     Ok(r)
 }
 
-fn write_rseed<W: Write>(mut writer: W, rseed: &Rseed) -> io::Result<()> {
+pub(crate) fn write_rseed<W: Write>(mut writer: W, rseed: &Rseed) -> io::Result<()> {
     let note_type = match rseed {
         Rseed::BeforeZip212(_) => 1,
         Rseed::AfterZip212(_) => 2,
@@ -576,6 +578,44 @@ impl OutgoingTxMetadata {
     }
 }
 
+/// A transaction sent by this wallet that hasn't been mined yet. The raw bytes are kept so the
+/// transaction can be broadcast again if the server drops it from its mempool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingSend {
+    pub raw_tx: Vec<u8>,
+
+    // The tx can't be mined in a block after this height. 0 means it never expires
+    pub expiry_height: u32,
+
+    // Height of the latest block when the tx was last broadcast
+    pub last_broadcast_height: u32,
+}
+
+impl PendingSend {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let raw_tx = Vector::read(&mut reader, |r| r.read_u8())?;
+        let expiry_height = reader.read_u32::<LittleEndian>()?;
+        let last_broadcast_height = reader.read_u32::<LittleEndian>()?;
+
+        Ok(PendingSend {
+            raw_tx,
+            expiry_height,
+            last_broadcast_height,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        Vector::write(&mut writer, &self.raw_tx, |w, b| w.write_u8(*b))?;
+        writer.write_u32::<LittleEndian>(self.expiry_height)?;
+        writer.write_u32::<LittleEndian>(self.last_broadcast_height)
+    }
+
+    /// If the tx can no longer be mined once the chain is at `latest_height`
+    pub fn is_expired(&self, latest_height: u64) -> bool {
+        self.expiry_height != 0 && latest_height >= self.expiry_height as u64
+    }
+}
+
 pub struct WalletTx {
     // Block in which this tx was included
     pub block: BlockHeight,
@@ -613,11 +653,14 @@ pub struct WalletTx {
 
     // Price of Zec when this Tx was created
     pub zec_price: Option<f64>,
+
+    // Fee paid by this Tx, if it was sent by this wallet. Added in v22
+    pub fee: Option<u64>,
 }
 
 impl WalletTx {
     pub fn serialized_version() -> u64 {
-        return 21;
+        return 22;
     }
 
     pub fn new_txid(txid: &Vec<u8>) -> TxId {
@@ -655,6 +698,7 @@ impl WalletTx {
             outgoing_metadata: vec![],
             full_tx_scanned: false,
             zec_price: None,
+            fee: None,
         }
     }
 
@@ -703,6 +747,12 @@ impl WalletTx {
             })?
         };
 
+        let fee = if version <= 21 {
+            None
+        } else {
+            Optional::read(&mut reader, |r| r.read_u64::<LittleEndian>())?
+        };
+
         Ok(Self {
             block,
             unconfirmed,
@@ -716,6 +766,7 @@ impl WalletTx {
             outgoing_metadata,
             full_tx_scanned,
             zec_price,
+            fee,
         })
     }
 
@@ -746,6 +797,8 @@ impl WalletTx {
 
         Vector::write(&mut writer, &self.spent_nullifiers, |w, n| w.write_all(&n.0))?;
 
+        Optional::write(&mut writer, self.fee, |w, f| w.write_u64::<LittleEndian>(f))?;
+
         Ok(())
     }
 
@@ -858,3 +911,84 @@ impl WalletZecPriceInfo {
         Ok(())
     }
 }
+
+// Labels for addresses the user pays often, so they can be used in place of the address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddressBook {
+    // label -> address, sorted by label
+    entries: BTreeMap<String, String>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    /// Add a label for `address`, or change the address of an existing label.
+    /// The address is expected to be already validated
+    pub fn add(&mut self, label: &str, address: &str) -> Result<(), String> {
+        if label.is_empty() || label.chars().any(|c| c.is_whitespace() || c == ',') {
+            return Err(format!(
+                "Label '{}' can't be empty or have spaces or commas in it",
+                label
+            ));
+        }
+
+        self.entries.insert(label.to_string(), address.to_string());
+        Ok(())
+    }
+
+    /// Remove a label, returning the address it was for
+    pub fn remove(&mut self, label: &str) -> Option<String> {
+        self.entries.remove(label)
+    }
+
+    pub fn get_address(&self, label: &str) -> Option<&String> {
+        self.entries.get(label)
+    }
+
+    /// The first label of `address`, if it has one
+    pub fn get_label(&self, address: &str) -> Option<&String> {
+        self.entries.iter().find(|(_, a)| *a == address).map(|(l, _)| l)
+    }
+
+    /// All the (label, address) entries, sorted by label
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.iter()
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version > Self::serialized_version() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Can't read AddressBook because of incorrect version",
+            ));
+        }
+
+        let entries = Vector::read(&mut reader, |r| {
+            let label = utils::read_string(&mut *r)?;
+            let address = utils::read_string(&mut *r)?;
+
+            Ok((label, address))
+        })?;
+
+        Ok(Self {
+            entries: entries.into_iter().collect(),
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(Self::serialized_version())?;
+
+        let entries = self.entries.iter().collect::<Vec<_>>();
+        Vector::write(&mut writer, &entries, |w, (label, address)| {
+            utils::write_string(&mut *w, label)?;
+            utils::write_string(&mut *w, address)
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use tokio::sync::broadcast;
+use zcash_primitives::transaction::TxId;
+
+use crate::blaze::sync_status::SyncStatus;
+
+// How many events a subscriber can fall behind before it starts missing the oldest ones
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// The steps of an outgoing tx, in the order they happen
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendStep {
+    SelectingNotes,
+    // The prover reports how many of the spends and outputs have been proven so far
+    BuildingTransaction { progress: u32, total: u32 },
+    Broadcasting,
+    Finished { txid: String },
+    Failed { error: String },
+}
+
+/// Something that changed in the wallet or in the sync, for library users that want to react to changes
+/// instead of polling the sync status or the send progress
+#[derive(Debug, Clone)]
+pub enum WalletEvent {
+    SyncBatchStarted {
+        batch_num: usize,
+        batch_total: usize,
+        start_block: u64,
+        end_block: u64,
+    },
+    SyncProgress(SyncStatus),
+    SyncBatchFinished {
+        batch_num: usize,
+        batch_total: usize,
+        height: u64,
+    },
+
+    // A note or utxo we haven't seen before, either in the mempool or in a block
+    NewIncomingNote {
+        txid: TxId,
+        height: u64,
+        value: u64,
+        is_change: bool,
+        mempool: bool,
+    },
+    // A note or utxo that we first saw in the mempool was mined
+    NoteConfirmed {
+        txid: TxId,
+        height: u64,
+        value: u64,
+    },
+    // The block at this height was reorged out, along with all the wallet txns in it
+    ReorgDetected {
+        height: u64,
+    },
+
+    SendProgress {
+        id: u32,
+        step: SendStep,
+    },
+    PriceUpdated {
+        currency: String,
+        price: f64,
+    },
+}
+
+/// The sending half of the wallet's event stream. Cloning it gives another handle to the same stream.
+#[derive(Clone)]
+pub struct WalletEvents {
+    tx: broadcast::Sender<WalletEvent>,
+}
+
+impl WalletEvents {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WalletEvent> {
+        self.tx.subscribe()
+    }
+
+    pub fn emit(&self, event: WalletEvent) {
+        // This only fails if nobody is subscribed, in which case there's no one to tell
+        let _ = self.tx.send(event);
+    }
+}
+
+impl Default for WalletEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
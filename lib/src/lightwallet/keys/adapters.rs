@@ -169,6 +169,18 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Keystores<P> {
         memory.into_iter().flatten().chain(ledger.into_iter().flatten())
     }
 
+    /// Retrieve the OVK of the key that `address` belongs to
+    pub async fn get_ovk_of_address(&self, address: &PaymentAddress) -> Option<OutgoingViewingKey> {
+        //both iterators go over the keys in the same order
+        let ovks = self.get_all_ovks().await;
+
+        self.get_all_ivks()
+            .await
+            .zip(ovks)
+            .find(|(ivk, _)| ivk.to_payment_address(*address.diversifier()).as_ref() == Some(address))
+            .map(|(_, ovk)| ovk)
+    }
+
     /// Retrieve all known transparent addresses in the keystore
     pub async fn get_all_taddrs(&self) -> (impl Iterator<Item = String>, impl Iterator<Item = String>) {
         //see comment inside `get_all_ivks`
@@ -282,12 +294,22 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Keystores<P> {
         }
     }
 
+    /// Returns the OVK and payment address of the key `add_zaddr` would create next, without creating it
+    pub async fn next_zkey(&self) -> Option<(OutgoingViewingKey, PaymentAddress)> {
+        match self {
+            Self::Memory(this) => this.next_zkey().map(|zk| (zk.extfvk.fvk.ovk, zk.zaddress)),
+            //the ledger derives new keys on the device, which can't be done without adding them
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => None,
+        }
+    }
+
     /// Compute wheter the given `addr` is a shielded address w.r.t. the given set of params
     pub fn is_shielded_address(addr: &String, params: &P) -> bool {
         use zcash_client_backend::address::RecipientAddress;
 
-        match RecipientAddress::decode(params, addr) {
-            Some(RecipientAddress::Shielded(_)) => true,
+        match crate::lightwallet::unified::decode_recipient(params, addr) {
+            Ok(RecipientAddress::Shielded(_)) => true,
             _ => false,
         }
     }
@@ -372,6 +394,74 @@ impl<P: consensus::Parameters + Send + Sync + 'static> Keystores<P> {
         }
     }
 
+    /// Create a new unified address, bundling a new shielded address and a new transparent address.
+    /// Returns the unified address and the derivation paths of its receivers
+    pub async fn add_uaddr(&mut self) -> (String, String) {
+        match self {
+            Self::Memory(this) => (this.add_uaddr(), "".to_string()),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => (
+                "Error: Unified addresses aren't supported with a Ledger device yet".to_string(),
+                "".to_string(),
+            ),
+        }
+    }
+
+    /// Create a new diversified address of the key that `zaddr` belongs to.
+    /// Returns the new address, the address of the key and the diversifier index
+    pub async fn add_diversified_zaddr(&mut self, zaddr: &str) -> Result<(String, String, u64), String> {
+        match self {
+            Self::Memory(this) => this.add_diversified_zaddr(zaddr),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => Err("Diversified addresses aren't supported with a Ledger device yet".to_string()),
+        }
+    }
+
+    /// Create a new diversified address of the key with `ivk` to receive change, if the keystore supports it
+    pub async fn add_change_zaddr(&mut self, ivk: &SaplingIvk) -> Option<PaymentAddress> {
+        match self {
+            Self::Memory(this) => this.add_change_zaddr(ivk),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => None,
+        }
+    }
+
+    /// The address `add_change_zaddr` would create next for the key with `ivk`, without recording it
+    pub async fn next_change_zaddr(&self, ivk: &SaplingIvk) -> Option<PaymentAddress> {
+        match self {
+            Self::Memory(this) => this.next_change_zaddr(ivk),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => None,
+        }
+    }
+
+    /// Whether `address` is one of the change addresses created in the keystore
+    pub async fn is_change_address(&self, address: &PaymentAddress) -> bool {
+        match self {
+            Self::Memory(this) => this.is_change_address(address),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => false,
+        }
+    }
+
+    /// Retrieve all the unified addresses in the keystore
+    pub async fn get_all_uaddresses(&self) -> Vec<String> {
+        match self {
+            Self::Memory(this) => this.get_all_uaddresses(),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => vec![],
+        }
+    }
+
+    /// Retrieve all the diversified addresses in the keystore, as (address, address of the key, diversifier index)
+    pub async fn get_all_diversified_zaddresses(&self) -> Vec<(String, String, u64)> {
+        match self {
+            Self::Memory(this) => this.get_all_diversified_zaddresses(),
+            #[cfg(feature = "ledger-support")]
+            Self::Ledger(_) => vec![],
+        }
+    }
+
     //this is the same code as Note's cm_full_point
     fn compute_note_commitment(note: &Note) -> jubjub::SubgroupPoint {
         use byteorder::{LittleEndian, WriteBytesExt};
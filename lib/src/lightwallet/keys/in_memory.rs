@@ -9,16 +9,24 @@ use rand::{rngs::OsRng, Rng};
 use sodiumoxide::crypto::secretbox;
 use zcash_client_backend::{
     address,
-    encoding::{encode_extended_full_viewing_key, encode_extended_spending_key, encode_payment_address},
+    encoding::{
+        decode_payment_address, encode_extended_full_viewing_key, encode_extended_spending_key, encode_payment_address,
+    },
 };
 use zcash_encoding::Vector;
-use zcash_primitives::{consensus::BlockHeight, consensus, legacy::TransparentAddress, sapling::{PaymentAddress, SaplingIvk}, zip32::{ChildIndex, ExtendedFullViewingKey, ExtendedSpendingKey}};
+use zcash_primitives::{
+    consensus,
+    consensus::BlockHeight,
+    legacy::TransparentAddress,
+    sapling::{PaymentAddress, SaplingIvk},
+    zip32::{ChildIndex, DiversifierIndex, ExtendedFullViewingKey, ExtendedSpendingKey},
+};
 
 use crate::{
     lightclient::lightclient_config::{LightClientConfig, GAP_RULE_UNUSED_ADDRESSES},
     lightwallet::{
         keys::{double_sha256, InsecureKeystore, Keystore, KeystoreBuilderLifetime, ToBase58Check},
-        utils,
+        unified, utils,
         wallettkey::{WalletTKey, WalletTKeyType},
         walletzkey::{WalletZKey, WalletZKeyType},
     },
@@ -53,11 +61,14 @@ pub struct InMemoryKeys<P> {
     // Transparent keys. If the wallet is locked, then the secret keys will be encrypted,
     // but the addresses will be present. This Vec contains both wallet and imported tkeys
     pub(crate) tkeys: Vec<WalletTKey>,
+
+    // Unified addresses created in this wallet. Their receivers are addresses of the keys above
+    pub(crate) uaddrs: Vec<String>,
 }
 
 impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
     pub fn serialized_version() -> u64 {
-        return 21;
+        return 22;
     }
 
     #[cfg(test)]
@@ -72,6 +83,7 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             seed: [0u8; 32],
             zkeys: vec![],
             tkeys: vec![],
+            uaddrs: vec![],
         }
     }
 
@@ -104,6 +116,7 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             seed: seed_bytes,
             zkeys: vec![],
             tkeys: vec![],
+            uaddrs: vec![],
         };
 
         // Derive only the first sk and address
@@ -242,6 +255,8 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             Vector::read(&mut reader, |r| WalletTKey::read(r))?
         };
 
+        let uaddrs = vec![];
+
         Ok(Self {
             config: config.clone(),
             encrypted,
@@ -251,6 +266,7 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             seed: seed_bytes,
             zkeys,
             tkeys,
+            uaddrs,
         })
     }
 
@@ -297,6 +313,12 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             Vector::read(&mut reader, |r| WalletTKey::read(r))?
         };
 
+        let uaddrs = if version <= 21 {
+            vec![]
+        } else {
+            Vector::read(&mut reader, |r| utils::read_string(r))?
+        };
+
         Ok(Self {
             config: config.clone(),
             encrypted,
@@ -306,6 +328,7 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             seed: seed_bytes,
             zkeys,
             tkeys,
+            uaddrs,
         })
     }
 
@@ -334,6 +357,9 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
         // Write the transparent private keys
         Vector::write(&mut writer, &self.tkeys, |w, sk| sk.write(w))?;
 
+        // Write the unified addresses
+        Vector::write(&mut writer, &self.uaddrs, |w, a| utils::write_string(w, a))?;
+
         Ok(())
     }
 
@@ -502,6 +528,15 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
             return "Error: Can't add key while wallet is locked".to_string();
         }
 
+        //only error available is if the wallet is locked but we already checked it
+        let newkey = self.next_zkey().unwrap();
+        self.zkeys.push(newkey.clone());
+
+        encode_payment_address(self.config.hrp_sapling_address(), &newkey.zaddress)
+    }
+
+    /// The key `add_zaddr` would add next, without adding it. Returns `None` if the wallet is locked
+    pub fn next_zkey(&self) -> Option<WalletZKey> {
         // Find the highest pos we have
         let pos = self
             .zkeys
@@ -514,14 +549,75 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
         let extsk = futures::executor::block_on(
             self.get_z_private_spending_key(&Self::z_derivation_path(self.config.get_coin_type(), pos)),
         )
-        //only error available is if the wallet is locked but we already checked it
-        .unwrap();
+        .ok()?;
 
-        // let zaddr = encode_payment_address(self.config.hrp_sapling_address(), &address);
-        let newkey = WalletZKey::new_hdkey(pos, extsk);
-        self.zkeys.push(newkey.clone());
+        Some(WalletZKey::new_hdkey(pos, extsk))
+    }
 
-        encode_payment_address(self.config.hrp_sapling_address(), &newkey.zaddress)
+    /// Adds a new diversified address of the key that `zaddr` belongs to. The key is the same, so no new IVK has to be
+    /// trial-decrypted with, and the address is detected without a rescan.
+    /// Returns the new address, the address of the key and the diversifier index.
+    pub fn add_diversified_zaddr(&mut self, zaddr: &str) -> Result<(String, String, u64), String> {
+        let address = match decode_payment_address(self.config.hrp_sapling_address(), zaddr) {
+            Ok(Some(pa)) => pa,
+            _ => return Err(format!("{} is not a valid z address", zaddr)),
+        };
+
+        let zkey = self
+            .zkeys
+            .iter_mut()
+            .find(|zk| zk.extfvk.fvk.vk.ivk().to_payment_address(*address.diversifier()) == Some(address.clone()))
+            .ok_or(format!("{} doesn't belong to this wallet", zaddr))?;
+
+        let (index, new_address) = zkey
+            .new_diversified_address()
+            .ok_or("No more diversified addresses left for this key".to_string())?;
+
+        Ok((
+            encode_payment_address(self.config.hrp_sapling_address(), &new_address),
+            encode_payment_address(self.config.hrp_sapling_address(), &zkey.zaddress),
+            diversifier_index_to_u64(&index),
+        ))
+    }
+
+    /// Create a new diversified address of the key with `ivk` to receive change
+    pub fn add_change_zaddr(&mut self, ivk: &SaplingIvk) -> Option<PaymentAddress> {
+        self.zkeys
+            .iter_mut()
+            .find(|zk| zk.extfvk.fvk.vk.ivk().to_repr() == ivk.to_repr())
+            .and_then(|zk| zk.new_change_address())
+    }
+
+    /// The diversified address `add_change_zaddr` would create next for the key with `ivk`, without recording it
+    pub fn next_change_zaddr(&self, ivk: &SaplingIvk) -> Option<PaymentAddress> {
+        self.zkeys
+            .iter()
+            .find(|zk| zk.extfvk.fvk.vk.ivk().to_repr() == ivk.to_repr())
+            .and_then(|zk| zk.next_diversified_address())
+            .map(|(_, address)| address)
+    }
+
+    /// Whether `address` is one of the change addresses created in this wallet
+    pub fn is_change_address(&self, address: &PaymentAddress) -> bool {
+        self.zkeys.iter().any(|zk| zk.is_change_address(address))
+    }
+
+    /// All the diversified addresses created in this wallet, as (address, address of the key, diversifier index)
+    pub fn get_all_diversified_zaddresses(&self) -> Vec<(String, String, u64)> {
+        let hrp = self.config.hrp_sapling_address();
+
+        self.zkeys
+            .iter()
+            .flat_map(|zk| {
+                zk.diversified_addresses().into_iter().map(move |(index, pa)| {
+                    (
+                        encode_payment_address(hrp, &pa),
+                        encode_payment_address(hrp, &zk.zaddress),
+                        diversifier_index_to_u64(&index),
+                    )
+                })
+            })
+            .collect()
     }
 
     pub const fn t_derivation_path(coin_type: u32, index: u32) -> [ChildIndex; 5] {
@@ -564,6 +660,43 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
         address
     }
 
+    /// Adds a new unified address to the wallet, bundling a new z address and a new t address. It has no Orchard
+    /// receiver, since the wallet can't detect Orchard notes. The unified address is remembered, so it is listed with
+    /// the wallet's addresses.
+    /// NOTE: This will not rescan the wallet
+    pub fn add_uaddr(&mut self) -> String {
+        let zaddr = self.add_zaddr();
+        if zaddr.starts_with("Error") {
+            return zaddr;
+        }
+        let taddr = self.add_taddr();
+        if taddr.starts_with("Error") {
+            return taddr;
+        }
+
+        let params = self.config.get_params();
+        let sapling = match address::RecipientAddress::decode(&params, &zaddr) {
+            Some(address::RecipientAddress::Shielded(pa)) => pa,
+            _ => return format!("Error: Invalid new z address {}", zaddr),
+        };
+        let transparent = match address::RecipientAddress::decode(&params, &taddr) {
+            Some(address::RecipientAddress::Transparent(ta)) => ta,
+            _ => return format!("Error: Invalid new t address {}", taddr),
+        };
+
+        match unified::encode_address(&params, Some(&sapling), Some(&transparent)) {
+            Ok(ua) => {
+                self.uaddrs.push(ua.clone());
+                ua
+            }
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+
+    pub fn get_all_uaddresses(&self) -> Vec<String> {
+        self.uaddrs.clone()
+    }
+
     // Get all z-address private keys. Returns a Vector of (address, privatekey, viewkey)
     pub fn get_z_private_keys(&self) -> Vec<(String, String, String)> {
         let keys = self
@@ -592,6 +725,50 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
         keys
     }
 
+    // The chain code and public key of the BIP-44 account that all the HD t addresses are derived from
+    fn get_t_account_pubkey(&self) -> Option<(Vec<u8>, secp256k1::PublicKey)> {
+        use crate::lightwallet::extended_key::{ExtendedPrivKey, KeyIndex};
+
+        if !self.unlocked {
+            return None;
+        }
+
+        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).ok()?, "");
+
+        let mut ext_t_key = ExtendedPrivKey::with_seed(bip39_seed.as_bytes()).ok()?;
+        for index in [44, self.config.get_coin_type(), 0] {
+            ext_t_key = ext_t_key
+                .derive_private_key(KeyIndex::hardened_from_normalize_index(index).ok()?)
+                .ok()?;
+        }
+
+        let secp = secp256k1::Secp256k1::signing_only();
+        let pubkey = secp256k1::PublicKey::from_secret_key(&secp, &ext_t_key.private_key);
+
+        Some((ext_t_key.chain_code, pubkey))
+    }
+
+    /// Get the ZIP-316 unified full viewing key of every z address. Returns a Vector of (address, ufvk).
+    /// All the HD t addresses are derived from account 0, so only the first HD z address' ufvk includes them.
+    pub fn get_unified_full_viewing_keys(&self) -> Vec<(String, String)> {
+        let t_account = self.get_t_account_pubkey();
+
+        self.zkeys
+            .iter()
+            .map(|k| {
+                let transparent = match (k.hdkey_num, &t_account) {
+                    (Some(0), Some((chain_code, pubkey))) => Some((chain_code.as_slice(), pubkey)),
+                    _ => None,
+                };
+
+                (
+                    encode_payment_address(self.config.hrp_sapling_address(), &k.zaddress),
+                    unified::encode_ufvk(&self.config.get_params(), &k.extfvk, transparent),
+                )
+            })
+            .collect()
+    }
+
     /// Get all t-address private keys. Returns a Vector of (address, secretkey)
     pub fn get_t_secret_keys(&self) -> Vec<(String, String)> {
         self.tkeys
@@ -784,8 +961,8 @@ impl<P: consensus::Parameters + Send + Sync+ 'static> InMemoryKeys<P> {
     }
 
     pub fn is_shielded_address(addr: &String, config: &LightClientConfig<P>) -> bool {
-        match address::RecipientAddress::decode(&config.get_params(), addr) {
-            Some(address::RecipientAddress::Shielded(_)) => true,
+        match crate::lightwallet::unified::decode_recipient(&config.get_params(), addr) {
+            Ok(address::RecipientAddress::Shielded(_)) => true,
             _ => false,
         }
     }
@@ -881,3 +1058,11 @@ impl <P: consensus::Parameters + Send + Sync+ 'static>Keystore for InMemoryKeys<
         Ok(InMemoryBuilder::new(self.config.get_params(), target_height, self))
     }
 }
+
+/// Diversifier indexes are 88 bit numbers, but the ones we create count up from the default address,
+/// so they fit in a u64
+fn diversifier_index_to_u64(index: &DiversifierIndex) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&index.0[..8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,188 @@
+use rand::{rngs::OsRng, seq::SliceRandom};
+use zcash_primitives::consensus::BlockHeight;
+
+use super::data::SpendableNote;
+
+/// A note that could be spent, along with the height of the block it was mined in
+pub struct CandidateNote {
+    pub spendable: SpendableNote,
+    pub height: BlockHeight,
+}
+
+/// Decides the order in which candidate notes are picked when selecting notes for a transaction.
+/// Notes are picked from the front until the target value is reached.
+pub trait NoteSelectionStrategy: Send + Sync {
+    fn order(&self, candidates: &mut Vec<CandidateNote>);
+}
+
+/// Pick the largest notes first. This minimizes the number of notes spent
+pub struct LargestFirst;
+
+impl NoteSelectionStrategy for LargestFirst {
+    fn order(&self, candidates: &mut Vec<CandidateNote>) {
+        candidates.sort_by(|a, b| b.spendable.note.value.cmp(&a.spendable.note.value));
+    }
+}
+
+/// Pick the smallest notes first, consolidating small notes as a side effect of sending
+pub struct SmallestFirst;
+
+impl NoteSelectionStrategy for SmallestFirst {
+    fn order(&self, candidates: &mut Vec<CandidateNote>) {
+        candidates.sort_by(|a, b| a.spendable.note.value.cmp(&b.spendable.note.value));
+    }
+}
+
+/// Pick the notes that were received first
+pub struct OldestFirst;
+
+impl NoteSelectionStrategy for OldestFirst {
+    fn order(&self, candidates: &mut Vec<CandidateNote>) {
+        candidates.sort_by(|a, b| a.height.cmp(&b.height));
+    }
+}
+
+/// Pick notes in a random order, so the selected notes don't reveal anything about the wallet's other notes
+pub struct RandomOrder;
+
+impl NoteSelectionStrategy for RandomOrder {
+    fn order(&self, candidates: &mut Vec<CandidateNote>) {
+        candidates.shuffle(&mut OsRng);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zcash_primitives::consensus::BlockHeight;
+
+    use crate::{
+        blaze::test_utils::FakeCompactBlockList,
+        lightclient::{
+            lightclient_config::UnitTestNetwork,
+            test_server::{create_test_server, mine_pending_blocks, mine_random_blocks},
+            LightClient,
+        },
+        lightwallet::{data::SpendableNote, FeePolicy, NoteSelectionOption},
+    };
+
+    use super::{CandidateNote, LargestFirst, NoteSelectionStrategy, OldestFirst, RandomOrder, SmallestFirst};
+
+    // A wallet that received notes of the given values in separate blocks. The server is shut down before the
+    // wallet is returned, since choosing notes doesn't need it
+    async fn wallet_with_notes(values: &[u64]) -> LightClient<UnitTestNetwork> {
+        let (data, config, ready_rx, stop_tx, h1) = create_test_server(UnitTestNetwork).await;
+        ready_rx.await.unwrap();
+
+        let lc = LightClient::test_new(&config, None, 0).await.unwrap();
+        let mut fcbl = FakeCompactBlockList::new(0);
+        mine_random_blocks(&mut fcbl, &data, &lc, 10).await;
+
+        let extfvk1 = lc
+            .wallet
+            .in_memory_keys()
+            .await
+            .expect("in memory keystore")
+            .get_all_extfvks()[0]
+            .clone();
+
+        for value in values {
+            fcbl.add_tx_paying(&extfvk1, *value);
+            mine_pending_blocks(&mut fcbl, &data, &lc).await;
+        }
+        mine_random_blocks(&mut fcbl, &data, &lc, 5).await;
+
+        // Shutdown everything cleanly
+        stop_tx.send(true).unwrap();
+        h1.await.unwrap();
+
+        lc
+    }
+
+    // The wallet's spendable notes, in the order they are stored in
+    async fn candidates(lc: &LightClient<UnitTestNetwork>) -> Vec<CandidateNote> {
+        lc.wallet
+            .txns
+            .read()
+            .await
+            .current
+            .iter()
+            .flat_map(|(txid, wtx)| {
+                wtx.notes.iter().filter_map(move |nd| {
+                    SpendableNote::from(*txid, nd, 0, &nd.ivk).map(|spendable| CandidateNote {
+                        spendable,
+                        height: wtx.block,
+                    })
+                })
+            })
+            .collect::<Vec<_>>()
+    }
+
+    fn values(candidates: &Vec<CandidateNote>) -> Vec<u64> {
+        candidates.iter().map(|c| c.spendable.note.value).collect()
+    }
+
+    #[tokio::test]
+    async fn strategies() {
+        let lc = wallet_with_notes(&[20_000, 50_000, 10_000, 30_000]).await;
+
+        let mut c = candidates(&lc).await;
+        LargestFirst.order(&mut c);
+        assert_eq!(values(&c), vec![50_000, 30_000, 20_000, 10_000]);
+
+        let mut c = candidates(&lc).await;
+        SmallestFirst.order(&mut c);
+        assert_eq!(values(&c), vec![10_000, 20_000, 30_000, 50_000]);
+
+        let mut c = candidates(&lc).await;
+        OldestFirst.order(&mut c);
+        assert_eq!(values(&c), vec![20_000, 50_000, 10_000, 30_000]);
+
+        let heights = c.iter().map(|c| c.height).collect::<Vec<BlockHeight>>();
+        assert!(heights.windows(2).all(|w| w[0] < w[1]));
+
+        // Same notes, in whatever order
+        let mut c = candidates(&lc).await;
+        RandomOrder.order(&mut c);
+        let mut v = values(&c);
+        v.sort();
+        assert_eq!(v, vec![10_000, 20_000, 30_000, 50_000]);
+    }
+
+    #[tokio::test]
+    async fn wallet_uses_selected_strategy() {
+        use zcash_primitives::transaction::components::Amount;
+
+        let lc = wallet_with_notes(&[20_000, 50_000, 10_000]).await;
+        let amt = Amount::from_u64(5_000).unwrap();
+
+        // Largest first needs only the 50k note
+        lc.wallet.set_note_selection(NoteSelectionOption::LargestFirst).await;
+        let (notes, _, _, _) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
+        assert_eq!(notes.iter().map(|n| n.note.value).collect::<Vec<_>>(), vec![50_000]);
+
+        // Smallest first needs the 10k and the 20k notes
+        lc.wallet.set_note_selection(NoteSelectionOption::SmallestFirst).await;
+        let (notes, _, _, _) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
+        assert_eq!(
+            notes.iter().map(|n| n.note.value).collect::<Vec<_>>(),
+            vec![10_000, 20_000]
+        );
+
+        // Oldest first spends the 20k note
+        lc.wallet.set_note_selection(NoteSelectionOption::OldestFirst).await;
+        let (notes, _, _, _) = lc
+            .wallet
+            .select_notes_and_utxos(amt, false, false, 0, 0, true, &[], FeePolicy::Zip317)
+            .await
+            .unwrap();
+        assert_eq!(notes.iter().map(|n| n.note.value).collect::<Vec<_>>(), vec![20_000]);
+    }
+}
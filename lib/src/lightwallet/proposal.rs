@@ -0,0 +1,278 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ff::PrimeField;
+use zcash_encoding::Vector;
+use zcash_primitives::{
+    keys::OutgoingViewingKey,
+    memo::MemoBytes,
+    merkle_tree::IncrementalWitness,
+    sapling::{Diversifier, Node, Nullifier, PaymentAddress, SaplingIvk},
+    transaction::TxId,
+};
+
+use super::{
+    data::{read_rseed, write_rseed, SpendableNote, Utxo},
+    utils::{read_string, write_string},
+};
+
+/// A single output of a proposed transaction
+#[derive(Clone, Debug)]
+pub struct ProposalOutput {
+    pub address: String,
+    pub value: u64,
+    pub memo: MemoBytes,
+}
+
+impl ProposalOutput {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let address = read_string(&mut reader)?;
+        let value = reader.read_u64::<LittleEndian>()?;
+
+        let memo_bytes = Vector::read(&mut reader, |r| r.read_u8())?;
+        let memo = MemoBytes::from_bytes(&memo_bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't read memo"))?;
+
+        Ok(Self { address, value, memo })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        write_string(&mut writer, &self.address)?;
+        writer.write_u64::<LittleEndian>(self.value)?;
+
+        Vector::write(&mut writer, self.memo.as_slice(), |w, b| w.write_u8(*b))
+    }
+}
+
+/// An unsigned transaction, as selected by a (possibly watch-only) online wallet.
+///
+/// The proposal carries everything an offline wallet needs to build and sign the
+/// transaction: the notes being spent along with their witnesses, the utxos, the
+/// outputs and the fee. It doesn't contain any spending key material.
+#[derive(Clone)]
+pub struct TxProposal {
+    pub chain_name: String,
+    pub consensus_branch_id: u32,
+    pub target_height: u32,
+    pub fee: u64,
+
+    pub notes: Vec<SpendableNote>,
+    pub utxos: Vec<Utxo>,
+    pub outputs: Vec<ProposalOutput>,
+
+    // Where change goes, and the ovk used to encrypt the outgoing outputs
+    pub ovk: OutgoingViewingKey,
+    pub change_address: PaymentAddress,
+}
+
+impl TxProposal {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    pub fn total_input(&self) -> u64 {
+        self.notes.iter().map(|n| n.note.value).sum::<u64>() + self.utxos.iter().map(|u| u.value).sum::<u64>()
+    }
+
+    pub fn total_output(&self) -> u64 {
+        self.outputs.iter().map(|o| o.value).sum::<u64>()
+    }
+
+    /// The value that will be returned to the wallet as change
+    pub fn change(&self) -> u64 {
+        self.total_input().saturating_sub(self.total_output() + self.fee)
+    }
+
+    /// The address of the first spent note, which is where the builder sends change by default
+    pub fn spent_note_address(&self) -> Option<PaymentAddress> {
+        self.notes
+            .first()
+            .and_then(|sn| sn.ivk.to_payment_address(sn.diversifier))
+    }
+
+    fn read_note<R: Read>(mut reader: R) -> io::Result<SpendableNote> {
+        let mut txid_bytes = [0u8; 32];
+        reader.read_exact(&mut txid_bytes)?;
+        let txid = TxId::from_bytes(txid_bytes);
+
+        let mut nullifier = [0u8; 32];
+        reader.read_exact(&mut nullifier)?;
+        let nullifier = Nullifier(nullifier);
+
+        let mut ivk_bytes = [0u8; 32];
+        reader.read_exact(&mut ivk_bytes)?;
+        let ivk = jubjub::Fr::from_repr(ivk_bytes);
+        if ivk.is_none().into() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid note ivk"));
+        }
+        let ivk = SaplingIvk(ivk.unwrap());
+
+        let mut diversifier_bytes = [0u8; 11];
+        reader.read_exact(&mut diversifier_bytes)?;
+        let diversifier = Diversifier { 0: diversifier_bytes };
+
+        let value = reader.read_u64::<LittleEndian>()?;
+        let rseed = read_rseed(&mut reader)?;
+
+        let note = ivk
+            .to_payment_address(diversifier)
+            .and_then(|addr| addr.create_note(value, rseed))
+            .ok_or(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Couldn't create the note for the address",
+            ))?;
+
+        let witness = IncrementalWitness::<Node>::read(&mut reader)?;
+
+        Ok(SpendableNote {
+            txid,
+            nullifier,
+            diversifier,
+            note,
+            witness,
+            ivk,
+        })
+    }
+
+    fn write_note<W: Write>(mut writer: W, sn: &SpendableNote) -> io::Result<()> {
+        writer.write_all(sn.txid.as_ref())?;
+        writer.write_all(&sn.nullifier.0)?;
+        writer.write_all(&sn.ivk.to_repr())?;
+        writer.write_all(&sn.diversifier.0)?;
+
+        writer.write_u64::<LittleEndian>(sn.note.value)?;
+        write_rseed(&mut writer, &sn.note.rseed)?;
+
+        sn.witness.write(&mut writer)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version > Self::serialized_version() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't read proposal version {}", version),
+            ));
+        }
+
+        let chain_name = read_string(&mut reader)?;
+        let consensus_branch_id = reader.read_u32::<LittleEndian>()?;
+        let target_height = reader.read_u32::<LittleEndian>()?;
+        let fee = reader.read_u64::<LittleEndian>()?;
+
+        let notes = Vector::read(&mut reader, |r| Self::read_note(r))?;
+        let utxos = Vector::read(&mut reader, |r| Utxo::read(r))?;
+        let outputs = Vector::read(&mut reader, |r| ProposalOutput::read(r))?;
+
+        let mut ovk = [0u8; 32];
+        reader.read_exact(&mut ovk)?;
+        let ovk = OutgoingViewingKey(ovk);
+
+        let mut addr_bytes = [0u8; 43];
+        reader.read_exact(&mut addr_bytes)?;
+        let change_address = PaymentAddress::from_bytes(&addr_bytes)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid change address"))?;
+
+        Ok(Self {
+            chain_name,
+            consensus_branch_id,
+            target_height,
+            fee,
+            notes,
+            utxos,
+            outputs,
+            ovk,
+            change_address,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        // Write the version
+        writer.write_u64::<LittleEndian>(Self::serialized_version())?;
+
+        write_string(&mut writer, &self.chain_name)?;
+        writer.write_u32::<LittleEndian>(self.consensus_branch_id)?;
+        writer.write_u32::<LittleEndian>(self.target_height)?;
+        writer.write_u64::<LittleEndian>(self.fee)?;
+
+        Vector::write(&mut writer, &self.notes, |w, sn| Self::write_note(w, sn))?;
+        Vector::write(&mut writer, &self.utxos, |w, u| u.write(w))?;
+        Vector::write(&mut writer, &self.outputs, |w, o| o.write(w))?;
+
+        writer.write_all(&self.ovk.0)?;
+        writer.write_all(&self.change_address.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ff::PrimeField;
+    use zcash_primitives::{
+        memo::MemoBytes,
+        merkle_tree::{CommitmentTree, IncrementalWitness},
+        sapling::{Node, Nullifier, Rseed},
+        transaction::TxId,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use crate::lightwallet::data::SpendableNote;
+
+    use super::{ProposalOutput, TxProposal};
+
+    #[test]
+    fn proposal_roundtrip() {
+        let extsk = ExtendedSpendingKey::master(&[1u8; 32]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let addr = extfvk.default_address().1;
+        let diversifier = *addr.diversifier();
+        let ivk = extfvk.fvk.vk.ivk();
+
+        let note = addr.create_note(100_000, Rseed::AfterZip212([7u8; 32])).unwrap();
+
+        let mut tree = CommitmentTree::<Node>::empty();
+        tree.append(Node::new(note.cmu().to_repr())).unwrap();
+        let witness = IncrementalWitness::from_tree(&tree);
+
+        let proposal = TxProposal {
+            chain_name: "test".to_string(),
+            consensus_branch_id: 0xc2d6d0b4,
+            target_height: 1_000,
+            fee: 10_000,
+            notes: vec![SpendableNote {
+                txid: TxId::from_bytes([3u8; 32]),
+                nullifier: Nullifier([5u8; 32]),
+                diversifier,
+                note: note.clone(),
+                witness,
+                ivk,
+            }],
+            utxos: vec![],
+            outputs: vec![ProposalOutput {
+                address: "ztestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d"
+                    .to_string(),
+                value: 90_000,
+                memo: MemoBytes::from_bytes("hello".as_bytes()).unwrap(),
+            }],
+            ovk: extfvk.fvk.ovk,
+            change_address: addr.clone(),
+        };
+
+        let mut buf = vec![];
+        proposal.write(&mut buf).unwrap();
+
+        let read = TxProposal::read(&buf[..]).unwrap();
+        assert_eq!(read.chain_name, proposal.chain_name);
+        assert_eq!(read.consensus_branch_id, proposal.consensus_branch_id);
+        assert_eq!(read.target_height, proposal.target_height);
+        assert_eq!(read.fee, proposal.fee);
+        assert_eq!(read.notes.len(), 1);
+        assert_eq!(read.notes[0].note, note);
+        assert_eq!(read.notes[0].nullifier, proposal.notes[0].nullifier);
+        assert_eq!(read.notes[0].witness.root(), proposal.notes[0].witness.root());
+        assert_eq!(read.outputs[0].address, proposal.outputs[0].address);
+        assert_eq!(read.outputs[0].memo.as_slice(), proposal.outputs[0].memo.as_slice());
+        assert_eq!(read.change_address, addr);
+        assert_eq!(read.total_input(), 100_000);
+        assert_eq!(read.total_output(), 90_000);
+    }
+}
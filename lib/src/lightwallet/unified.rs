@@ -0,0 +1,430 @@
+use std::convert::TryInto;
+use std::io::{self, Read};
+
+use bech32::{FromBase32, ToBase32, Variant};
+use blake2b_simd::Params;
+use zcash_client_backend::address::RecipientAddress;
+use zcash_encoding::CompactSize;
+use zcash_primitives::{consensus, legacy::TransparentAddress, sapling::PaymentAddress, zip32::ExtendedFullViewingKey};
+
+// ZIP-316 Unified Addresses and Unified Full Viewing Keys.
+//
+// A unified encoding is a list of (typecode, data) items, sorted by typecode. The raw encoding of the items is
+// padded with the HRP, jumbled with F4Jumble and then Bech32m encoded.
+
+pub const TYPECODE_P2PKH: u32 = 0x00;
+pub const TYPECODE_P2SH: u32 = 0x01;
+pub const TYPECODE_SAPLING: u32 = 0x02;
+pub const TYPECODE_ORCHARD: u32 = 0x03;
+
+const PADDING_LEN: usize = 16;
+
+// F4Jumble message lengths, in bytes
+const MIN_LEN: usize = 48;
+const MAX_LEN: usize = 4194368;
+
+/// The kind of unified encoding, which determines the HRP
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnifiedKind {
+    Address,
+    FullViewingKey,
+}
+
+/// The HRP of a unified encoding on the network of `params`
+pub fn hrp<P: consensus::Parameters>(params: &P, kind: UnifiedKind) -> &'static str {
+    let network = params.hrp_sapling_payment_address();
+    match (kind, network) {
+        (UnifiedKind::Address, "zs") => "u",
+        (UnifiedKind::Address, "zregtestsapling") => "uregtest",
+        (UnifiedKind::Address, _) => "utest",
+        (UnifiedKind::FullViewingKey, "zs") => "uview",
+        (UnifiedKind::FullViewingKey, "zregtestsapling") => "uviewregtest",
+        (UnifiedKind::FullViewingKey, _) => "uviewtest",
+    }
+}
+
+fn h_pers(i: u8) -> [u8; 16] {
+    let mut p = *b"UA_F4Jumble_H___";
+    p[13..].copy_from_slice(&[i, 0, 0]);
+    p
+}
+
+fn g_pers(i: u8, j: u16) -> [u8; 16] {
+    let mut p = *b"UA_F4Jumble_G___";
+    p[13] = i;
+    p[14..].copy_from_slice(&j.to_le_bytes());
+    p
+}
+
+// left ^= H_i(right)
+fn h_round(i: u8, left: &mut [u8], right: &[u8]) {
+    let hash = Params::new().hash_length(left.len()).personal(&h_pers(i)).hash(right);
+    left.iter_mut().zip(hash.as_bytes()).for_each(|(l, h)| *l ^= h);
+}
+
+// right ^= G_i(left)
+fn g_round(i: u8, left: &[u8], right: &mut [u8]) {
+    for (j, chunk) in right.chunks_mut(64).enumerate() {
+        let hash = Params::new().hash_length(64).personal(&g_pers(i, j as u16)).hash(left);
+        chunk.iter_mut().zip(hash.as_bytes()).for_each(|(r, h)| *r ^= h);
+    }
+}
+
+fn split_len(len: usize) -> Option<usize> {
+    if len < MIN_LEN || len > MAX_LEN {
+        None
+    } else {
+        Some(std::cmp::min(64, len / 2))
+    }
+}
+
+/// The F4Jumble permutation from ZIP-316
+pub fn f4jumble(message: &[u8]) -> Option<Vec<u8>> {
+    let mut m = message.to_vec();
+    let (left, right) = m.split_at_mut(split_len(message.len())?);
+
+    g_round(0, left, right);
+    h_round(0, left, right);
+    g_round(1, left, right);
+    h_round(1, left, right);
+
+    Some(m)
+}
+
+/// The inverse of `f4jumble`
+pub fn f4jumble_inv(message: &[u8]) -> Option<Vec<u8>> {
+    let mut m = message.to_vec();
+    let (left, right) = m.split_at_mut(split_len(message.len())?);
+
+    h_round(1, left, right);
+    g_round(1, left, right);
+    h_round(0, left, right);
+    g_round(0, left, right);
+
+    Some(m)
+}
+
+fn padding(hrp: &str) -> [u8; PADDING_LEN] {
+    let mut p = [0u8; PADDING_LEN];
+    p[..hrp.len()].copy_from_slice(hrp.as_bytes());
+    p
+}
+
+/// Encode the items of a unified address or viewing key. Items are sorted by typecode. The encoding must be at least
+/// 48 bytes long, which any encoding with a shielded item is
+pub fn encode(hrp: &str, items: &[(u32, Vec<u8>)]) -> String {
+    let mut items = items.to_vec();
+    items.sort_by_key(|(typecode, _)| *typecode);
+
+    let mut raw = vec![];
+    for (typecode, data) in &items {
+        CompactSize::write(&mut raw, *typecode as usize).unwrap();
+        CompactSize::write(&mut raw, data.len()).unwrap();
+        raw.extend_from_slice(data);
+    }
+    raw.extend_from_slice(&padding(hrp));
+
+    let jumbled = f4jumble(&raw).expect("unified encoding has a valid length");
+    bech32::encode(hrp, jumbled.to_base32(), Variant::Bech32m).expect("valid hrp")
+}
+
+/// Decode a unified encoding with the expected `hrp` into its items. Returns None if the encoding is invalid
+pub fn decode(hrp: &str, s: &str) -> Option<Vec<(u32, Vec<u8>)>> {
+    let (decoded_hrp, data, variant) = bech32::decode(s).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32m {
+        return None;
+    }
+
+    let raw = f4jumble_inv(&Vec::<u8>::from_base32(&data).ok()?)?;
+    let (raw, pad) = raw.split_at(raw.len() - PADDING_LEN);
+    if pad != padding(hrp) {
+        return None;
+    }
+
+    let mut reader = raw;
+    let mut items: Vec<(u32, Vec<u8>)> = vec![];
+    while !reader.is_empty() {
+        let item = (|| -> io::Result<(u32, Vec<u8>)> {
+            let typecode = CompactSize::read(&mut reader)?;
+            let len = CompactSize::read(&mut reader)?;
+
+            let mut data = vec![0u8; len as usize];
+            reader.read_exact(&mut data)?;
+
+            Ok((typecode as u32, data))
+        })()
+        .ok()?;
+
+        // Items must be in strictly increasing typecode order, which also rules out duplicates
+        if items.last().map(|(t, _)| *t >= item.0).unwrap_or(false) {
+            return None;
+        }
+        items.push(item);
+    }
+
+    if items.is_empty() {
+        return None;
+    }
+
+    Some(items)
+}
+
+/// Encode a unified address with the given Sapling and transparent receivers
+pub fn encode_address<P: consensus::Parameters>(
+    params: &P,
+    sapling: Option<&PaymentAddress>,
+    transparent: Option<&TransparentAddress>,
+) -> Result<String, String> {
+    let mut items = vec![];
+
+    if let Some(pa) = sapling {
+        items.push((TYPECODE_SAPLING, pa.to_bytes().to_vec()));
+    }
+    match transparent {
+        Some(TransparentAddress::PublicKey(hash)) => items.push((TYPECODE_P2PKH, hash.to_vec())),
+        Some(TransparentAddress::Script(hash)) => items.push((TYPECODE_P2SH, hash.to_vec())),
+        None => {}
+    }
+
+    // A unified address needs at least one shielded receiver
+    if sapling.is_none() {
+        return Err("A unified address needs a shielded receiver".to_string());
+    }
+
+    Ok(encode(hrp(params, UnifiedKind::Address), &items))
+}
+
+/// Decode a unified address into the receiver we can send to, which is its Sapling receiver, or its transparent
+/// receiver if it has no Sapling one. Receivers with typecodes we don't know are skipped.
+pub fn decode_address<P: consensus::Parameters>(params: &P, s: &str) -> Result<RecipientAddress, String> {
+    let invalid = || format!("Invalid recipient address: '{}'", s);
+    let items = decode(hrp(params, UnifiedKind::Address), s).ok_or_else(invalid)?;
+
+    // Known receivers must have the right lengths, and there can't be both a P2PKH and a P2SH receiver
+    let mut sapling = None;
+    let mut transparent = None;
+    let mut shielded = false;
+    for (typecode, data) in &items {
+        match *typecode {
+            TYPECODE_P2PKH | TYPECODE_P2SH => {
+                let hash: [u8; 20] = data.as_slice().try_into().map_err(|_| invalid())?;
+                if transparent.is_some() {
+                    return Err(invalid());
+                }
+                transparent = Some(if *typecode == TYPECODE_P2PKH {
+                    TransparentAddress::PublicKey(hash)
+                } else {
+                    TransparentAddress::Script(hash)
+                });
+            }
+            TYPECODE_SAPLING => {
+                let bytes: [u8; 43] = data.as_slice().try_into().map_err(|_| invalid())?;
+                sapling = Some(PaymentAddress::from_bytes(&bytes).ok_or_else(invalid)?);
+                shielded = true;
+            }
+            TYPECODE_ORCHARD => {
+                if data.len() != 43 {
+                    return Err(invalid());
+                }
+                shielded = true;
+            }
+            _ => {}
+        }
+    }
+
+    // A unified address needs at least one shielded receiver, which has to be one of the known shielded types
+    if !shielded {
+        return Err(invalid());
+    }
+
+    match (sapling, transparent) {
+        (Some(pa), _) => Ok(RecipientAddress::Shielded(pa)),
+        (None, Some(ta)) => Ok(RecipientAddress::Transparent(ta)),
+        (None, None) => Err(format!(
+            "Unified address '{}' has no receiver this wallet can send to",
+            s
+        )),
+    }
+}
+
+/// Decode a Sapling, transparent or unified address. For unified addresses, see `decode_address`
+pub fn decode_recipient<P: consensus::Parameters>(params: &P, s: &str) -> Result<RecipientAddress, String> {
+    match RecipientAddress::decode(params, s) {
+        Some(ra) => Ok(ra),
+        None => decode_address(params, s),
+    }
+}
+
+/// Encode a unified full viewing key with the given Sapling key, and optionally the BIP-44 account level
+/// transparent key as (chain code, compressed public key)
+pub fn encode_ufvk<P: consensus::Parameters>(
+    params: &P,
+    sapling: &ExtendedFullViewingKey,
+    transparent: Option<(&[u8], &secp256k1::PublicKey)>,
+) -> String {
+    // The Sapling item is (ak, nk, ovk, dk), i.e., the extended key without its ZIP-32 header
+    let mut extfvk = vec![];
+    sapling.write(&mut extfvk).unwrap();
+    let mut items = vec![(TYPECODE_SAPLING, extfvk[extfvk.len() - 128..].to_vec())];
+
+    if let Some((chain_code, pubkey)) = transparent {
+        let mut data = chain_code.to_vec();
+        data.extend_from_slice(&pubkey.serialize());
+        items.push((TYPECODE_P2PKH, data));
+    }
+
+    encode(hrp(params, UnifiedKind::FullViewingKey), &items)
+}
+
+#[cfg(test)]
+mod test {
+    use zcash_client_backend::address::RecipientAddress;
+    use zcash_primitives::{
+        consensus::{MainNetwork, TestNetwork},
+        legacy::TransparentAddress,
+        sapling::PaymentAddress,
+        zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    };
+
+    use super::{
+        decode, decode_address, decode_recipient, encode, encode_address, encode_ufvk, f4jumble, f4jumble_inv,
+    };
+
+    #[test]
+    fn f4jumble_roundtrip() {
+        for len in [48, 64, 65, 127, 128, 129, 200, 1000] {
+            let m = (0..len).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+            let jumbled = f4jumble(&m).unwrap();
+            assert_ne!(jumbled, m);
+            assert_eq!(f4jumble_inv(&jumbled).unwrap(), m);
+        }
+
+        assert!(f4jumble(&[0u8; 47]).is_none());
+    }
+
+    #[test]
+    fn f4jumble_test_vector() {
+        // From the ZIP-316 F4Jumble test vectors
+        let normal = [
+            0x5d, 0x7a, 0x8f, 0x73, 0x9a, 0x2d, 0x9e, 0x94, 0x5b, 0x0c, 0xe1, 0x52, 0xa8, 0x04, 0x9e, 0x29, 0x4c, 0x4d,
+            0x6e, 0x66, 0xb1, 0x64, 0x93, 0x9d, 0xaf, 0xfa, 0x2e, 0xf6, 0xee, 0x69, 0x21, 0x48, 0x1c, 0xdd, 0x86, 0xb3,
+            0xcc, 0x43, 0x18, 0xd9, 0x61, 0x4f, 0xc8, 0x20, 0x90, 0x5d, 0x04, 0x2b,
+        ];
+        let jumbled = [
+            0x03, 0x04, 0xd0, 0x29, 0x14, 0x1b, 0x99, 0x5d, 0xa5, 0x38, 0x7c, 0x12, 0x59, 0x70, 0x67, 0x35, 0x04, 0xd6,
+            0xc7, 0x64, 0xd9, 0x1e, 0xa6, 0xc0, 0x82, 0x12, 0x37, 0x70, 0xc7, 0x13, 0x9c, 0xcd, 0x88, 0xee, 0x27, 0x36,
+            0x8c, 0xd0, 0xc0, 0x92, 0x1a, 0x04, 0x44, 0xc8, 0xe5, 0x85, 0x8d, 0x22,
+        ];
+
+        assert_eq!(f4jumble(&normal).unwrap(), jumbled);
+        assert_eq!(f4jumble_inv(&jumbled).unwrap(), normal);
+    }
+
+    #[test]
+    fn address_test_vector() {
+        // From the ZIP-316 unified address test vectors, with a P2PKH and a Sapling receiver
+        let ua = "u1l9f0l4348negsncgr9pxd9d3qaxagmqv3lnexcplmufpq7muffvfaue6ksevfvd7wrz7xrvn95rc5zjtn7ugkmgh5rnxswmcj30y0pw52pn0zjvy38rn2esfgve64rj5pcmazxgpyuj";
+        let p2pkh = hex::decode("d66905f9e11334c098c89b34399732827d0a0e2f").unwrap();
+        let sapling =
+            hex::decode("607d667c03acca80e6e258fdafe27a85ad081427b64108cd87eb2cd5c878c2c6cdd8bdf72a5adbfc67a9a9")
+                .unwrap();
+
+        let items = decode("u", ua).unwrap();
+        assert_eq!(items, vec![(0, p2pkh.clone()), (2, sapling.clone())]);
+        assert_eq!(encode("u", &items), ua);
+
+        let mut sapling_bytes = [0u8; 43];
+        sapling_bytes.copy_from_slice(&sapling);
+        let pa = PaymentAddress::from_bytes(&sapling_bytes).unwrap();
+        assert_eq!(
+            decode_address(&MainNetwork, ua),
+            Ok(RecipientAddress::Shielded(pa.clone()))
+        );
+
+        let mut p2pkh_bytes = [0u8; 20];
+        p2pkh_bytes.copy_from_slice(&p2pkh);
+        assert_eq!(
+            encode_address(
+                &MainNetwork,
+                Some(&pa),
+                Some(&TransparentAddress::PublicKey(p2pkh_bytes))
+            ),
+            Ok(ua.to_string())
+        );
+    }
+
+    #[test]
+    fn address_roundtrip() {
+        let extsk = ExtendedSpendingKey::master(&[1u8; 32]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+        let zaddr = extfvk.default_address().1;
+        let taddr = TransparentAddress::PublicKey([7u8; 20]);
+
+        let ua = encode_address(&MainNetwork, Some(&zaddr), Some(&taddr)).unwrap();
+        assert!(ua.starts_with("u1"));
+
+        // The sapling receiver is preferred
+        assert_eq!(
+            decode_recipient(&MainNetwork, &ua),
+            Ok(RecipientAddress::Shielded(zaddr.clone()))
+        );
+
+        // Wrong network
+        assert!(decode_address(&TestNetwork, &ua).is_err());
+        let ua_test = encode_address(&TestNetwork, Some(&zaddr), None).unwrap();
+        assert!(ua_test.starts_with("utest1"));
+        assert!(decode_address(&TestNetwork, &ua_test).is_ok());
+
+        // Transparent only UAs aren't allowed
+        assert!(encode_address(&MainNetwork, None, Some(&taddr)).is_err());
+
+        // If the only shielded receiver is one we don't support, the transparent one is used
+        let orchard_and_t = encode("u", &[(0, vec![7u8; 20]), (3, vec![1u8; 43])]);
+        assert_eq!(
+            decode_address(&MainNetwork, &orchard_and_t),
+            Ok(RecipientAddress::Transparent(taddr))
+        );
+        assert_eq!(
+            decode_recipient(&MainNetwork, &orchard_and_t),
+            Ok(RecipientAddress::Transparent(taddr))
+        );
+        assert!(decode_address(&MainNetwork, &encode("u", &[(3, vec![1u8; 43])])).is_err());
+
+        // Unknown typecodes are skipped, and don't count as a shielded receiver
+        let unknown_and_t = encode("u", &[(0, vec![7u8; 20]), (0x40, vec![1u8; 43])]);
+        assert!(decode_address(&MainNetwork, &unknown_and_t).is_err());
+        let sapling_and_unknown = encode("u", &[(2, zaddr.to_bytes().to_vec()), (0x40, vec![1u8; 43])]);
+        assert_eq!(
+            decode_address(&MainNetwork, &sapling_and_unknown),
+            Ok(RecipientAddress::Shielded(zaddr.clone()))
+        );
+
+        // Corrupted
+        let mut bad = ua.clone();
+        bad.replace_range(10..11, if &ua[10..11] == "q" { "p" } else { "q" });
+        assert!(decode_address(&MainNetwork, &bad).is_err());
+
+        // Duplicate typecodes and wrong lengths
+        assert!(decode(
+            "u",
+            &encode("u", &[(2, zaddr.to_bytes().to_vec()), (2, zaddr.to_bytes().to_vec())])
+        )
+        .is_none());
+        assert!(decode_address(&MainNetwork, &encode("u", &[(2, vec![1u8; 42])])).is_err());
+    }
+
+    #[test]
+    fn ufvk_encoding() {
+        let extsk = ExtendedSpendingKey::master(&[1u8; 32]);
+        let extfvk = ExtendedFullViewingKey::from(&extsk);
+
+        let ufvk = encode_ufvk(&MainNetwork, &extfvk, None);
+        assert!(ufvk.starts_with("uview1"));
+
+        let items = decode("uview", &ufvk).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].0, 2);
+        assert_eq!(items[0].1.len(), 128);
+        assert_eq!(&items[0].1[64..96], &extfvk.fvk.ovk.0);
+    }
+}
@@ -9,14 +9,17 @@ use zcash_encoding::Vector;
 use zcash_primitives::{
     consensus::BlockHeight,
     memo::Memo,
-    merkle_tree::IncrementalWitness,
+    merkle_tree::{CommitmentTree, IncrementalWitness},
     sapling::{Node, Note, Nullifier, PaymentAddress, SaplingIvk},
     transaction::{components::TxOut, TxId},
 };
 
-use crate::lightclient::lightclient_config::MAX_REORG;
+use crate::{blaze::block_witness_data::rewind_witness, lightclient::lightclient_config::MAX_REORG};
 
-use super::data::{OutgoingTxMetadata, SaplingNoteData, Utxo, WalletTx, WitnessCache};
+use super::{
+    data::{OutgoingTxMetadata, PendingSend, SaplingNoteData, Utxo, WalletTx, WitnessCache},
+    events::{WalletEvent, WalletEvents},
+};
 
 /// List of all transactions in a wallet.
 /// Note that the parent is expected to hold a RwLock, so we will assume that all accesses to
@@ -25,11 +28,17 @@ use super::data::{OutgoingTxMetadata, SaplingNoteData, Utxo, WalletTx, WitnessCa
 pub struct WalletTxns {
     pub(crate) current: HashMap<TxId, WalletTx>,
     pub(crate) last_txid: Option<TxId>,
+
+    // Raw txns we sent that haven't been mined yet, so they can be rebroadcast
+    pub(crate) pending_sends: HashMap<TxId, PendingSend>,
+
+    // Non-serialized. The wallet emits its own events through a clone of this
+    pub(crate) events: WalletEvents,
 }
 
 impl WalletTxns {
     pub fn serialized_version() -> u64 {
-        return 21;
+        return 22;
     }
 
     pub fn new() -> Self {
@@ -49,6 +58,8 @@ impl WalletTxns {
         Ok(Self {
             current: txs,
             last_txid: None,
+            pending_sends: HashMap::new(),
+            events: WalletEvents::new(),
         })
     }
 
@@ -94,7 +105,25 @@ impl WalletTxns {
             vec![]
         };
 
-        Ok(Self { current, last_txid })
+        let pending_sends = if version <= 21 {
+            HashMap::new()
+        } else {
+            Vector::read(&mut reader, |r| {
+                let mut txid_bytes = [0u8; 32];
+                r.read_exact(&mut txid_bytes)?;
+
+                Ok((TxId::from_bytes(txid_bytes), PendingSend::read(r)?))
+            })?
+            .into_iter()
+            .collect()
+        };
+
+        Ok(Self {
+            current,
+            last_txid,
+            pending_sends,
+            events: WalletEvents::new(),
+        })
     }
 
     pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
@@ -113,11 +142,23 @@ impl WalletTxns {
             })?;
         }
 
+        // Pending sends, also sorted
+        {
+            let mut pending = self.pending_sends.iter().collect::<Vec<(&TxId, &PendingSend)>>();
+            pending.sort_by(|a, b| a.0.partial_cmp(b.0).unwrap());
+
+            Vector::write(&mut writer, &pending, |w, (k, v)| {
+                w.write_all(k.as_ref())?;
+                v.write(w)
+            })?;
+        }
+
         Ok(())
     }
 
     pub fn clear(&mut self) {
         self.current.clear();
+        self.pending_sends.clear();
     }
 
     pub fn adjust_spendable_status(&mut self, spendable_keys: Vec<SaplingIvk>) {
@@ -202,6 +243,58 @@ impl WalletTxns {
         }
     }
 
+    // When a sync batch is abandoned, remove the confirmed txns it found above the last scanned height, and all spends
+    // that refer to them. Witnesses don't need trimming, since they are only updated once all the blocks are fetched.
+    pub fn remove_txns_above_height(&mut self, height: u64) {
+        let height = BlockHeight::from_u32(height as u32);
+
+        let txids_to_remove = self
+            .current
+            .values()
+            .filter(|wtx| !wtx.unconfirmed && wtx.block > height)
+            .map(|wtx| wtx.txid.clone())
+            .collect::<Vec<_>>();
+        self.remove_txids(txids_to_remove);
+    }
+
+    // When rolling back to `height`, further back than the witnesses go, rewind the witnesses of the notes that will be
+    // unspent once the txns above `height` are removed, to when the commitment tree was `tree`. Fails without changing
+    // any witnesses if one of them can't be rewound that far.
+    pub(crate) fn rewind_witnesses(&mut self, height: u64, tree: &CommitmentTree<Node>) -> Result<(), String> {
+        let block = BlockHeight::from_u32(height as u32);
+
+        let mut rewound = vec![];
+        for wtx in self
+            .current
+            .values()
+            .filter(|wtx| !wtx.unconfirmed && wtx.block <= block)
+        {
+            for (i, nd) in wtx.notes.iter().enumerate() {
+                // Notes spent at or below `height` stay spent, and don't need witnesses
+                let unspent = nd.spent.map(|(_, h)| h > height as u32).unwrap_or(true);
+                if !nd.have_spending_key || !unspent {
+                    continue;
+                }
+
+                match nd.witnesses.last().and_then(|w| rewind_witness(w, tree)) {
+                    Some(w) => rewound.push((wtx.txid, i, w)),
+                    None => {
+                        return Err(format!(
+                            "Couldn't rewind the witnesses of note {} of tx {} to height {}",
+                            i, wtx.txid, height
+                        ))
+                    }
+                }
+            }
+        }
+
+        for (txid, i, w) in rewound {
+            self.current.get_mut(&txid).unwrap().notes[i].witnesses = WitnessCache::new(vec![w], height);
+        }
+
+        Ok(())
+    }
+
     pub fn get_last_txid(&self) -> &'_ Option<TxId> {
         &self.last_txid
     }
@@ -268,8 +361,10 @@ impl WalletTxns {
             .witnesses = witnesses;
     }
 
+    // Spent notes keep their witnesses for twice MAX_REORG blocks, so a reorg deeper than the blocks the wallet keeps
+    // can unspend them and rewind their witnesses
     pub(crate) fn clear_old_witnesses(&mut self, latest_height: u64) {
-        let cutoff = (latest_height.saturating_sub(MAX_REORG as u64)) as u32;
+        let cutoff = (latest_height.saturating_sub(2 * MAX_REORG as u64)) as u32;
 
         self.current.iter_mut().for_each(|(_, wtx)| {
             wtx.notes
@@ -296,6 +391,47 @@ impl WalletTxns {
         self.remove_txids(txids_to_remove);
     }
 
+    /// Remember the raw bytes of a tx we just broadcast, so it can be rebroadcast until it is mined
+    pub fn add_pending_send(&mut self, txid: TxId, raw_tx: Vec<u8>, expiry_height: u32, latest_height: u32) {
+        self.pending_sends.insert(
+            txid,
+            PendingSend {
+                raw_tx,
+                expiry_height,
+                last_broadcast_height: latest_height,
+            },
+        );
+    }
+
+    /// Forget the pending sends that were mined, have expired or were removed from the wallet
+    pub(crate) fn clear_pending_sends(&mut self, latest_height: u64) {
+        let current = &self.current;
+        self.pending_sends.retain(|txid, ps| {
+            let pending = current.get(txid).map(|wtx| wtx.unconfirmed).unwrap_or(false);
+            if pending && ps.is_expired(latest_height) {
+                info!("Pending send {} expired at height {}", txid, ps.expiry_height);
+            }
+
+            pending && !ps.is_expired(latest_height)
+        });
+    }
+
+    /// The pending sends that haven't been broadcast in the last `interval` blocks
+    pub fn pending_sends_to_rebroadcast(&self, latest_height: u64, interval: u32) -> Vec<(TxId, Vec<u8>)> {
+        self.pending_sends
+            .iter()
+            .filter(|(_, ps)| !ps.is_expired(latest_height))
+            .filter(|(_, ps)| latest_height >= (ps.last_broadcast_height + interval) as u64)
+            .map(|(txid, ps)| (txid.clone(), ps.raw_tx.clone()))
+            .collect()
+    }
+
+    pub fn set_pending_send_broadcast(&mut self, txid: &TxId, latest_height: u32) {
+        if let Some(ps) = self.pending_sends.get_mut(txid) {
+            ps.last_broadcast_height = latest_height;
+        }
+    }
+
     // Will mark the nullifier of the given txid as spent. Returns the amount of the nullifier
     pub fn mark_txid_nf_spent(
         &mut self,
@@ -455,17 +591,29 @@ impl WalletTxns {
         vout: &TxOut,
         output_num: u32,
     ) {
+        let was_unconfirmed = self.current.get(&txid).map(|wtx| wtx.unconfirmed).unwrap_or(false);
+
         // Read or create the current TxId
         let wtx = self.get_or_create_tx(&txid, BlockHeight::from(height), unconfirmed, timestamp);
 
         // Add this UTXO if it doesn't already exist
-        if let Some(utxo) = wtx
+        let event = if let Some(utxo) = wtx
             .utxos
             .iter_mut()
             .find(|utxo| utxo.txid == txid && utxo.output_index == output_num as u64)
         {
             // If it already exists, it is likely an mempool tx, so update the height
-            utxo.height = height as i32
+            utxo.height = height as i32;
+
+            if was_unconfirmed && !unconfirmed {
+                Some(WalletEvent::NoteConfirmed {
+                    txid: txid.clone(),
+                    height: height as u64,
+                    value: utxo.value,
+                })
+            } else {
+                None
+            }
         } else {
             wtx.utxos.push(Utxo {
                 address: taddr,
@@ -478,6 +626,18 @@ impl WalletTxns {
                 spent: None,
                 unconfirmed_spent: None,
             });
+
+            Some(WalletEvent::NewIncomingNote {
+                txid: txid.clone(),
+                height: height as u64,
+                value: vout.value.into(),
+                is_change: false,
+                mempool: unconfirmed,
+            })
+        };
+
+        if let Some(event) = event {
+            self.events.emit(event);
         }
     }
 
@@ -489,9 +649,11 @@ impl WalletTxns {
         note: Note,
         to: PaymentAddress,
         ivk: &SaplingIvk,
+        at_change_address: bool,
     ) {
-        // Check if this is a change note
-        let is_change = self.total_funds_spent_in(&txid) > 0;
+        // Notes received at one of our change addresses are change. Change sent back to the address of a spent note
+        // can only be told apart by this tx spending our funds
+        let is_change = at_change_address || self.total_funds_spent_in(&txid) > 0;
 
         let wtx = self.get_or_create_tx(&txid, BlockHeight::from(height), true, timestamp);
         // Update the block height, in case this was a mempool or unconfirmed tx.
@@ -499,6 +661,7 @@ impl WalletTxns {
 
         match wtx.notes.iter_mut().find(|n| n.note == note) {
             None => {
+                let value = note.value;
                 let nd = SaplingNoteData {
                     ivk: SaplingIvk(ivk.0.clone()),
                     diversifier: *to.diversifier(),
@@ -513,6 +676,14 @@ impl WalletTxns {
                 };
 
                 wtx.notes.push(nd);
+
+                self.events.emit(WalletEvent::NewIncomingNote {
+                    txid,
+                    height: u64::from(height),
+                    value,
+                    is_change,
+                    mempool: true,
+                });
             }
             Some(_) => {}
         }
@@ -530,9 +701,11 @@ impl WalletTxns {
         nullifier: Nullifier,
         have_spending_key: bool,
         witness: IncrementalWitness<Node>,
+        at_change_address: bool,
     ) {
-        // Check if this is a change note
-        let is_change = self.total_funds_spent_in(&txid) > 0;
+        // Notes received at one of our change addresses are change. Change sent back to the address of a spent note
+        // can only be told apart by this tx spending our funds
+        let is_change = at_change_address || self.total_funds_spent_in(&txid) > 0;
 
         let wtx = self.get_or_create_tx(&txid, BlockHeight::from(height), unconfirmed, timestamp);
         // Update the block height, in case this was a mempool or unconfirmed tx.
@@ -546,6 +719,10 @@ impl WalletTxns {
 
         match wtx.notes.iter_mut().find(|n| n.nullifier == nullifier) {
             None => {
+                // If we saw this note in the mempool, it has now been mined
+                let was_pending = wtx.notes.iter().any(|n| n.nullifier.0 == [0u8; 32] && n.note == note);
+                let value = note.value;
+
                 let nd = SaplingNoteData {
                     ivk: SaplingIvk(ivk.0.clone()),
                     diversifier: *to.diversifier(),
@@ -563,6 +740,19 @@ impl WalletTxns {
 
                 // Also remove any pending notes.
                 wtx.notes.retain(|n| n.nullifier.0 != [0u8; 32]);
+
+                let height = u64::from(height);
+                self.events.emit(if was_pending {
+                    WalletEvent::NoteConfirmed { txid, height, value }
+                } else {
+                    WalletEvent::NewIncomingNote {
+                        txid,
+                        height,
+                        value,
+                        is_change,
+                        mempool: unconfirmed,
+                    }
+                });
             }
             Some(n) => {
                 // If this note already exists, then just reset the witnesses, because we'll start scanning the witnesses
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::io::{self, Read, Write};
 use std::io::{Error, ErrorKind};
 
@@ -9,7 +10,7 @@ use zcash_encoding::{Optional, Vector};
 use zcash_primitives::{
     consensus,
     sapling::PaymentAddress,
-    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey},
+    zip32::{DiversifierIndex, ExtendedFullViewingKey, ExtendedSpendingKey},
 };
 
 use crate::lightclient::lightclient_config::LightClientConfig;
@@ -35,6 +36,15 @@ pub struct WalletZKey {
     // If this is a HD key, what is the key number
     pub(super) hdkey_num: Option<u32>,
 
+    // The diversifier indexes of the diversified addresses created from this key, in the order they were created
+    pub(super) diversifiers: Vec<DiversifierIndex>,
+
+    // The diversifier indexes in `diversifiers` of the addresses created to receive change
+    pub(super) change_diversifiers: Vec<DiversifierIndex>,
+
+    // The diversifiers of the change addresses, so a note's address can be checked without deriving them again
+    change_address_diversifiers: HashSet<[u8; 11]>,
+
     // If locked, the encrypted private key is stored here
     enc_key: Option<Vec<u8>>,
     nonce: Option<Vec<u8>>,
@@ -54,6 +64,9 @@ impl WalletZKey {
             hdkey_num: Some(hdkey_num),
             enc_key: None,
             nonce: None,
+            diversifiers: vec![],
+            change_diversifiers: vec![],
+            change_address_diversifiers: HashSet::new(),
         }
     }
 
@@ -69,6 +82,9 @@ impl WalletZKey {
             hdkey_num: Some(hdkey_num),
             enc_key: None,
             nonce: None,
+            diversifiers: vec![],
+            change_diversifiers: vec![],
+            change_address_diversifiers: HashSet::new(),
         }
     }
 
@@ -85,6 +101,9 @@ impl WalletZKey {
             hdkey_num: None,
             enc_key: None,
             nonce: None,
+            diversifiers: vec![],
+            change_diversifiers: vec![],
+            change_address_diversifiers: HashSet::new(),
         }
     }
 
@@ -100,6 +119,9 @@ impl WalletZKey {
             hdkey_num: None,
             enc_key: None,
             nonce: None,
+            diversifiers: vec![],
+            change_diversifiers: vec![],
+            change_address_diversifiers: HashSet::new(),
         }
     }
 
@@ -107,8 +129,59 @@ impl WalletZKey {
         self.extsk.is_some() || self.enc_key.is_some() || self.hdkey_num.is_some()
     }
 
+    /// Derive the next diversified address of this key, after the default address and any diversified addresses
+    /// created before. The diversifier index is remembered, so the address can be listed later.
+    pub fn new_diversified_address(&mut self) -> Option<(DiversifierIndex, PaymentAddress)> {
+        let (index, address) = self.next_diversified_address()?;
+        self.diversifiers.push(index);
+
+        Some((index, address))
+    }
+
+    /// The diversified address `new_diversified_address` would derive next, without recording it
+    pub fn next_diversified_address(&self) -> Option<(DiversifierIndex, PaymentAddress)> {
+        let mut next = self
+            .diversifiers
+            .last()
+            .copied()
+            .unwrap_or_else(|| self.extfvk.default_address().0);
+        next.increment().ok()?;
+
+        // Not every diversifier index gives a valid address, so search from `next` for the first one that does
+        self.extfvk.find_address(next)
+    }
+
+    /// Derive the next diversified address of this key to receive the change of a transaction. It is remembered as
+    /// a change address, so notes received at it can be marked as change.
+    pub fn new_change_address(&mut self) -> Option<PaymentAddress> {
+        let (index, address) = self.new_diversified_address()?;
+        self.change_diversifiers.push(index);
+        self.change_address_diversifiers.insert(address.diversifier().0);
+
+        Some(address)
+    }
+
+    /// Whether `address` is one of the change addresses created from this key
+    pub fn is_change_address(&self, address: &PaymentAddress) -> bool {
+        if !self.change_address_diversifiers.contains(&address.diversifier().0) {
+            return false;
+        }
+
+        // Another key can have an address with the same diversifier
+        let ivk = self.extfvk.fvk.vk.ivk();
+        ivk.to_payment_address(*address.diversifier()).as_ref() == Some(address)
+    }
+
+    /// All the diversified addresses created from this key, with their diversifier indexes
+    pub fn diversified_addresses(&self) -> Vec<(DiversifierIndex, PaymentAddress)> {
+        self.diversifiers
+            .iter()
+            .filter_map(|d| self.extfvk.find_address(*d))
+            .collect()
+    }
+
     fn serialized_version() -> u8 {
-        return 1;
+        return 3;
     }
 
     pub fn read<R: Read>(mut inp: R) -> io::Result<Self> {
@@ -136,6 +209,30 @@ impl WalletZKey {
         let enc_key = Optional::read(&mut inp, |r| Vector::read(r, |r| r.read_u8()))?;
         let nonce = Optional::read(&mut inp, |r| Vector::read(r, |r| r.read_u8()))?;
 
+        let read_diversifiers = |r: &mut R| {
+            Vector::read(r, |r| {
+                let mut index = [0u8; 11];
+                r.read_exact(&mut index)?;
+                Ok(DiversifierIndex(index))
+            })
+        };
+
+        let diversifiers = if version <= 1 {
+            vec![]
+        } else {
+            read_diversifiers(&mut inp)?
+        };
+        let change_diversifiers = if version <= 2 {
+            vec![]
+        } else {
+            read_diversifiers(&mut inp)?
+        };
+        let change_address_diversifiers = change_diversifiers
+            .iter()
+            .filter_map(|d| extfvk.find_address(*d))
+            .map(|(_, address)| address.diversifier().0)
+            .collect();
+
         Ok(WalletZKey {
             keytype,
             locked,
@@ -145,6 +242,9 @@ impl WalletZKey {
             hdkey_num,
             enc_key,
             nonce,
+            diversifiers,
+            change_diversifiers,
+            change_address_diversifiers,
         })
     }
 
@@ -171,7 +271,10 @@ impl WalletZKey {
         // Write nonce
         Optional::write(&mut out, self.nonce.as_ref(), |o, v| {
             Vector::write(o, &v[..], |o, n| o.write_u8(*n))
-        })
+        })?;
+
+        Vector::write(&mut out, &self.diversifiers, |o, d| o.write_all(&d.0))?;
+        Vector::write(&mut out, &self.change_diversifiers, |o, d| o.write_all(&d.0))
     }
 
     pub fn lock(&mut self) -> io::Result<()> {
@@ -319,18 +422,25 @@ pub mod tests {
     use zcash_client_backend::encoding::{
         decode_extended_full_viewing_key, decode_extended_spending_key, encode_payment_address,
     };
+    use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
 
     use super::WalletZKey;
-    use crate::lightclient::lightclient_config::{LightClientConfig, UnitTestNetwork};
+    use crate::grpc_connector::server_list::ServerList;
+    use crate::lightclient::lightclient_config::{LightClientConfig, UnitTestNetwork, DEFAULT_SYNC_SAVE_INTERVAL};
 
     fn get_config() -> LightClientConfig<UnitTestNetwork> {
         LightClientConfig {
-            server: "0.0.0.0:0".parse().unwrap(),
+            servers: ServerList::new(vec!["0.0.0.0:0".parse().unwrap()]),
+            cross_check_servers: 0,
             chain_name: "zs".to_string(),
             monitor_mempool: false,
             sapling_activation_height: 0,
             anchor_offset: [0u32; 5],
             data_dir: None,
+            block_cache_size: None,
+            sync_save_interval: DEFAULT_SYNC_SAVE_INTERVAL,
+            sync_memory_budget: 0,
+            checkpoints: vec![],
             params: UnitTestNetwork,
         }
     }
@@ -365,6 +475,59 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_diversified_addresses() {
+        let config = get_config();
+
+        // Priv Key's address is "zs1fxgluwznkzm52ux7jkf4st5znwzqay8zyz4cydnyegt2rh9uhr9458z0nk62fdsssx0cqhy6lyv"
+        let privkey = "secret-extended-key-main1q0p44m9zqqqqpqyxfvy5w2vq6ahvxyrwsk2w4h2zleun4cft4llmnsjlv77lhuuknv6x9jgu5g2clf3xq0wz9axxxq8klvv462r5pa32gjuj5uhxnvps6wsrdg6xll05unwks8qpgp4psmvy5e428uxaggn4l29duk82k3sv3njktaaj453fdmfmj2fup8rls4egqxqtj2p5a3yt4070khn99vzxj5ag5qjngc4v2kq0ctl9q2rpc2phu4p3e26egu9w88mchjf83sqgh3cev";
+
+        let esk = decode_extended_spending_key(config.hrp_sapling_private_key(), privkey)
+            .unwrap()
+            .unwrap();
+        let mut wzk = WalletZKey::new_imported_sk(esk);
+        assert!(wzk.diversified_addresses().is_empty());
+
+        let (default_index, _) = wzk.extfvk.default_address();
+        let (index1, addr1) = wzk.new_diversified_address().unwrap();
+        let (index2, addr2) = wzk.new_diversified_address().unwrap();
+
+        // Each address is new, and comes after the previous one
+        assert!(index1.0.iter().rev().cmp(default_index.0.iter().rev()).is_gt());
+        assert!(index2.0.iter().rev().cmp(index1.0.iter().rev()).is_gt());
+        assert_ne!(addr1, wzk.zaddress);
+        assert_ne!(addr1, addr2);
+
+        // They all belong to the same key
+        let ivk = wzk.extfvk.fvk.vk.ivk();
+        assert_eq!(ivk.to_payment_address(*addr1.diversifier()), Some(addr1.clone()));
+        assert_eq!(ivk.to_payment_address(*addr2.diversifier()), Some(addr2.clone()));
+
+        // Change addresses are diversified addresses too, but are remembered as change
+        let addr3 = wzk.new_change_address().unwrap();
+        assert_eq!(ivk.to_payment_address(*addr3.diversifier()), Some(addr3.clone()));
+        assert!(wzk.is_change_address(&addr3));
+        assert!(!wzk.is_change_address(&addr1));
+        assert!(!wzk.is_change_address(&wzk.zaddress));
+
+        // Another key's address with the same diversifier isn't
+        let other = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        if let Some(other_addr) = other.fvk.vk.ivk().to_payment_address(*addr3.diversifier()) {
+            assert!(!wzk.is_change_address(&other_addr));
+        }
+
+        // The diversifier indexes are saved with the key
+        let mut v: Vec<u8> = vec![];
+        wzk.write(&mut v).unwrap();
+        let wzk2 = WalletZKey::read(&v[..]).unwrap();
+        assert_eq!(wzk, wzk2);
+        assert!(wzk2.is_change_address(&addr3));
+        let addresses = wzk2.diversified_addresses();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[..2], [(index1, addr1), (index2, addr2)]);
+        assert_eq!(addresses[2].1, addr3);
+    }
+
     #[test]
     fn test_encrypt_decrypt_sk() {
         let config = get_config();
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+
+use zcash_client_backend::address::RecipientAddress;
+use zcash_primitives::{consensus, memo::MemoBytes};
+
+use super::unified;
+
+// ZIP-321 payment request URIs, eg.
+// zcash:zs1...?amount=1.5&memo=SGVsbG8&label=Coffee
+// zcash:?address=zs1...&amount=1&address.1=t1...&amount.1=0.25
+
+const SCHEME: &str = "zcash:";
+const COIN: u64 = 100_000_000;
+pub(crate) const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+/// A single payment in a payment request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payment {
+    pub address: String,
+    pub amount: u64,
+    pub memo: Option<MemoBytes>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+impl Payment {
+    /// The memo in the format expected by `send_to_address`, i.e., the text if it is plain text,
+    /// or "0x" followed by the hex encoded memo bytes otherwise
+    pub fn memo_string(&self) -> Option<String> {
+        self.memo.as_ref().map(|m| {
+            let bytes = trim_memo(m);
+            match String::from_utf8(bytes.to_vec()) {
+                Ok(s) if !s.to_lowercase().starts_with("0x") => s,
+                _ => format!("0x{}", hex::encode(bytes)),
+            }
+        })
+    }
+}
+
+pub fn is_payment_request(s: &str) -> bool {
+    s.get(..SCHEME.len())
+        .map(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+        .unwrap_or(false)
+}
+
+fn trim_memo(memo: &MemoBytes) -> &[u8] {
+    let bytes = memo.as_slice();
+    let len = bytes.iter().rposition(|b| *b != 0).map(|p| p + 1).unwrap_or(0);
+
+    &bytes[..len]
+}
+
+pub(crate) fn parse_amount(s: &str) -> Result<u64, String> {
+    let (whole, frac) = match s.find('.') {
+        Some(pos) => (&s[..pos], &s[pos + 1..]),
+        None => (s, ""),
+    };
+
+    if whole.is_empty() || !whole.chars().all(|c| c.is_ascii_digit()) || !frac.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid amount '{}'", s));
+    }
+
+    if frac.len() > 8 {
+        return Err(format!("Amount '{}' has more than 8 decimal places", s));
+    }
+
+    let whole = whole.parse::<u64>().map_err(|_| format!("Invalid amount '{}'", s))?;
+    let frac = format!("{:0<8}", frac).parse::<u64>().unwrap();
+
+    whole
+        .checked_mul(COIN)
+        .and_then(|w| w.checked_add(frac))
+        .filter(|a| *a <= MAX_MONEY)
+        .ok_or(format!("Amount '{}' is too large", s))
+}
+
+fn format_amount(zats: u64) -> String {
+    let frac = format!("{:08}", zats % COIN);
+    let frac = frac.trim_end_matches('0');
+
+    if frac.is_empty() {
+        format!("{}", zats / COIN)
+    } else {
+        format!("{}.{}", zats / COIN, frac)
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s
+                .get(i + 1..i + 3)
+                .ok_or(format!("Invalid percent encoding in '{}'", s))?;
+            let b = u8::from_str_radix(hex, 16).map_err(|_| format!("Invalid percent encoding in '{}'", s))?;
+            out.push(b);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(out).map_err(|_| format!("Invalid UTF-8 in '{}'", s))
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Split a param name like "amount.2" into ("amount", 2). Indices can't have leading zeros
+fn parse_param_name(name: &str) -> Result<(&str, u32), String> {
+    match name.find('.') {
+        None => Ok((name, 0)),
+        Some(pos) => {
+            let idx = &name[pos + 1..];
+            if idx.is_empty() || idx.len() > 4 || idx.starts_with('0') || !idx.chars().all(|c| c.is_ascii_digit()) {
+                return Err(format!("Invalid parameter index in '{}'", name));
+            }
+
+            Ok((&name[..pos], idx.parse::<u32>().unwrap()))
+        }
+    }
+}
+
+#[derive(Default)]
+struct PartialPayment {
+    address: Option<String>,
+    amount: Option<u64>,
+    memo: Option<MemoBytes>,
+    label: Option<String>,
+    message: Option<String>,
+}
+
+fn set_once<T>(field: &mut Option<T>, value: T, name: &str) -> Result<(), String> {
+    if field.is_some() {
+        return Err(format!("Duplicate parameter '{}'", name));
+    }
+
+    *field = Some(value);
+    Ok(())
+}
+
+/// Parse a ZIP-321 payment request URI into its payments, validating every address against `params`
+pub fn parse_uri<P: consensus::Parameters>(params: &P, uri: &str) -> Result<Vec<Payment>, String> {
+    if !is_payment_request(uri) {
+        return Err(format!("Not a zcash payment request: '{}'", uri));
+    }
+
+    let rest = &uri[SCHEME.len()..];
+    let (path, query) = match rest.find('?') {
+        Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+        None => (rest, ""),
+    };
+
+    let mut payments: BTreeMap<u32, PartialPayment> = BTreeMap::new();
+    if !path.is_empty() {
+        payments.entry(0).or_default().address = Some(percent_decode(path)?);
+    }
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(pos) => (&param[..pos], &param[pos + 1..]),
+            None => return Err(format!("Parameter '{}' has no value", param)),
+        };
+
+        let (key, idx) = parse_param_name(name)?;
+        let payment = payments.entry(idx).or_default();
+
+        match key {
+            "address" => set_once(&mut payment.address, percent_decode(value)?, name)?,
+            "amount" => set_once(&mut payment.amount, parse_amount(value)?, name)?,
+            "memo" => {
+                let bytes = base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+                    .map_err(|e| format!("Invalid memo in '{}': {}", name, e))?;
+                let memo = MemoBytes::from_bytes(&bytes).map_err(|_| format!("Memo in '{}' is too long", name))?;
+                set_once(&mut payment.memo, memo, name)?
+            }
+            "label" => set_once(&mut payment.label, percent_decode(value)?, name)?,
+            "message" => set_once(&mut payment.message, percent_decode(value)?, name)?,
+            k if k.starts_with("req-") => return Err(format!("Unsupported required parameter '{}'", name)),
+            // Unknown optional parameters are ignored
+            _ => {}
+        }
+    }
+
+    if payments.is_empty() {
+        return Err("Payment request has no payments".to_string());
+    }
+
+    payments
+        .into_iter()
+        .map(|(idx, p)| {
+            let address = p.address.ok_or(format!("Payment {} has no address", idx))?;
+            // The payer could choose the amount, but a send always needs one
+            let amount = p.amount.ok_or(format!("Payment {} has no amount", idx))?;
+
+            match unified::decode_recipient(params, &address) {
+                Ok(RecipientAddress::Shielded(_)) => {}
+                Ok(RecipientAddress::Transparent(_)) => {
+                    if p.memo.is_some() {
+                        return Err(format!("Can't send a memo to the non-shielded address {}", address));
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+
+            Ok(Payment {
+                address,
+                amount,
+                memo: p.memo,
+                label: p.label,
+                message: p.message,
+            })
+        })
+        .collect()
+}
+
+/// Encode the payments as a ZIP-321 payment request URI
+pub fn to_uri(payments: &[Payment]) -> String {
+    let mut params = vec![];
+
+    let path = if payments.len() == 1 {
+        percent_encode(&payments[0].address)
+    } else {
+        String::new()
+    };
+
+    for (i, p) in payments.iter().enumerate() {
+        let suffix = if i == 0 { String::new() } else { format!(".{}", i) };
+
+        if payments.len() > 1 {
+            params.push(format!("address{}={}", suffix, percent_encode(&p.address)));
+        }
+        if p.amount > 0 {
+            params.push(format!("amount{}={}", suffix, format_amount(p.amount)));
+        }
+        if let Some(memo) = &p.memo {
+            params.push(format!(
+                "memo{}={}",
+                suffix,
+                base64::encode_config(trim_memo(memo), base64::URL_SAFE_NO_PAD)
+            ));
+        }
+        if let Some(label) = &p.label {
+            params.push(format!("label{}={}", suffix, percent_encode(label)));
+        }
+        if let Some(message) = &p.message {
+            params.push(format!("message{}={}", suffix, percent_encode(message)));
+        }
+    }
+
+    if params.is_empty() {
+        format!("{}{}", SCHEME, path)
+    } else {
+        format!("{}{}?{}", SCHEME, path, params.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zcash_primitives::{consensus::MainNetwork, memo::MemoBytes};
+
+    use super::{format_amount, parse_amount, parse_uri, to_uri, Payment};
+
+    const ZADDR: &str = "zs1va5902apnzlhdu0pw9r9q7ca8s4vnsrp2alr6xndt69jnepn2v2qrj9vg3wfcnjyks5pg65g9dc";
+    const TADDR: &str = "t1NoS6ZgaUTpmjkge2cVpXGcySasdYDrXqh";
+
+    #[test]
+    fn amounts() {
+        assert_eq!(parse_amount("1").unwrap(), 100_000_000);
+        assert_eq!(parse_amount("0.0001").unwrap(), 10_000);
+        assert_eq!(parse_amount("1.23456789").unwrap(), 123_456_789);
+        assert!(parse_amount("1.234567891").is_err());
+        assert!(parse_amount(".5").is_err());
+        assert!(parse_amount("1e3").is_err());
+        assert!(parse_amount("21000001").is_err());
+
+        assert_eq!(format_amount(100_000_000), "1");
+        assert_eq!(format_amount(10_000), "0.0001");
+        assert_eq!(format_amount(123_456_789), "1.23456789");
+    }
+
+    #[test]
+    fn single_payment() {
+        let uri = format!("zcash:{}?amount=1.5&memo=SGVsbG8&label=Coffee%20shop", ZADDR);
+        let payments = parse_uri(&MainNetwork, &uri).unwrap();
+
+        assert_eq!(payments.len(), 1);
+        assert_eq!(payments[0].address, ZADDR);
+        assert_eq!(payments[0].amount, 150_000_000);
+        assert_eq!(payments[0].memo_string(), Some("Hello".to_string()));
+        assert_eq!(payments[0].label, Some("Coffee shop".to_string()));
+    }
+
+    #[test]
+    fn multiple_payments() {
+        let uri = format!("zcash:?address={}&amount=1&address.1={}&amount.1=0.25", ZADDR, TADDR);
+        let payments = parse_uri(&MainNetwork, &uri).unwrap();
+
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].address, ZADDR);
+        assert_eq!(payments[0].amount, 100_000_000);
+        assert_eq!(payments[1].address, TADDR);
+        assert_eq!(payments[1].amount, 25_000_000);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        // Memo to a t-address
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?amount=1&memo=SGVsbG8", TADDR)).is_err());
+        // Bad address
+        assert!(parse_uri(&MainNetwork, "zcash:notanaddress?amount=1").is_err());
+        // Duplicate params
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?amount=1&amount=2", ZADDR)).is_err());
+        // Address in both the path and the params
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?address={}", ZADDR, ZADDR)).is_err());
+        // Missing address for an index
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?amount=1&amount.1=1", ZADDR)).is_err());
+        // No amount
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}", ZADDR)).is_err());
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?memo=SGVsbG8", ZADDR)).is_err());
+        // Leading zero in index
+        assert!(parse_uri(&MainNetwork, &format!("zcash:?address.01={}", ZADDR)).is_err());
+        // Required params we don't understand
+        assert!(parse_uri(&MainNetwork, &format!("zcash:{}?req-foo=bar", ZADDR)).is_err());
+        // Not a zcash uri
+        assert!(parse_uri(&MainNetwork, &format!("bitcoin:{}", ZADDR)).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let payments = vec![
+            Payment {
+                address: ZADDR.to_string(),
+                amount: 123_000,
+                memo: Some(MemoBytes::from_bytes("Thanks!".as_bytes()).unwrap()),
+                label: Some("Invoice #1".to_string()),
+                message: None,
+            },
+            Payment {
+                address: TADDR.to_string(),
+                amount: 100_000_000,
+                memo: None,
+                label: None,
+                message: Some("Tip & thanks".to_string()),
+            },
+        ];
+
+        let uri = to_uri(&payments);
+        assert_eq!(parse_uri(&MainNetwork, &uri).unwrap(), payments);
+
+        let uri = to_uri(&payments[..1]);
+        assert!(uri.starts_with(&format!("zcash:{}?", ZADDR)));
+        assert_eq!(parse_uri(&MainNetwork, &uri).unwrap(), payments[..1].to_vec());
+    }
+}